@@ -0,0 +1,305 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::collections::{ BTreeMap, HashMap };
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::{ Path, PathBuf };
+use chrono::prelude::*;
+use parser::{ OpenConnection };
+
+fn default_max_entries() -> usize {
+    10_000
+}
+
+// Remembers which remote ends each process has connected with, so a first
+// connection with one can be flagged as novel.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BaselineConfig {
+    #[serde(default)]
+    pub enabled : bool,
+    // Past this many pairs the least recently seen are forgotten.
+    #[serde(default = "default_max_entries")]
+    pub max_entries : usize,
+}
+
+impl Default for BaselineConfig {
+    fn default() -> BaselineConfig {
+        BaselineConfig {
+            enabled: false,
+            max_entries: default_max_entries(),
+        }
+    }
+}
+
+// The destination is the remote end, named as it was when the baseline file
+// was first written.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    process : String,
+    destination : Ipv4Addr,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Seen {
+    process : String,
+    destination : Ipv4Addr,
+    first_seen : DateTime<Utc>,
+    last_seen : DateTime<Utc>,
+}
+
+struct Entry {
+    first_seen : DateTime<Utc>,
+    last_seen : DateTime<Utc>,
+    used : u64,
+}
+
+// The (process, destination) pairs seen so far, kept in the data directory
+// between runs.
+pub struct Baseline {
+    enabled : bool,
+    max_entries : usize,
+    file : Option<PathBuf>,
+    entries : HashMap<Key, Entry>,
+    // The keys from least to most recently seen.
+    order : BTreeMap<u64, Key>,
+    next : u64,
+}
+
+// The baseline is only saved when there is a data directory.
+pub fn baseline_file(directory: &Option<String>) -> Option<PathBuf> {
+    directory.as_ref().map(|directory| Path::new(directory).join("baseline.json"))
+}
+
+fn load(file: &Path) -> Vec<Seen> {
+    let contents = match fs::read_to_string(file) {
+        Ok(x) => x,
+        Err(_err) => return Vec::new(),
+    };
+
+    match serde_json::from_str(&contents) {
+        Ok(x) => x,
+        Err(err) => {
+            warn!("unable to parse the saved baseline: {}", err);
+            Vec::new()
+        }
+    }
+}
+
+impl Baseline {
+    pub fn new(config: &BaselineConfig, file: Option<PathBuf>) -> Baseline {
+        let mut baseline = Baseline {
+            enabled: config.enabled,
+            max_entries: config.max_entries.max(1),
+            file,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next: 0,
+        };
+
+        if let Some(file) = baseline.file.clone().filter(|_| baseline.enabled) {
+            // Saved least recently seen first, so the order comes back too.
+            for seen in load(&file) {
+                baseline.insert(Key { process: seen.process, destination: seen.destination }, seen.first_seen, seen.last_seen);
+            }
+            debug!("loaded {} baseline entries", baseline.entries.len());
+        }
+
+        baseline
+    }
+
+    fn insert(&mut self, key: Key, first_seen: DateTime<Utc>, last_seen: DateTime<Utc>) {
+        if let Some(entry) = self.entries.remove(&key) {
+            self.order.remove(&entry.used);
+        }
+
+        while self.entries.len() >= self.max_entries {
+            let oldest = match self.order.keys().next() {
+                Some(x) => *x,
+                None => break,
+            };
+            if let Some(evicted) = self.order.remove(&oldest) {
+                self.entries.remove(&evicted);
+            }
+        }
+
+        let used = self.next;
+        self.next += 1;
+        self.order.insert(used, key.clone());
+        self.entries.insert(key, Entry { first_seen, last_seen, used });
+    }
+
+    // Whether the process has never been seen with the remote end before,
+    // None when the baseline is off or the process is unknown. For an
+    // accepted connection the remote end is the source, when neither end is
+    // this host it is the destination.
+    pub fn observe(&mut self, connection: &OpenConnection, now: DateTime<Utc>) -> Option<bool> {
+        if !self.enabled {
+            return None;
+        }
+
+        let details = connection.program_details.as_ref()?;
+        let key = Key { process: details.process_name.clone(), destination: connection.remote_address.unwrap_or(connection.destination) };
+        let (novel, first_seen) = match self.entries.get(&key) {
+            Some(entry) => (false, entry.first_seen),
+            None => (true, now),
+        };
+
+        self.insert(key, first_seen, now);
+        Some(novel)
+    }
+
+    // Writes the baseline to the data directory, through a temporary file so
+    // a crash part way through doesn't lose the last one.
+    pub fn save(&self) -> Result<(), String> {
+        let file = match self.file {
+            Some(ref x) if self.enabled => x,
+            _ => return Ok(()),
+        };
+
+        let seen : Vec<Seen> = self.order.values()
+            .filter_map(|key| self.entries.get(key).map(|entry| Seen {
+                process: key.process.clone(),
+                destination: key.destination,
+                first_seen: entry.first_seen,
+                last_seen: entry.last_seen,
+            }))
+            .collect();
+
+        let contents = match serde_json::to_string(&seen) {
+            Ok(x) => x,
+            Err(err) => return Err(format!("unable to serialize the baseline: {}", err)),
+        };
+
+        let partial = file.with_extension("json.partial");
+        if let Err(err) = fs::write(&partial, contents) {
+            return Err(format!("unable to save the baseline: {}", err));
+        }
+
+        match fs::rename(&partial, file) {
+            Ok(()) => Ok(()),
+            Err(err) => Err(format!("unable to save the baseline: {}", err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::Payload;
+    use enums::Direction;
+    use test_support::{ default_open_payload, program };
+    use tempfile::tempdir;
+    use serde_yaml;
+
+    fn connection(process : &str, destination : Ipv4Addr) -> OpenConnection {
+        match default_open_payload(40000, 443, Some(program(process))) {
+            Payload::Open(connection) => OpenConnection { destination, .. connection },
+            _ => panic!("expected an open"),
+        }
+    }
+
+    fn enabled(max_entries : usize) -> BaselineConfig {
+        BaselineConfig { enabled: true, max_entries }
+    }
+
+    #[test]
+    fn test_config() {
+        let config : BaselineConfig = serde_yaml::from_str("enabled: true").unwrap();
+        assert!(config.enabled);
+        assert_eq!(10_000, config.max_entries);
+        assert!(!BaselineConfig::default().enabled);
+    }
+
+    #[test]
+    fn test_first_connection_is_novel() {
+        let mut baseline = Baseline::new(&enabled(10), None);
+        let web = Ipv4Addr::new(93, 184, 216, 34);
+
+        assert_eq!(Some(true), baseline.observe(&connection("curl", web), Utc::now()));
+        assert_eq!(Some(false), baseline.observe(&connection("curl", web), Utc::now()));
+
+        // The same destination is still new for another process.
+        assert_eq!(Some(true), baseline.observe(&connection("wget", web), Utc::now()));
+    }
+
+    #[test]
+    fn test_accepted_connection_keyed_on_remote_end() {
+        let mut baseline = Baseline::new(&enabled(10), None);
+        let local = Ipv4Addr::new(192, 168, 0, 2);
+        let client = Ipv4Addr::new(93, 184, 216, 34);
+        let accepted = |client : Ipv4Addr| OpenConnection {
+            source: client,
+            direction: Some(Direction::Inbound),
+            local_address: Some(local),
+            remote_address: Some(client),
+            .. connection("nginx", local)
+        };
+
+        // Every client connects to the same local address, each of them is
+        // new all the same.
+        assert_eq!(Some(true), baseline.observe(&accepted(client), Utc::now()));
+        assert_eq!(Some(false), baseline.observe(&accepted(client), Utc::now()));
+        assert_eq!(Some(true), baseline.observe(&accepted(Ipv4Addr::new(8, 8, 8, 8)), Utc::now()));
+    }
+
+    #[test]
+    fn test_disabled_or_unknown_process() {
+        let web = Ipv4Addr::new(93, 184, 216, 34);
+        let mut baseline = Baseline::new(&BaselineConfig::default(), None);
+        assert_eq!(None, baseline.observe(&connection("curl", web), Utc::now()));
+
+        let mut baseline = Baseline::new(&enabled(10), None);
+        match default_open_payload(40000, 443, None) {
+            Payload::Open(connection) => assert_eq!(None, baseline.observe(&connection, Utc::now())),
+            _ => panic!("expected an open"),
+        }
+    }
+
+    #[test]
+    fn test_least_recently_seen_is_evicted() {
+        let mut baseline = Baseline::new(&enabled(2), None);
+        let first = Ipv4Addr::new(10, 0, 0, 1);
+        let second = Ipv4Addr::new(10, 0, 0, 2);
+        let third = Ipv4Addr::new(10, 0, 0, 3);
+
+        baseline.observe(&connection("curl", first), Utc::now());
+        baseline.observe(&connection("curl", second), Utc::now());
+        // Seeing the first again makes the second the oldest.
+        baseline.observe(&connection("curl", first), Utc::now());
+        baseline.observe(&connection("curl", third), Utc::now());
+
+        assert_eq!(2, baseline.entries.len());
+        assert_eq!(Some(false), baseline.observe(&connection("curl", first), Utc::now()));
+        assert_eq!(Some(true), baseline.observe(&connection("curl", second), Utc::now()));
+    }
+
+    #[test]
+    fn test_saved_between_runs() {
+        let directory = tempdir().unwrap();
+        let file = baseline_file(&Some(directory.path().to_str().unwrap().to_string()));
+        let web = Ipv4Addr::new(93, 184, 216, 34);
+        let first_seen : DateTime<Utc> = "2019-01-01T00:00:00Z".parse().unwrap();
+
+        let mut baseline = Baseline::new(&enabled(10), file.clone());
+        baseline.observe(&connection("curl", web), first_seen);
+        baseline.save().unwrap();
+
+        let mut baseline = Baseline::new(&enabled(10), file);
+        assert_eq!(Some(false), baseline.observe(&connection("curl", web), Utc::now()));
+        assert_eq!(first_seen, baseline.entries.values().next().unwrap().first_seen);
+    }
+}
@@ -21,8 +21,9 @@ extern crate simple_logger;
 #[macro_use]
 extern crate log;
 use log::Level;
+use std::process;
 use clap::{Arg, App};
-use zerotrust_track::{NoTrack};
+use zerotrust_track::{NoTrack, read_configs, override_agent};
 
 fn main() {
     let matches = App::new("ZeroTrust Tracker")
@@ -33,8 +34,10 @@ fn main() {
             .short("c")
             .long("config")
             .value_name("FILE")
-            .help("Defines a custom config file")
+            .help("Defines a custom config file, or a directory of *.yaml files. Can be given more than once, later files override earlier ones")
             .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
             .required(false)
         ).arg(Arg::with_name("v")
             .short("v")
@@ -47,28 +50,116 @@ fn main() {
             .help("Defines the data dirctory")
             .takes_value(true)
             .required(false)
+        ).arg(Arg::with_name("replay")
+            .long("replay")
+            .value_name("FILE")
+            .help("Re-sends the events in a dead-letter file through the outputs and exits")
+            .takes_value(true)
+            .required(false)
+        ).arg(Arg::with_name("oneshot")
+            .long("oneshot")
+            .help("Sends the connections currently in the conntrack table through the outputs and exits")
+            .required(false)
+        ).arg(Arg::with_name("test_output")
+            .long("test-output")
+            .value_name("NAME")
+            .help("Sends a synthetic connection through the outputs of the kind named, or all of them, reports how each went and exits")
+            .takes_value(true)
+            .required(false)
+        ).arg(Arg::with_name("dry_run")
+            .long("dry-run")
+            .help("Logs the payloads at info instead of sending them to the outputs")
+            .required(false)
+        ).arg(Arg::with_name("name")
+            .long("name")
+            .value_name("NAME")
+            .help("Sets the agent name, overriding the config and the saved name")
+            .takes_value(true)
+            .required(false)
+        ).arg(Arg::with_name("uuid")
+            .long("uuid")
+            .value_name("UUID")
+            .help("Sets the agent uuid, overriding the config and the saved uuid")
+            .takes_value(true)
+            .required(false)
         ).get_matches();
 
+    // A dry run is no use if the payloads aren't shown.
+    let dry_run = matches.is_present("dry_run");
     match matches.occurrences_of("v") {
-        0 => simple_logger::init_with_level(Level::Warn).unwrap(),
-        1 => simple_logger::init_with_level(Level::Info).unwrap(),
+        0 if !dry_run => simple_logger::init_with_level(Level::Warn).unwrap(),
+        0 | 1 => simple_logger::init_with_level(Level::Info).unwrap(),
         2 => simple_logger::init_with_level(Level::Debug).unwrap(),
         3 | _ => simple_logger::init_with_level(Level::Trace).unwrap(),
     };
 
-    let config = matches.value_of("config").unwrap_or("/etc/zerotrust/config.yaml");
+    let configs : Vec<&str> = match matches.values_of("config") {
+        Some(values) => values.collect(),
+        None => vec!("/etc/zerotrust/config.yaml"),
+    };
     let data_directory = matches.value_of("data_directory");
 
-    info!("loading config: {}", config);
+    info!("loading config: {:?}", configs);
+
+    let config = read_configs(&configs, data_directory)
+        .and_then(|config| override_agent(config, matches.value_of("name"), matches.value_of("uuid")))
+        .map(|mut config| {
+            config.dry_run |= dry_run;
+            config
+        });
 
-    let mut app = match NoTrack::from_file(&config, data_directory) {
-        Ok(app) => app,
+    let mut app = match config.and_then(NoTrack::new) {
+        Ok(app) => app.reload_from(&configs, data_directory),
         Err(err) => {
             error!("{}", err);
             return;
         },
     };
 
+    if let Some(file) = matches.value_of("replay") {
+        info!("replaying dead-letter file: {}", file);
+        if let Err(err) = app.replay(file) {
+            error!("{}", err);
+        }
+
+        return;
+    }
+
+    if let Some(name) = matches.value_of("test_output") {
+        let results = match app.test_outputs(name) {
+            Ok(x) => x,
+            Err(err) => {
+                error!("{}", err);
+                process::exit(1);
+            },
+        };
+
+        let mut failed = false;
+        for (output, result) in results {
+            match result {
+                Ok(()) => println!("{}: ok", output),
+                Err(err) => {
+                    println!("{}: failed, {}", output, err);
+                    failed = true;
+                },
+            }
+        }
+
+        if failed {
+            process::exit(1);
+        }
+
+        return;
+    }
+
+    if matches.is_present("oneshot") {
+        if let Err(err) = app.oneshot() {
+            error!("{}", err);
+        }
+
+        return;
+    }
+
     if let Err(err) = app.run() {
         error!("{}", err);
     }
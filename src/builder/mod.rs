@@ -0,0 +1,183 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use uuid::Uuid;
+use enums::{ Config };
+use error::{ Error };
+use filters::{ FiltersConfig, PreFilter, ProcessFilter };
+use outputs::{ SyslogConfig, MqttConfig, NatsConfig, PostgresConfig, LokiConfig, RedisConfig, WebhookConfig, ParquetConfig, normalize_urls };
+use NoTrack;
+
+// One output to send to, with the same settings it takes in the config file.
+#[derive(Debug)]
+pub enum OutputConfig {
+    Syslog(SyslogConfig),
+    Elasticsearch(String),
+    ZerotrustEndpoint(String),
+    Mqtt(MqttConfig),
+    Nats(NatsConfig),
+    Postgres(PostgresConfig),
+    Loki(LokiConfig),
+    Redis(RedisConfig),
+    Webhook(WebhookConfig),
+    Parquet(ParquetConfig),
+}
+
+// Connections which are never reported on, added to the filters.
+#[derive(Debug)]
+pub enum Ignore {
+    Port(u16),
+    // A network in CIDR notation, either end of the connection can be in it.
+    Network(String),
+    Process(ProcessFilter),
+    Domain(String),
+}
+
+// Makes a NoTrack without a config file. It starts from the defaults of the
+// config file, with no outputs, and is checked the same way when it is built.
+#[derive(Debug, Default)]
+pub struct NoTrackBuilder {
+    config : Config,
+}
+
+impl NoTrackBuilder {
+    pub fn new() -> NoTrackBuilder {
+        NoTrackBuilder::default()
+    }
+
+    pub fn data_directory(mut self, directory: &str) -> NoTrackBuilder {
+        self.config.directory = Some(String::from(directory));
+        self
+    }
+
+    pub fn create_directory(mut self, create: bool) -> NoTrackBuilder {
+        self.config.create_directory = create;
+        self
+    }
+
+    pub fn agent_name(mut self, name: &str) -> NoTrackBuilder {
+        self.config.name = Some(String::from(name));
+        self
+    }
+
+    pub fn agent_uuid(mut self, uuid: Uuid) -> NoTrackBuilder {
+        self.config.uuid = Some(uuid);
+        self
+    }
+
+    // Outputs are added to the ones already there, including those of the
+    // same kind.
+    pub fn add_output(mut self, output: OutputConfig) -> NoTrackBuilder {
+        let outputs = &mut self.config.outputs;
+        match output {
+            OutputConfig::Syslog(syslog) => outputs.syslog.get_or_insert_with(Vec::new).push(syslog),
+            OutputConfig::Elasticsearch(url) => outputs.elasticsearch.get_or_insert_with(Vec::new).push(url),
+            OutputConfig::ZerotrustEndpoint(url) => outputs.zerotrust_endpoint.get_or_insert_with(Vec::new).push(url),
+            OutputConfig::Mqtt(mqtt) => outputs.mqtt.get_or_insert_with(Vec::new).push(mqtt),
+            OutputConfig::Nats(nats) => outputs.nats.get_or_insert_with(Vec::new).push(nats),
+            OutputConfig::Postgres(postgres) => outputs.postgres.get_or_insert_with(Vec::new).push(postgres),
+            OutputConfig::Loki(loki) => outputs.loki.get_or_insert_with(Vec::new).push(loki),
+            OutputConfig::Redis(redis) => outputs.redis.get_or_insert_with(Vec::new).push(redis),
+            OutputConfig::Webhook(webhook) => outputs.webhook.get_or_insert_with(Vec::new).push(webhook),
+            OutputConfig::Parquet(parquet) => outputs.parquet.get_or_insert_with(Vec::new).push(parquet),
+        };
+        self
+    }
+
+    // Replaces all of the filters.
+    pub fn filters(mut self, filters: FiltersConfig) -> NoTrackBuilder {
+        self.config.filters = filters;
+        self
+    }
+
+    pub fn filter(mut self, ignore: Ignore) -> NoTrackBuilder {
+        let filters = &mut self.config.filters;
+        match ignore {
+            Ignore::Port(port) => filters.ignore_ports.push(port),
+            Ignore::Network(network) => filters.ignore_networks.push(network),
+            Ignore::Process(process) => filters.ignore_processes.push(process),
+            Ignore::Domain(domain) => filters.ignore_domains.push(domain),
+        };
+        self
+    }
+
+    // Anything the builder doesn't have a method for.
+    pub fn config<F>(mut self, change: F) -> NoTrackBuilder
+        where F: FnOnce(&mut Config)
+    {
+        change(&mut self.config);
+        self
+    }
+
+    // Fails on what would stop the agent starting from a config file, such as
+    // a data directory which doesn't exist, a network which can't be parsed or
+    // an endpoint url without a scheme.
+    pub fn build(mut self) -> Result<NoTrack, Error> {
+        normalize_urls(&mut self.config.outputs).map_err(Error::Config)?;
+        let config = ::set_directory(self.config, None)?;
+        PreFilter::new(&config.filters).map_err(Error::Config)?;
+        NoTrack::new(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use filters::{ Matching };
+
+    #[test]
+    fn test_build() {
+        let directory = tempdir().unwrap();
+        let uuid = Uuid::new_v4();
+
+        let notrack = NoTrackBuilder::new()
+            .data_directory(directory.path().to_str().unwrap())
+            .agent_name("gerald")
+            .agent_uuid(uuid)
+            .add_output(OutputConfig::Elasticsearch(String::from("http://127.0.0.1:9200")))
+            .add_output(OutputConfig::Parquet(ParquetConfig { directory: String::from(directory.path().to_str().unwrap()), rows_per_file: 1, seconds_per_file: 0 }))
+            .filter(Ignore::Port(22))
+            .filter(Ignore::Network(String::from("10.0.0.0/8")))
+            .filter(Ignore::Process(ProcessFilter { process_name: Some(String::from("ssh*")), matching: Matching::Glob, .. Default::default() }))
+            .config(|config| config.max_connections = 10)
+            .build()
+            .unwrap();
+
+        assert_eq!("gerald", notrack.identity.name);
+        assert_eq!(uuid, notrack.identity.uuid);
+        assert_eq!(directory.path().to_str().map(String::from), notrack.config.directory);
+        assert_eq!(2, notrack.outputs.len());
+        assert_eq!(vec!(22), notrack.config.filters.ignore_ports);
+        assert_eq!(10, notrack.config.max_connections);
+    }
+
+    #[test]
+    fn test_build_validates() {
+        let directory = tempdir().unwrap();
+
+        assert!(matches!(NoTrackBuilder::new().build(), Err(Error::Config(_))));
+        assert!(matches!(NoTrackBuilder::new().data_directory("/I_like_strange_things").build(), Err(Error::Config(_))));
+        assert!(matches!(NoTrackBuilder::new()
+            .data_directory(directory.path().to_str().unwrap())
+            .filter(Ignore::Network(String::from("not a network")))
+            .build(), Err(Error::Config(_))));
+        assert!(matches!(NoTrackBuilder::new()
+            .data_directory(directory.path().to_str().unwrap())
+            .add_output(OutputConfig::Elasticsearch(String::from("127.0.0.1:9200")))
+            .build(), Err(Error::Config(_))));
+    }
+}
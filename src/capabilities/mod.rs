@@ -0,0 +1,117 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::fs;
+use metrics::{ Metrics };
+
+// The capabilities the agent needs, without them it still starts but quietly
+// sees a lot less.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Capability {
+    NetAdmin,
+    SysPtrace,
+}
+
+const NEEDED : [Capability; 2] = [Capability::NetAdmin, Capability::SysPtrace];
+
+impl Capability {
+    fn bit(self) -> u32 {
+        match self {
+            Capability::NetAdmin => 12,
+            Capability::SysPtrace => 19,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::NetAdmin => "CAP_NET_ADMIN",
+            Capability::SysPtrace => "CAP_SYS_PTRACE",
+        }
+    }
+
+    fn consequence(self) -> &'static str {
+        match self {
+            Capability::NetAdmin => "conntrack can't be subscribed to, so no connections will be seen",
+            Capability::SysPtrace => "the processes of other users can't be read, so most connections will have no program details",
+        }
+    }
+}
+
+// The needed capabilities missing from the effective set in the contents of
+// /proc/<pid>/status, none when the set can't be found.
+pub fn missing(status: &str) -> Vec<Capability> {
+    let effective = status.lines()
+        .find(|line| line.starts_with("CapEff:"))
+        .and_then(|line| u64::from_str_radix(line["CapEff:".len()..].trim(), 16).ok());
+
+    match effective {
+        Some(effective) => NEEDED.iter().cloned().filter(|capability| effective & (1 << capability.bit()) == 0).collect(),
+        None => Vec::new(),
+    }
+}
+
+// Warns about each capability the agent is running without, so empty output
+// has an explanation.
+pub fn check(metrics: &Metrics) -> Vec<Capability> {
+    let status = match fs::read_to_string("/proc/self/status") {
+        Ok(x) => x,
+        Err(err) => {
+            debug!("unable to read our capabilities: {}", err);
+            return Vec::new();
+        }
+    };
+
+    let missing = missing(&status);
+    for capability in &missing {
+        warn!("running without {}, {}. Run as root or grant it with: setcap 'cap_sys_ptrace,cap_net_admin,cap_dac_read_search=+ep' <path to zerotrust-track>",
+            capability.name(), capability.consequence());
+    }
+    metrics.capabilities_missing(missing.len());
+
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(effective: &str) -> String {
+        format!("Name:\tzerotrust-track\nCapInh:\t0000000000000000\nCapPrm:\t000001ffffffffff\nCapEff:\t{}\nCapBnd:\t000001ffffffffff\n", effective)
+    }
+
+    #[test]
+    fn test_missing() {
+        assert!(missing(&status("000001ffffffffff")).is_empty());
+        assert_eq!(vec!(Capability::NetAdmin, Capability::SysPtrace), missing(&status("0000000000000000")));
+
+        // Only CAP_NET_ADMIN, as given by setcap cap_net_admin+ep.
+        assert_eq!(vec!(Capability::SysPtrace), missing(&status("0000000000001000")));
+        assert_eq!(vec!(Capability::NetAdmin), missing(&status("0000000000080000")));
+    }
+
+    #[test]
+    fn test_unreadable_status() {
+        assert!(missing("Name:\tzerotrust-track\n").is_empty());
+        assert!(missing(&status("not hex")).is_empty());
+    }
+
+    #[test]
+    fn test_check_sets_metric() {
+        let metrics = Metrics::new();
+        let missing = check(&metrics);
+        assert_eq!(missing.len(), metrics.missing_capabilities());
+    }
+}
@@ -17,6 +17,7 @@
 use std::mem::size_of;
 use std::net::Ipv4Addr;
 use std::io;
+use std::time::{ SystemTime, UNIX_EPOCH };
 
 extern crate libc;
 extern crate crslmnl as mnl;
@@ -25,12 +26,71 @@ extern crate log;
 use mnl::linux::netlink as netlink;
 use mnl::linux::netfilter::nfnetlink_conntrack as conntrack;
 use mnl::linux::netfilter::nfnetlink as nfnetlink;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{ SyncSender, TrySendError };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
 
-use enums::{Protocol, State};
+use enums::{ CloseReason, Protocol, State, StatusFlag };
+use metrics::Metrics;
 
 
 
+fn default_true() -> bool {
+    true
+}
+
+// Which conntrack event groups we subscribe to.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct ConntrackConfig {
+    #[serde(default = "default_true")]
+    pub new : bool,
+    #[serde(default = "default_true")]
+    pub destroy : bool,
+    // Updates are only counted into the update_count of the close, they
+    // aren't sent and skip the process lookups.
+    #[serde(default)]
+    pub update : bool,
+    // Logs a hex dump of every message at debug, only meant for tracking
+    // down parsing problems.
+    #[serde(default)]
+    pub raw : bool,
+    // Dumps the table when the agent starts, so the connections which are
+    // already open are sent as well as the new ones.
+    #[serde(default = "default_true")]
+    pub dump_on_start : bool,
+}
+
+impl Default for ConntrackConfig {
+    fn default() -> ConntrackConfig {
+        ConntrackConfig {
+            new: true,
+            destroy: true,
+            update: false,
+            raw: false,
+            dump_on_start: true,
+        }
+    }
+}
+
+impl ConntrackConfig {
+    pub fn groups(&self) -> u32 {
+        let mut groups = 0;
+        if self.new {
+            groups |= conntrack::NF_NETLINK_CONNTRACK_NEW;
+        }
+
+        if self.destroy {
+            groups |= conntrack::NF_NETLINK_CONNTRACK_DESTROY;
+        }
+
+        if self.update {
+            groups |= conntrack::NF_NETLINK_CONNTRACK_UPDATE;
+        }
+
+        groups
+    }
+}
+
 #[derive(Debug)]
 pub enum ProtoDetails {
     ICMP {
@@ -58,7 +118,115 @@ pub struct ConnectionDetails {
 #[derive(Debug)]
 pub struct Connection {
     pub state : State,
-    pub details : ConnectionDetails
+    pub details : ConnectionDetails,
+    pub reply : Option<ConnectionDetails>,
+    // The CTA_STATUS bits, when the message had them.
+    pub status : Option<u32>,
+    // From a dump of the table rather than an event, the connection was
+    // already open before it was seen.
+    pub pre_existing : bool,
+}
+
+const STATUS_FLAGS : [StatusFlag; 15] = [
+    StatusFlag::Expected,
+    StatusFlag::SeenReply,
+    StatusFlag::Assured,
+    StatusFlag::Confirmed,
+    StatusFlag::SrcNat,
+    StatusFlag::DstNat,
+    StatusFlag::SeqAdjust,
+    StatusFlag::SrcNatDone,
+    StatusFlag::DstNatDone,
+    StatusFlag::Dying,
+    StatusFlag::FixedTimeout,
+    StatusFlag::Template,
+    StatusFlag::Untracked,
+    StatusFlag::Helper,
+    StatusFlag::Offload,
+];
+
+// The flags which are set, bits newer kernels add are left out.
+pub fn status_flags(status : u32) -> Vec<StatusFlag> {
+    STATUS_FLAGS.iter().enumerate()
+        .filter(|(bit, _)| status & (1 << bit) != 0)
+        .map(|(_, flag)| *flag)
+        .collect()
+}
+
+pub fn close_reason(status : u32) -> CloseReason {
+    let flags = status_flags(status);
+    if flags.contains(&StatusFlag::Assured) {
+        CloseReason::Assured
+    } else if flags.contains(&StatusFlag::SeenReply) {
+        CloseReason::Replied
+    } else {
+        CloseReason::Unreplied
+    }
+}
+
+// Hands connections over to the parser without ever blocking the conntrack
+// thread, if the channel is full the connection is dropped and counted.
+pub struct Dispatcher {
+    tx : SyncSender<Connection>,
+    metrics : Arc<Metrics>,
+    blocking : bool,
+    raw : bool,
+}
+
+impl Dispatcher {
+    pub fn new(tx : SyncSender<Connection>, metrics : Arc<Metrics>) -> Dispatcher {
+        Dispatcher {
+            tx,
+            metrics,
+            blocking: false,
+            raw: false,
+        }
+    }
+
+    // Waits for room in the channel instead of dropping, used when dumping the
+    // table as nothing is lost by going slower.
+    pub fn blocking(tx : SyncSender<Connection>, metrics : Arc<Metrics>) -> Dispatcher {
+        Dispatcher {
+            tx,
+            metrics,
+            blocking: true,
+            raw: false,
+        }
+    }
+
+    pub fn with_raw(self, raw : bool) -> Dispatcher {
+        Dispatcher {
+            raw,
+            .. self
+        }
+    }
+
+    // The depth is counted before the send, as the receiving end can take
+    // the connection off the channel before the send has returned.
+    pub fn dispatch(&self, connection : Connection) {
+        sampled_debug!("sending {:?} over channel", connection);
+        self.metrics.channel_push();
+        if self.blocking {
+            if let Err(err) = self.tx.send(connection) {
+                self.metrics.channel_pop();
+                error!("unable to send connection details {:?}", err);
+            }
+            return;
+        }
+
+        match self.tx.try_send(connection) {
+            Ok(()) => (),
+            Err(TrySendError::Full(connection)) => {
+                self.metrics.channel_pop();
+                self.metrics.channel_dropped();
+                warn!("channel is full, dropping {:?}", connection);
+            },
+            Err(TrySendError::Disconnected(connection)) => {
+                self.metrics.channel_pop();
+                error!("unable to send connection details {:?}", connection);
+            },
+        }
+    }
 }
 
 pub struct Conntrack<'a> {
@@ -66,29 +234,109 @@ pub struct Conntrack<'a> {
 }
 
 impl<'a> Conntrack<'a> {
-    pub fn new() -> Result<Conntrack<'a>, io::Error> {
+    pub fn new(config : &ConntrackConfig) -> Result<Conntrack<'a>, io::Error> {
+        let nl =  mnl::Socket::open(netlink::Family::NETFILTER)?;
+        nl.bind(config.groups(), mnl::SOCKET_AUTOPID)?;
+
+        Ok(Conntrack {
+            socket: nl,
+        })
+    }
+
+    // A socket which isn't subscribed to any events, used to dump the table.
+    pub fn oneshot() -> Result<Conntrack<'a>, io::Error> {
         let nl =  mnl::Socket::open(netlink::Family::NETFILTER)?;
-        nl.bind(conntrack::NF_NETLINK_CONNTRACK_NEW | conntrack::NF_NETLINK_CONNTRACK_DESTROY, mnl::SOCKET_AUTOPID)?;
+        nl.bind(0, mnl::SOCKET_AUTOPID)?;
 
         Ok(Conntrack {
             socket: nl,
         })
     }
 
-    pub fn start(&mut self, tx: &mut Sender<Connection>) {
+    // Asks conntrack for every connection currently in its table and sends
+    // them to the dispatcher as new connections.
+    pub fn dump(&mut self, dispatcher: &mut Dispatcher) -> Result<(), io::Error> {
+        let mut buf = vec![0u8; mnl::SOCKET_BUFFER_SIZE()];
+        let seq = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(x) => x.as_secs() as u32,
+            Err(_) => 0,
+        };
+
+        let len = build_dump_request(&mut buf, seq)?;
+        self.socket.sendto(&buf[0..len])?;
+        let portid = self.socket.portid();
+
+        loop {
+            let recv = self.socket.recvfrom(&mut buf)?;
+            sampled_trace!("received dump update");
+
+            if let mnl::CbRet::STOP = mnl::cb_run(&buf[0..recv], seq, portid, Some(process_dump_callback), dispatcher)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn start(&mut self, dispatcher: &mut Dispatcher) {
         let mut buf = vec![0u8; mnl::SOCKET_BUFFER_SIZE()];
         loop {
             let recv = self.socket.recvfrom(&mut buf)
                 .unwrap_or_else(|errno| panic!("failed to recieve from conntrack! {}", errno));
-            trace!("received connection update");
+            sampled_trace!("received connection update");
 
-            mnl::cb_run(&buf[0..recv], 0, 0, Some(process_data_callback), tx)
+            mnl::cb_run(&buf[0..recv], 0, 0, Some(process_data_callback), dispatcher)
                 .unwrap_or_else(|errno| panic!("failed to invoke callback! {}", errno));
         }
     }
 
 }
 
+// Set by SIGUSR2, the main loop picks it up and dumps the table into the
+// running pipeline.
+static DUMP_REQUESTED : AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_dump_signal(_signal : libc::c_int) {
+    request_dump();
+}
+
+// Makes SIGUSR2 ask for a dump of the conntrack table.
+pub fn handle_dump_signal() -> Result<(), io::Error> {
+    let handler = on_dump_signal as extern "C" fn(libc::c_int);
+    match unsafe { libc::signal(libc::SIGUSR2, handler as libc::sighandler_t) } {
+        libc::SIG_ERR => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+pub fn request_dump() {
+    DUMP_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+// Whether a dump was asked for since this was last called.
+pub fn dump_requested() -> bool {
+    DUMP_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+// Lower case hex of every byte, two characters each.
+fn hex_dump(buf: &[u8]) -> String {
+    buf.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Writes a conntrack GET request with the dump flag into buf, returning its length.
+fn build_dump_request(buf: &mut [u8], seq: u32) -> Result<usize, io::Error> {
+    let mut nlh = mnl::Nlmsg::new(buf)?;
+    *nlh.nlmsg_type = (nfnetlink::NFNL_SUBSYS_CTNETLINK << 8) | conntrack::IPCTNL_MSG_CT_GET;
+    *nlh.nlmsg_flags = netlink::NLM_F_REQUEST | netlink::NLM_F_DUMP;
+    *nlh.nlmsg_seq = seq;
+
+    let nfh = nlh.put_sized_header::<nfnetlink::Nfgenmsg>()?;
+    nfh.nfgen_family = libc::AF_INET as u8;
+    nfh.version = nfnetlink::NFNETLINK_V0;
+    nfh.res_id = 0;
+
+    Ok(*nlh.nlmsg_len as usize)
+}
 
 //***********************************************************************************************************************************************
 // Call Backs
@@ -177,19 +425,21 @@ fn process_tuple_callback<'a>(attr: &'a mnl::Attr, tb: &mut [Option<&'a mnl::Att
 
 #[allow(dead_code)]
 fn process_attributes_callback<'a>(attr: &'a mnl::Attr, buf: &mut [Option<&'a mnl::Attr>]) -> mnl::CbRet {
-    if let Err(_) = attr.type_valid(conntrack::CTA_MAX as u16) {
+    if let Err(_) = attr.type_valid(conntrack::CTA_MAX) {
         return mnl::CbRet::OK;
     }
 
     let attribute_type = attr.atype();
     match attribute_type {
-        n if n == conntrack::CtattrType::TUPLE_ORIG as u16 => {
+        n if (n == conntrack::CtattrType::TUPLE_ORIG as u16 ||
+            n == conntrack::CtattrType::TUPLE_REPLY as u16) => {
             if let Err(errno) = attr.validate(mnl::AttrDataType::NESTED) {
                 error!("unable to validate attributes {}", errno);
                 return mnl::CbRet::ERROR;
             }
         },
-        n if (n == conntrack::CtattrType::TIMEOUT as u16 ||
+        n if (n == conntrack::CtattrType::STATUS as u16 ||
+            n == conntrack::CtattrType::TIMEOUT as u16 ||
             n == conntrack::CtattrType::MARK as u16 ||
             n == conntrack::CtattrType::SECMARK as u16) => {
             if let Err(errno) = attr.validate(mnl::AttrDataType::U32) {
@@ -200,23 +450,31 @@ fn process_attributes_callback<'a>(attr: &'a mnl::Attr, buf: &mut [Option<&'a mn
         _ => {},
     }
 
-    buf[attribute_type as usize] = Some(attr);
+    // type_valid already keeps this in range, but an attribute which isn't
+    // is dropped rather than taking the conntrack thread down with it.
+    if let Some(slot) = buf.get_mut(attribute_type as usize) {
+        *slot = Some(attr);
+    }
     mnl::CbRet::OK
 }
 
+// Room for every attribute up to CTA_MAX, looked up with attribute() so an
+// unexpected type can't index past the end.
+const ATTRIBUTES : usize = conntrack::CTA_MAX as usize + 1;
 
+fn attribute<'a>(buf: &[Option<&'a mnl::Attr>], attribute_type: conntrack::CtattrType) -> Option<&'a mnl::Attr> {
+    buf.get(attribute_type as usize).cloned().flatten()
+}
 
 #[allow(dead_code)]
-fn process_data_callback(message : mnl::Nlmsg, sender: &mut Sender<Connection>) -> mnl::CbRet {
-    let mut buf: [Option<&mnl::Attr>; conntrack::CTA_MAX as usize + 1] = [None; conntrack::CTA_MAX as usize + 1];
-
-
+fn process_data_callback(message : mnl::Nlmsg, dispatcher: &mut Dispatcher) -> mnl::CbRet {
     let state : State = match *message.nlmsg_type & 0xFF {
         n if n == conntrack::CtnlMsgTypes::NEW as u16 => {
+            // Conntrack sends updates as NEW too, only without the create flag.
             if *message.nlmsg_flags & (netlink::NLM_F_CREATE) != 0 {
                 State::New
             } else {
-                State::Unknown
+                State::Update
             }
         },
         n if n == conntrack::CtnlMsgTypes::DELETE as u16 => {
@@ -225,20 +483,64 @@ fn process_data_callback(message : mnl::Nlmsg, sender: &mut Sender<Connection>)
         _ => { State::Unknown }
     };
 
-    trace!("state: {:?}", state);
+    sampled_trace!("state: {:?}", state);
+    process_message(message, state, false, dispatcher)
+}
+
+#[allow(dead_code)]
+fn process_dump_callback(message : mnl::Nlmsg, dispatcher: &mut Dispatcher) -> mnl::CbRet {
+    // Everything in a dump is a connection which is currently open.
+    process_message(message, State::New, true, dispatcher)
+}
+
+fn process_message(mut message : mnl::Nlmsg, state : State, pre_existing : bool, dispatcher: &mut Dispatcher) -> mnl::CbRet {
+    let raw = if dispatcher.raw {
+        Some(hex_dump(message.payload_bytes()))
+    } else {
+        None
+    };
+
+    let mut buf: [Option<&mnl::Attr>; ATTRIBUTES] = [None; ATTRIBUTES];
+    let parsed = message.parse(size_of::<nfnetlink::Nfgenmsg>(), process_attributes_callback, &mut buf);
+
+    // Not every message the subscription delivers is about a connection,
+    // those without an original tuple aren't and are skipped.
+    let original = match (parsed.is_ok(), attribute(&buf, conntrack::CtattrType::TUPLE_ORIG)) {
+        (true, Some(original)) => original,
+        (true, None) => {
+            sampled_trace!("conntrack message without an original tuple, skipping it");
+            return mnl::CbRet::OK;
+        },
+        (false, _) => {
+            sampled_debug!("unable to parse conntrack message, dropping it");
+            dispatcher.metrics.parse_failed();
+            return mnl::CbRet::OK;
+        },
+    };
+    let details = match extract_tuple(original) {
+        Some(details) => details,
+        None => {
+            sampled_debug!("unable to parse conntrack message, dropping it");
+            dispatcher.metrics.parse_failed();
+            return mnl::CbRet::OK;
+        },
+    };
+    let reply = attribute(&buf, conntrack::CtattrType::TUPLE_REPLY).and_then(extract_tuple);
+    let status = attribute(&buf, conntrack::CtattrType::STATUS).map(|attribute| u32::from_be(attribute.u32()));
+
+    if let Some(raw) = raw {
+        debug!("raw conntrack message type {:#06x} flags {:#06x} for {:?}: {}", *message.nlmsg_type, *message.nlmsg_flags, details, raw);
+    }
 
-    let _ = message.parse(size_of::<nfnetlink::Nfgenmsg>(), process_attributes_callback, &mut buf);
-    let details = extract_tuple(buf[conntrack::CtattrType::TUPLE_ORIG as usize].unwrap());
     let connection = Connection {
         state,
-        details
+        details,
+        reply,
+        status,
+        pre_existing,
     };
 
-    debug!("sending {:?} over channel", connection);
-    if let Err(x) = sender.send(connection) {
-        // Handle error.
-        error!("unable to send connection details {:?}", x);
-    }
+    dispatcher.dispatch(connection);
 
     mnl::CbRet::OK
 }
@@ -246,6 +548,8 @@ fn process_data_callback(message : mnl::Nlmsg, sender: &mut Sender<Connection>)
 //***********************************************************************************************************************************************
 // Extractions
 //***********************************************************************************************************************************************
+// These return None when an attribute we rely on is missing, such as for
+// families other than IPv4, rather than bringing the conntrack thread down.
 #[allow(dead_code)]
 fn extract_ip(nest: &mnl::Attr) -> (Option<Ipv4Addr>, Option<Ipv4Addr>){
     let mut buf: [Option<&mnl::Attr>; conntrack::CTA_IP_MAX as usize + 1] = [None; conntrack::CTA_IP_MAX as usize + 1];
@@ -264,12 +568,12 @@ fn extract_ip(nest: &mnl::Attr) -> (Option<Ipv4Addr>, Option<Ipv4Addr>){
     (source, destination)
 }
 #[allow(dead_code)]
-fn extract_proto(nest: &mnl::Attr) -> ProtoDetails {
+fn extract_proto(nest: &mnl::Attr) -> Option<ProtoDetails> {
     let mut tb: [Option<&mnl::Attr>; conntrack::CTA_PROTO_MAX as usize + 1] = [None; conntrack::CTA_PROTO_MAX as usize + 1];
 
     let _ = nest.parse_nested(process_proto_callback, &mut tb);
 
-    let proto = tb[conntrack::CtattrL4proto::NUM as usize].unwrap().u8();
+    let proto = tb[conntrack::CtattrL4proto::NUM as usize]?.u8();
 
     let source = match tb[conntrack::CtattrL4proto::SRC_PORT as usize] {
         None => None,
@@ -298,26 +602,276 @@ fn extract_proto(nest: &mnl::Attr) -> ProtoDetails {
     };
 
     let details = match proto {
-        0x01 => ProtoDetails::ICMP { icmp_id: icmp_id.unwrap(), icmp_type: icmp_type.unwrap(), icmp_code: icmp_code.unwrap() },
-        0x06 => ProtoDetails::IP{ protocol : Protocol::TCP , source_port : source.unwrap(), destination_port : destination.unwrap() },
-        0x11 => ProtoDetails::IP{ protocol : Protocol::UDP , source_port : source.unwrap(), destination_port : destination.unwrap() },
+        0x01 => ProtoDetails::ICMP { icmp_id: icmp_id?, icmp_type: icmp_type?, icmp_code: icmp_code? },
+        0x06 => ProtoDetails::IP{ protocol : Protocol::TCP , source_port : source?, destination_port : destination? },
+        0x11 => ProtoDetails::IP{ protocol : Protocol::UDP , source_port : source?, destination_port : destination? },
         _ => ProtoDetails::NotSupported
     };
 
-    details
+    Some(details)
 }
 
 #[allow(dead_code)]
-fn extract_tuple(nest: &mnl::Attr) -> ConnectionDetails {
+fn extract_tuple(nest: &mnl::Attr) -> Option<ConnectionDetails> {
     let mut buf: [Option<&mnl::Attr>; conntrack::CTA_TUPLE_MAX as usize + 1] = [None; conntrack::CTA_TUPLE_MAX as usize + 1];
     let _ = nest.parse_nested(process_tuple_callback, &mut buf);
 
-    let addresses = extract_ip(&buf[conntrack::CtattrTuple::IP as usize].unwrap());
-    let protocol_details = extract_proto(buf[conntrack::CtattrTuple::PROTO as usize].unwrap());
+    let addresses = extract_ip(buf[conntrack::CtattrTuple::IP as usize]?);
+    let protocol_details = extract_proto(buf[conntrack::CtattrTuple::PROTO as usize]?)?;
 
-    ConnectionDetails  {
-        source : addresses.0.unwrap(),
-        destination : addresses.1.unwrap(),
+    Some(ConnectionDetails  {
+        source : addresses.0?,
+        destination : addresses.1?,
         protocol: protocol_details
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+    use std::time::Duration;
+    use filters::PreFilter;
+    use parser::{ Parser, ParserConfig, Payload };
+    use parser::redact::Redactor;
+    use state;
+    use uuid::Uuid;
+
+    fn connection() -> Connection {
+        Connection {
+            state: State::New,
+            details: ConnectionDetails {
+                source: Ipv4Addr::new(127, 0, 0, 1),
+                destination: Ipv4Addr::new(127, 0, 0, 1),
+                protocol: ProtoDetails::IP {
+                    protocol: Protocol::TCP,
+                    source_port: 22,
+                    destination_port: 22,
+                },
+            },
+            reply: None,
+            status: None,
+            pre_existing: false,
+        }
+    }
+
+    #[test]
+    fn test_dispatch_full_channel_drops() {
+        let metrics = Arc::new(Metrics::new());
+        let (tx, rx) = sync_channel(2);
+        let dispatcher = Dispatcher::new(tx, metrics.clone());
+
+        for _ in 0..5 {
+            dispatcher.dispatch(connection());
+        }
+
+        assert_eq!(2, metrics.channel_depth());
+        assert_eq!(3, metrics.dropped_events());
+        assert_eq!(2, rx.try_iter().count());
+    }
+
+    #[test]
+    fn test_groups_default() {
+        let config = ConntrackConfig::default();
+        assert_eq!(conntrack::NF_NETLINK_CONNTRACK_NEW | conntrack::NF_NETLINK_CONNTRACK_DESTROY, config.groups());
+    }
+
+    #[test]
+    fn test_groups_selected() {
+        let config = ConntrackConfig { new: false, destroy: true, update: true, .. Default::default() };
+        assert_eq!(conntrack::NF_NETLINK_CONNTRACK_DESTROY | conntrack::NF_NETLINK_CONNTRACK_UPDATE, config.groups());
+
+        let config = ConntrackConfig { new: true, destroy: false, update: false, .. Default::default() };
+        assert_eq!(conntrack::NF_NETLINK_CONNTRACK_NEW, config.groups());
+    }
+
+    #[test]
+    fn test_build_dump_request() {
+        let mut buf = vec![0u8; 64];
+        let len = build_dump_request(&mut buf, 42).unwrap();
+
+        let nlmsg_type = u16::from_ne_bytes([buf[4], buf[5]]);
+        let nlmsg_flags = u16::from_ne_bytes([buf[6], buf[7]]);
+        let nlmsg_seq = u32::from_ne_bytes([buf[8], buf[9], buf[10], buf[11]]);
+
+        assert_eq!(20, len);
+        assert_eq!(len as u32, u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]));
+        assert_eq!((nfnetlink::NFNL_SUBSYS_CTNETLINK << 8) | conntrack::IPCTNL_MSG_CT_GET, nlmsg_type);
+        assert_eq!(netlink::NLM_F_REQUEST | netlink::NLM_F_DUMP, nlmsg_flags);
+        assert_eq!(42, nlmsg_seq);
+        assert_eq!(libc::AF_INET as u8, buf[16]);
+        assert_eq!(nfnetlink::NFNETLINK_V0, buf[17]);
+    }
+
+    #[test]
+    fn test_build_dump_request_small_buffer() {
+        let mut buf = vec![0u8; 8];
+        assert!(build_dump_request(&mut buf, 42).is_err());
+    }
+
+    #[test]
+    fn test_dump_signal() {
+        handle_dump_signal().unwrap();
+        dump_requested();
+
+        unsafe { libc::raise(libc::SIGUSR2) };
+        assert!(dump_requested());
+        // Each request is only picked up once.
+        assert!(!dump_requested());
+
+        request_dump();
+        request_dump();
+        assert!(dump_requested());
+        assert!(!dump_requested());
+    }
+
+    #[test]
+    fn test_hex_dump() {
+        assert_eq!("", hex_dump(&[]));
+        assert_eq!("0200000a0ff0ff", hex_dump(&[0x02, 0x00, 0x00, 0x0a, 0x0f, 0xf0, 0xff]));
+    }
+
+    // A conntrack message holding only a mark, as stats and expectations have
+    // no original tuple.
+    fn message_without_tuple(buf: &mut [u8]) -> usize {
+        build_dump_request(buf, 1).unwrap();
+        let mut nlh = mnl::Nlmsg::from_bytes(buf).unwrap();
+        nlh.put_u32(conntrack::CtattrType::MARK as u16, 7).unwrap();
+
+        *nlh.nlmsg_len as usize
+    }
+
+    #[test]
+    fn test_message_without_tuple_skipped() {
+        let mut buf = vec![0u8; 256];
+        let len = message_without_tuple(&mut buf);
+
+        let metrics = Arc::new(Metrics::new());
+        let (tx, rx) = sync_channel(1);
+        let mut dispatcher = Dispatcher::new(tx, metrics.clone());
+        let nlh = mnl::Nlmsg::from_bytes(&mut buf[0..len]).unwrap();
+
+        assert!(matches!(process_message(nlh, State::New, false, &mut dispatcher), mnl::CbRet::OK));
+        assert!(rx.try_recv().is_err());
+        assert_eq!(0, metrics.parse_errors());
+    }
+
+    // A TCP connection from 10.0.0.1:40000 to 10.0.0.2:22 with the status.
+    fn message_with_status(buf: &mut [u8], status: u32) -> usize {
+        build_dump_request(buf, 1).unwrap();
+        let mut nlh = mnl::Nlmsg::from_bytes(buf).unwrap();
+
+        let tuple = nlh.nest_start(conntrack::CtattrType::TUPLE_ORIG as u16).unwrap();
+        let ip = nlh.nest_start(conntrack::CtattrTuple::IP as u16).unwrap();
+        nlh.put(conntrack::CtattrIp::V4_SRC as u16, &Ipv4Addr::new(10, 0, 0, 1)).unwrap();
+        nlh.put(conntrack::CtattrIp::V4_DST as u16, &Ipv4Addr::new(10, 0, 0, 2)).unwrap();
+        nlh.nest_end(ip);
+        let proto = nlh.nest_start(conntrack::CtattrTuple::PROTO as u16).unwrap();
+        nlh.put_u8(conntrack::CtattrL4proto::NUM as u16, 6).unwrap();
+        nlh.put_u16(conntrack::CtattrL4proto::SRC_PORT as u16, 40000u16.to_be()).unwrap();
+        nlh.put_u16(conntrack::CtattrL4proto::DST_PORT as u16, 22u16.to_be()).unwrap();
+        nlh.nest_end(proto);
+        nlh.nest_end(tuple);
+        nlh.put_u32(conntrack::CtattrType::STATUS as u16, status.to_be()).unwrap();
+
+        *nlh.nlmsg_len as usize
+    }
+
+    #[test]
+    fn test_status_attribute() {
+        let mut buf = vec![0u8; 256];
+        // SEEN_REPLY, ASSURED and CONFIRMED, as a finished TCP session has.
+        let len = message_with_status(&mut buf, 0b1110);
+
+        let (tx, rx) = sync_channel(1);
+        let mut dispatcher = Dispatcher::new(tx, Arc::new(Metrics::new()));
+        let nlh = mnl::Nlmsg::from_bytes(&mut buf[0..len]).unwrap();
+        process_message(nlh, State::Destroy, false, &mut dispatcher);
+
+        let connection = rx.try_recv().unwrap();
+        assert_eq!(Some(0b1110), connection.status);
+        assert!(!connection.pre_existing);
+        assert_eq!(vec!(StatusFlag::SeenReply, StatusFlag::Assured, StatusFlag::Confirmed), status_flags(0b1110));
+        assert_eq!(CloseReason::Assured, close_reason(0b1110));
+    }
+
+    #[test]
+    fn test_dump_is_pre_existing() {
+        let mut buf = vec![0u8; 256];
+        let len = message_with_status(&mut buf, 0b1110);
+
+        let (tx, rx) = sync_channel(1);
+        let mut dispatcher = Dispatcher::blocking(tx, Arc::new(Metrics::new()));
+        let nlh = mnl::Nlmsg::from_bytes(&mut buf[0..len]).unwrap();
+        process_dump_callback(nlh, &mut dispatcher);
+
+        let connection = rx.try_recv().unwrap();
+        assert!(matches!(connection.state, State::New));
+        assert!(connection.pre_existing);
+    }
+
+    // The same connection as an event, NEW with the create flag when it opens
+    // and without it when it is updated.
+    fn event(buf: &mut [u8], message_type: u16, flags: u16) -> usize {
+        let len = message_with_status(buf, 0b1110);
+        let mut nlh = mnl::Nlmsg::from_bytes(&mut buf[0..len]).unwrap();
+        *nlh.nlmsg_type = (nfnetlink::NFNL_SUBSYS_CTNETLINK << 8) | message_type;
+        *nlh.nlmsg_flags = flags;
+
+        len
+    }
+
+    #[test]
+    fn test_update_counted() {
+        let metrics = Arc::new(Metrics::new());
+        let (tx, rx) = sync_channel(4);
+        let mut dispatcher = Dispatcher::new(tx, metrics.clone());
+        let mut parser = Parser::new(Uuid::new_v4(), ParserConfig::default(), PreFilter::default(), Redactor::default(), metrics.clone()).unwrap();
+        let mut state = state::State::new(0, Duration::from_secs(0), Duration::from_secs(0), metrics).unwrap();
+
+        let messages = [
+            (conntrack::IPCTNL_MSG_CT_NEW, netlink::NLM_F_CREATE),
+            (conntrack::IPCTNL_MSG_CT_NEW, 0),
+            (conntrack::IPCTNL_MSG_CT_NEW, 0),
+            (conntrack::IPCTNL_MSG_CT_DELETE, 0),
+        ];
+        for (message_type, flags) in messages.iter() {
+            let mut buf = vec![0u8; 256];
+            let len = event(&mut buf, *message_type, *flags);
+            process_data_callback(mnl::Nlmsg::from_bytes(&mut buf[0..len]).unwrap(), &mut dispatcher);
+        }
+
+        let connections : Vec<Connection> = rx.try_iter().collect();
+        assert_eq!(vec!(&State::New, &State::Update, &State::Update, &State::Destroy), connections.iter().map(|connection| &connection.state).collect::<Vec<&State>>());
+
+        let mut emitted = Vec::new();
+        for connection in connections {
+            let update = matches!(connection.state, State::Update);
+            let payload = parser.parse(connection).unwrap();
+            assert_eq!(update, matches!(payload, Payload::Update(_)));
+            emitted.extend(state.transform(payload));
+        }
+
+        match emitted.as_slice() {
+            [Payload::Open(_), Payload::Close(close)] => assert_eq!(2, close.update_count),
+            _ => panic!("expected the open and its close"),
+        }
+    }
+
+    #[test]
+    fn test_close_reason() {
+        // Only CONFIRMED, nothing ever came back.
+        assert_eq!(CloseReason::Unreplied, close_reason(0b1000));
+        assert_eq!(CloseReason::Replied, close_reason(0b1010));
+        assert_eq!(vec!(StatusFlag::Dying), status_flags(1 << 9 | 1 << 20));
+        assert!(status_flags(0).is_empty());
+    }
+
+    #[test]
+    fn test_dispatcher_raw() {
+        let (tx, _rx) = sync_channel(1);
+        let dispatcher = Dispatcher::new(tx, Arc::new(Metrics::new()));
+        assert!(!dispatcher.raw);
+        assert!(dispatcher.with_raw(true).raw);
     }
 }
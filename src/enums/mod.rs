@@ -15,21 +15,160 @@
  */
 
 use std::fmt;
+use std::str::FromStr;
 use outputs::OutputsConfig;
 use filters::FiltersConfig;
+use parser::ParserConfig;
+use conn_track::ConntrackConfig;
+use listeners::ListenersConfig;
+use rollup::RollupConfig;
+use baseline::BaselineConfig;
+use live::LiveConfig;
+use pseudonymize::PseudonymizeConfig;
 use uuid::Uuid;
 
 
+pub fn default_channel_depth() -> usize {
+    4096
+}
+
+pub fn default_log_sample_rate() -> usize {
+    1
+}
+
+pub fn default_max_connections() -> usize {
+    1_000_000
+}
+
+pub fn default_use_hostname_as_name() -> bool {
+    true
+}
+
+pub fn default_persist_identity() -> bool {
+    true
+}
+
+pub fn default_emit_ttl() -> u64 {
+    3600
+}
+
+// Whether opens and closes are sent as they happen or only a single record
+// once the connection has closed.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum EmitMode {
+    #[default]
+    OpenAndClose,
+    CloseOnly,
+}
+
+// Who the agent is, worked out once at start up so every output and payload
+// reports the same thing.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct AgentIdentity {
+    pub name: String,
+    pub uuid: Uuid,
+    pub hostname: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
+    #[serde(default)]
     pub directory : Option<String>,
+    // Create the data directory, and its parents, when it does not exist.
+    #[serde(default)]
+    pub create_directory : bool,
+    #[serde(default)]
     pub name: Option<String>,
+    // Name the agent after its host when there is no name, rather than
+    // picking one from names.txt.
+    #[serde(default = "default_use_hostname_as_name")]
+    pub use_hostname_as_name: bool,
+    #[serde(default)]
     pub uuid: Option<Uuid>,
+    // Save the name and uuid to name_tuple.yaml in the data directory so the
+    // agent keeps them across restarts. Turned off for read only filesystems.
+    #[serde(default = "default_persist_identity")]
+    pub persist_identity: bool,
     pub outputs : OutputsConfig,
     pub filters : FiltersConfig,
+    #[serde(default)]
+    pub parser : ParserConfig,
+    // Number of connections which can be queued between conntrack and the parser.
+    #[serde(default = "default_channel_depth")]
+    pub channel_depth : usize,
+    // Only log 1 in every N of the per connection trace and debug lines.
+    #[serde(default = "default_log_sample_rate")]
+    pub log_sample_rate : usize,
+    #[serde(default)]
+    pub conntrack : ConntrackConfig,
+    // Once this many connections are open the oldest are forgotten, 0 turns it off.
+    #[serde(default = "default_max_connections")]
+    pub max_connections : usize,
+    // Milliseconds a close is held back for in case the connection is reopened, 0 turns it off.
+    #[serde(default)]
+    pub debounce_window : u64,
+    // Seconds within which UDP flows to the same destination are reported as one, 0 turns it off.
+    #[serde(default)]
+    pub udp_aggregate_window : u64,
+    #[serde(default)]
+    pub emit_mode : EmitMode,
+    // Seconds a connection is held for in CloseOnly, one which is still open
+    // by then is sent without its close.
+    #[serde(default = "default_emit_ttl")]
+    pub emit_ttl : u64,
+    // Log the payloads rather than sending them to the outputs.
+    #[serde(default)]
+    pub dry_run : bool,
+    // The connections an output turned away are appended here, to be sent
+    // again with --replay.
+    #[serde(default)]
+    pub dead_letter : Option<String>,
+    #[serde(default)]
+    pub listeners : ListenersConfig,
+    #[serde(default)]
+    pub rollup : RollupConfig,
+    #[serde(default)]
+    pub baseline : BaselineConfig,
+    #[serde(default)]
+    pub live : LiveConfig,
+    #[serde(default)]
+    pub pseudonymize : PseudonymizeConfig,
 }
 
-#[derive(Debug, Serialize)]
+// Nothing is sent anywhere and there is no data directory, which has to be set
+// before the agent can start.
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            directory: None,
+            create_directory: false,
+            name: None,
+            use_hostname_as_name: default_use_hostname_as_name(),
+            uuid: None,
+            persist_identity: default_persist_identity(),
+            outputs: OutputsConfig::default(),
+            filters: FiltersConfig::default(),
+            parser: ParserConfig::default(),
+            channel_depth: default_channel_depth(),
+            log_sample_rate: default_log_sample_rate(),
+            conntrack: ConntrackConfig::default(),
+            max_connections: default_max_connections(),
+            debounce_window: 0,
+            udp_aggregate_window: 0,
+            emit_mode: EmitMode::default(),
+            emit_ttl: default_emit_ttl(),
+            dry_run: false,
+            dead_letter: None,
+            listeners: ListenersConfig::default(),
+            rollup: RollupConfig::default(),
+            baseline: BaselineConfig::default(),
+            live: LiveConfig::default(),
+            pseudonymize: PseudonymizeConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 pub enum Protocol {
     UDP,
     TCP,
@@ -44,9 +183,102 @@ impl fmt::Display for Protocol {
     }
 }
 
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Protocol, String> {
+        match s.to_uppercase().as_str() {
+            "UDP" => Ok(Protocol::UDP),
+            "TCP" => Ok(Protocol::TCP),
+            _ => Err(format!("unknown protocol {}", s)),
+        }
+    }
+}
+
+// Outbound when this host opened the connection, inbound when it accepted it.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Outbound,
+    Inbound,
+}
+
+// How the process a socket is attributed to was found: the process owning
+// it, the parent of an owner which had already exited, or only the name the
+// owner had.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution {
+    Direct,
+    Parent,
+    Unknown,
+}
+
+// The CTA_STATUS bits conntrack had set on a connection, in bit order.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum StatusFlag {
+    Expected,
+    SeenReply,
+    Assured,
+    Confirmed,
+    SrcNat,
+    DstNat,
+    SeqAdjust,
+    SrcNatDone,
+    DstNatDone,
+    Dying,
+    FixedTimeout,
+    Template,
+    Untracked,
+    Helper,
+    Offload,
+}
+
+// How a connection ended going by its status: nothing ever came back, only
+// some traffic came back, or conntrack saw it through to an established
+// session.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum CloseReason {
+    Unreplied,
+    Replied,
+    Assured,
+}
+
 #[derive(Debug, Serialize, PartialEq)]
 pub enum State {
     New,
+    // A connection which is already open changing, such as TCP moving on to
+    // its next state.
+    Update,
     Destroy,
     Unknown,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+    use serde_json;
+
+    #[test]
+    fn test_protocol_serde() {
+        let protocols : Vec<Protocol> = serde_yaml::from_str("[ TCP, UDP ]").unwrap();
+        assert_eq!(vec!(Protocol::TCP, Protocol::UDP), protocols);
+
+        let json = serde_json::to_string(&Protocol::UDP).unwrap();
+        assert_eq!("\"UDP\"", json);
+        assert_eq!(Protocol::UDP, serde_json::from_str(&json).unwrap());
+    }
+
+    #[test]
+    fn test_protocol_from_str() {
+        for protocol in &[Protocol::TCP, Protocol::UDP] {
+            assert_eq!(*protocol, Protocol::from_str(&protocol.to_string()).unwrap());
+        }
+
+        assert_eq!(Protocol::TCP, "tcp".parse().unwrap());
+        assert!(Protocol::from_str("ICMP").is_err());
+    }
+}
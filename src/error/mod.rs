@@ -0,0 +1,70 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::error;
+use std::fmt;
+use std::io;
+
+// What the public API returns when something fails, by the part of the agent
+// it failed in. Displaying one gives the same message as before.
+#[derive(Debug)]
+pub enum Error {
+    // The config couldn't be parsed or doesn't make sense.
+    Config(String),
+    // Conntrack couldn't be bound to or read from.
+    Conntrack(String),
+    // An output couldn't be made or sent to.
+    Output(String),
+    // The parsers or the state which turn conntrack events into payloads
+    // couldn't be started.
+    Parser(String),
+    // A file couldn't be read or written, along with what was being done.
+    Io(String, io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(message) | Error::Conntrack(message) | Error::Output(message) | Error::Parser(message) => write!(f, "{}", message),
+            Error::Io(message, err) => write!(f, "{}: {}", message, err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as StdError;
+
+    #[test]
+    fn test_display_keeps_message() {
+        assert_eq!("unable to parse config", Error::Config(String::from("unable to parse config")).to_string());
+        assert!(Error::Output(String::from("no syslog output is configured")).source().is_none());
+
+        let err = Error::Io(String::from("unable to read config file /etc/zerotrust/config.yaml"), io::Error::new(io::ErrorKind::PermissionDenied, "permission denied"));
+        assert_eq!("unable to read config file /etc/zerotrust/config.yaml: permission denied", err.to_string());
+        assert!(err.source().is_some());
+    }
+}
@@ -0,0 +1,159 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::collections::{ HashMap, HashSet };
+use std::net::{ Ipv4Addr, SocketAddr, ToSocketAddrs };
+use std::sync::{ Arc, RwLock };
+use std::sync::mpsc::{ channel, RecvTimeoutError, Sender };
+use std::thread;
+use std::time::Duration;
+
+// Looks up the IPv4 addresses of a domain.
+pub type Resolver = fn(&str) -> Result<Vec<Ipv4Addr>, String>;
+
+pub fn resolve(domain: &str) -> Result<Vec<Ipv4Addr>, String> {
+    match (domain, 0).to_socket_addrs() {
+        Ok(addresses) => Ok(addresses.filter_map(|address| match address {
+            SocketAddr::V4(address) => Some(*address.ip()),
+            SocketAddr::V6(_) => None,
+        }).collect()),
+        Err(err) => Err(format!("unable to resolve {}: {}", domain, err)),
+    }
+}
+
+// The addresses each domain resolved to last. A domain which can't be
+// resolved keeps the addresses it had.
+struct DomainCache {
+    resolver : Resolver,
+    resolved : HashMap<String, Vec<Ipv4Addr>>,
+}
+
+impl DomainCache {
+    fn new(domains: &[String], resolver: Resolver) -> DomainCache {
+        DomainCache {
+            resolver,
+            resolved: domains.iter().map(|domain| (domain.clone(), Vec::new())).collect(),
+        }
+    }
+
+    fn refresh(&mut self) -> HashSet<Ipv4Addr> {
+        for (domain, addresses) in self.resolved.iter_mut() {
+            match (self.resolver)(domain) {
+                Ok(x) => *addresses = x,
+                Err(err) => warn!("keeping the last addresses of the ignored domain {}: {}", domain, err),
+            }
+        }
+
+        self.resolved.values().flat_map(|addresses| addresses.iter().cloned()).collect()
+    }
+}
+
+// The addresses of the ignored domains, resolved again every refresh from a
+// thread of its own so DNS never holds up the filter. The thread stops once
+// the last copy is dropped, as that drops the other end of its channel.
+#[derive(Debug, Clone)]
+pub struct Domains {
+    addresses : Arc<RwLock<HashSet<Ipv4Addr>>>,
+    _stop : Arc<Sender<()>>,
+}
+
+impl Domains {
+    // The domains are resolved once before returning, so connections are
+    // dropped from the start.
+    pub fn start(domains: &[String], refresh: Duration, resolver: Resolver) -> Domains {
+        let mut cache = DomainCache::new(domains, resolver);
+        let addresses = Arc::new(RwLock::new(cache.refresh()));
+        let (stop, stopped) = channel::<()>();
+
+        let shared = addresses.clone();
+        thread::spawn(move || {
+            loop {
+                match stopped.recv_timeout(refresh) {
+                    Err(RecvTimeoutError::Timeout) => (),
+                    _ => {
+                        debug!("stopping the ignored domains thread");
+                        break;
+                    },
+                }
+
+                let resolved = cache.refresh();
+                if let Ok(mut addresses) = shared.write() {
+                    *addresses = resolved;
+                }
+            }
+        });
+
+        Domains { addresses, _stop: Arc::new(stop) }
+    }
+
+    pub fn contains(&self, address: &Ipv4Addr) -> bool {
+        self.addresses.read().map(|addresses| addresses.contains(address)).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{ AtomicUsize, Ordering };
+
+    fn resolver(domain: &str) -> Result<Vec<Ipv4Addr>, String> {
+        match domain {
+            "telemetry.example.com" => Ok(vec!(Ipv4Addr::new(203, 0, 113, 5), Ipv4Addr::new(203, 0, 113, 6))),
+            _ => Err(format!("no such domain {}", domain)),
+        }
+    }
+
+    #[test]
+    fn test_failure_keeps_last_addresses() {
+        let mut cache = DomainCache::new(&[String::from("telemetry.example.com"), String::from("gone.example.com")], resolver);
+        cache.resolved.insert(String::from("gone.example.com"), vec!(Ipv4Addr::new(198, 51, 100, 1)));
+
+        let addresses = cache.refresh();
+        assert_eq!(3, addresses.len());
+        assert!(addresses.contains(&Ipv4Addr::new(198, 51, 100, 1)));
+        assert!(addresses.contains(&Ipv4Addr::new(203, 0, 113, 5)));
+    }
+
+    fn counted(domain: &str) -> Result<Vec<Ipv4Addr>, String> {
+        REFRESHES.fetch_add(1, Ordering::SeqCst);
+        resolver(domain)
+    }
+
+    static REFRESHES : AtomicUsize = AtomicUsize::new(0);
+
+    #[test]
+    fn test_dropped_stops_refreshing() {
+        let domains = Domains::start(&[String::from("telemetry.example.com")], Duration::from_millis(10), counted);
+        let copy = domains.clone();
+        drop(domains);
+        thread::sleep(Duration::from_millis(50));
+        assert!(REFRESHES.load(Ordering::SeqCst) > 1);
+        assert!(copy.contains(&Ipv4Addr::new(203, 0, 113, 5)));
+
+        drop(copy);
+        thread::sleep(Duration::from_millis(20));
+        let refreshes = REFRESHES.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(refreshes, REFRESHES.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_start_resolves() {
+        let domains = Domains::start(&[String::from("telemetry.example.com")], Duration::from_secs(300), resolver);
+        assert!(domains.contains(&Ipv4Addr::new(203, 0, 113, 6)));
+        assert!(!domains.contains(&Ipv4Addr::new(1, 1, 1, 1)));
+    }
+}
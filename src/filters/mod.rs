@@ -14,145 +14,418 @@
  *
  */
 
-use std::collections::HashSet;
+use std::collections::{ HashMap, HashSet };
+use std::net::{ Ipv4Addr, SocketAddrV4 };
+use std::str::FromStr;
+use std::time::{ Duration, Instant };
 use libc::{ getpid };
-use parser::{ Payload };
+use ipnetwork::Ipv4Network;
+use regex::Regex;
+use parser::{ Payload, Program };
+use enums::{ Protocol };
+
+mod rules;
+mod domains;
+use self::rules::RulesFile;
+pub use self::domains::{ Domains, Resolver };
+
+// The rule a connection was dropped by, so the drops can be counted by rule.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FilterRule {
+    DnsRequests,
+    IgnorePorts,
+    IgnoreNetworks,
+    // The servers the agent sends to.
+    OwnEndpoints,
+    NonProcessConnections,
+    ZerotrustTrackConnections,
+    IgnoreProcesses,
+    Protocols,
+    RulesFile,
+    IgnoreDomains,
+}
+
+pub const FILTER_RULES : usize = 10;
+
+impl FilterRule {
+    pub const ALL : [FilterRule; FILTER_RULES] = [
+        FilterRule::DnsRequests,
+        FilterRule::IgnorePorts,
+        FilterRule::IgnoreNetworks,
+        FilterRule::OwnEndpoints,
+        FilterRule::NonProcessConnections,
+        FilterRule::ZerotrustTrackConnections,
+        FilterRule::IgnoreProcesses,
+        FilterRule::Protocols,
+        FilterRule::RulesFile,
+        FilterRule::IgnoreDomains,
+    ];
+}
 
- #[derive(Debug, Serialize, Deserialize, Copy, Clone)]
+// Whether the protocols listed in the filter are the only ones kept or the
+// ones dropped.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum ProtocolMode {
+    #[default]
+    Allow,
+    Deny,
+}
+
+ #[derive(Debug, Serialize, Deserialize, Clone)]
  pub struct FiltersConfig {
      pub non_process_connections : bool,
      pub dns_requests : bool,
      pub zerotrust_track_connections: bool,
+     // Destination ports which are dropped before any process attribution.
+     #[serde(default)]
+     pub ignore_ports : Vec<u16>,
+     // Networks (CIDR) which are dropped before any process attribution when
+     // either end of the connection is inside of them.
+     #[serde(default)]
+     pub ignore_networks : Vec<String>,
+     // An empty list reports on every protocol.
+     #[serde(default)]
+     pub protocols : Vec<Protocol>,
+     #[serde(default)]
+     pub protocol_mode : ProtocolMode,
+     // Connections made by processes matching any of these are dropped.
+     #[serde(default)]
+     pub ignore_processes : Vec<ProcessFilter>,
+     // A file of allow and deny rules, one per line, which is re-read every
+     // rules_reload seconds.
+     #[serde(default)]
+     pub rules_file : Option<String>,
+     #[serde(default = "default_rules_reload")]
+     pub rules_reload : u64,
+     // Connections to the addresses these resolve to are dropped, they are
+     // resolved again every ignore_domains_refresh seconds whatever the TTL
+     // of the records, ignore_domains_ttl is its old name.
+     #[serde(default)]
+     pub ignore_domains : Vec<String>,
+     #[serde(default = "default_ignore_domains_refresh", alias = "ignore_domains_ttl")]
+     pub ignore_domains_refresh : u64,
  }
 
+pub fn default_rules_reload() -> u64 {
+    60
+}
+
+// The same as the filters in the default config file.
+impl Default for FiltersConfig {
+    fn default() -> FiltersConfig {
+        FiltersConfig {
+            non_process_connections: true,
+            dns_requests: true,
+            zerotrust_track_connections: true,
+            ignore_ports: Vec::new(),
+            ignore_networks: Vec::new(),
+            protocols: Vec::new(),
+            protocol_mode: ProtocolMode::default(),
+            ignore_processes: Vec::new(),
+            rules_file: None,
+            rules_reload: default_rules_reload(),
+            ignore_domains: Vec::new(),
+            ignore_domains_refresh: default_ignore_domains_refresh(),
+        }
+    }
+}
+
+pub fn default_ignore_domains_refresh() -> u64 {
+    300
+}
+
+// How the patterns of a process filter are matched.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum Matching {
+    #[default]
+    Regex,
+    Glob,
+}
+
+// Matches when every pattern which is set matches, the command line is
+// matched with its arguments joined by spaces.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ProcessFilter {
+    #[serde(default)]
+    pub process_name : Option<String>,
+    #[serde(default)]
+    pub command_line : Option<String>,
+    #[serde(default)]
+    pub matching : Matching,
+}
+
+// A * matches any run of characters, slashes included, and a ? any single
+// one. The whole of the value has to match.
+pub fn glob_match(pattern: &str, value: &str) -> bool {
+    let pattern : Vec<char> = pattern.chars().collect();
+    let value : Vec<char> = value.chars().collect();
+    let (mut p, mut v) = (0, 0);
+    // Where the last * was and how much of the value it has taken so far.
+    let mut star : Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == value[v]) {
+            p += 1;
+            v += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, v));
+            p += 1;
+        } else if let Some((star_p, star_v)) = star {
+            p = star_p + 1;
+            v = star_v + 1;
+            star = Some((star_p, star_v + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    Regex(Regex),
+    Glob(String),
+}
+
+impl Pattern {
+    fn new(pattern: &str, matching: Matching) -> Result<Pattern, String> {
+        match matching {
+            Matching::Regex => match Regex::new(pattern) {
+                Ok(x) => Ok(Pattern::Regex(x)),
+                Err(_err) => Err(format!("unable to parse process filter {}", pattern)),
+            },
+            Matching::Glob => Ok(Pattern::Glob(pattern.to_string())),
+        }
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Pattern::Regex(regex) => regex.is_match(value),
+            Pattern::Glob(glob) => glob_match(glob, value),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ProcessRule {
+    process_name : Option<Pattern>,
+    command_line : Option<Pattern>,
+}
+
+impl ProcessRule {
+    fn new(config: &ProcessFilter) -> Result<ProcessRule, String> {
+        let pattern = |pattern: &Option<String>| match pattern {
+            Some(pattern) => Pattern::new(pattern, config.matching).map(Some),
+            None => Ok(None),
+        };
+
+        Ok(ProcessRule {
+            process_name: pattern(&config.process_name)?,
+            command_line: pattern(&config.command_line)?,
+        })
+    }
+
+    fn matches(&self, details: &Program) -> bool {
+        if self.process_name.is_none() && self.command_line.is_none() {
+            return false;
+        }
+
+        self.process_name.as_ref().is_none_or(|pattern| pattern.matches(&details.process_name)) &&
+            self.command_line.as_ref().is_none_or(|pattern| pattern.matches(&details.command_line.join(" ")))
+    }
+}
+
+// Drops connections on the tuple alone, this runs in the parser before the
+// expensive /proc look ups. As it only looks at the tuple the open and close
+// of a connection always get the same answer, so no state is kept.
+#[derive(Debug, Clone, Default)]
+pub struct PreFilter {
+    dns_requests : bool,
+    ports : HashSet<u16>,
+    networks : Vec<Ipv4Network>,
+    // Servers the agent itself sends to, so it doesn't report on its own traffic.
+    endpoints : HashSet<SocketAddrV4>,
+}
+
+impl PreFilter {
+    pub fn new(config: &FiltersConfig) -> Result<PreFilter, String> {
+        let mut networks = Vec::new();
+        for network in &config.ignore_networks {
+            match Ipv4Network::from_str(network) {
+                Ok(x) => networks.push(x),
+                Err(_err) => return Err(format!("unable to parse ignored network {}", network)),
+            }
+        }
+
+        Ok(PreFilter {
+            dns_requests: config.dns_requests,
+            ports: config.ignore_ports.iter().cloned().collect(),
+            networks,
+            endpoints: HashSet::new(),
+        })
+    }
+
+    pub fn with_endpoints(self, endpoints : Vec<SocketAddrV4>) -> PreFilter {
+        PreFilter {
+            endpoints: endpoints.into_iter().collect(),
+            .. self
+        }
+    }
+
+    pub fn apply(&self, source: &Ipv4Addr, destination: &Ipv4Addr, destination_port: u16) -> Option<FilterRule> {
+        if self.dns_requests && (destination_port == 53 || destination_port == 5353) {
+            sampled_trace!("dropping connection as it's a DNS request");
+            return Some(FilterRule::DnsRequests);
+        }
+
+        if self.ports.contains(&destination_port) {
+            sampled_trace!("dropping connection as the port {} is ignored", destination_port);
+            return Some(FilterRule::IgnorePorts);
+        }
+
+        if self.networks.iter().any(|network| network.contains(*source) || network.contains(*destination)) {
+            sampled_trace!("dropping connection as the network is ignored");
+            return Some(FilterRule::IgnoreNetworks);
+        }
+
+        if self.endpoints.contains(&SocketAddrV4::new(*destination, destination_port)) {
+            sampled_trace!("dropping connection as it's to one of our own endpoints");
+            return Some(FilterRule::OwnEndpoints);
+        }
+
+        None
+    }
+}
+
 #[derive(Clone)]
  pub struct Filter {
      config : FiltersConfig,
-     filtered : HashSet<i64>,
+     processes : Vec<ProcessRule>,
+     rules : Option<RulesFile>,
+     domains : Option<Domains>,
+     filtered : HashMap<i64, FilterRule>,
      pid: u32,
  }
 
 
 impl Filter {
     pub fn new(config: FiltersConfig) -> Result<Filter, String> {
-        Ok(Filter {
+        let processes = config.ignore_processes.iter()
+            .map(ProcessRule::new)
+            .collect::<Result<Vec<ProcessRule>, String>>()?;
+        let rules = config.rules_file.as_ref()
+            .map(|path| RulesFile::new(path, Duration::from_secs(config.rules_reload)));
+
+        let filter = Filter {
             config: config,
+            processes,
+            rules,
+            domains: None,
             pid : unsafe { getpid() } as u32,
-            filtered: HashSet::new(),
-        })
+            filtered: HashMap::new(),
+        };
+
+        if filter.config.ignore_domains.is_empty() {
+            return Ok(filter);
+        }
+
+        let domains = Domains::start(&filter.config.ignore_domains, Duration::from_secs(filter.config.ignore_domains_refresh), domains::resolve);
+        Ok(filter.with_domains(domains))
     }
 
-    pub fn apply(&mut self, payload: &Payload) -> bool {
+    pub fn with_domains(self, domains: Domains) -> Filter {
+        Filter {
+            domains: Some(domains),
+            .. self
+        }
+    }
+
+    fn drop(&mut self, hash: i64, rule: FilterRule) -> Option<FilterRule> {
+        self.filtered.insert(hash, rule);
+        Some(rule)
+    }
+
+    // The rule the payload is dropped by, the close of a dropped open is
+    // dropped by the same rule.
+    pub fn apply(&mut self, payload: &Payload) -> Option<FilterRule> {
         match payload {
             Payload::Open(connection) => {
-                if self.config.non_process_connections && connection.program_details.is_none() {
+                // Connections left out of attribution are sent without a process.
+                if self.config.non_process_connections && connection.program_details.is_none() && !connection.attribution_skipped {
                     trace!("dropping payload as it doesn't include process information");
-                    self.filtered.insert(connection.hash);
-                    return true;
+                    return self.drop(connection.hash, FilterRule::NonProcessConnections);
                 }
 
                 if self.config.zerotrust_track_connections {
                     if let Some(ref details) = connection.program_details {
                         if details.pid == self.pid {
                             trace!("dropping payload is the pid is the same as ours");
-                            self.filtered.insert(connection.hash);
-                            return true;
+                            return self.drop(connection.hash, FilterRule::ZerotrustTrackConnections);
                         }
                     }
                 }
 
+                if let Some(ref details) = connection.program_details {
+                    if self.processes.iter().any(|rule| rule.matches(details)) {
+                        trace!("dropping payload as the process is filtered");
+                        return self.drop(connection.hash, FilterRule::IgnoreProcesses);
+                    }
+                }
+
+                if !self.config.protocols.is_empty() &&
+                    self.config.protocols.contains(&connection.protocol) != (self.config.protocol_mode == ProtocolMode::Allow)
+                {
+                    trace!("dropping payload as the protocol is filtered");
+                    return self.drop(connection.hash, FilterRule::Protocols);
+                }
+
+                let ruled = match self.rules {
+                    Some(ref mut rules) => rules.rules(Instant::now()).drops(connection),
+                    None => false,
+                };
+                if ruled {
+                    trace!("dropping payload as it's filtered by the rules file");
+                    return self.drop(connection.hash, FilterRule::RulesFile);
+                }
+
+                if self.domains.as_ref().is_some_and(|domains| domains.contains(&connection.destination)) {
+                    trace!("dropping payload as the destination is an ignored domain");
+                    return self.drop(connection.hash, FilterRule::IgnoreDomains);
+                }
+
                 if self.config.dns_requests &&
                     ( connection.destination_port == 53 || connection.destination_port == 5353)
                 {
                     trace!("dropping payload as it's a DNS request");
-                    self.filtered.insert(connection.hash);
-                    return true;
+                    return self.drop(connection.hash, FilterRule::DnsRequests);
                 }
 
             },
             Payload::Close(connection) => {
-                if self.filtered.contains(&connection.hash)
-                {
+                if let Some(rule) = self.filtered.remove(&connection.hash) {
                     trace!("removing payload from filter hash set");
-                    self.filtered.remove(&connection.hash);
-
-                    return true;
+                    return Some(rule);
                 }
-            }
-
+            },
+            Payload::ListenOpen(_) | Payload::ListenClose(_) | Payload::Rollup(_) | Payload::Connection(_) | Payload::Update(_) => (),
         }
 
         trace!("allowing payload");
-        false
+        None
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use parser::{ Payload, OpenConnection, CloseConnection };
-    use enums::{ Protocol };
+    use parser::{ Payload, OpenConnection, Program };
+    use enums::Protocol;
     use std::net::Ipv4Addr;
-    use parser::{ Program, generate_hash };
-    use chrono::prelude::*;
-    use uuid::Uuid;
-
-
-    fn default_close_payload() -> Payload {
-        Payload::Close(CloseConnection {
-            hash: generate_hash(
-                &Protocol::TCP.to_string(),
-                &Ipv4Addr::new(127, 0, 0, 1),
-                &22,
-                &Ipv4Addr::new(127, 0, 0, 1),
-                &22
-            ) as i64,
-            uuid: None,
-            agent: Uuid::new_v4(),
-            timestamp: Utc::now().to_rfc3339(),
-            protocol: Protocol::TCP,
-            source_port : 22,
-            source: Ipv4Addr::new(127, 0, 0, 1),
-            destination_port : 22,
-            destination : Ipv4Addr::new(127, 0, 0, 1),
-        })
-    }
-
-    fn default_open_payload(
-        source_port : u16,
-        destination_port : u16,
-        program_details: Option<Program>
-    ) -> Payload {
-        Payload::Open(OpenConnection {
-            hash: generate_hash(
-                &Protocol::TCP.to_string(),
-                &Ipv4Addr::new(127, 0, 0, 1),
-                &22,
-                &Ipv4Addr::new(127, 0, 0, 1),
-                &22
-            ) as i64,
-            uuid: Uuid::new_v4(),
-            agent: Uuid::new_v4(),
-            timestamp: Utc::now().to_rfc3339(),
-            protocol: Protocol::TCP,
-            source_port : source_port,
-            source: Ipv4Addr::new(127, 0, 0, 1),
-            destination_port : destination_port,
-            destination : Ipv4Addr::new(127, 0, 0, 1),
-            username : String::from("hello"),
-            uid: 10,
-            program_details : program_details,
-        })
-    }
-
-    fn default_filters() -> FiltersConfig {
-        FiltersConfig {
-            non_process_connections: true,
-            dns_requests : true,
-            zerotrust_track_connections: true,
-        }
-    }
-
+    use serde_yaml;
+    use test_support::{ default_open_payload, default_close_payload, default_filters, program };
+    use tempfile::tempdir;
+    use std::fs;
 
     #[test]
     fn test_filter_set_true() {
@@ -162,14 +435,14 @@ mod tests {
         }).unwrap();
 
         let payload = default_open_payload(0, 0, None);
-        assert_eq!(true, filter.apply(&payload));
+        assert!(filter.apply(&payload).is_some());
 
         let payload = default_close_payload();
 
-        assert_eq!(true, filter.apply(&payload));
+        assert!(filter.apply(&payload).is_some());
 
         let payload = default_close_payload();
-        assert_eq!(false, filter.apply(&payload));
+        assert!(filter.apply(&payload).is_none());
     }
 
 
@@ -183,7 +456,7 @@ mod tests {
         }).unwrap();
 
         let payload = default_open_payload(0, 0, None);
-        assert_eq!(false, filter.apply(&payload));
+        assert!(filter.apply(&payload).is_none());
     }
 
     #[test]
@@ -194,7 +467,13 @@ mod tests {
         }).unwrap();
 
         let payload = default_open_payload(0, 0, None);
-        assert_eq!(true, filter.apply(&payload));
+        assert!(filter.apply(&payload).is_some());
+
+        let payload = match default_open_payload(0, 0, None) {
+            Payload::Open(connection) => Payload::Open(OpenConnection { attribution_skipped: true, .. connection }),
+            _ => panic!("expected an open"),
+        };
+        assert!(filter.apply(&payload).is_none());
     }
 
     #[test]
@@ -206,7 +485,7 @@ mod tests {
         }).unwrap();
 
         let payload = default_open_payload(53, 53, None);
-        assert_eq!(false, filter.apply(&payload));
+        assert!(filter.apply(&payload).is_none());
     }
 
     #[test]
@@ -218,7 +497,7 @@ mod tests {
         }).unwrap();
 
         let payload = default_open_payload(53, 53, None);
-        assert_eq!(true, filter.apply(&payload));
+        assert_eq!(Some(FilterRule::DnsRequests), filter.apply(&payload));
     }
 
 
@@ -230,13 +509,11 @@ mod tests {
         }).unwrap();
 
         let payload = default_open_payload(0, 0, Some(Program {
-                    inode: 0,
-                    pid: unsafe { getpid() } as u32,
-                    process_name : String::from("I am a program"),
-                    command_line : Vec::new()
-            }));
+            pid: unsafe { getpid() } as u32,
+            .. program("I am a program")
+        }));
 
-        assert_eq!(true, filter.apply(&payload));
+        assert!(filter.apply(&payload).is_some());
     }
 
     #[test]
@@ -247,13 +524,212 @@ mod tests {
         }).unwrap();
 
         let payload = default_open_payload(0, 0, Some(Program {
-                    inode: 0,
-                    pid: unsafe { getpid() } as u32,
-                    process_name : String::from("I am a program"),
-                    command_line : Vec::new()
-            }));
+            pid: unsafe { getpid() } as u32,
+            .. program("I am a program")
+        }));
+
+        assert!(filter.apply(&payload).is_none());
+    }
+
+    #[test]
+    fn test_pre_filter() {
+        let filter = PreFilter::new(&FiltersConfig {
+            dns_requests: true,
+            ignore_ports: vec!(8080),
+            ignore_networks: vec!(String::from("10.0.0.0/8")),
+            .. default_filters()
+        }).unwrap();
+
+        let local = Ipv4Addr::new(192, 168, 1, 10);
+        let remote = Ipv4Addr::new(192, 168, 1, 20);
+
+        assert_eq!(Some(FilterRule::DnsRequests), filter.apply(&local, &remote, 53));
+        assert_eq!(Some(FilterRule::IgnorePorts), filter.apply(&local, &remote, 8080));
+        assert_eq!(Some(FilterRule::IgnoreNetworks), filter.apply(&local, &Ipv4Addr::new(10, 1, 2, 3), 443));
+        assert_eq!(Some(FilterRule::IgnoreNetworks), filter.apply(&Ipv4Addr::new(10, 1, 2, 3), &remote, 443));
+        assert!(filter.apply(&local, &remote, 443).is_none());
+    }
+
+    #[test]
+    fn test_pre_filter_endpoints() {
+        let server = Ipv4Addr::new(192, 168, 1, 20);
+        let filter = PreFilter::new(&default_filters()).unwrap()
+            .with_endpoints(vec!(SocketAddrV4::new(server, 8080)));
+
+        let local = Ipv4Addr::new(192, 168, 1, 10);
+        assert_eq!(Some(FilterRule::OwnEndpoints), filter.apply(&local, &server, 8080));
+        assert!(filter.apply(&local, &server, 443).is_none());
+        assert!(filter.apply(&local, &Ipv4Addr::new(192, 168, 1, 30), 8080).is_none());
+    }
+
+    #[test]
+    fn test_pre_filter_dns_requests_false() {
+        let filter = PreFilter::new(&FiltersConfig {
+            dns_requests: false,
+            .. default_filters()
+        }).unwrap();
+
+        assert!(filter.apply(&Ipv4Addr::new(127, 0, 0, 1), &Ipv4Addr::new(127, 0, 0, 1), 53).is_none());
+    }
 
-        assert_eq!(false, filter.apply(&payload));
+    #[test]
+    fn test_pre_filter_invalid_network() {
+        assert!(PreFilter::new(&FiltersConfig {
+            ignore_networks: vec!(String::from("I_am_not_a_network")),
+            .. default_filters()
+        }).is_err());
     }
 
+    fn udp_open_payload() -> Payload {
+        match default_open_payload(0, 0, None) {
+            Payload::Open(connection) => Payload::Open(OpenConnection { protocol: Protocol::UDP, .. connection }),
+            payload => payload,
+        }
+    }
+
+    #[test]
+    fn test_filter_protocols_allow() {
+        let mut filter = Filter::new(FiltersConfig {
+            non_process_connections: false,
+            protocols: vec!(Protocol::TCP),
+            protocol_mode: ProtocolMode::Allow,
+            .. default_filters()
+        }).unwrap();
+
+        assert!(filter.apply(&udp_open_payload()).is_some());
+        assert!(filter.apply(&default_close_payload()).is_some());
+        assert!(filter.apply(&default_open_payload(0, 0, None)).is_none());
+    }
+
+    #[test]
+    fn test_filter_protocols_deny() {
+        let mut filter = Filter::new(FiltersConfig {
+            non_process_connections: false,
+            protocols: vec!(Protocol::UDP),
+            protocol_mode: ProtocolMode::Deny,
+            .. default_filters()
+        }).unwrap();
+
+        assert!(filter.apply(&udp_open_payload()).is_some());
+        assert!(filter.apply(&default_open_payload(0, 0, None)).is_none());
+    }
+
+    #[test]
+    fn test_filter_protocols_config() {
+        let config : FiltersConfig = serde_yaml::from_str("{ non_process_connections: false, dns_requests: false, zerotrust_track_connections: false, protocols: [ UDP ], protocol_mode: Deny }").unwrap();
+        assert_eq!(vec!(Protocol::UDP), config.protocols);
+        assert_eq!(ProtocolMode::Deny, config.protocol_mode);
+
+        let config : FiltersConfig = serde_yaml::from_str("{ non_process_connections: false, dns_requests: false, zerotrust_track_connections: false }").unwrap();
+        assert!(config.protocols.is_empty());
+        assert_eq!(ProtocolMode::Allow, config.protocol_mode);
+    }
+
+    fn program_payload(process_name : &str, command_line : &[&str]) -> Payload {
+        default_open_payload(0, 0, Some(Program {
+            command_line: command_line.iter().map(|arg| arg.to_string()).collect(),
+            .. program(process_name)
+        }))
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*/python3", "/usr/bin/python3"));
+        assert!(glob_match("curl*", "curl"));
+        assert!(glob_match("s?h", "ssh"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("*/python3", "/usr/bin/python3.7"));
+        assert!(!glob_match("curl*", "/usr/bin/curl"));
+        assert!(!glob_match("s?h", "sh"));
+    }
+
+    #[test]
+    fn test_filter_process_glob() {
+        let mut filter = Filter::new(FiltersConfig {
+            ignore_processes: vec!(ProcessFilter {
+                command_line: Some(String::from("*/python3 *")),
+                matching: Matching::Glob,
+                .. Default::default()
+            }),
+            .. default_filters()
+        }).unwrap();
+
+        assert_eq!(Some(FilterRule::IgnoreProcesses), filter.apply(&program_payload("python3", &["/usr/bin/python3", "server.py"])));
+        assert_eq!(Some(FilterRule::IgnoreProcesses), filter.apply(&default_close_payload()));
+        assert!(filter.apply(&program_payload("python3", &["python3", "server.py"])).is_none());
+    }
+
+    #[test]
+    fn test_filter_process_regex() {
+        let mut filter = Filter::new(FiltersConfig {
+            ignore_processes: vec!(ProcessFilter {
+                process_name: Some(String::from("^curl$")),
+                .. Default::default()
+            }),
+            .. default_filters()
+        }).unwrap();
+
+        assert!(filter.apply(&program_payload("curl", &["curl", "example.com"])).is_some());
+        assert!(filter.apply(&program_payload("curlie", &["curlie", "example.com"])).is_none());
+    }
+
+    #[test]
+    fn test_filter_process_config() {
+        let config : FiltersConfig = serde_yaml::from_str("{ non_process_connections: false, dns_requests: false, zerotrust_track_connections: false, ignore_processes: [ { process_name: \"curl*\", matching: Glob } ] }").unwrap();
+        assert_eq!(Matching::Glob, config.ignore_processes[0].matching);
+
+        assert!(Filter::new(FiltersConfig {
+            ignore_processes: vec!(ProcessFilter {
+                process_name: Some(String::from("(")),
+                .. Default::default()
+            }),
+            .. default_filters()
+        }).is_err());
+    }
+
+    #[test]
+    fn test_filter_rules_file_reloaded() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join("rules");
+        fs::write(&path, "deny port 8080\n").unwrap();
+
+        let mut filter = Filter::new(FiltersConfig {
+            non_process_connections: false,
+            rules_file: Some(path.to_str().unwrap().to_string()),
+            rules_reload: 0,
+            .. default_filters()
+        }).unwrap();
+
+        assert!(filter.apply(&default_open_payload(40000, 8080, None)).is_some());
+        assert!(filter.apply(&default_open_payload(40000, 9090, None)).is_none());
+
+        fs::write(&path, "# moved\ndeny port 9090\nnot a rule\n").unwrap();
+        assert!(filter.apply(&default_open_payload(40000, 8080, None)).is_none());
+        assert!(filter.apply(&default_open_payload(40000, 9090, None)).is_some());
+    }
+
+    fn resolver(domain: &str) -> Result<Vec<Ipv4Addr>, String> {
+        match domain {
+            "telemetry.example.com" => Ok(vec!(Ipv4Addr::new(203, 0, 113, 5))),
+            _ => Err(format!("no such domain {}", domain)),
+        }
+    }
+
+    #[test]
+    fn test_filter_ignored_domain() {
+        let domains = vec!(String::from("telemetry.example.com"));
+        let mut filter = Filter::new(FiltersConfig {
+            non_process_connections: false,
+            .. default_filters()
+        }).unwrap().with_domains(Domains::start(&domains, Duration::from_secs(300), resolver));
+
+        let open = |destination| match default_open_payload(40000, 443, None) {
+            Payload::Open(connection) => Payload::Open(OpenConnection { destination, .. connection }),
+            _ => panic!("expected an open"),
+        };
+
+        assert!(filter.apply(&open(Ipv4Addr::new(203, 0, 113, 5))).is_some());
+        assert!(filter.apply(&default_close_payload()).is_some());
+        assert!(filter.apply(&open(Ipv4Addr::new(203, 0, 113, 6))).is_none());
+    }
 }
@@ -0,0 +1,247 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{ Duration, Instant };
+use ipnetwork::Ipv4Network;
+use regex::Regex;
+use parser::OpenConnection;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Port(u16),
+    Network(Ipv4Network),
+    Process(Regex),
+}
+
+impl Matcher {
+    fn matches(&self, connection: &OpenConnection) -> bool {
+        match self {
+            Matcher::Port(port) => connection.destination_port == *port,
+            Matcher::Network(network) => network.contains(connection.source) || network.contains(connection.destination),
+            Matcher::Process(regex) => connection.program_details.as_ref()
+                .is_some_and(|details| regex.is_match(&details.process_name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    action : Action,
+    matcher : Matcher,
+}
+
+// A line is `allow` or `deny`, then `port`, `network` or `process` and its
+// value, such as `deny network 10.0.0.0/8`. Anything after a # is a comment.
+fn parse_line(line: &str) -> Result<Option<Rule>, String> {
+    let line = match line.find('#') {
+        Some(comment) => &line[..comment],
+        None => line,
+    };
+    let words : Vec<&str> = line.split_whitespace().collect();
+    if words.is_empty() {
+        return Ok(None);
+    }
+    if words.len() != 3 {
+        return Err(format!("expected an action, a kind and a value but got {} words", words.len()));
+    }
+
+    let action = match words[0] {
+        "allow" => Action::Allow,
+        "deny" => Action::Deny,
+        action => return Err(format!("unknown action {}", action)),
+    };
+
+    let matcher = match words[1] {
+        "port" => match u16::from_str(words[2]) {
+            Ok(x) => Matcher::Port(x),
+            Err(_err) => return Err(format!("unable to parse port {}", words[2])),
+        },
+        "network" => match Ipv4Network::from_str(words[2]) {
+            Ok(x) => Matcher::Network(x),
+            Err(_err) => return Err(format!("unable to parse network {}", words[2])),
+        },
+        "process" => match Regex::new(words[2]) {
+            Ok(x) => Matcher::Process(x),
+            Err(_err) => return Err(format!("unable to parse process {}", words[2])),
+        },
+        kind => return Err(format!("unknown kind {}", kind)),
+    };
+
+    Ok(Some(Rule { action, matcher }))
+}
+
+// Rules pushed out in a file of their own, so they can be changed without
+// restarting the agent. A connection matching a deny rule is dropped, and
+// once there are allow rules so is one which matches none of them.
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    rules : Vec<Rule>,
+}
+
+impl Rules {
+    // Lines which can't be parsed are skipped with a warning, the rest still
+    // apply.
+    pub fn parse(contents: &str) -> Rules {
+        let mut rules = Vec::new();
+        for (number, line) in contents.lines().enumerate() {
+            match parse_line(line) {
+                Ok(Some(rule)) => rules.push(rule),
+                Ok(None) => (),
+                Err(err) => warn!("skipping line {} of the filter rules: {}", number + 1, err),
+            }
+        }
+
+        Rules { rules }
+    }
+
+    fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn drops(&self, connection: &OpenConnection) -> bool {
+        let matching = |action| self.rules.iter()
+            .filter(|rule| rule.action == action)
+            .any(|rule| rule.matcher.matches(connection));
+
+        if matching(Action::Deny) {
+            return true;
+        }
+
+        self.rules.iter().any(|rule| rule.action == Action::Allow) && !matching(Action::Allow)
+    }
+}
+
+// Re-reads the rules every interval, only parsing them again when the file
+// has changed. When it can't be read the last rules which were loaded stay.
+#[derive(Debug, Clone)]
+pub struct RulesFile {
+    path : PathBuf,
+    interval : Duration,
+    checked : Instant,
+    contents : Option<String>,
+    rules : Rules,
+}
+
+impl RulesFile {
+    pub fn new(path: &str, interval: Duration) -> RulesFile {
+        let mut file = RulesFile {
+            path: PathBuf::from(path),
+            interval,
+            checked: Instant::now(),
+            contents: None,
+            rules: Rules::default(),
+        };
+        file.reload();
+
+        file
+    }
+
+    // Whether the rules were changed.
+    pub fn reload(&mut self) -> bool {
+        self.checked = Instant::now();
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(x) => x,
+            Err(err) => {
+                warn!("unable to read the filter rules {}, keeping the last ones: {}", self.path.display(), err);
+                return false;
+            }
+        };
+
+        if self.contents.as_ref() == Some(&contents) {
+            return false;
+        }
+
+        self.rules = Rules::parse(&contents);
+        self.contents = Some(contents);
+        info!("loaded {} filter rules from {}", self.rules.len(), self.path.display());
+        true
+    }
+
+    pub fn rules(&mut self, now: Instant) -> &Rules {
+        if now.duration_since(self.checked) >= self.interval {
+            self.reload();
+        }
+
+        &self.rules
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use tempfile::tempdir;
+    use parser::Payload;
+    use test_support::default_open_payload;
+
+    fn connection(destination : Ipv4Addr, destination_port : u16) -> OpenConnection {
+        match default_open_payload(40000, destination_port, None) {
+            Payload::Open(connection) => OpenConnection { source: Ipv4Addr::new(192, 168, 1, 2), destination, .. connection },
+            _ => panic!("expected an open"),
+        }
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let rules = Rules::parse("# pushed by the SOC\n\ndeny port 22\ndeny network 10.0.0.0/8 # the lab\ndeny port ssh\nallow\nblock port 80\n");
+        assert_eq!(2, rules.len());
+
+        assert!(rules.drops(&connection(Ipv4Addr::new(1, 1, 1, 1), 22)));
+        assert!(rules.drops(&connection(Ipv4Addr::new(10, 2, 3, 4), 443)));
+        assert!(!rules.drops(&connection(Ipv4Addr::new(1, 1, 1, 1), 443)));
+    }
+
+    #[test]
+    fn test_allow_rules() {
+        let rules = Rules::parse("allow network 192.168.0.0/16\ndeny port 23\n");
+        assert!(!rules.drops(&connection(Ipv4Addr::new(1, 1, 1, 1), 443)));
+        assert!(rules.drops(&connection(Ipv4Addr::new(1, 1, 1, 1), 23)));
+
+        let rules = Rules::parse("allow port 443\n");
+        assert!(!rules.drops(&connection(Ipv4Addr::new(1, 1, 1, 1), 443)));
+        assert!(rules.drops(&connection(Ipv4Addr::new(1, 1, 1, 1), 80)));
+    }
+
+    #[test]
+    fn test_reload_on_change() {
+        let directory = tempdir().unwrap();
+        let path = directory.path().join("rules");
+        fs::write(&path, "deny port 22\n").unwrap();
+
+        let mut file = RulesFile::new(path.to_str().unwrap(), Duration::from_secs(60));
+        let ssh = connection(Ipv4Addr::new(1, 1, 1, 1), 22);
+        assert!(file.rules(Instant::now()).drops(&ssh));
+
+        fs::write(&path, "deny port 80\n").unwrap();
+        assert!(file.rules(Instant::now()).drops(&ssh));
+        assert!(!file.rules(Instant::now() + Duration::from_secs(60)).drops(&ssh));
+        assert!(!file.reload());
+
+        // The last rules are kept while the file is gone.
+        fs::remove_file(&path).unwrap();
+        assert!(!file.reload());
+        assert!(file.rules(Instant::now()).drops(&connection(Ipv4Addr::new(1, 1, 1, 1), 80)));
+    }
+}
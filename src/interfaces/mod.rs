@@ -0,0 +1,93 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{ Duration, Instant };
+use pnet::datalink::{ self, NetworkInterface };
+use ipnetwork::IpNetwork;
+
+// Addresses can come and go (DHCP, VPNs) so the map is rebuilt this often.
+const REFRESH : u64 = 60;
+
+// Maps the local addresses of this machine to the interface which owns them.
+pub struct Interfaces {
+    map : HashMap<Ipv4Addr, String>,
+    refreshed : Instant,
+}
+
+fn build(interfaces : Vec<NetworkInterface>) -> HashMap<Ipv4Addr, String> {
+    let mut map = HashMap::new();
+    for interface in interfaces {
+        for address in interface.ips {
+            if let IpNetwork::V4(network) = address {
+                map.insert(network.ip(), interface.name.clone());
+            }
+        }
+    }
+
+    map
+}
+
+impl Interfaces {
+    pub fn new() -> Interfaces {
+        Interfaces {
+            map: build(datalink::interfaces()),
+            refreshed: Instant::now(),
+        }
+    }
+
+    pub fn find(&mut self, address : &Ipv4Addr) -> Option<String> {
+        if self.refreshed.elapsed() > Duration::from_secs(REFRESH) {
+            sampled_debug!("refreshing interface addresses");
+            self.map = build(datalink::interfaces());
+            self.refreshed = Instant::now();
+        }
+
+        self.map.get(address).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipnetwork::Ipv4Network;
+
+    #[test]
+    fn test_build() {
+        let interfaces : Vec<NetworkInterface> = vec!(NetworkInterface {
+            name: String::from("wg0"),
+            index: 3,
+            mac: None,
+            ips: vec!(IpNetwork::V4(Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 24).unwrap())),
+            flags: 0,
+        });
+
+        let map = build(interfaces);
+        assert_eq!(Some(&String::from("wg0")), map.get(&Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(None, map.get(&Ipv4Addr::new(10, 0, 0, 2)));
+    }
+
+    #[test]
+    fn test_loopback() {
+        let loopback = datalink::interfaces().into_iter()
+            .find(|interface| interface.is_loopback())
+            .map(|interface| interface.name);
+
+        let mut interfaces = Interfaces::new();
+        assert_eq!(loopback, interfaces.find(&Ipv4Addr::new(127, 0, 0, 1)));
+    }
+}
@@ -40,40 +40,84 @@ extern crate rand;
 extern crate tempfile;
 extern crate timer;
 extern crate hostname;
+extern crate mqtt;
+extern crate native_tls;
+extern crate openssl;
+extern crate postgres;
+extern crate parquet;
+extern crate regex;
+extern crate rmp_serde;
+#[cfg(feature = "async-http")]
+extern crate futures;
+#[cfg(feature = "async-http")]
+extern crate tokio;
 
 
 use std::sync::mpsc::Sender;
+use std::sync::mpsc::SyncSender;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::channel;
+use std::sync::mpsc::sync_channel;
 use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
 
 use std::thread;
 use std::fs::File;
-use std::path::Path;
+use std::path::{ Path, PathBuf };
 use std::io::prelude::*;
 use std::time::{ Duration, Instant };
+use std::net::Ipv4Addr;
 
-use parser::{ Parser, Payload };
-use conn_track::Conntrack;
+use parser::{ Payload, OpenConnection, ConnectionRecord, Program, SCHEMA_VERSION, generate_hash };
+use parser::pool::{ ParserPool };
+use parser::redact::{ Redactor };
+use conn_track::{ Conntrack, Dispatcher };
 use rand::Rng;
-use enums::{ Config };
-use filters::{ Filter };
+use enums::{ AgentIdentity, Config, EmitMode };
+use outputs::{ OutputsConfig, Route, Serialization };
+use filters::{ Filter, PreFilter };
 use state::{ State };
+use metrics::{ Metrics };
+use rollup::{ Rollup };
+use baseline::{ Baseline };
+use pseudonymize::{ Pseudonymizer };
 use hostname::get_hostname;
+use error::{ Error };
+use chrono::Utc;
 
-mod conn_track;
+#[macro_use]
+mod sampling;
 mod proc_chomper;
-mod parser;
 mod proc;
 mod state;
+mod replay;
+mod interfaces;
+mod services;
+mod summary;
+mod capabilities;
+#[cfg(test)]
+mod test_support;
 
 pub mod outputs;
 pub mod enums;
 pub mod filters;
+pub mod parser;
+pub mod conn_track;
+pub mod metrics;
+pub mod listeners;
+pub mod rollup;
+pub mod baseline;
+pub mod live;
+pub mod error;
+pub mod builder;
+pub mod pseudonymize;
 
 use uuid::Uuid;
 use std::fs;
+use std::mem;
 use std::io::BufReader;
+use std::io;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct NameTuple {
@@ -81,125 +125,155 @@ struct NameTuple {
     uuid: Option<Uuid>
 }
 
+// The name of an output and how sending to it went.
+pub type OutputResult = (String, Result<(), String>);
+
 pub struct NoTrack {
     pub config : Config,
+    pub identity : AgentIdentity,
     filter: Filter,
     outputs : Vec<Box<outputs::Output>>,
+    // What each of the outputs was made from, to tell which change on a reload.
+    output_keys : Vec<String>,
+    metrics : Arc<Metrics>,
+    // The seq of the last payload emitted.
+    seq : u64,
+    rollup : Rollup,
+    baseline : Baseline,
+    pseudonymizer : Pseudonymizer,
+    // The config files and data directory the outputs are read from again
+    // on SIGHUP.
+    config_files : Vec<String>,
+    data_directory : Option<String>,
 }
 
-impl NoTrack {
-    pub fn from_str(config: &str, data_directory: Option<&str>) -> Result<NoTrack, String> {
-        let mut config : Config = match serde_yaml::from_str(config) {
-            Ok(x) => x,
-            Err(err) => {
-                error!("Unable to parse config: {}", err);
-                return Err(String::from("unable to parse config"));
-            }
-        };
-
-        let directory = match  data_directory {
-            Some(directory) => String::from(directory),
-            None => {
-                match config.directory {
-                    Some(directory) => directory,
-                    None => return Err(String::from("no data directory defined")),
-                }
-            }
-        };
+// Set by SIGHUP, the main loop picks it up and reloads the outputs.
+static RELOAD_REQUESTED : AtomicBool = AtomicBool::new(false);
 
-        if check_directory(&directory) == false {
-            return Err(String::from("data directory defined, does not exist"));
-        }
+extern "C" fn on_reload_signal(_signal : libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
 
+// Makes SIGHUP ask for the outputs to be reloaded.
+fn handle_reload_signal() -> Result<(), io::Error> {
+    let handler = on_reload_signal as extern "C" fn(libc::c_int);
+    match unsafe { libc::signal(libc::SIGHUP, handler as libc::sighandler_t) } {
+        libc::SIG_ERR => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
 
-        config = Config {
-            directory: Some(String::from(directory)),
-            .. config
-        };
+// Whether a reload was asked for since this was last called.
+fn reload_requested() -> bool {
+    RELOAD_REQUESTED.swap(false, Ordering::SeqCst)
+}
 
-        NoTrack::new(config)
+impl NoTrack {
+    pub fn from_str(config: &str, data_directory: Option<&str>) -> Result<NoTrack, Error> {
+        NoTrack::new(parse_config(config, data_directory)?)
     }
 
-    pub fn from_file(name: &str, data_directory : Option<&str>) -> Result<NoTrack, String> {
-        let mut file = match File::open(name) {
-            Ok(x) => x,
-            Err(_err) => return Err(String::from("unable to open config file")),
-        };
-
-        let mut contents = String::new();
-        if let Err(_) = file.read_to_string(&mut contents) {
-            return Err(String::from("unable to read config file"));
-        }
+    pub fn from_file(name: &str, data_directory : Option<&str>) -> Result<NoTrack, Error> {
+        NoTrack::new(read_config(name, data_directory)?)
+    }
 
-        NoTrack::from_str(&contents, data_directory)
+    // Configures the agent in code rather than with a config file.
+    pub fn builder() -> builder::NoTrackBuilder {
+        builder::NoTrackBuilder::new()
     }
 
-    pub fn new(config: Config) -> Result<NoTrack, String> {
-        let config = populate_config(config);
-        let outputs = outputs::create(&config)?;
-        let filter = Filter::new(config.filters)?;
+    pub fn new(config: Config) -> Result<NoTrack, Error> {
+        let (config, identity) = populate_config(config);
+        sampling::set_rate(config.log_sample_rate);
+        let (output_keys, outputs) : (Vec<String>, Vec<Box<outputs::Output>>) = outputs::create_reusing(&config, &identity, &mut Vec::new())?.into_iter().unzip();
+        let metrics = Arc::new(Metrics::new());
+        metrics.outputs_changed(outputs::latencies(&output_keys, &outputs));
+        let filter = Filter::new(config.filters.clone()).map_err(Error::Config)?;
+        let rollup = Rollup::new(&config.rollup, Instant::now());
+        let baseline = Baseline::new(&config.baseline, baseline::baseline_file(&config.directory));
+        let pseudonymizer = Pseudonymizer::new(&config.pseudonymize).map_err(Error::Config)?;
 
         Ok(NoTrack {
             config : config,
+            identity,
             outputs :  outputs,
+            output_keys,
             filter: filter,
+            metrics,
+            seq: 0,
+            rollup,
+            baseline,
+            pseudonymizer,
+            config_files: Vec::new(),
+            data_directory: None,
         })
     }
 
-    pub fn run(&mut self) -> Result<(), String> {
-        let agent = self.config.uuid.unwrap_or(Uuid::new_v4());
+    // Where the config came from, so the outputs can be reloaded from it.
+    pub fn reload_from(self, names: &[&str], data_directory: Option<&str>) -> NoTrack {
+        NoTrack {
+            config_files: names.iter().map(|name| name.to_string()).collect(),
+            data_directory: data_directory.map(String::from),
+            .. self
+        }
+    }
 
-        let mut tracker=  match Conntrack::new() {
-            Ok(x) => x,
-            Err(_err) => return Err(String::from("unable to bind to conntrack, please check permissions")),
-        };
+    pub fn run(&mut self) -> Result<(), Error> {
+        let agent = self.identity.uuid;
 
-        let mut parser = match Parser::new(agent) {
+        capabilities::check(&self.metrics);
+        let tracker = Conntrack::new(&self.config.conntrack);
+        self.log_summary(tracker.is_ok());
+
+        let mut tracker=  match tracker {
             Ok(x) => x,
-            Err(_err) => return Err(String::from("unable to parse process descriptors, please check permissions")),
+            Err(_err) => return Err(Error::Conntrack(String::from("unable to bind to conntrack, please check permissions"))),
         };
 
-        let (mut tx, rx) : (Sender<conn_track::Connection>, Receiver<conn_track::Connection>) = channel();
+        let (tx, rx) : (SyncSender<conn_track::Connection>, Receiver<conn_track::Connection>) = sync_channel(self.config.channel_depth);
+        let dump_tx = tx.clone();
+        let mut dispatcher = Dispatcher::new(tx, self.metrics.clone()).with_raw(self.config.conntrack.raw);
+        let (payload_tx, payload_rx) : (Sender<Payload>, Receiver<Payload>) = channel();
+        if self.config.listeners.enabled {
+            listeners::start(&self.config.listeners, agent, self.config.parser.clone(), payload_tx.clone()).map_err(Error::Parser)?;
+        }
+        self.start_parsers(agent, rx, payload_tx, false)?;
 
-        let mut state = match State::new() {
-            Ok(x) => x,
-            Err(_err) => return Err(String::from("unable to start the state module")),
-        };
+        let mut state = self.state()?;
+        if let Some(ref bind) = self.config.live.bind {
+            let connections = live::connections();
+            live::start(bind, connections.clone(), self.pseudonymizer.clone()).map_err(Error::Output)?;
+            state = state.live(connections);
+        }
 
         thread::spawn(move || {
             info!("starting conntrack");
-            tracker.start(&mut tx);
+            tracker.start(&mut dispatcher);
         });
 
+        // The subscription is already up, so nothing opening during the dump
+        // is missed.
+        if self.config.conntrack.dump_on_start {
+            self.dump_conntrack(dump_tx.clone());
+        }
+
+        if let Err(err) = conn_track::handle_dump_signal() {
+            warn!("unable to handle SIGUSR2, the conntrack table can't be dumped on request: {}", err);
+        }
+
+        if let Err(err) = handle_reload_signal() {
+            warn!("unable to handle SIGHUP, the outputs can't be reloaded: {}", err);
+        }
+
         let duration = Duration::from_secs(60);
+        let tick = Duration::from_secs(1);
         let mut time = Instant::now();
         let mut finished = false;
 
         info!("starting main loop");
         while !finished {
-            match rx.recv_timeout(duration) {
-                Ok(con) => {
-                    trace!("received {:?} from channel, parsing", con);
-                    if let Some(payload) = parser.parse(con) {
-                        if ! self.filter.apply(&payload) {
-                            let payload = state.transform(payload);
-                            let json = match payload {
-                                Payload::Open(ref connection)  => serde_json::to_string(connection).unwrap(),
-                                Payload::Close(ref connection) => serde_json::to_string(connection).unwrap(),
-                            };
-
-                            trace!("created json payload: {}", json);
-                            for output in &self.outputs {
-                                match payload {
-                                    Payload::Open(_) => output.process_open_connection(&json),
-                                    Payload::Close(_) => output.process_close_connection(&json),
-                                }
-                            }
-                        }
-                    } else {
-                        debug!("received none, dropping packet");
-                    }
-                },
+            match payload_rx.recv_timeout(tick) {
+                Ok(payload) => self.process_payload(&mut state, payload),
                 Err(err) => {
                     match err {
                         RecvTimeoutError::Disconnected => { finished = true; },
@@ -208,27 +282,573 @@ impl NoTrack {
                 }
             }
 
+            for payload in state.expired() {
+                self.emit(payload);
+            }
+
+            if conn_track::dump_requested() {
+                self.dump_conntrack(dump_tx.clone());
+            }
+
+            if reload_requested() {
+                self.reload_config();
+            }
+
+            if let Some(summary) = self.rollup.due(agent, Instant::now()) {
+                self.emit(Payload::Rollup(summary));
+            }
+
             if time.elapsed() >= duration {
+                info!("metrics: {:?}", self.metrics.snapshot());
                 trace!("sending alive connections");
                 let alive_connections = state.connections();
                 for output in &self.outputs {
                     output.process_alive_connections(&alive_connections);
                 }
+                self.save_baseline();
 
                 time = Instant::now();
             }
+        }
+
+        for payload in state.drain() {
+            self.emit(payload);
+        }
+        self.finish_rollup(agent);
+        self.save_baseline();
+
+        self.flush();
+        Ok(())
+    }
+
+    // Dumps the current conntrack table through the parser, filters and
+    // outputs once and then returns.
+    pub fn oneshot(&mut self) -> Result<(), Error> {
+        let agent = self.identity.uuid;
+
+        capabilities::check(&self.metrics);
+        let mut tracker=  match Conntrack::oneshot() {
+            Ok(x) => x,
+            Err(_err) => return Err(Error::Conntrack(String::from("unable to bind to conntrack, please check permissions"))),
+        };
+
+        let (tx, rx) : (SyncSender<conn_track::Connection>, Receiver<conn_track::Connection>) = sync_channel(self.config.channel_depth);
+        let mut dispatcher = Dispatcher::blocking(tx, self.metrics.clone()).with_raw(self.config.conntrack.raw);
+        // Only the parsers hold the sender, so the payloads end with the dump.
+        let (payload_tx, payload_rx) : (Sender<Payload>, Receiver<Payload>) = channel();
+        self.start_parsers(agent, rx, payload_tx, true)?;
+
+        let mut state = self.state()?;
+
+        let dump = thread::spawn(move || {
+            info!("dumping conntrack table");
+            tracker.dump(&mut dispatcher)
+        });
+
+        for payload in payload_rx {
+            self.process_payload(&mut state, payload);
+        }
+
+        for payload in state.drain() {
+            self.emit(payload);
+        }
+        self.finish_rollup(agent);
+        self.save_baseline();
+
+        self.flush();
+        match dump.join() {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(err)) => Err(Error::Conntrack(format!("unable to dump conntrack table: {}", err))),
+            Err(_err) => Err(Error::Conntrack(String::from("conntrack dump thread panicked"))),
+        }
+    }
+
+    // Dumps the conntrack table into the running pipeline, alongside the
+    // events, from a thread of its own. Connections which are already tracked
+    // aren't sent again.
+    fn dump_conntrack(&self, tx: SyncSender<conn_track::Connection>) {
+        let mut dispatcher = Dispatcher::blocking(tx, self.metrics.clone()).with_raw(self.config.conntrack.raw);
+        thread::spawn(move || {
+            let mut tracker = match Conntrack::oneshot() {
+                Ok(x) => x,
+                Err(err) => {
+                    error!("unable to bind to conntrack to dump the table: {}", err);
+                    return;
+                },
+            };
+
+            info!("dumping conntrack table on request");
+            if let Err(err) = tracker.dump(&mut dispatcher) {
+                error!("unable to dump conntrack table: {}", err);
+            }
+        });
+    }
 
+    fn state(&self) -> Result<State, Error> {
+        let state = match State::new(self.config.max_connections, Duration::from_millis(self.config.debounce_window), Duration::from_secs(self.config.udp_aggregate_window), self.metrics.clone()) {
+            Ok(x) => x,
+            Err(_err) => return Err(Error::Parser(String::from("unable to start the state module"))),
+        };
 
+        match self.config.emit_mode {
+            EmitMode::OpenAndClose => Ok(state),
+            EmitMode::CloseOnly => Ok(state.close_only(Duration::from_secs(self.config.emit_ttl))),
+        }
+    }
 
+    fn start_parsers(&self, agent: Uuid, rx: Receiver<conn_track::Connection>, payload_tx: Sender<Payload>, blocking: bool) -> Result<(), Error> {
+        let mut pre_filter = PreFilter::new(&self.config.filters).map_err(Error::Config)?;
+        if self.config.filters.zerotrust_track_connections {
+            pre_filter = pre_filter.with_endpoints(outputs::endpoint_addresses(&self.config.outputs));
         }
 
+        let redactor = Redactor::new(&self.config.parser.redact).map_err(Error::Config)?;
+        let pool = match ParserPool::new(agent, self.config.parser.clone(), pre_filter, redactor, self.metrics.clone(), self.config.channel_depth, payload_tx) {
+            Ok(x) => x.with_blocking(blocking),
+            Err(_err) => return Err(Error::Parser(String::from("unable to parse process descriptors, please check permissions"))),
+        };
+
+        let metrics = self.metrics.clone();
+        thread::spawn(move || {
+            for con in rx {
+                metrics.channel_pop();
+                pool.dispatch(con);
+            }
+        });
+
         Ok(())
     }
 
-    pub fn dump_config(&self) -> Result<(), String> {
+    fn process_payload(&mut self, state: &mut State, payload: Payload) {
+        sampled_trace!("received {:?} from parser", payload);
+        if let Some(rule) = self.filter.apply(&payload) {
+            self.metrics.connection_filtered(rule);
+            return;
+        }
+
+        if let Some(payload) = state.transform(payload) {
+            self.emit(payload);
+        }
+    }
+
+    fn emit(&mut self, mut payload: Payload) {
+        let route = match payload {
+            Payload::Open(_) => Route::Open,
+            Payload::Close(_) => Route::Close,
+            Payload::Connection(_) => Route::Connection,
+            Payload::ListenOpen(_) | Payload::ListenClose(_) | Payload::Rollup(_) => Route::Event,
+            // The state keeps updates to itself, there is nothing to send.
+            Payload::Update(_) => return,
+        };
+
+        self.seq += 1;
+        match payload {
+            Payload::Open(ref mut connection) => {
+                connection.seq = self.seq;
+                connection.novel = self.baseline.observe(connection, Utc::now());
+            },
+            Payload::Close(ref mut connection) => connection.seq = self.seq,
+            Payload::ListenOpen(ref mut event) | Payload::ListenClose(ref mut event) => event.seq = self.seq,
+            Payload::Rollup(ref mut summary) => summary.seq = self.seq,
+            Payload::Connection(ref mut record) => {
+                record.open.seq = self.seq;
+                record.open.novel = self.baseline.observe(&record.open, Utc::now());
+            },
+            Payload::Update(_) => (),
+        }
+
+        match payload {
+            Payload::Open(ref connection) | Payload::Connection(ConnectionRecord { open: ref connection, .. }) => self.rollup.record(connection),
+            _ => (),
+        }
+
+        // Hashed only now, so everything before sees the real values.
+        let json = self.pseudonymizer.to_json(&payload).unwrap();
+
+        // Made once for every output which asked for it, an output left
+        // without it makes its own from the JSON.
+        let msgpack = match self.outputs.iter().any(|output| output.serialization() == Serialization::Msgpack) {
+            true => match self.pseudonymizer.to_msgpack(&payload) {
+                Ok(x) => Some(x),
+                Err(err) => {
+                    error!("{}", err);
+                    None
+                },
+            },
+            false => None,
+        };
+
+        sampled_trace!("created json payload: {}", json);
+        let mut delivered = Vec::new();
+        let mut failed = false;
+        for (index, output) in self.outputs.iter().enumerate() {
+            let result = match (output.serialization(), &msgpack) {
+                (Serialization::Msgpack, Some(encoded)) => output.process_encoded(route, &json, encoded),
+                _ => route.process(output.as_ref(), &json),
+            };
+
+            match result {
+                Ok(()) => delivered.extend(self.output_keys.get(index)),
+                Err(err) => {
+                    error!("unable to send payload to output: {}", err);
+                    failed = true;
+                },
+            }
+        }
+
+        if failed {
+            self.dead_letter(route, &json, &delivered);
+        }
+    }
+
+    // Keeps a connection which an output turned away for --replay, the outputs
+    // which took it are noted so they don't get it twice.
+    fn dead_letter(&self, route: Route, json: &str, delivered: &[&String]) {
+        let (file, event) = match (&self.config.dead_letter, replay::Event::from_route(route)) {
+            (Some(file), Some(event)) => (file, event),
+            _ => return,
+        };
+
+        if let Err(err) = replay::write(file, event, json, delivered) {
+            error!("{}", err);
+        }
+    }
+
+    pub fn dump_config(&self) -> Result<(), Error> {
         dump_config(&self.config)
     }
 
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub fn replay(&mut self, file: &str) -> Result<usize, Error> {
+        let replayed = replay::replay(file, &self.output_keys, &self.outputs);
+        self.flush();
+        replayed.map_err(Error::Output)
+    }
+
+    fn log_summary(&self, conntrack: bool) {
+        let summary = summary::summary(&self.config, conntrack);
+        match serde_json::to_string(&summary) {
+            Ok(json) => info!("starting with: {}", json),
+            Err(err) => warn!("unable to create the start up summary: {}", err),
+        }
+
+        for output in &summary.outputs {
+            if output.reachable == Some(false) {
+                warn!("the {} output is not reachable at {:?}", output.output, output.target);
+            }
+        }
+    }
+
+    // Swaps in new outputs settings, only the outputs whose settings changed
+    // are made again and the ones which are gone are flushed and dropped. When
+    // an output can't be made everything carries on as it was.
+    pub fn reload_outputs(&mut self, mut config: OutputsConfig) -> Result<(), Error> {
+        outputs::normalize_urls(&mut config).map_err(Error::Config)?;
+        let previous = mem::replace(&mut self.config.outputs, config);
+        let mut running : Vec<outputs::KeyedOutput> = self.output_keys.drain(..).zip(self.outputs.drain(..)).collect();
+
+        let (reloaded, result) = match outputs::create_reusing(&self.config, &self.identity, &mut running) {
+            Ok(reloaded) => {
+                // The rest of the key is the settings, which can hold passwords.
+                for (key, mut output) in running {
+                    info!("removing {} output", key.split(':').next().unwrap_or_default());
+                    if let Err(err) = output.flush() {
+                        warn!("{}", err);
+                    }
+                }
+                (reloaded, Ok(()))
+            },
+            Err(err) => {
+                self.config.outputs = previous;
+                (running, Err(err))
+            },
+        };
+
+        let (output_keys, outputs) = reloaded.into_iter().unzip();
+        self.output_keys = output_keys;
+        self.outputs = outputs;
+        self.metrics.outputs_changed(outputs::latencies(&self.output_keys, &self.outputs));
+        result
+    }
+
+    // Reads the config files again and swaps in their outputs, the rest of the
+    // config only changes on a restart.
+    fn reload_config(&mut self) {
+        if self.config_files.is_empty() {
+            warn!("the outputs can't be reloaded, the config didn't come from files");
+            return;
+        }
+
+        let names : Vec<&str> = self.config_files.iter().map(String::as_str).collect();
+        let result = read_configs(&names, self.data_directory.as_deref())
+            .and_then(|config| self.reload_outputs(config.outputs));
+
+        match result {
+            Ok(()) => info!("reloaded the outputs from {:?}", self.config_files),
+            Err(err) => warn!("unable to reload the outputs, keeping the running ones: {}", err),
+        }
+    }
+
+    // Sends the rollup of what was counted since the last one.
+    fn finish_rollup(&mut self, agent: Uuid) {
+        if self.rollup.enabled() {
+            let summary = self.rollup.finish(agent, Instant::now());
+            self.emit(Payload::Rollup(summary));
+        }
+    }
+
+    fn save_baseline(&self) {
+        if let Err(err) = self.baseline.save() {
+            warn!("{}", err);
+        }
+    }
+
+    // Sends a made up open through the outputs of the kind named, or through
+    // all of them, so a collector can be checked without waiting for traffic.
+    // Each output is waited on afterwards, so the ones which send in the
+    // background report whether it got through and not only that it was queued.
+    pub fn test_outputs(&mut self, name: &str) -> Result<Vec<OutputResult>, Error> {
+        let json = serde_json::to_string(&synthetic_open(&self.identity)).unwrap();
+        let mut results = Vec::new();
+        let labels = outputs::labels(&self.output_keys);
+
+        for ((key, label), output) in self.output_keys.iter().zip(labels).zip(self.outputs.iter()) {
+            let kind = key.split(':').next().unwrap_or_default();
+            if name != "all" && name != kind {
+                continue;
+            }
+
+            let result = output.process_open_connection(&json).and_then(|()| output.confirm());
+            results.push((label, result));
+        }
+
+        if results.is_empty() {
+            return Err(Error::Output(format!("no {} output is configured", name)));
+        }
+
+        Ok(results)
+    }
+
+    // Waits for the outputs to send everything they have queued.
+    pub fn flush(&mut self) {
+        for output in self.outputs.iter_mut() {
+            if let Err(err) = output.flush() {
+                warn!("{}", err);
+            }
+        }
+    }
+
+}
+
+// An open between two documentation addresses (RFC 5737) made by a process
+// called zerotrust-track-test, so it can't be mistaken for real traffic.
+fn synthetic_open(identity: &AgentIdentity) -> Payload {
+    let source = Ipv4Addr::new(192, 0, 2, 1);
+    let destination = Ipv4Addr::new(192, 0, 2, 2);
+    let hash = generate_hash("TCP", &source, &40000, &destination, &9) as i64;
+
+    Payload::Open(OpenConnection {
+        hash,
+        flow: hash,
+        schema_version: SCHEMA_VERSION,
+        seq: 0,
+        uuid: Uuid::new_v4(),
+        agent: identity.uuid,
+        timestamp: Utc::now().to_rfc3339(),
+        protocol: enums::Protocol::TCP,
+        source,
+        destination,
+        source_port: 40000,
+        destination_port: 9,
+        direction: Some(enums::Direction::Outbound),
+        local_address: Some(source),
+        local_port: Some(40000),
+        remote_address: Some(destination),
+        remote_port: Some(9),
+        username: String::from("zerotrust-track-test"),
+        username_resolved: true,
+        uid: 0,
+        program_details: Some(Program {
+            command_line: vec!(String::from("zerotrust-track"), String::from("--test-output")),
+            .. Program::new(0, 0, String::from("zerotrust-track-test"), enums::Resolution::Direct)
+        }),
+        attribution_skipped: false,
+        status_flags: Vec::new(),
+        interface: None,
+        destination_service: None,
+        novel: None,
+        pre_existing: false,
+    })
+}
+
+pub fn parse_config(config: &str, data_directory: Option<&str>) -> Result<Config, Error> {
+    let mut config : Config = match serde_yaml::from_str(config) {
+        Ok(x) => x,
+        Err(err) => {
+            error!("Unable to parse config: {}", err);
+            return Err(Error::Config(String::from("unable to parse config")));
+        }
+    };
+
+    outputs::normalize_urls(&mut config.outputs).map_err(Error::Config)?;
+    check_config(&config)?;
+    set_directory(config, data_directory)
+}
+
+// Settings which parse but which the agent can't run with.
+fn check_config(config: &Config) -> Result<(), Error> {
+    // A channel with no room only takes a connection when the other end is
+    // already waiting for one, so nearly all of them would be dropped.
+    if config.channel_depth == 0 {
+        return Err(Error::Config(String::from("channel_depth needs to be at least 1")));
+    }
+
+    // Every key starts with an empty prefix, so it would send the whole
+    // environment.
+    if config.parser.environment_keys.iter().any(|key| key.is_empty()) {
+        return Err(Error::Config(String::from("environment_keys can't contain an empty prefix")));
+    }
+
+    // The domains would be resolved over and over without a pause.
+    if config.filters.ignore_domains_refresh == 0 {
+        return Err(Error::Config(String::from("ignore_domains_refresh needs to be at least 1")));
+    }
+
+    Ok(())
+}
+
+fn set_directory(config: Config, data_directory: Option<&str>) -> Result<Config, Error> {
+    let directory = match  data_directory {
+        Some(directory) => String::from(directory),
+        None => {
+            match config.directory {
+                Some(directory) => directory,
+                None => return Err(Error::Config(String::from("no data directory defined"))),
+            }
+        }
+    };
+
+    if check_directory(&directory) == false {
+        if !config.create_directory {
+            return Err(Error::Config(String::from("data directory defined, does not exist")));
+        }
+
+        if let Err(err) = fs::create_dir_all(&directory) {
+            error!("Unable to create data directory {}: {}", directory, err);
+            return Err(Error::Io(format!("unable to create data directory {}", directory), err));
+        }
+
+        info!("created data directory {}", directory);
+    }
+
+
+    Ok(Config {
+        directory: Some(String::from(directory)),
+        .. config
+    })
+}
+
+pub fn read_config(name: &str, data_directory : Option<&str>) -> Result<Config, Error> {
+    read_configs(&[name], data_directory)
+}
+
+// Reads each file in order, a directory stands for the *.yaml files inside of
+// it sorted by name, and merges them with later files winning.
+pub fn read_configs(names: &[&str], data_directory : Option<&str>) -> Result<Config, Error> {
+    let mut merged = serde_yaml::Value::Null;
+
+    for name in names {
+        for file in config_files(name)? {
+            let mut contents = String::new();
+            if let Err(err) = File::open(&file).and_then(|mut file| file.read_to_string(&mut contents)) {
+                return Err(Error::Io(format!("unable to read config file {}", file.display()), err));
+            }
+
+            let fragment : serde_yaml::Value = match serde_yaml::from_str(&contents) {
+                Ok(x) => x,
+                Err(err) => {
+                    error!("Unable to parse config {}: {}", file.display(), err);
+                    return Err(Error::Config(format!("unable to parse config file {}", file.display())));
+                }
+            };
+
+            merge_config(&mut merged, fragment);
+        }
+    }
+
+    let mut config : Config = match serde_yaml::from_value(merged) {
+        Ok(x) => x,
+        Err(err) => {
+            error!("Unable to parse config: {}", err);
+            return Err(Error::Config(String::from("unable to parse config")));
+        }
+    };
+
+    outputs::normalize_urls(&mut config.outputs).map_err(Error::Config)?;
+    check_config(&config)?;
+    set_directory(config, data_directory)
+}
+
+fn config_files(name: &str) -> Result<Vec<PathBuf>, Error> {
+    let path = Path::new(name);
+    if !path.is_dir() {
+        if !path.is_file() {
+            return Err(Error::Config(String::from("unable to open config file")));
+        }
+
+        return Ok(vec!(path.to_path_buf()));
+    }
+
+    let entries = match fs::read_dir(path) {
+        Ok(x) => x,
+        Err(err) => return Err(Error::Io(String::from("unable to read config directory"), err)),
+    };
+
+    let mut files : Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|file| file.is_file() && file.extension().is_some_and(|extension| extension == "yaml" || extension == "yml"))
+        .collect();
+    files.sort();
+
+    Ok(files)
+}
+
+// Maps are merged key by key, anything else, including lists, is replaced by
+// the later value.
+fn merge_config(base: &mut serde_yaml::Value, fragment: serde_yaml::Value) {
+    match (base, fragment) {
+        (_, serde_yaml::Value::Null) => (),
+        (serde_yaml::Value::Mapping(ref mut base), serde_yaml::Value::Mapping(fragment)) => {
+            for (key, value) in fragment {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_config(existing, value),
+                    None => { base.insert(key, value); },
+                }
+            }
+        },
+        (base, fragment) => *base = fragment,
+    }
+}
+
+// Name and uuid given on the command line, these win over both the config
+// file and the saved name tuple.
+pub fn override_agent(config: Config, name: Option<&str>, uuid: Option<&str>) -> Result<Config, Error> {
+    let uuid = match uuid {
+        Some(uuid) => match Uuid::parse_str(uuid) {
+            Ok(x) => Some(x),
+            Err(_err) => return Err(Error::Config(format!("invalid uuid {}", uuid))),
+        },
+        None => config.uuid,
+    };
+
+    Ok(Config {
+        name: name.map(String::from).or(config.name),
+        uuid,
+        .. config
+    })
 }
 
 fn check_directory(directory : &str) -> bool {
@@ -287,20 +907,50 @@ fn save_uuid_name_tuple(file: &str, tuple : &NameTuple) -> Result<(), String>{
     Ok(())
 }
 
-fn populate_config(config: Config) -> Config {
+// Where the agent keeps its state when no directory is configured, the XDG
+// state directory when the environment has one and then the system one.
+fn fallback_candidates(state_home: Option<String>) -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+    if let Some(state_home) = state_home {
+        if !state_home.is_empty() {
+            candidates.push(Path::new(&state_home).join("zerotrust"));
+        }
+    }
+
+    candidates.push(PathBuf::from("/var/lib/zerotrust"));
+    candidates
+}
+
+// The first candidate which exists or can be created, /tmp is world writable
+// so it is only used when none of them can be.
+fn fallback_directory(candidates: &[PathBuf]) -> String {
+    for candidate in candidates {
+        match fs::create_dir_all(candidate) {
+            Ok(()) => {
+                let directory = candidate.to_string_lossy().to_string();
+                warn!("No working directory set, using {}", directory);
+                return directory;
+            },
+            Err(err) => debug!("unable to use {} as the working directory: {}", candidate.display(), err),
+        }
+    }
+
+    error!("No working directory set and none could be created, using /tmp which is shared with every user, set directory in the config");
+    String::from("/tmp")
+}
+
+fn populate_config(config: Config) -> (Config, AgentIdentity) {
 
     let directory = match config.directory {
         Some(directory) => directory,
-        None => {
-            warn!("No working directory set, using /tmp");
-            String::from("/tmp")
-        }
+        None => fallback_directory(&fallback_candidates(std::env::var("XDG_STATE_HOME").ok())),
     };
 
     let tuple_file_name = format!("{}/{}", directory, "/name_tuple.yaml");
     let names_file_name = format!("{}/{}", directory, "/names.txt");
     let tuple = load_uuid_name_tuple(&tuple_file_name);
     let names = load_names(&names_file_name);
+    let hostname = get_hostname();
 
     let uuid = match config.uuid {
         Some(uuid) => uuid,
@@ -318,9 +968,9 @@ fn populate_config(config: Config) -> Config {
             let name = match tuple.name {
                 Some(name) => name,
                 None => {
-                    let name = match get_hostname() {
-                        Some(name) => name.clone(),
-                        None => {
+                    let name = match hostname {
+                        Some(ref name) if config.use_hostname_as_name => name.clone(),
+                        _ => {
                             let name = match rand::thread_rng().choose(&names) {
                                 Some(name) => name.clone(),
                                 None => String::from("unknown"),
@@ -339,22 +989,32 @@ fn populate_config(config: Config) -> Config {
         },
     };
 
-    if let Err(err) = save_uuid_name_tuple(&tuple_file_name, &NameTuple { name: Some(name.clone()), uuid: Some(uuid.clone())}) {
-        warn!("unable to save file tuple {}", err);
+    if config.persist_identity {
+        if let Err(err) = save_uuid_name_tuple(&tuple_file_name, &NameTuple { name: Some(name.clone()), uuid: Some(uuid.clone())}) {
+            warn!("unable to save file tuple {}", err);
+        }
     }
 
-    Config {
+    let identity = AgentIdentity {
+        name: name.clone(),
+        uuid,
+        hostname: hostname.unwrap_or_else(|| String::from("unknown")),
+    };
+
+    let config = Config {
         name: Some(name),
         uuid: Some(uuid),
         directory: Some(directory),
         .. config
-    }
+    };
+
+    (config, identity)
 }
 
-pub fn dump_config(config: &Config) -> Result<(), String> {
+pub fn dump_config(config: &Config) -> Result<(), Error> {
     let config = match serde_yaml::to_string(config) {
         Ok(x) => x,
-        Err(_err) => return Err(String::from("Unable to dump config!")),
+        Err(_err) => return Err(Error::Config(String::from("Unable to dump config!"))),
     };
 
     println!("{}", config);
@@ -366,36 +1026,76 @@ pub fn dump_config(config: &Config) -> Result<(), String> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use filters::FiltersConfig;
-    use outputs::OutputsConfig;
     use tempfile::{tempdir};
     use uuid::Uuid;
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use test_support::{ open_payload, close_payload, default_config, default_filters, default_open_payload, default_close_payload, HttpServer };
+    use filters::{ FilterRule, FiltersConfig };
+    use parser::CloseConnection;
+
+    struct RecordingOutput {
+        events: Rc<RefCell<Vec<String>>>,
+    }
 
-    fn config_string() -> String {
-        let string = String::from("---\ndirectory: /tmp\noutputs:\n  syslog: []\nfilters:\n  non_process_connections: true\n  dns_requests : true\n  zerotrust_track_connections: true");
-        return string;
+    impl outputs::Output for RecordingOutput {
+        fn process_open_connection(&self, json: &str) -> Result<(), String> {
+            self.events.borrow_mut().push(String::from(json));
+            Ok(())
+        }
+
+        fn process_close_connection(&self, json: &str) -> Result<(), String> {
+            self.events.borrow_mut().push(String::from(json));
+            Ok(())
+        }
+
+        fn process_alive_connections(&self, _: &Vec<i64>) { }
     }
 
-    fn default_filters() -> FiltersConfig {
-        FiltersConfig {
-            non_process_connections: true,
-            dns_requests : true,
-            zerotrust_track_connections: true,
+    // Keeps the MessagePack it is handed.
+    struct MsgpackOutput {
+        encoded: Rc<RefCell<Vec<(outputs::Route, Vec<u8>)>>>,
+    }
+
+    impl outputs::Output for MsgpackOutput {
+        fn process_open_connection(&self, _: &str) -> Result<(), String> {
+            Err(String::from("expected msgpack"))
+        }
+
+        fn process_close_connection(&self, _: &str) -> Result<(), String> {
+            Err(String::from("expected msgpack"))
+        }
+
+        fn process_alive_connections(&self, _: &Vec<i64>) { }
+
+        fn serialization(&self) -> Serialization {
+            Serialization::Msgpack
+        }
+
+        fn process_encoded(&self, route: outputs::Route, _: &str, encoded: &[u8]) -> Result<(), String> {
+            self.encoded.borrow_mut().push((route, encoded.to_vec()));
+            Ok(())
         }
     }
 
-    fn default_config() -> Config {
-        Config {
-            directory: None,
-            name: None,
-            uuid: None,
-            outputs : OutputsConfig {
-                zerotrust_endpoint: None,
-                syslog : Some(Vec::new()),
-                elasticsearch : None,
-            },
-            filters: default_filters(),
+    // Turns everything away.
+    struct FailingOutput;
+
+    impl outputs::Output for FailingOutput {
+        fn process_open_connection(&self, _: &str) -> Result<(), String> {
+            Err(String::from("I am unreachable"))
         }
+
+        fn process_close_connection(&self, _: &str) -> Result<(), String> {
+            Err(String::from("I am unreachable"))
+        }
+
+        fn process_alive_connections(&self, _: &Vec<i64>) { }
+    }
+
+    fn config_string() -> String {
+        let string = String::from("---\ndirectory: /tmp\noutputs:\n  syslog: []\nfilters:\n  non_process_connections: true\n  dns_requests : true\n  zerotrust_track_connections: true");
+        return string;
     }
 
 
@@ -407,7 +1107,7 @@ mod tests {
 
     #[test]
     fn test_from_str_fail() {
-        assert!(NoTrack::from_str("", None).is_err());
+        assert!(matches!(NoTrack::from_str("", None), Err(Error::Config(_))));
     }
 
     #[test]
@@ -416,6 +1116,44 @@ mod tests {
         assert!(!NoTrack::from_str(&string, None).is_err());
     }
 
+    #[test]
+    fn test_parse_config_normalizes_urls() {
+        let config = parse_config("---\ndirectory: /tmp\noutputs:\n  zerotrust_endpoint: \"http://collector:8080/\"\nfilters:\n  non_process_connections: true\n  dns_requests : true\n  zerotrust_track_connections: true", None).unwrap();
+        assert_eq!(Some(vec!(String::from("http://collector:8080"))), config.outputs.zerotrust_endpoint);
+
+        let err = parse_config("---\ndirectory: /tmp\noutputs:\n  elasticsearch: \"collector:9200\"\nfilters:\n  non_process_connections: true\n  dns_requests : true\n  zerotrust_track_connections: true", None).err().unwrap();
+        assert_eq!("the url collector:9200 has no scheme, such as http://", err.to_string());
+    }
+
+    #[test]
+    fn test_parse_config_channel_depth() {
+        let config = "---\ndirectory: /tmp\nchannel_depth: {}\nfilters:\n  non_process_connections: true\n  dns_requests : true\n  zerotrust_track_connections: true";
+        assert_eq!(1, parse_config(&config.replace("{}", "1"), None).unwrap().channel_depth);
+
+        let err = parse_config(&config.replace("{}", "0"), None).err().unwrap();
+        assert_eq!("channel_depth needs to be at least 1", err.to_string());
+    }
+
+    #[test]
+    fn test_parse_config_ignore_domains_refresh() {
+        let config = "---\ndirectory: /tmp\nfilters:\n  non_process_connections: true\n  dns_requests : true\n  zerotrust_track_connections: true\n  {}";
+        assert_eq!(300, parse_config(&config.replace("{}", ""), None).unwrap().filters.ignore_domains_refresh);
+        // The name it had before.
+        assert_eq!(60, parse_config(&config.replace("{}", "ignore_domains_ttl: 60"), None).unwrap().filters.ignore_domains_refresh);
+
+        let err = parse_config(&config.replace("{}", "ignore_domains_refresh: 0"), None).err().unwrap();
+        assert_eq!("ignore_domains_refresh needs to be at least 1", err.to_string());
+    }
+
+    #[test]
+    fn test_parse_config_environment_keys() {
+        let config = "---\ndirectory: /tmp\nparser:\n  include_environment: true\n  environment_keys: [{}]\nfilters:\n  non_process_connections: true\n  dns_requests : true\n  zerotrust_track_connections: true";
+        assert_eq!(vec![String::from("LANG")], parse_config(&config.replace("{}", "LANG"), None).unwrap().parser.environment_keys);
+
+        let err = parse_config(&config.replace("{}", "LANG, ''"), None).err().unwrap();
+        assert_eq!("environment_keys can't contain an empty prefix", err.to_string());
+    }
+
     #[test]
     fn test_from_file_fail() {
         let temp_file = tempfile::NamedTempFile::new().unwrap();
@@ -445,6 +1183,345 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_default_config_file() {
+        let config : Config = serde_yaml::from_str(include_str!("../resources/default_config/config.yaml")).unwrap();
+
+        assert_eq!(Some(String::from("/usr/share/zerotrust")), config.directory);
+        assert!(config.filters.zerotrust_track_connections);
+        assert!(config.outputs.zerotrust_endpoint.is_none());
+        assert_eq!(1, config.outputs.syslog.unwrap().len());
+    }
+
+    #[test]
+    fn test_config_without_identity() {
+        let config : Config = serde_yaml::from_str("outputs:\n  syslog: []\nfilters:\n  non_process_connections: true\n  dns_requests : true\n  zerotrust_track_connections: true").unwrap();
+        assert!(config.directory.is_none());
+        assert!(config.name.is_none());
+        assert!(config.uuid.is_none());
+
+        let tempdir = tempdir().unwrap();
+        let (config, identity) = populate_config(Config { directory: tempdir.path().to_str().map(String::from), .. config });
+        assert_eq!(Some(identity.name), config.name);
+        assert_eq!(Some(identity.uuid), config.uuid);
+    }
+
+    #[test]
+    fn test_emit_seq_increases() {
+        let tempdir = tempdir().unwrap();
+        let mut notrack = NoTrack::from_str(&config_string(), tempdir.path().to_str()).unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        notrack.outputs = vec!(Box::new(RecordingOutput { events: events.clone() }));
+
+        // The timestamps tie, the seq still orders them.
+        for hash in 1..5 {
+            notrack.emit(close_payload(hash));
+        }
+
+        let seqs : Vec<u64> = events.borrow().iter()
+            .map(|json| serde_json::from_str::<serde_json::Value>(json).unwrap()["seq"].as_u64().unwrap())
+            .collect();
+        assert_eq!(vec!(1, 2, 3, 4), seqs);
+    }
+
+    #[test]
+    fn test_emit_dead_letter() {
+        let tempdir = tempdir().unwrap();
+        let file = tempdir.path().join("dead-letter.jsonl");
+        let mut notrack = NoTrack::from_str(&config_string(), tempdir.path().to_str()).unwrap();
+        notrack.config.dead_letter = file.to_str().map(String::from);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        notrack.outputs = vec!(Box::new(RecordingOutput { events: events.clone() }), Box::new(FailingOutput));
+        notrack.output_keys = vec!(String::from("webhook:{}"), String::from("syslog:{}"));
+
+        notrack.emit(open_payload(1));
+        notrack.emit(Payload::ListenOpen(parser::ListenEvent {
+            schema_version: SCHEMA_VERSION,
+            seq: 0,
+            agent: notrack.identity.uuid,
+            timestamp: Utc::now().to_rfc3339(),
+            protocol: enums::Protocol::TCP,
+            address: "127.0.0.1".parse().unwrap(),
+            port: 22,
+            username: String::new(),
+            uid: 0,
+            program_details: None,
+        }));
+
+        // Only the connection is kept, the listen event would be out of date.
+        let contents = fs::read_to_string(&file).unwrap();
+        assert_eq!(1, contents.lines().count());
+        let letter : serde_json::Value = serde_json::from_str(contents.lines().next().unwrap()).unwrap();
+        assert_eq!("open", letter["event"]);
+        assert_eq!(1, letter["payload"]["hash"]);
+        assert_eq!(1, letter["delivered"].as_array().unwrap().len());
+
+        // Replayed once the output is back, only to the one which missed it.
+        notrack.outputs = vec!(Box::new(RecordingOutput { events: events.clone() }), Box::new(RecordingOutput { events: events.clone() }));
+        let before = events.borrow().len();
+        assert_eq!(1, notrack.replay(file.to_str().unwrap()).unwrap());
+        assert_eq!(before + 1, events.borrow().len());
+    }
+
+    #[test]
+    fn test_filtered_counted_by_rule() {
+        let tempdir = tempdir().unwrap();
+        let mut notrack = NoTrack::from_str(&config_string(), tempdir.path().to_str()).unwrap();
+        notrack.filter = Filter::new(FiltersConfig { non_process_connections: false, dns_requests: true, .. default_filters() }).unwrap();
+        let mut state = notrack.state().unwrap();
+
+        notrack.process_payload(&mut state, default_open_payload(40000, 53, None));
+        assert_eq!(1, notrack.metrics.filtered_connections());
+        assert_eq!(1, notrack.metrics.filtered_by_rule(FilterRule::DnsRequests));
+        assert_eq!(0, notrack.metrics.filtered_by_rule(FilterRule::NonProcessConnections));
+
+        notrack.process_payload(&mut state, default_open_payload(40000, 443, None));
+        assert_eq!(1, notrack.metrics.filtered_connections());
+    }
+
+    #[test]
+    fn test_close_only_reaches_webhook() {
+        let tempdir = tempdir().unwrap();
+        let webhook = HttpServer::start("200 OK");
+        let config = format!("---
+directory: /tmp
+emit_mode: CloseOnly
+outputs:
+  webhook: {{ url: \"{}\", kind: Slack, allowed_networks: [ 10.0.0.0/8 ] }}
+filters:
+  non_process_connections: false
+  dns_requests: false
+  zerotrust_track_connections: false
+", webhook.url);
+        let mut notrack = NoTrack::from_str(&config, tempdir.path().to_str()).unwrap();
+        let mut state = notrack.state().unwrap();
+
+        let open = match default_open_payload(40000, 443, None) {
+            Payload::Open(connection) => OpenConnection {
+                destination: Ipv4Addr::new(8, 8, 8, 8),
+                remote_address: Some(Ipv4Addr::new(8, 8, 8, 8)),
+                remote_port: Some(443),
+                .. connection
+            },
+            _ => panic!("expected an open"),
+        };
+        let close = match default_close_payload() {
+            Payload::Close(connection) => CloseConnection { uuid: Some(open.uuid), destination: open.destination, .. connection },
+            _ => panic!("expected a close"),
+        };
+
+        // Nothing goes out until the close, which brings the open with it.
+        notrack.process_payload(&mut state, Payload::Open(open));
+        notrack.process_payload(&mut state, Payload::Close(close));
+        notrack.outputs[0].confirm().unwrap();
+
+        let alert : serde_json::Value = serde_json::from_str(&webhook.request().body).unwrap();
+        assert!(alert["text"].as_str().unwrap().contains("8.8.8.8:443"));
+    }
+
+    #[test]
+    fn test_emit_msgpack() {
+        let tempdir = tempdir().unwrap();
+        let mut notrack = NoTrack::from_str(&config_string(), tempdir.path().to_str()).unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let encoded = Rc::new(RefCell::new(Vec::new()));
+        notrack.outputs = vec!(
+            Box::new(RecordingOutput { events: events.clone() }),
+            Box::new(MsgpackOutput { encoded: encoded.clone() }),
+        );
+
+        notrack.emit(open_payload(1));
+        notrack.emit(close_payload(1));
+
+        let json : Vec<serde_json::Value> = events.borrow().iter().map(|json| serde_json::from_str(json).unwrap()).collect();
+        let encoded = encoded.borrow();
+        assert_eq!(vec!(Route::Open, Route::Close), encoded.iter().map(|(route, _)| *route).collect::<Vec<Route>>());
+        for (json, (_, encoded)) in json.iter().zip(encoded.iter()) {
+            let decoded : serde_json::Value = rmp_serde::from_slice(encoded).unwrap();
+            assert_eq!(json, &decoded);
+        }
+    }
+
+    #[test]
+    fn test_emitted_username_pseudonymized() {
+        let emitted = |config: &str| {
+            let tempdir = tempdir().unwrap();
+            let mut notrack = NoTrack::from_str(config, tempdir.path().to_str()).unwrap();
+            let events = Rc::new(RefCell::new(Vec::new()));
+            notrack.outputs = vec!(Box::new(RecordingOutput { events: events.clone() }));
+
+            notrack.emit(open_payload(1));
+            notrack.emit(open_payload(2));
+            let payloads : Vec<serde_json::Value> = events.borrow().iter()
+                .map(|json| serde_json::from_str(json).unwrap())
+                .collect();
+            payloads
+        };
+        let usernames = |payloads: &[serde_json::Value]| payloads.iter()
+            .map(|payload| payload["username"].as_str().unwrap().to_string())
+            .collect::<Vec<String>>();
+
+        let payloads = emitted(&config_string());
+        assert_eq!(vec!("hello", "hello"), usernames(&payloads));
+        assert_eq!(10, payloads[0]["uid"]);
+
+        let config = format!("{}\npseudonymize:\n  username: true\n  key: a secret", config_string());
+        let payloads = emitted(&config);
+        let hashed = usernames(&payloads);
+        assert_ne!("hello", hashed[0]);
+        assert_eq!(64, hashed[0].len());
+        assert_eq!(hashed[0], hashed[1]);
+
+        // The uid would give the user away just the same.
+        assert!(payloads.iter().all(|payload| payload["uid"].is_null()));
+
+        // The hash carries across restarts with the same key.
+        assert_eq!(hashed, usernames(&emitted(&config)));
+        let tempdir = tempdir().unwrap();
+        assert!(NoTrack::from_str(&format!("{}\npseudonymize:\n  username: true", config_string()), tempdir.path().to_str()).is_err());
+    }
+
+    #[test]
+    fn test_emitted_opens_are_rolled_up() {
+        let tempdir = tempdir().unwrap();
+        let config = format!("{}\nrollup:\n  interval: 60", config_string());
+        let mut notrack = NoTrack::from_str(&config, tempdir.path().to_str()).unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        notrack.outputs = vec!(Box::new(RecordingOutput { events: events.clone() }));
+
+        for hash in 1..4 {
+            notrack.emit(open_payload(hash));
+        }
+        notrack.emit(close_payload(1));
+        let agent = notrack.identity.uuid;
+        notrack.finish_rollup(agent);
+
+        let rollup : serde_json::Value = serde_json::from_str(events.borrow().last().unwrap()).unwrap();
+        assert_eq!("rollup", rollup["event_type"]);
+        assert_eq!(5, rollup["seq"]);
+        assert_eq!(3, rollup["connections"]);
+        assert_eq!(3, rollup["top_destination_ports"][0]["count"]);
+    }
+
+    #[test]
+    fn test_synthetic_event_reaches_outputs() {
+        let tempdir = tempdir().unwrap();
+        let mut notrack = NoTrack::from_str(&config_string(), tempdir.path().to_str()).unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        notrack.outputs = vec!(
+            Box::new(RecordingOutput { events: events.clone() }),
+            Box::new(RecordingOutput { events: events.clone() }),
+        );
+        notrack.output_keys = vec!(String::from("webhook:{}"), String::from("webhook:{}"));
+
+        let results = notrack.test_outputs("all").unwrap();
+        assert_eq!(vec!(String::from("webhook"), String::from("webhook 2")), results.iter().map(|(label, _)| label.clone()).collect::<Vec<String>>());
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+
+        let open : serde_json::Value = serde_json::from_str(&events.borrow()[0]).unwrap();
+        assert_eq!(2, events.borrow().len());
+        assert_eq!("open", open["event_type"]);
+        assert_eq!("zerotrust-track-test", open["program_details"]["process_name"]);
+        assert_eq!(notrack.identity.uuid.to_string(), open["agent"]);
+
+        assert!(matches!(notrack.test_outputs("syslog"), Err(Error::Output(_))));
+    }
+
+    #[test]
+    fn test_synthetic_event_reports_background_failure() {
+        let tempdir = tempdir().unwrap();
+        let config = "---\ndirectory: /tmp\noutputs:\n  elasticsearch: http://127.0.0.1:1/zerotrust\n";
+        let mut notrack = NoTrack::from_str(config, tempdir.path().to_str()).unwrap();
+
+        // Queued without a problem, it is the thread which can't send it.
+        let results = notrack.test_outputs("elasticsearch").unwrap();
+        assert_eq!(1, results.len());
+        assert!(results[0].1.is_err());
+    }
+
+    #[test]
+    fn test_reload_outputs() {
+        let tempdir = tempdir().unwrap();
+        let mut notrack = NoTrack::from_str(&config_string(), tempdir.path().to_str()).unwrap();
+        assert!(notrack.outputs.is_empty());
+
+        let parquet = format!("parquet:\n  directory: {}\n", tempdir.path().to_str().unwrap());
+        notrack.reload_outputs(serde_yaml::from_str(&parquet).unwrap()).unwrap();
+        assert_eq!(1, notrack.outputs.len());
+        assert_eq!(1, notrack.config.outputs.parquet.as_ref().map_or(0, Vec::len));
+
+        // A bad reload keeps what was running.
+        let missing = "parquet:\n  directory: /I_like_strange_things\n";
+        assert!(matches!(notrack.reload_outputs(serde_yaml::from_str(missing).unwrap()), Err(Error::Output(_))));
+        assert_eq!(1, notrack.outputs.len());
+        assert_eq!(tempdir.path().to_str().map(String::from), notrack.config.outputs.parquet.as_ref().map(|parquet| parquet[0].directory.clone()));
+
+        notrack.reload_outputs(serde_yaml::from_str("syslog: []").unwrap()).unwrap();
+        assert!(notrack.outputs.is_empty());
+    }
+
+    #[test]
+    fn test_reload_config() {
+        let tempdir = tempdir().unwrap();
+        let data_directory = tempdir.path().to_str();
+        let file = tempdir.path().join("config.yaml");
+        let name = file.to_str().unwrap();
+        fs::write(&file, config_string()).unwrap();
+
+        let mut notrack = NoTrack::new(read_configs(&[name], data_directory).unwrap()).unwrap();
+        notrack.reload_config();
+        assert!(notrack.outputs.is_empty());
+
+        let mut notrack = notrack.reload_from(&[name], data_directory);
+        let parquet = format!("---\ndirectory: /tmp\noutputs:\n  parquet:\n    directory: {}\n", tempdir.path().to_str().unwrap());
+        fs::write(&file, parquet).unwrap();
+        notrack.reload_config();
+        assert_eq!(1, notrack.outputs.len());
+
+        // A config which can't be read keeps what was running.
+        fs::write(&file, "outputs: [").unwrap();
+        notrack.reload_config();
+        assert_eq!(1, notrack.outputs.len());
+    }
+
+    #[test]
+    fn test_create_data_directory() {
+        let tempdir = tempdir().unwrap();
+        let data_directory = tempdir.path().join("data/zerotrust");
+        let data_directory = data_directory.to_str().unwrap();
+
+        assert!(NoTrack::from_str(&config_string(), Some(data_directory)).is_err());
+
+        let config = format!("{}\ncreate_directory: true", config_string());
+        let notrack = NoTrack::from_str(&config, Some(data_directory)).unwrap();
+        assert_eq!(Some(String::from(data_directory)), notrack.config.directory);
+        assert!(check_directory(data_directory));
+    }
+
+    #[test]
+    fn test_fallback_candidates() {
+        assert_eq!(vec!(PathBuf::from("/var/lib/zerotrust")), fallback_candidates(None));
+        assert_eq!(vec!(PathBuf::from("/var/lib/zerotrust")), fallback_candidates(Some(String::new())));
+        assert_eq!(
+            vec!(PathBuf::from("/home/alex/.local/state/zerotrust"), PathBuf::from("/var/lib/zerotrust")),
+            fallback_candidates(Some(String::from("/home/alex/.local/state")))
+        );
+    }
+
+    #[test]
+    fn test_fallback_directory() {
+        let tempdir = tempdir().unwrap();
+        let file = tempdir.path().join("file");
+        fs::write(&file, "").unwrap();
+        let state = tempdir.path().join("state/zerotrust");
+
+        // A file can't be used as a directory, so the next candidate is created.
+        assert_eq!(state.to_str().unwrap(), fallback_directory(&[file.join("zerotrust"), state.clone()]));
+        assert!(state.is_dir());
+
+        assert_eq!("/tmp", fallback_directory(&[file.join("zerotrust")]));
+    }
+
     #[test]
     fn test_check_directory_success() {
         let tempdir = tempdir().unwrap();
@@ -493,10 +1570,12 @@ mod tests {
             .. default_config()
         };
 
-        let updated_config = populate_config(config);
+        let (updated_config, identity) = populate_config(config);
 
         assert_eq!(Some(String::from("I am a name")), updated_config.name);
         assert_eq!(Some(uuid), updated_config.uuid);
+        assert_eq!("I am a name", identity.name);
+        assert_eq!(uuid, identity.uuid);
     }
 
     #[test]
@@ -506,7 +1585,7 @@ mod tests {
             uuid : None,
             .. default_config()
         };
-        let updated_config = populate_config(config);
+        let (updated_config, _) = populate_config(config);
         assert_eq!(Some(String::from("I am a name")), updated_config.name, "names are not the same");
 
         match updated_config.uuid {
@@ -525,7 +1604,7 @@ mod tests {
             .. default_config()
         };
 
-        let updated_config = populate_config(config);
+        let (updated_config, _) = populate_config(config);
         assert_eq!(Some(uuid), updated_config.uuid, "uuid's are not the same");
         match updated_config.name {
             None =>  assert!(false, "name is not defined"),
@@ -534,4 +1613,154 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_populate_config_hostname() {
+        let directory = tempdir().unwrap();
+        let config = Config {
+            directory: directory.path().to_str().map(String::from),
+            use_hostname_as_name: true,
+            .. default_config()
+        };
+
+        let (updated_config, identity) = populate_config(config);
+        assert_eq!(get_hostname(), updated_config.name);
+        assert_eq!(identity.hostname, identity.name);
+
+        // Without it the name is picked from names.txt.
+        let directory = tempdir().unwrap();
+        fs::write(directory.path().join("names.txt"), "gerald\n").unwrap();
+        let config = Config {
+            directory: directory.path().to_str().map(String::from),
+            use_hostname_as_name: false,
+            .. default_config()
+        };
+
+        let (updated_config, _) = populate_config(config);
+        assert_eq!(Some(String::from("gerald")), updated_config.name);
+    }
+
+    #[test]
+    fn test_populate_config_without_persisting() {
+        let directory = tempdir().unwrap();
+        let config = Config {
+            directory: directory.path().to_str().map(String::from),
+            persist_identity: false,
+            .. default_config()
+        };
+
+        let (updated_config, _) = populate_config(config);
+        assert!(updated_config.name.is_some());
+        assert!(updated_config.uuid.is_some());
+        assert!(!directory.path().join("name_tuple.yaml").exists());
+
+        let config = Config {
+            directory: directory.path().to_str().map(String::from),
+            .. default_config()
+        };
+
+        populate_config(config);
+        assert!(directory.path().join("name_tuple.yaml").exists());
+    }
+
+    #[test]
+    fn test_override_agent() {
+        let tempdir = tempdir().unwrap();
+        let directory = tempdir.path().to_str().unwrap();
+        let tuple_file = format!("{}/{}", directory, "/name_tuple.yaml");
+        save_uuid_name_tuple(&tuple_file, &NameTuple { name: Some(String::from("saved")), uuid: Some(Uuid::new_v4()) }).unwrap();
+
+        let config = parse_config(&config_string(), Some(directory)).unwrap();
+        assert_eq!(Some(String::from("saved")), populate_config(parse_config(&config_string(), Some(directory)).unwrap()).0.name);
+
+        let uuid = Uuid::new_v4();
+        let config = override_agent(config, Some("flagged"), Some(&uuid.to_string())).unwrap();
+        let (config, _) = populate_config(config);
+
+        assert_eq!(Some(String::from("flagged")), config.name);
+        assert_eq!(Some(uuid), config.uuid);
+        assert_eq!(Some(String::from("flagged")), load_uuid_name_tuple(&tuple_file).name);
+    }
+
+    #[test]
+    fn test_override_agent_keeps_config() {
+        let uuid = Uuid::new_v4();
+        let config = Config {
+            name: Some(String::from("I am a name")),
+            uuid: Some(uuid),
+            .. default_config()
+        };
+
+        let config = override_agent(config, None, None).unwrap();
+        assert_eq!(Some(String::from("I am a name")), config.name);
+        assert_eq!(Some(uuid), config.uuid);
+    }
+
+    #[test]
+    fn test_override_agent_invalid_uuid() {
+        match override_agent(default_config(), None, Some("I am not a uuid")) {
+            Err(Error::Config(message)) => assert_eq!("invalid uuid I am not a uuid", message),
+            _ => panic!("expected a config error"),
+        }
+    }
+
+    #[test]
+    fn test_read_configs_merge() {
+        let tempdir = tempdir().unwrap();
+        let base = tempdir.path().join("base.yaml");
+        let host = tempdir.path().join("host.yaml");
+
+        fs::write(&base, "directory: /tmp\nname: base\nmax_connections: 10\noutputs:\n  syslog: [ Localhost ]\n  elasticsearch: \"http://127.0.0.1:9200\"\nfilters:\n  non_process_connections: true\n  dns_requests : true\n  zerotrust_track_connections: true\n  ignore_ports: [ 22, 80 ]\n").unwrap();
+        fs::write(&host, "name: host\noutputs:\n  syslog: []\nfilters:\n  dns_requests: false\n  ignore_ports: [ 443 ]\n").unwrap();
+
+        let config = read_configs(&[base.to_str().unwrap(), host.to_str().unwrap()], None).unwrap();
+
+        assert_eq!(Some(String::from("host")), config.name);
+        assert_eq!(10, config.max_connections);
+        assert!(config.filters.non_process_connections);
+        assert!(!config.filters.dns_requests);
+        // Lists are replaced rather than appended to.
+        assert_eq!(vec!(443), config.filters.ignore_ports);
+        assert_eq!(0, config.outputs.syslog.unwrap().len());
+        // Outputs only set in the base are kept.
+        assert_eq!(Some(vec!(String::from("http://127.0.0.1:9200"))), config.outputs.elasticsearch);
+    }
+
+    #[test]
+    fn test_read_configs_directory() {
+        let tempdir = tempdir().unwrap();
+        fs::write(tempdir.path().join("10-base.yaml"), config_string()).unwrap();
+        fs::write(tempdir.path().join("20-name.yaml"), "name: second").unwrap();
+        fs::write(tempdir.path().join("30-name.yml"), "name: third").unwrap();
+        fs::write(tempdir.path().join("README"), "I am not yaml: [").unwrap();
+
+        let config = read_configs(&[tempdir.path().to_str().unwrap()], None).unwrap();
+        assert_eq!(Some(String::from("third")), config.name);
+
+        // Files given after the directory still win.
+        let last = tempfile::NamedTempFile::new().unwrap();
+        write!(&last, "name: last").unwrap();
+        let config = read_configs(&[tempdir.path().to_str().unwrap(), last.path().to_str().unwrap()], None).unwrap();
+        assert_eq!(Some(String::from("last")), config.name);
+    }
+
+    #[test]
+    fn test_read_configs_missing_file() {
+        assert!(matches!(read_configs(&["/I_like_strange_things"], None), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn test_read_configs_unreadable_file() {
+        let tempdir = tempdir().unwrap();
+        let config = tempdir.path().join("config.yaml");
+        fs::write(&config, &[0xff, 0xfe]).unwrap();
+
+        // The file isn't UTF-8, the cause comes along with the message.
+        match read_configs(&[config.to_str().unwrap()], None) {
+            Err(Error::Io(message, err)) => {
+                assert_eq!(format!("unable to read config file {}", config.display()), message);
+                assert_eq!(::std::io::ErrorKind::InvalidData, err.kind());
+            },
+            _ => panic!("expected an io error"),
+        }
+    }
 }
@@ -0,0 +1,311 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::collections::{ HashMap, HashSet };
+use std::fs;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+use chrono::prelude::*;
+use users::{ UsersCache };
+use uuid::Uuid;
+use enums::{ Protocol };
+use parser::{ Payload, ListenEvent, ParserConfig, Program, SCHEMA_VERSION, program_details, resolve_username };
+use parser::redact::{ Redactor };
+use proc::{ Proc };
+use proc_chomper::{ is_hex, parse_socket_line };
+
+pub static TCP_LIST: &str = "/proc/net/tcp";
+pub static TCP6_LIST: &str = "/proc/net/tcp6";
+
+// The st column of /proc/net/tcp for a socket in the LISTEN state.
+const LISTEN : &str = "0A";
+
+fn default_interval() -> u64 {
+    10
+}
+
+// Reports processes starting and stopping to listen on TCP ports, found by
+// reading the socket tables every interval seconds.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ListenersConfig {
+    #[serde(default)]
+    pub enabled : bool,
+    #[serde(default = "default_interval")]
+    pub interval : u64,
+}
+
+impl Default for ListenersConfig {
+    fn default() -> ListenersConfig {
+        ListenersConfig {
+            enabled: false,
+            interval: default_interval(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Listener {
+    pub address : IpAddr,
+    pub port : u16,
+    pub uid : u32,
+    pub inode : u32,
+}
+
+// Every listening socket in the contents of /proc/net/tcp or /proc/net/tcp6.
+pub fn parse_listeners(contents: &str) -> Vec<Listener> {
+    contents.lines().skip(1).filter_map(parse_listener).collect()
+}
+
+fn parse_listener(line: &str) -> Option<Listener> {
+    let line = parse_socket_line(line, parse_end)?;
+    if line.state != LISTEN {
+        return None;
+    }
+
+    Some(Listener { address: line.local.0, port: line.local.1, uid: line.uid, inode: line.inode })
+}
+
+// An IPv4 or IPv6 address and port, 8 or 32 hex digits and 4, so the queue
+// and timer columns can't be taken for one.
+fn parse_end(item: &str) -> Option<(IpAddr, u16)> {
+    let mut split = item.split(':');
+    let (address, port) = (split.next()?, split.next()?);
+    if split.next().is_some() || !(is_hex(address, Some(8)) || is_hex(address, Some(32))) || !is_hex(port, Some(4)) {
+        return None;
+    }
+
+    Some((parse_address(address)?, u16::from_str_radix(port, 16).ok()?))
+}
+
+// The kernel prints each 32 bit word of the address in host order.
+fn parse_address(hex: &str) -> Option<IpAddr> {
+    let words = (0..hex.len() / 8)
+        .map(|word| u32::from_str_radix(hex.get(word * 8..word * 8 + 8)?, 16).ok())
+        .collect::<Option<Vec<u32>>>()?;
+
+    match words.len() {
+        1 => Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(words[0])))),
+        4 => {
+            let mut octets = [0u8; 16];
+            for (word, value) in words.iter().enumerate() {
+                octets[word * 4..word * 4 + 4].copy_from_slice(&u32::from_be(*value).to_be_bytes());
+            }
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        },
+        _ => None,
+    }
+}
+
+// The listeners which appeared and the ones which went away between the two
+// snapshots.
+pub fn diff(previous: &HashSet<Listener>, current: &HashSet<Listener>) -> (Vec<Listener>, Vec<Listener>) {
+    let added = current.difference(previous).cloned().collect();
+    let removed = previous.difference(current).cloned().collect();
+    (added, removed)
+}
+
+fn snapshot() -> HashSet<Listener> {
+    let mut listeners = HashSet::new();
+    for file in &[TCP_LIST, TCP6_LIST] {
+        match fs::read_to_string(file) {
+            Ok(contents) => listeners.extend(parse_listeners(&contents)),
+            Err(err) => sampled_debug!("unable to read {}: {}", file, err),
+        }
+    }
+
+    listeners
+}
+
+struct Watcher {
+    agent : Uuid,
+    config : ParserConfig,
+    redactor : Redactor,
+    proc : Proc,
+    user_cache : UsersCache,
+    listeners : HashSet<Listener>,
+    // The details given in the listen-open, reused for the listen-close as the
+    // process has often gone by then.
+    owners : HashMap<Listener, (String, Option<Program>)>,
+}
+
+impl Watcher {
+    fn event(&self, listener: &Listener, username: String, program_details: Option<Program>) -> ListenEvent {
+        ListenEvent {
+            schema_version: SCHEMA_VERSION,
+            seq: 0,
+            agent: self.agent,
+            timestamp: Utc::now().to_rfc3339(),
+            protocol: Protocol::TCP,
+            address: listener.address,
+            port: listener.port,
+            username,
+            uid: listener.uid,
+            program_details,
+        }
+    }
+
+    fn poll(&mut self) -> Vec<Payload> {
+        let current = snapshot();
+        let (added, removed) = diff(&self.listeners, &current);
+        let mut payloads = Vec::new();
+
+        if !added.is_empty() {
+            let _ = self.proc.update();
+        }
+
+        for listener in added {
+            let (username, _) = resolve_username(&self.user_cache, listener.uid);
+            let program = program_details(&mut self.proc, listener.inode, listener.uid, &self.config, &self.redactor);
+
+            payloads.push(Payload::ListenOpen(self.event(&listener, username.clone(), program.clone())));
+            self.owners.insert(listener, (username, program));
+        }
+
+        for listener in removed {
+            let (username, program) = self.owners.remove(&listener).unwrap_or_default();
+            payloads.push(Payload::ListenClose(self.event(&listener, username, program)));
+        }
+
+        self.listeners = current;
+        payloads
+    }
+}
+
+// Polls the socket tables on its own thread until the payload channel closes.
+// Sockets which are already listening are reported on the first poll.
+pub fn start(config: &ListenersConfig, agent: Uuid, parser_config: ParserConfig, tx: Sender<Payload>) -> Result<(), String> {
+    let redactor = Redactor::new(&parser_config.redact)?;
+    let proc = match Proc::new() {
+        Ok(x) => x,
+        Err(_err) => return Err(String::from("unable to read processes for the listeners")),
+    };
+
+    let mut watcher = Watcher {
+        agent,
+        config: parser_config,
+        redactor,
+        proc,
+        user_cache: UsersCache::new(),
+        listeners: HashSet::new(),
+        owners: HashMap::new(),
+    };
+    let interval = Duration::from_secs(config.interval.max(1));
+
+    thread::spawn(move || {
+        info!("watching for listening sockets");
+        loop {
+            for payload in watcher.poll() {
+                if tx.send(payload).is_err() {
+                    return;
+                }
+            }
+
+            thread::sleep(interval);
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+
+    const HEADER : &str = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n";
+
+    fn line(local: &str, state: &str, inode: u32) -> String {
+        format!("   0: {} 00000000:0000 {} 00000000:00000000 00:00000000 00000000     0        0 {} 1 0000000000000000 100 0 0 10 0\n", local, state, inode)
+    }
+
+    #[test]
+    fn test_config() {
+        let config : ListenersConfig = serde_yaml::from_str("enabled: true").unwrap();
+        assert!(config.enabled);
+        assert_eq!(10, config.interval);
+        assert!(!ListenersConfig::default().enabled);
+    }
+
+    #[test]
+    fn test_parse_listeners() {
+        let contents = format!("{}{}{}{}",
+            HEADER,
+            line("0100007F:0016", LISTEN, 100),
+            // Established connections aren't listeners.
+            line("0100007F:9C40", "01", 101),
+            line("I am not an address", LISTEN, 102));
+
+        assert_eq!(vec!(Listener {
+            address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            port: 22,
+            uid: 0,
+            inode: 100,
+        }), parse_listeners(&contents));
+    }
+
+    #[test]
+    fn test_parse_listeners_v6() {
+        let contents = format!("{}{}{}",
+            HEADER,
+            line("00000000000000000000000001000000:0050", LISTEN, 200),
+            line("00000000000000000000000000000000:01BB", LISTEN, 201));
+
+        let listeners = parse_listeners(&contents);
+        assert_eq!(IpAddr::V6(Ipv6Addr::LOCALHOST), listeners[0].address);
+        assert_eq!(80, listeners[0].port);
+        assert_eq!(IpAddr::V6(Ipv6Addr::UNSPECIFIED), listeners[1].address);
+        assert_eq!(443, listeners[1].port);
+    }
+
+    #[test]
+    fn test_parse_listeners_layout() {
+        // An extra column ahead of the addresses is stepped over, a line
+        // cut short or with a uid which isn't one is skipped.
+        let contents = format!("{}   0: extra {}   1: 0100007F:0016 00000000:0000 0A 00000000:00000000\n{}",
+            HEADER,
+            line("0100007F:0016", LISTEN, 100),
+            line("0100007F:0017", LISTEN, 101).replace("     0        0 101", "     root        0 101"));
+
+        let listeners = parse_listeners(&contents);
+        assert_eq!(1, listeners.len());
+        assert_eq!(22, listeners[0].port);
+        assert_eq!(100, listeners[0].inode);
+    }
+
+    #[test]
+    fn test_diff() {
+        let before : HashSet<Listener> = parse_listeners(&format!("{}{}{}",
+            HEADER,
+            line("00000000:0016", LISTEN, 100),
+            line("0100007F:0019", LISTEN, 101))).into_iter().collect();
+        let after : HashSet<Listener> = parse_listeners(&format!("{}{}{}",
+            HEADER,
+            line("00000000:0016", LISTEN, 100),
+            line("00000000:115C", LISTEN, 102))).into_iter().collect();
+
+        let (added, removed) = diff(&before, &after);
+        assert_eq!(1, added.len());
+        assert_eq!(4444, added[0].port);
+        assert_eq!(1, removed.len());
+        assert_eq!(25, removed[0].port);
+
+        let (added, removed) = diff(&after, &after);
+        assert!(added.is_empty());
+        assert!(removed.is_empty());
+    }
+}
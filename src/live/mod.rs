@@ -0,0 +1,252 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::collections::HashMap;
+use std::io::{ BufRead, BufReader, Read, Write };
+use std::net::{ TcpListener, TcpStream };
+use std::sync::{ Arc, Mutex };
+use std::thread;
+use std::time::Duration;
+use parser::{ OpenConnection };
+use pseudonymize::{ Pseudonymizer };
+
+// Requests are answered one at a time, so a client which is slow or sends
+// too much is cut off rather than holding up the rest.
+const REQUEST_TIMEOUT : Duration = Duration::from_secs(5);
+const MAX_REQUEST : u64 = 8192;
+
+// A page which fetches /connections every couple of seconds and lists them.
+static PAGE : &str = "<!DOCTYPE html>
+<html>
+<head><meta charset=\"utf-8\"><title>zerotrust-track</title></head>
+<body>
+<table>
+<thead><tr><th>Opened</th><th>Protocol</th><th>Source</th><th>Destination</th><th>User</th><th>Process</th></tr></thead>
+<tbody id=\"connections\"></tbody>
+</table>
+<script>
+function cell(row, text) {
+  row.insertCell().textContent = text;
+}
+
+function refresh() {
+  fetch('/connections').then(res => res.json()).then(connections => {
+    const body = document.getElementById('connections');
+    body.innerHTML = '';
+    for (const connection of connections) {
+      const row = body.insertRow();
+      cell(row, connection.timestamp);
+      cell(row, connection.protocol);
+      cell(row, connection.source + ':' + connection.source_port);
+      cell(row, connection.destination + ':' + connection.destination_port);
+      cell(row, connection.username);
+      cell(row, connection.program_details ? connection.program_details.process_name : '');
+    }
+  });
+}
+
+refresh();
+setInterval(refresh, 2000);
+</script>
+</body>
+</html>
+";
+
+// Serves the connections which are open, on a local address so they can be
+// looked at without a collector.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct LiveConfig {
+    // Where to listen, for instance 127.0.0.1:8000. Nothing is served when
+    // it isn't set.
+    #[serde(default)]
+    pub bind : Option<String>,
+}
+
+// The opens the state is tracking, by hash, shared with the server thread.
+pub type Connections = Arc<Mutex<HashMap<i64, OpenConnection>>>;
+
+pub fn connections() -> Connections {
+    Arc::new(Mutex::new(HashMap::new()))
+}
+
+// The open connections as a JSON array, the oldest first, hashed as the
+// payloads are.
+fn connections_json(connections : &Connections, pseudonymizer : &Pseudonymizer) -> String {
+    let connections = match connections.lock() {
+        Ok(x) => x,
+        Err(_err) => return String::from("[]"),
+    };
+
+    let mut opens : Vec<&OpenConnection> = connections.values().collect();
+    opens.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    pseudonymizer.to_json(&opens).unwrap_or_else(|_| String::from("[]"))
+}
+
+fn respond(mut stream : TcpStream, status : &str, content_type : &str, body : &str) {
+    let response = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, content_type, body.len(), body);
+    if let Err(err) = stream.write_all(response.as_bytes()) {
+        debug!("unable to write live response: {}", err);
+    }
+}
+
+fn handle(stream : TcpStream, connections : &Connections, pseudonymizer : &Pseudonymizer) {
+    if let Err(err) = stream.set_read_timeout(Some(REQUEST_TIMEOUT)).and_then(|_| stream.set_write_timeout(Some(REQUEST_TIMEOUT))) {
+        debug!("unable to set the live request timeout: {}", err);
+        return;
+    }
+
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST));
+    let mut line = String::new();
+    if let Err(err) = reader.read_line(&mut line) {
+        debug!("unable to read live request: {}", err);
+        return;
+    }
+
+    // The headers are read too, closing with them unread resets the connection.
+    let mut header = String::new();
+    while reader.read_line(&mut header).map(|read| read > 0).unwrap_or(false) && header.trim_end() != "" {
+        header.clear();
+    }
+
+    let mut split = line.split_whitespace();
+    match (split.next(), split.next()) {
+        (Some("GET"), Some("/connections")) => respond(stream, "200 OK", "application/json", &connections_json(connections, pseudonymizer)),
+        (Some("GET"), Some("/")) => respond(stream, "200 OK", "text/html; charset=utf-8", PAGE),
+        (Some("GET"), Some(_)) => respond(stream, "404 Not Found", "text/plain", "not found"),
+        _ => respond(stream, "405 Method Not Allowed", "text/plain", "method not allowed"),
+    }
+}
+
+// Binds to the address and serves the connections from a thread of its own,
+// one request at a time.
+pub fn start(bind : &str, connections : Connections, pseudonymizer : Pseudonymizer) -> Result<(), String> {
+    let listener = match TcpListener::bind(bind) {
+        Ok(x) => x,
+        Err(err) => return Err(format!("unable to listen on {}: {}", bind, err)),
+    };
+
+    info!("serving live connections on {}", bind);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => handle(stream, &connections, &pseudonymizer),
+                Err(err) => debug!("unable to accept live connection: {}", err),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use serde_json;
+    use metrics::{ Metrics };
+    use pseudonymize::{ PseudonymizeConfig };
+    use state::{ State };
+    use parser::{ Payload };
+    use test_support::{ open_payload, close_payload, free_address };
+
+    fn get(address : &str, path : &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, address).as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn hashes(address : &str) -> Vec<i64> {
+        let response = get(address, "/connections");
+        let body = response.splitn(2, "\r\n\r\n").nth(1).unwrap();
+        let value : serde_json::Value = serde_json::from_str(body).unwrap();
+        let mut hashes : Vec<i64> = value.as_array().unwrap().iter().map(|open| open["hash"].as_i64().unwrap()).collect();
+        hashes.sort();
+        hashes
+    }
+
+    #[test]
+    fn test_connections_reflect_state() {
+        let connections = connections();
+        let mut state = State::new(0, Duration::from_secs(0), Duration::from_secs(0), Arc::new(Metrics::new())).unwrap().live(connections.clone());
+
+        let address = free_address();
+        start(&address, connections, Pseudonymizer::default()).unwrap();
+        assert!(hashes(&address).is_empty());
+
+        let opened = match state.transform(open_payload(1)) {
+            Some(Payload::Open(open)) => open,
+            _ => panic!("expected an open"),
+        };
+        state.transform(open_payload(2));
+        assert_eq!(vec!(1, 2), hashes(&address));
+
+        // The attribution comes along with the connection.
+        let response = get(&address, "/connections");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains(&opened.uuid.to_string()));
+
+        state.transform(close_payload(1));
+        assert_eq!(vec!(2), hashes(&address));
+
+        assert!(get(&address, "/").contains("<html>"));
+        assert!(get(&address, "/missing").starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_stalled_client_cut_off() {
+        let connections = connections();
+        connections.lock().unwrap().insert(1, match open_payload(1) {
+            Payload::Open(open) => open,
+            _ => panic!("expected an open"),
+        });
+
+        let address = free_address();
+        start(&address, connections, Pseudonymizer::default()).unwrap();
+
+        // Neither of these ever finishes its request line.
+        let mut silent = TcpStream::connect(&address).unwrap();
+        let mut endless = TcpStream::connect(&address).unwrap();
+        endless.write_all(&vec![b'A'; MAX_REQUEST as usize * 2]).unwrap();
+
+        let started = Instant::now();
+        assert_eq!(vec!(1), hashes(&address));
+        assert!(started.elapsed() < REQUEST_TIMEOUT * 2, "took {:?}", started.elapsed());
+
+        // The silent one was given up on without an answer.
+        let mut response = String::new();
+        silent.read_to_string(&mut response).unwrap();
+        assert!(response.is_empty());
+    }
+
+    #[test]
+    fn test_connections_pseudonymized() {
+        let connections = connections();
+        connections.lock().unwrap().insert(1, match open_payload(1) {
+            Payload::Open(open) => open,
+            _ => panic!("expected an open"),
+        });
+
+        let pseudonymizer = Pseudonymizer::new(&PseudonymizeConfig { username: true, key: Some(String::from("a secret")), .. PseudonymizeConfig::default() }).unwrap();
+        let json : serde_json::Value = serde_json::from_str(&connections_json(&connections, &pseudonymizer)).unwrap();
+        assert_eq!(64, json[0]["username"].as_str().unwrap().len());
+        assert!(json[0]["uid"].is_null());
+
+        let json : serde_json::Value = serde_json::from_str(&connections_json(&connections, &Pseudonymizer::default())).unwrap();
+        assert_eq!("hello", json[0]["username"]);
+    }
+}
@@ -0,0 +1,256 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::collections::BTreeMap;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicU64, AtomicUsize, Ordering };
+use std::time::Duration;
+use filters::{ FilterRule, FILTER_RULES };
+
+// Upper bounds of the latency buckets in milliseconds, anything slower goes
+// in the last bucket.
+const LATENCY_BOUNDS_MS : [u64; 8] = [1, 5, 10, 50, 100, 500, 1000, 5000];
+
+// How long messages waited in the channel of an output before its thread
+// picked them up. A collector which is slowing down shows here well before
+// the channel fills.
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    buckets: [AtomicUsize; 9],
+    count: AtomicUsize,
+    total_ms: AtomicU64,
+    max_ms: AtomicU64,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct LatencyBucket {
+    // None for the last bucket, which has no upper bound.
+    pub le_ms: Option<u64>,
+    pub count: usize,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct LatencySnapshot {
+    pub count: usize,
+    pub total_ms: u64,
+    pub max_ms: u64,
+    pub buckets: Vec<LatencyBucket>,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram::default()
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let ms = latency.as_millis() as u64;
+        let bucket = LATENCY_BOUNDS_MS.iter().position(|bound| ms <= *bound).unwrap_or(LATENCY_BOUNDS_MS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_ms.fetch_add(ms, Ordering::Relaxed);
+        self.max_ms.fetch_max(ms, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> LatencySnapshot {
+        LatencySnapshot {
+            count: self.count.load(Ordering::Relaxed),
+            total_ms: self.total_ms.load(Ordering::Relaxed),
+            max_ms: self.max_ms.load(Ordering::Relaxed),
+            buckets: self.buckets.iter().enumerate().map(|(bucket, count)| LatencyBucket {
+                le_ms: LATENCY_BOUNDS_MS.get(bucket).cloned(),
+                count: count.load(Ordering::Relaxed),
+            }).collect(),
+        }
+    }
+}
+
+// Counters shared between the conntrack thread, the state and the main loop.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    channel_depth: AtomicUsize,
+    dropped_events: AtomicUsize,
+    evicted_connections: AtomicUsize,
+    // Connections which never reach the outputs, so we know what isn't seen.
+    unsupported_protocol: AtomicUsize,
+    parse_errors: AtomicUsize,
+    filtered_connections: AtomicUsize,
+    // The filtered connections again, by the rule which dropped them.
+    filtered_by_rule: [AtomicUsize; FILTER_RULES],
+    // Set once at start up, see the capabilities module.
+    missing_capabilities: AtomicUsize,
+    // The queue latency of each output, by the name of the output. Set again
+    // whenever the outputs are made.
+    output_latency: Mutex<Vec<(String, Arc<LatencyHistogram>)>>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct MetricsSnapshot {
+    pub channel_depth: usize,
+    pub dropped_events: usize,
+    pub evicted_connections: usize,
+    pub unsupported_protocol: usize,
+    pub parse_errors: usize,
+    pub filtered_connections: usize,
+    pub filtered_by_rule: BTreeMap<FilterRule, usize>,
+    pub missing_capabilities: usize,
+    pub output_latency: BTreeMap<String, LatencySnapshot>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn channel_push(&self) {
+        self.channel_depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn channel_pop(&self) {
+        self.channel_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn channel_dropped(&self) {
+        self.dropped_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_evicted(&self) {
+        self.evicted_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn protocol_unsupported(&self) {
+        self.unsupported_protocol.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn parse_failed(&self) {
+        self.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_filtered(&self, rule: FilterRule) {
+        self.filtered_connections.fetch_add(1, Ordering::Relaxed);
+        self.filtered_by_rule[rule as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn capabilities_missing(&self, count: usize) {
+        self.missing_capabilities.store(count, Ordering::Relaxed);
+    }
+
+    pub fn outputs_changed(&self, latencies: Vec<(String, Arc<LatencyHistogram>)>) {
+        if let Ok(mut output_latency) = self.output_latency.lock() {
+            *output_latency = latencies;
+        }
+    }
+
+    pub fn channel_depth(&self) -> usize {
+        self.channel_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_events(&self) -> usize {
+        self.dropped_events.load(Ordering::Relaxed)
+    }
+
+    pub fn evicted_connections(&self) -> usize {
+        self.evicted_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn unsupported_protocol(&self) -> usize {
+        self.unsupported_protocol.load(Ordering::Relaxed)
+    }
+
+    pub fn parse_errors(&self) -> usize {
+        self.parse_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn filtered_connections(&self) -> usize {
+        self.filtered_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn filtered_by_rule(&self, rule: FilterRule) -> usize {
+        self.filtered_by_rule[rule as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn missing_capabilities(&self) -> usize {
+        self.missing_capabilities.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            channel_depth: self.channel_depth(),
+            dropped_events: self.dropped_events(),
+            evicted_connections: self.evicted_connections(),
+            unsupported_protocol: self.unsupported_protocol(),
+            parse_errors: self.parse_errors(),
+            filtered_connections: self.filtered_connections(),
+            filtered_by_rule: FilterRule::ALL.iter().map(|rule| (*rule, self.filtered_by_rule(*rule))).collect(),
+            missing_capabilities: self.missing_capabilities(),
+            output_latency: self.output_latency.lock()
+                .map(|latencies| latencies.iter().map(|(name, latency)| (name.clone(), latency.snapshot())).collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_depth() {
+        let metrics = Metrics::new();
+        metrics.channel_push();
+        metrics.channel_push();
+        metrics.channel_pop();
+        metrics.channel_dropped();
+        metrics.connection_evicted();
+        metrics.protocol_unsupported();
+        metrics.parse_failed();
+        metrics.parse_failed();
+        metrics.connection_filtered(FilterRule::IgnorePorts);
+        metrics.capabilities_missing(2);
+
+        let mut filtered_by_rule : BTreeMap<FilterRule, usize> = FilterRule::ALL.iter().map(|rule| (*rule, 0)).collect();
+        filtered_by_rule.insert(FilterRule::IgnorePorts, 1);
+        assert_eq!(MetricsSnapshot {
+            channel_depth: 1,
+            dropped_events: 1,
+            evicted_connections: 1,
+            unsupported_protocol: 1,
+            parse_errors: 2,
+            filtered_connections: 1,
+            filtered_by_rule,
+            missing_capabilities: 2,
+            output_latency: BTreeMap::new(),
+        }, metrics.snapshot());
+    }
+
+    #[test]
+    fn test_latency_histogram() {
+        let latency = Arc::new(LatencyHistogram::new());
+        latency.record(Duration::from_micros(300));
+        latency.record(Duration::from_millis(70));
+        latency.record(Duration::from_secs(9));
+
+        let metrics = Metrics::new();
+        metrics.outputs_changed(vec!((String::from("loki"), latency.clone())));
+
+        let snapshot = metrics.snapshot().output_latency.remove("loki").unwrap();
+        assert_eq!(3, snapshot.count);
+        assert_eq!(9070, snapshot.total_ms);
+        assert_eq!(9000, snapshot.max_ms);
+        assert_eq!(vec!(1, 0, 0, 0, 1, 0, 0, 0, 1), snapshot.buckets.iter().map(|bucket| bucket.count).collect::<Vec<usize>>());
+        assert_eq!(Some(100), snapshot.buckets[4].le_ms);
+        assert_eq!(None, snapshot.buckets[8].le_ms);
+    }
+}
@@ -0,0 +1,103 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::sync::{ Arc, Condvar, Mutex };
+use std::thread;
+use std::thread::JoinHandle;
+use futures::Future;
+use tokio::runtime::Runtime;
+use outputs::worker::{ QueueReceiver };
+
+// Counts the requests which are in flight, so no more than the limit are
+// started while the collector is slow.
+#[derive(Default)]
+struct InFlight {
+    count : Mutex<usize>,
+    finished : Condvar,
+}
+
+impl InFlight {
+    fn start(&self, limit : usize) {
+        let mut count = self.count.lock().unwrap();
+        while *count >= limit {
+            count = self.finished.wait(count).unwrap();
+        }
+        *count += 1;
+    }
+
+    fn finish(&self) {
+        *self.count.lock().unwrap() -= 1;
+        self.finished.notify_one();
+    }
+}
+
+// The thread of an output which sends from a runtime rather than one request
+// at a time, up to concurrency requests can overlap. Like the synchronous
+// threads it stops once the channel is closed, but only after everything in
+// flight has finished, so a Worker flushes it the same way. Each request is
+// recorded as delivered once it has finished, the error is logged here.
+pub fn spawn<T, F, R>(rx : QueueReceiver<T>, concurrency : usize, send : F) -> JoinHandle<()>
+    where T: Send + 'static, F: Fn(T) -> R + Send + 'static, R: Future<Item = (), Error = String> + Send + 'static
+{
+    thread::spawn(move || {
+        let runtime = match Runtime::new() {
+            Ok(x) => x,
+            Err(err) => {
+                error!("unable to start the http runtime: {}", err);
+                return;
+            }
+        };
+
+        let in_flight = Arc::new(InFlight::default());
+        let delivery = rx.delivery();
+        for message in rx {
+            in_flight.start(concurrency.max(1));
+            let finished = in_flight.clone();
+            let delivery = delivery.clone();
+            runtime.executor().spawn(send(message).then(move |result| {
+                if let Err(ref err) = result {
+                    error!("{}", err);
+                }
+                delivery.record(1, &result);
+                finished.finish();
+                Ok(())
+            }));
+        }
+
+        let _ = runtime.shutdown_on_idle().wait();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_in_flight_limit() {
+        let in_flight = Arc::new(InFlight::default());
+        in_flight.start(1);
+
+        let waiting = in_flight.clone();
+        let handle = thread::spawn(move || waiting.start(1));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!handle.is_finished());
+
+        in_flight.finish();
+        handle.join().unwrap();
+        assert_eq!(1, *in_flight.count.lock().unwrap());
+    }
+}
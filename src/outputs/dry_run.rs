@@ -0,0 +1,63 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use outputs::{ Output };
+
+// Stands in for every configured output in a dry run, logging what would
+// have been sent to them.
+#[derive(Default)]
+pub struct DryRun {}
+
+fn line(kind: &str, message: &str) -> String {
+    format!("dry run {}: {}", kind, message)
+}
+
+impl DryRun {
+    pub fn new() -> DryRun {
+        DryRun {}
+    }
+}
+
+impl Output for DryRun {
+    fn process_open_connection(&self, message: &str) -> Result<(), String> {
+        info!("{}", line("open", message));
+        Ok(())
+    }
+
+    fn process_close_connection(&self, message: &str) -> Result<(), String> {
+        info!("{}", line("close", message));
+        Ok(())
+    }
+
+    fn process_alive_connections(&self, connections: &Vec<i64>) {
+        info!("dry run alive connections: {}", connections.len());
+    }
+
+    fn process_event(&self, message: &str) -> Result<(), String> {
+        info!("{}", line("event", message));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line() {
+        assert_eq!("dry run open: {\"hash\":1}", line("open", "{\"hash\":1}"));
+    }
+}
@@ -14,74 +14,175 @@
  *
  */
 
-use std::sync::mpsc::Sender;
-use std::sync::mpsc::channel;
+use std::sync::Arc;
+#[cfg(not(feature = "async-http"))]
 use std::thread;
-use outputs::{ Output };
+use std::thread::JoinHandle;
+#[cfg(not(feature = "async-http"))]
+use std::io;
+use outputs::{ Output, FLUSH_TIMEOUT };
+use outputs::worker::{ queue, QueueReceiver, Worker };
+use metrics::{ LatencyHistogram };
+#[cfg(not(feature = "async-http"))]
 use reqwest;
 use reqwest::{ StatusCode };
 use reqwest::header::{ CONTENT_TYPE };
+use outputs::http;
+use outputs::http::{ HttpConfig };
+#[cfg(feature = "async-http")]
+use futures::{ Future, Stream };
+#[cfg(feature = "async-http")]
+use outputs::async_http;
 
 pub struct Elasticsearch {
-    tx : Sender<String>,
+    worker : Worker<String>,
 }
 
+// Inserts one payload at a time, the default.
+#[cfg(not(feature = "async-http"))]
+fn spawn(url: String, config: &HttpConfig, rx: QueueReceiver<String>) -> Result<JoinHandle<()>, String> {
+    let client = http::client(config)?;
 
-impl Elasticsearch {
-    pub fn new(url: &str) -> Result<Elasticsearch, String> {
-        let url = format!("{}/_doc", url);
-
-        let (tx, rx) = channel();
-
-        thread::spawn(move || {
-            loop {
-                match rx.recv() {
-                    Ok(message) => {
-                        info!("sending payload to ES: {}", &message);
-                        let res = reqwest::Client::new()
-                            .post(&url)
-                            .header(CONTENT_TYPE, "application/json")
-                            .body(message)
-                            .send();
-
-                        match res {
-                            Err(err) => error!("unable to send to ES: {}", err),
-                            Ok(mut res) => {
-                                match res.status() {
-                                     StatusCode::CREATED => info!("successfully inserted into ES"),
-                                     _ => match res.text() {
-                                             Err(err) => error!("failed to insert to ES: {}", err),
-                                             Ok(body) => error!("failed to insert to ES: {}", body)
-                                     },
-                                }
-                            }
-                        };
+    Ok(thread::spawn(move || {
+        loop {
+            match rx.recv() {
+                Ok(message) => {
+                    let result = insert(&client, &url, message);
+                    if let Err(ref err) = result {
+                        error!("{}", err);
+                    }
+                    rx.delivered(1, &result);
+                },
+                Err(err) => {
+                    error!("closing thread: {}", err);
+                    break;
+                }
+            }
+        }
+    }))
+}
 
+// Inserts up to the configured concurrency of payloads at once.
+#[cfg(feature = "async-http")]
+fn spawn(url: String, config: &HttpConfig, rx: QueueReceiver<String>) -> Result<JoinHandle<()>, String> {
+    let client = http::async_client(config)?;
+
+    Ok(async_http::spawn(rx, config.concurrency, move |message| {
+        info!("sending payload to ES: {}", &message);
+        client
+            .post(&url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(message)
+            .send()
+            .and_then(|res| {
+                let status = res.status();
+                res.into_body().concat2().map(move |body| (status, String::from_utf8_lossy(&body).into_owned()))
+            })
+            .then(|res| {
+                match res {
+                    Err(err) => Err(format!("unable to send to ES: {}", err)),
+                    Ok((StatusCode::CREATED, _)) => {
+                        info!("successfully inserted into ES");
+                        Ok(())
                     },
-                    Err(err) => {
-                        error!("closing thread: {}", err);
-                        break;
-                    }
+                    Ok((_, body)) => Err(format!("failed to insert to ES: {}", body)),
                 }
+            })
+    }))
+}
+
+#[cfg(not(feature = "async-http"))]
+fn insert(client: &reqwest::Client, url: &str, message: String) -> Result<(), String> {
+    info!("sending payload to ES: {}", &message);
+    let res = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(message)
+        .send();
+
+    match res {
+        Err(err) => Err(format!("unable to send to ES: {}", err)),
+        Ok(mut res) => {
+            match res.status() {
+                 StatusCode::CREATED => {
+                     // Reading the body hands the connection back to the pool.
+                     let _ = res.copy_to(&mut io::sink());
+                     info!("successfully inserted into ES");
+                     Ok(())
+                 },
+                 _ => match res.text() {
+                         Err(err) => Err(format!("failed to insert to ES: {}", err)),
+                         Ok(body) => Err(format!("failed to insert to ES: {}", body)),
+                 },
             }
-        });
+        }
+    }
+}
+
+// The pipeline and document type end up in the URL, so only allow the
+// characters Elasticsearch takes in their names.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+// Where the documents are posted, the index URL followed by the document type
+// and the ingest pipeline when there is one.
+fn insert_url(url: &str, pipeline: Option<&str>, document_type: Option<&str>) -> Result<String, String> {
+    let document_type = document_type.unwrap_or("_doc");
+    if !valid_name(document_type) {
+        return Err(format!("invalid elasticsearch document type: {}", document_type));
+    }
+
+    match pipeline {
+        None => Ok(format!("{}/{}", url, document_type)),
+        Some(pipeline) if valid_name(pipeline) => Ok(format!("{}/{}?pipeline={}", url, document_type, pipeline)),
+        Some(pipeline) => Err(format!("invalid elasticsearch pipeline: {}", pipeline)),
+    }
+}
+
+impl Elasticsearch {
+    pub fn new(url: &str, pipeline: Option<&str>, document_type: Option<&str>, config: &HttpConfig) -> Result<Elasticsearch, String> {
+        let url = insert_url(url, pipeline, document_type)?;
+        let (tx, rx) = queue();
+        let handle = spawn(url, config, rx)?;
 
         Ok(Elasticsearch {
-            tx
+            worker: Worker::new(tx, handle),
         })
     }
 }
 
 impl Output for Elasticsearch {
-    fn process_open_connection(&self, message: &str) {
-        let _ = self.tx.send(message.to_string());
+    fn process_open_connection(&self, message: &str) -> Result<(), String> {
+        match self.worker.send(message.to_string()) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(String::from("elasticsearch thread has stopped")),
+        }
     }
 
-    fn process_close_connection(&self, message: &str) {
-        let _ = self.tx.send(message.to_string());
+    fn process_close_connection(&self, message: &str) -> Result<(), String> {
+        match self.worker.send(message.to_string()) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(String::from("elasticsearch thread has stopped")),
+        }
     }
 
     fn process_alive_connections(&self, _ : &Vec<i64>) { }
+
+    fn queue_latency(&self) -> Option<Arc<LatencyHistogram>> {
+        Some(self.worker.latency())
+    }
+
+    fn confirm(&self) -> Result<(), String> {
+        self.worker.confirm(FLUSH_TIMEOUT)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        match self.worker.flush(FLUSH_TIMEOUT) {
+            true => Ok(()),
+            false => Err(String::from("timed out flushing the ES output")),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -90,7 +191,43 @@ mod tests {
 
     #[test]
     fn test_valid_url() {
-        let elasticsearch = Elasticsearch::new("http://127.0.0.1:9200");
+        let elasticsearch = Elasticsearch::new("http://127.0.0.1:9200", None, None, &HttpConfig::default());
         assert!(!elasticsearch.is_err());
     }
+
+    #[test]
+    fn test_insert_url() {
+        assert_eq!(Ok(String::from("http://127.0.0.1:9200/my_index/_doc")), insert_url("http://127.0.0.1:9200/my_index", None, None));
+        assert_eq!(Ok(String::from("http://127.0.0.1:9200/my_index/_doc?pipeline=geoip-enrich")), insert_url("http://127.0.0.1:9200/my_index", Some("geoip-enrich"), None));
+        assert_eq!(Ok(String::from("http://127.0.0.1:9200/my_index/connection?pipeline=geoip")), insert_url("http://127.0.0.1:9200/my_index", Some("geoip"), Some("connection")));
+
+        assert!(insert_url("http://127.0.0.1:9200/my_index", Some("geoip&refresh=true"), None).is_err());
+        assert!(insert_url("http://127.0.0.1:9200/my_index", Some(""), None).is_err());
+        assert!(insert_url("http://127.0.0.1:9200/my_index", None, Some("../_delete_by_query")).is_err());
+        assert!(Elasticsearch::new("http://127.0.0.1:9200", Some("a pipeline"), None, &HttpConfig::default()).is_err());
+    }
+
+    #[cfg(feature = "async-http")]
+    mod concurrent {
+        use super::*;
+        use std::time::{ Duration, Instant };
+        use test_support::HttpServer;
+
+        #[test]
+        fn test_delivers_concurrently() {
+            let server = HttpServer::with_delay("201 Created", Duration::from_millis(300));
+            let mut elasticsearch = Elasticsearch::new(&server.url, None, None, &HttpConfig { concurrency: 8, .. HttpConfig::default() }).unwrap();
+
+            let started = Instant::now();
+            for hash in 0..8 {
+                elasticsearch.process_open_connection(&format!("{{\"hash\":{}}}", hash)).unwrap();
+            }
+            elasticsearch.flush().unwrap();
+
+            assert_eq!(8, server.received().len());
+            assert!(server.most_at_once() > 1);
+            // One at a time these would take at least 2.4 seconds.
+            assert!(started.elapsed() < Duration::from_millis(2000), "{:?}", started.elapsed());
+        }
+    }
 }
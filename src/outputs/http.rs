@@ -0,0 +1,360 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::env;
+use std::fs;
+use std::time::Duration;
+use reqwest;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+
+fn default_connect_timeout() -> u64 {
+    10
+}
+
+fn default_request_timeout() -> u64 {
+    30
+}
+
+fn default_concurrency() -> usize {
+    16
+}
+
+// Timeouts in seconds for the HTTP based outputs, without these a hung
+// collector wedges the output thread.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HttpConfig {
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout : u64,
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout : u64,
+    // Every request goes through this proxy, when it isn't set HTTP_PROXY and
+    // HTTPS_PROXY are used instead.
+    #[serde(default)]
+    pub proxy : Option<String>,
+    // A PEM file with the CA the collectors' certificates are signed by, it is
+    // trusted along with the system ones.
+    #[serde(default)]
+    pub ca_certificate : Option<String>,
+    // Turns off checking the collectors' certificates altogether.
+    #[serde(default)]
+    pub danger_accept_invalid_certs : bool,
+    // How many requests an output may have in flight, only when built with
+    // the async-http feature.
+    #[serde(default = "default_concurrency")]
+    pub concurrency : usize,
+}
+
+impl Default for HttpConfig {
+    fn default() -> HttpConfig {
+        HttpConfig {
+            connect_timeout: default_connect_timeout(),
+            request_timeout: default_request_timeout(),
+            proxy: None,
+            ca_certificate: None,
+            danger_accept_invalid_certs: false,
+            concurrency: default_concurrency(),
+        }
+    }
+}
+
+// A certificate the agent authenticates itself with, either a PEM certificate
+// with its PEM key or a PKCS#12 bundle holding both.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ClientCertificateConfig {
+    pub certificate : String,
+    #[serde(default)]
+    pub key : Option<String>,
+    #[serde(default)]
+    pub password : Option<String>,
+}
+
+fn variable<F>(lookup : &F, name : &str) -> Option<String> where F: Fn(&str) -> Option<String> {
+    lookup(name)
+        .or_else(|| lookup(&name.to_lowercase()))
+        .filter(|value| !value.is_empty())
+}
+
+// The proxies to send requests through, the configured one or else the ones
+// in the environment as curl would use them.
+fn proxies<F>(config : &HttpConfig, lookup : F) -> Result<Vec<reqwest::Proxy>, String> where F: Fn(&str) -> Option<String> {
+    let proxies = match config.proxy {
+        Some(ref url) => vec!(reqwest::Proxy::all(url.as_str())),
+        None => {
+            let mut proxies = Vec::new();
+            if let Some(url) = variable(&lookup, "HTTP_PROXY") {
+                proxies.push(reqwest::Proxy::http(url.as_str()));
+            }
+            if let Some(url) = variable(&lookup, "HTTPS_PROXY") {
+                proxies.push(reqwest::Proxy::https(url.as_str()));
+            }
+            proxies
+        },
+    };
+
+    match proxies.into_iter().collect() {
+        Ok(proxies) => Ok(proxies),
+        Err(err) => Err(format!("unable to use http proxy: {}", err)),
+    }
+}
+
+fn certificate(file : &str) -> Result<reqwest::Certificate, String> {
+    let pem = match fs::read(file) {
+        Ok(x) => x,
+        Err(err) => return Err(format!("unable to read ca certificate {}: {}", file, err)),
+    };
+
+    match reqwest::Certificate::from_pem(&pem) {
+        Ok(x) => Ok(x),
+        Err(err) => Err(format!("unable to parse ca certificate {}: {}", file, err)),
+    }
+}
+
+fn read(file : &str) -> Result<Vec<u8>, String> {
+    fs::read(file).map_err(|err| format!("unable to read {}: {}", file, err))
+}
+
+// The PEM pair is turned into PKCS#12 as that is all native-tls loads.
+fn pkcs12(config : &ClientCertificateConfig) -> Result<Vec<u8>, String> {
+    let key = match config.key {
+        Some(ref key) => key,
+        None => return read(&config.certificate),
+    };
+
+    let certificate = X509::from_pem(&read(&config.certificate)?).map_err(|err| format!("unable to parse {}: {}", config.certificate, err))?;
+    let key = PKey::private_key_from_pem(&read(key)?).map_err(|err| format!("unable to parse {}: {}", key, err))?;
+    let password = config.password.clone().unwrap_or_default();
+
+    match Pkcs12::builder().build(&password, "zerotrust-track", &key, &certificate) {
+        Ok(bundle) => bundle.to_der().map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+pub fn identity(config : &ClientCertificateConfig) -> Result<reqwest::Identity, String> {
+    let der = pkcs12(config).map_err(|err| format!("unable to load client certificate: {}", err))?;
+    let password = config.password.clone().unwrap_or_default();
+
+    match reqwest::Identity::from_pkcs12_der(&der, &password) {
+        Ok(identity) => Ok(identity),
+        Err(err) => Err(format!("unable to load client certificate {}: {}", config.certificate, err)),
+    }
+}
+
+// Builds the client an output keeps for its lifetime.
+pub fn client(config : &HttpConfig) -> Result<reqwest::Client, String> {
+    build(builder(config)?)
+}
+
+// A client which presents the certificate to the servers it connects to.
+pub fn client_with_identity(config : &HttpConfig, certificate : &ClientCertificateConfig) -> Result<reqwest::Client, String> {
+    build(builder(config)?.identity(identity(certificate)?))
+}
+
+fn builder(config : &HttpConfig) -> Result<reqwest::ClientBuilder, String> {
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(config.connect_timeout))
+        .timeout(Duration::from_secs(config.request_timeout));
+
+    for proxy in proxies(config, |name| env::var(name).ok())? {
+        builder = builder.proxy(proxy);
+    }
+
+    if let Some(ref file) = config.ca_certificate {
+        builder = builder.add_root_certificate(certificate(file)?);
+    }
+
+    if config.danger_accept_invalid_certs {
+        warn!("certificates are not being checked, anyone on the path to the collectors can read and change what is sent");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    Ok(builder)
+}
+
+// The same client for outputs which send from a runtime, see async_http.
+#[cfg(feature = "async-http")]
+pub fn async_client(config : &HttpConfig) -> Result<reqwest::async::Client, String> {
+    let mut builder = reqwest::async::Client::builder()
+        .connect_timeout(Duration::from_secs(config.connect_timeout))
+        .timeout(Duration::from_secs(config.request_timeout));
+
+    for proxy in proxies(config, |name| env::var(name).ok())? {
+        builder = builder.proxy(proxy);
+    }
+
+    if let Some(ref file) = config.ca_certificate {
+        builder = builder.add_root_certificate(certificate(file)?);
+    }
+
+    if config.danger_accept_invalid_certs {
+        warn!("certificates are not being checked, anyone on the path to the collectors can read and change what is sent");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    match builder.build() {
+        Ok(client) => Ok(client),
+        Err(err) => Err(format!("unable to create http client: {}", err)),
+    }
+}
+
+fn build(builder : reqwest::ClientBuilder) -> Result<reqwest::Client, String> {
+    match builder.build() {
+        Ok(client) => Ok(client),
+        Err(err) => Err(format!("unable to create http client: {}", err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::Instant;
+    use std::io;
+    use std::collections::HashMap;
+    use native_tls::{ Identity, TlsAcceptor };
+    use test_support::HttpServer;
+
+    const CERTIFICATE : &str = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/test/localhost.pem");
+    const KEY : &str = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/test/localhost.key");
+    const BUNDLE : &str = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/test/localhost.p12");
+
+    // Serves a 200 over TLS with the self signed localhost certificate.
+    fn tls_server() -> HttpServer {
+        let identity = Identity::from_pkcs12(&fs::read(BUNDLE).unwrap(), "zerotrust").unwrap();
+        HttpServer::start_tls("200 OK", TlsAcceptor::new(identity).unwrap())
+    }
+
+    #[test]
+    fn test_default_config() {
+        let config : HttpConfig = serde_yaml::from_str("{}").unwrap();
+        assert_eq!(10, config.connect_timeout);
+        assert_eq!(30, config.request_timeout);
+        assert_eq!(None, config.proxy);
+        assert_eq!(None, config.ca_certificate);
+        assert!(!config.danger_accept_invalid_certs);
+    }
+
+    #[test]
+    fn test_client_ca_certificate() {
+        let server = tls_server();
+
+        let res = client(&HttpConfig::default()).unwrap().get(&server.url).send();
+        assert!(res.is_err());
+
+        let config = HttpConfig { ca_certificate: Some(String::from(CERTIFICATE)), .. HttpConfig::default() };
+        let res = client(&config).unwrap().get(&server.url).send().unwrap();
+        assert_eq!(reqwest::StatusCode::OK, res.status());
+
+        let config = HttpConfig { ca_certificate: Some(String::from("/does/not/exist.pem")), .. HttpConfig::default() };
+        assert!(client(&config).is_err());
+    }
+
+    #[test]
+    fn test_identity() {
+        let pem = ClientCertificateConfig {
+            certificate: String::from(CERTIFICATE),
+            key: Some(String::from(KEY)),
+            password: None,
+        };
+        assert!(identity(&pem).is_ok());
+        assert!(client_with_identity(&HttpConfig::default(), &pem).is_ok());
+
+        let bundle = ClientCertificateConfig {
+            certificate: String::from(BUNDLE),
+            key: None,
+            password: Some(String::from("zerotrust")),
+        };
+        assert!(identity(&bundle).is_ok());
+
+        let wrong_password = ClientCertificateConfig { password: Some(String::from("wrong")), .. bundle };
+        assert!(identity(&wrong_password).is_err());
+
+        // The key has to be the one for the certificate.
+        let mismatched = ClientCertificateConfig { key: Some(String::from(CERTIFICATE)), .. pem.clone() };
+        assert!(identity(&mismatched).is_err());
+
+        let missing = ClientCertificateConfig { certificate: String::from("/does/not/exist.pem"), .. pem };
+        assert!(identity(&missing).unwrap_err().contains("/does/not/exist.pem"));
+    }
+
+    #[test]
+    fn test_client_accept_invalid_certs() {
+        let server = tls_server();
+
+        let config = HttpConfig { danger_accept_invalid_certs: true, .. HttpConfig::default() };
+        let res = client(&config).unwrap().get(&server.url).send().unwrap();
+        assert_eq!(reqwest::StatusCode::OK, res.status());
+    }
+
+    #[test]
+    fn test_client_uses_proxy() {
+        let proxy = HttpServer::start("200 OK");
+
+        // The collector doesn't exist, only the proxy can answer.
+        let client = client(&HttpConfig { proxy: Some(proxy.url.clone()), .. HttpConfig::default() }).unwrap();
+        let res = client.post("http://collector.invalid:9200/index").body("{}").send().unwrap();
+        assert_eq!(reqwest::StatusCode::OK, res.status());
+        assert_eq!("http://collector.invalid:9200/index", proxy.request().path);
+    }
+
+    #[test]
+    fn test_proxies() {
+        let mut variables = HashMap::new();
+        assert!(proxies(&HttpConfig::default(), |name| variables.get(name).cloned()).unwrap().is_empty());
+
+        variables.insert("https_proxy", String::from("http://proxy:3128"));
+        assert_eq!(1, proxies(&HttpConfig::default(), |name| variables.get(name).cloned()).unwrap().len());
+
+        variables.insert("HTTP_PROXY", String::from("http://proxy:3128"));
+        assert_eq!(2, proxies(&HttpConfig::default(), |name| variables.get(name).cloned()).unwrap().len());
+
+        // The configured proxy takes over from the environment.
+        let config = HttpConfig { proxy: Some(String::from("http://other:8080")), .. HttpConfig::default() };
+        assert_eq!(1, proxies(&config, |name| variables.get(name).cloned()).unwrap().len());
+
+        let config = HttpConfig { proxy: Some(String::from("not a url")), .. HttpConfig::default() };
+        assert!(proxies(&config, |_| None).is_err());
+    }
+
+    #[test]
+    fn test_client_request_timeout() {
+        // Accepts the connection but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        let client = client(&HttpConfig { connect_timeout: 1, request_timeout: 1, .. HttpConfig::default() }).unwrap();
+        let start = Instant::now();
+        let res = client.post(&url).body("{}").send();
+        assert!(res.unwrap_err().is_timeout());
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_client_reuses_connection() {
+        let server = HttpServer::start("200 OK");
+
+        let client = client(&HttpConfig::default()).unwrap();
+        for _ in 0..100 {
+            let mut res = client.post(&server.url).body("{}").send().unwrap();
+            assert_eq!(reqwest::StatusCode::OK, res.status());
+            res.copy_to(&mut io::sink()).unwrap();
+        }
+
+        assert_eq!(1, server.connections());
+    }
+}
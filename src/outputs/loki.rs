@@ -0,0 +1,332 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::io;
+use std::time::Duration;
+use outputs::{ Output, FLUSH_TIMEOUT };
+use outputs::worker::{ queue, QueueReceiver, Worker, Linger };
+use metrics::{ LatencyHistogram };
+use outputs::http;
+use outputs::http::{ HttpConfig };
+use reqwest::{ StatusCode };
+use reqwest::header::{ CONTENT_TYPE };
+use chrono::prelude::*;
+use serde_json;
+use serde_json::Value;
+use enums::{ AgentIdentity };
+
+fn default_batch_size() -> usize {
+    100
+}
+
+fn default_linger_ms() -> u64 {
+    2000
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LokiConfig {
+    // For instance http://loki:3100
+    pub url : String,
+    // Sent as X-Scope-OrgID for multi-tenant Loki.
+    #[serde(default)]
+    pub tenant : Option<String>,
+    // Extra labels attached to every stream.
+    #[serde(default)]
+    pub labels : BTreeMap<String, String>,
+    #[serde(default = "default_batch_size")]
+    pub batch_size : usize,
+    // The longest a partial batch is held before it is sent anyway.
+    #[serde(default = "default_linger_ms")]
+    pub linger_ms : u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Stream {
+    stream: BTreeMap<String, String>,
+    values: Vec<(String, String)>,
+}
+
+#[derive(Debug, Serialize)]
+struct Push {
+    streams: Vec<Stream>,
+}
+
+pub struct Loki {
+    worker : Worker<String>,
+}
+
+// Entries waiting to be pushed, grouped by their labels.
+struct Batch {
+    labels: BTreeMap<String, String>,
+    streams: BTreeMap<BTreeMap<String, String>, Vec<(String, String)>>,
+    size: usize,
+}
+
+impl Batch {
+    fn new(labels: BTreeMap<String, String>) -> Batch {
+        Batch {
+            labels,
+            streams: BTreeMap::new(),
+            size: 0,
+        }
+    }
+
+    fn add(&mut self, timestamp: i64, message: &str) {
+        // The direction is left off when no socket was found for the
+        // connection.
+        let mut labels = self.labels.clone();
+        if let Ok(payload) = serde_json::from_str::<Value>(message) {
+            for key in &["protocol", "direction", "event_type"] {
+                if let Some(value) = payload[*key].as_str() {
+                    labels.insert(key.to_string(), value.to_string());
+                }
+            }
+        }
+
+        self.streams.entry(labels)
+            .or_default()
+            .push((timestamp.to_string(), message.to_string()));
+        self.size += 1;
+    }
+
+    fn take(&mut self) -> Push {
+        let streams = self.streams.iter()
+            .map(|(labels, values)| Stream { stream: labels.clone(), values: values.clone() })
+            .collect();
+
+        self.streams.clear();
+        self.size = 0;
+        Push { streams }
+    }
+}
+
+fn push(client: &reqwest::Client, url: &str, tenant: &Option<String>, payload: &Push) -> Result<(), String> {
+    let payload = match serde_json::to_string(payload) {
+        Ok(x) => x,
+        Err(_err) => return Err(String::from("unable to serialize the loki push")),
+    };
+
+    let mut request = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(payload);
+
+    if let Some(tenant) = tenant {
+        request = request.header("X-Scope-OrgID", tenant.as_str());
+    }
+
+    match request.send() {
+        Err(err) => Err(format!("unable to send to loki: {}", err)),
+        Ok(mut res) => {
+            match res.status() {
+                StatusCode::NO_CONTENT | StatusCode::OK => {
+                    let _ = res.copy_to(&mut io::sink());
+                    Ok(())
+                },
+                _ => match res.text() {
+                    Err(err) => Err(format!("failed to push to loki: {}", err)),
+                    Ok(body) => Err(format!("failed to push to loki: {}", body)),
+                },
+            }
+        }
+    }
+}
+
+fn flush(client: &reqwest::Client, url: &str, tenant: &Option<String>, batch: &mut Batch, rx: &QueueReceiver<String>) {
+    if batch.size == 0 {
+        return;
+    }
+
+    let size = batch.size;
+    let result = push(client, url, tenant, &batch.take());
+    match result {
+        Ok(()) => sampled_debug!("pushed batch to loki"),
+        Err(ref err) => error!("{}", err),
+    }
+
+    rx.delivered(size, &result);
+}
+
+fn run(client: reqwest::Client, config: LokiConfig, mut batch: Batch, rx: QueueReceiver<String>) {
+    let url = format!("{}/loki/api/v1/push", config.url.trim_end_matches('/'));
+    let mut linger = Linger::new(Duration::from_millis(config.linger_ms));
+
+    loop {
+        match linger.recv(&rx) {
+            Ok(message) => batch.add(Utc::now().timestamp_nanos(), &message),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(err) => {
+                flush(&client, &url, &config.tenant, &mut batch, &rx);
+                error!("closing thread: {}", err);
+                break;
+            }
+        }
+
+        if batch.size >= config.batch_size || linger.expired() {
+            flush(&client, &url, &config.tenant, &mut batch, &rx);
+            linger.reset();
+        }
+    }
+}
+
+impl Loki {
+    // The labels every stream has, an agent label in the config takes the
+    // place of the agent name.
+    fn labels(config: &LokiConfig, identity: &AgentIdentity) -> BTreeMap<String, String> {
+        let mut labels = config.labels.clone();
+        labels.insert(String::from("job"), String::from("zerotrust-track"));
+        labels.entry(String::from("agent")).or_insert_with(|| identity.name.clone());
+        labels
+    }
+
+    pub fn new(config: &LokiConfig, identity: &AgentIdentity, http_config: &HttpConfig) -> Result<Loki, String> {
+        let client = http::client(http_config)?;
+        let batch = Batch::new(Loki::labels(config, identity));
+        let config = config.clone();
+        let (tx, rx) = queue();
+        let handle = thread::spawn(move || run(client, config, batch, rx));
+
+        Ok(Loki {
+            worker: Worker::new(tx, handle),
+        })
+    }
+}
+
+impl Output for Loki {
+    fn process_open_connection(&self, message: &str) -> Result<(), String> {
+        match self.worker.send(message.to_string()) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(String::from("loki thread has stopped")),
+        }
+    }
+
+    fn process_close_connection(&self, message: &str) -> Result<(), String> {
+        match self.worker.send(message.to_string()) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(String::from("loki thread has stopped")),
+        }
+    }
+
+    fn process_alive_connections(&self, _ : &Vec<i64>) { }
+
+    fn queue_latency(&self) -> Option<Arc<LatencyHistogram>> {
+        Some(self.worker.latency())
+    }
+
+    fn confirm(&self) -> Result<(), String> {
+        self.worker.confirm(FLUSH_TIMEOUT)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        match self.worker.flush(FLUSH_TIMEOUT) {
+            true => Ok(()),
+            false => Err(String::from("timed out flushing the loki output")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+    use uuid::Uuid;
+    use std::time::Instant;
+    use test_support::HttpServer;
+
+    #[test]
+    fn test_config() {
+        let config : LokiConfig = serde_yaml::from_str("url: http://127.0.0.1:3100").unwrap();
+        assert!(config.tenant.is_none());
+        assert!(config.labels.is_empty());
+        assert_eq!(100, config.batch_size);
+        assert_eq!(2000, config.linger_ms);
+    }
+
+    #[test]
+    fn test_push_structure() {
+        let mut labels = BTreeMap::new();
+        labels.insert(String::from("agent"), String::from("gerald"));
+        let mut batch = Batch::new(labels);
+
+        batch.add(1, "{\"event_type\":\"open\",\"protocol\":\"TCP\",\"direction\":\"outbound\",\"hash\":1}");
+        batch.add(2, "{\"event_type\":\"open\",\"protocol\":\"TCP\",\"direction\":\"outbound\",\"hash\":2}");
+        batch.add(3, "{\"event_type\":\"close\",\"protocol\":\"UDP\",\"direction\":null,\"hash\":3}");
+        batch.add(4, "{\"event_type\":\"open\",\"protocol\":\"TCP\",\"direction\":\"inbound\",\"hash\":4}");
+        assert_eq!(4, batch.size);
+
+        let push = serde_json::to_value(batch.take()).unwrap();
+        assert_eq!(0, batch.size);
+
+        let streams = push["streams"].as_array().unwrap();
+        assert_eq!(3, streams.len());
+
+        let inbound = &streams[0];
+        assert_eq!("inbound", inbound["stream"]["direction"]);
+        assert_eq!(1, inbound["values"].as_array().unwrap().len());
+
+        let outbound = &streams[1];
+        assert_eq!("TCP", outbound["stream"]["protocol"]);
+        assert_eq!("outbound", outbound["stream"]["direction"]);
+        assert_eq!(2, outbound["values"].as_array().unwrap().len());
+
+        let close = &streams[2];
+        assert_eq!("gerald", close["stream"]["agent"]);
+        assert_eq!("close", close["stream"]["event_type"]);
+        assert_eq!("UDP", close["stream"]["protocol"]);
+        assert!(close["stream"].get("direction").is_none());
+        assert_eq!("3", close["values"][0][0]);
+        assert_eq!("{\"event_type\":\"close\",\"protocol\":\"UDP\",\"direction\":null,\"hash\":3}", close["values"][0][1]);
+    }
+
+    #[test]
+    fn test_agent_label() {
+        let identity = AgentIdentity { name: String::from("gerald"), uuid: Uuid::new_v4(), hostname: String::from("localhost") };
+        let config : LokiConfig = serde_yaml::from_str("url: http://127.0.0.1:1").unwrap();
+        assert_eq!("gerald", Loki::labels(&config, &identity)["agent"]);
+
+        let config : LokiConfig = serde_yaml::from_str("{ url: \"http://127.0.0.1:1\", labels: { agent: web-01 } }").unwrap();
+        assert_eq!("web-01", Loki::labels(&config, &identity)["agent"]);
+    }
+
+    #[test]
+    fn test_unreachable_server() {
+        let config : LokiConfig = serde_yaml::from_str("{ url: \"http://127.0.0.1:1\", batch_size: 1 }").unwrap();
+        let identity = AgentIdentity { name: String::from("gerald"), uuid: Uuid::new_v4(), hostname: String::from("localhost") };
+        let loki = Loki::new(&config, &identity, &HttpConfig::default()).unwrap();
+        assert!(loki.process_open_connection("{}").is_ok());
+    }
+
+    #[test]
+    fn test_linger_flushes_partial_batch() {
+        let server = HttpServer::start("204 No Content");
+        let config : LokiConfig = serde_yaml::from_str(&format!("{{ url: \"{}\", batch_size: 100, linger_ms: 200 }}", server.url)).unwrap();
+
+        let identity = AgentIdentity { name: String::from("gerald"), uuid: Uuid::new_v4(), hostname: String::from("localhost") };
+        let loki = Loki::new(&config, &identity, &HttpConfig::default()).unwrap();
+
+        // One connection is far short of the batch size, it goes out once it
+        // has lingered and without anything else coming along.
+        let started = Instant::now();
+        loki.process_open_connection("{\"event_type\":\"open\",\"hash\":1}").unwrap();
+        let push : Value = serde_json::from_str(&server.request().body).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert_eq!("{\"event_type\":\"open\",\"hash\":1}", push["streams"][0]["values"][0][1]);
+    }
+}
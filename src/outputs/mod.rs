@@ -14,74 +14,531 @@
  *
  */
 
-use outputs::syslog::{SyslogConfig, Syslog};
+use outputs::syslog::{ Syslog };
 use outputs::elasticsearch::{ Elasticsearch };
 use outputs::server::{ Server };
-use enums::Config;
+use outputs::mqtt::{ Mqtt };
+use outputs::nats::{ Nats };
+use outputs::postgres::{ Postgres };
+use outputs::loki::{ Loki };
+use outputs::redis::{ Redis };
+use outputs::webhook::{ Webhook };
+use outputs::parquet::{ Parquet };
+// The settings of each output, so they can be made without going through
+// the config file.
+pub use outputs::syslog::{ SyslogConfig, SyslogSettings, SyslogFormat, SyslogSeverity };
+pub use outputs::http::{ HttpConfig, ClientCertificateConfig };
+pub use outputs::mqtt::{ MqttConfig };
+pub use outputs::nats::{ NatsConfig };
+pub use outputs::postgres::{ PostgresConfig };
+pub use outputs::loki::{ LokiConfig };
+pub use outputs::redis::{ RedisConfig, RedisMode };
+pub use outputs::webhook::{ WebhookConfig, WebhookKind };
+pub use outputs::parquet::{ ParquetConfig };
+use outputs::dry_run::{ DryRun };
+use enums::{ AgentIdentity, Config };
+use error::{ Error };
+use metrics::{ LatencyHistogram };
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use std::net::{ SocketAddr, SocketAddrV4, ToSocketAddrs };
+use reqwest;
+use serde::{ Serialize, Deserialize, Deserializer };
+use serde_json;
+use rmp_serde;
 
 mod syslog;
 mod elasticsearch;
 mod server;
+mod http;
+mod worker;
+mod mqtt;
+mod nats;
+mod postgres;
+mod loki;
+mod redis;
+mod webhook;
+mod parquet;
+mod dry_run;
+#[cfg(feature = "async-http")]
+mod async_http;
 
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Default)]
 pub struct OutputsConfig {
     pub syslog : Option<Vec<SyslogConfig>>,
-    pub elasticsearch : Option<String>,
-    pub zerotrust_endpoint : Option<String>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub elasticsearch : Option<Vec<String>>,
+    // Sent as the pipeline query parameter so the documents go through an
+    // ingest pipeline.
+    #[serde(default)]
+    pub elasticsearch_pipeline : Option<String>,
+    // The type segment of the insert URL, _doc when it isn't set.
+    #[serde(default)]
+    pub elasticsearch_document_type : Option<String>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub zerotrust_endpoint : Option<Vec<String>>,
+    // Authenticates the agent to the zerotrust_endpoint.
+    #[serde(default)]
+    pub zerotrust_client_certificate : Option<ClientCertificateConfig>,
+    #[serde(default)]
+    pub zerotrust_serialization : Serialization,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub mqtt : Option<Vec<MqttConfig>>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub nats : Option<Vec<NatsConfig>>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub postgres : Option<Vec<PostgresConfig>>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub loki : Option<Vec<LokiConfig>>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub redis : Option<Vec<RedisConfig>>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub webhook : Option<Vec<WebhookConfig>>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub parquet : Option<Vec<ParquetConfig>>,
+    #[serde(default)]
+    pub http : HttpConfig,
+}
+
+// An output on its own, as in the configs from before every kind could be
+// given more than once, or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+    where D: Deserializer<'de>, T: Deserialize<'de>
+{
+    let value : Option<OneOrMany<T>> = Option::deserialize(deserializer)?;
+    Ok(value.map(|value| match value {
+        OneOrMany::One(x) => vec!(x),
+        OneOrMany::Many(x) => x,
+    }))
 }
 
 pub trait Output {
-    fn process_open_connection(&self, &str);
-    fn process_close_connection(&self, &str);
+    fn process_open_connection(&self, &str) -> Result<(), String>;
+    fn process_close_connection(&self, &str) -> Result<(), String>;
     fn process_alive_connections(&self, &Vec<i64>);
+
+    // The whole connection in close only mode, which goes wherever closes go
+    // unless the output only cares about opens.
+    fn process_connection(&self, message: &str) -> Result<(), String> {
+        self.process_close_connection(message)
+    }
+
+    // Payloads which aren't connections, like the listen events, go wherever
+    // opens go unless the output has no place for them.
+    fn process_event(&self, message: &str) -> Result<(), String> {
+        self.process_open_connection(message)
+    }
+
+    // How the output puts the payloads on the wire. The ones which asked for
+    // MessagePack are handed it by process_encoded, made once for all of them.
+    fn serialization(&self) -> Serialization {
+        Serialization::Json
+    }
+
+    fn process_encoded(&self, route: Route, message: &str, _encoded: &[u8]) -> Result<(), String> {
+        route.process(self, message)
+    }
+
+    // How long payloads waited to be picked up, for the outputs which send
+    // from a thread of their own.
+    fn queue_latency(&self) -> Option<Arc<LatencyHistogram>> {
+        None
+    }
+
+    // Blocks until what was handed to the output so far has been sent and
+    // returns the first error since it was last asked. The outputs which send
+    // straight away have already returned theirs from the process calls.
+    fn confirm(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    // Blocks until everything queued has been sent, called on shutdown.
+    fn flush(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+// Which of the process calls a payload is handed to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Route {
+    Open,
+    Close,
+    Connection,
+    Event,
+}
+
+impl Route {
+    pub fn process<O : Output + ?Sized>(self, output: &O, message: &str) -> Result<(), String> {
+        match self {
+            Route::Open => output.process_open_connection(message),
+            Route::Close => output.process_close_connection(message),
+            Route::Connection => output.process_connection(message),
+            Route::Event => output.process_event(message),
+        }
+    }
+}
+
+// How long each output gets to send what it has queued on shutdown.
+pub const FLUSH_TIMEOUT : Duration = Duration::from_secs(10);
+
+// How the outputs which only carry bytes put the payloads on the wire,
+// MessagePack is a good deal smaller than JSON.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Serialization {
+    #[default]
+    Json,
+    Msgpack,
+}
+
+impl Serialization {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Serialization::Json => "application/json",
+            Serialization::Msgpack => "application/msgpack",
+        }
+    }
+
+    // For the payloads which only come as JSON, such as the replayed ones,
+    // the MessagePack is made from that.
+    pub fn encode(self, message : &str) -> Result<Vec<u8>, String> {
+        match self {
+            Serialization::Json => Ok(message.as_bytes().to_vec()),
+            Serialization::Msgpack => {
+                let value : serde_json::Value = match serde_json::from_str(message) {
+                    Ok(x) => x,
+                    Err(err) => return Err(format!("unable to parse payload: {}", err)),
+                };
+
+                to_msgpack(&value)
+            }
+        }
+    }
 }
 
+// Field names are kept and the addresses, uuids and times are written as the
+// same strings as in the JSON, rather than the packed forms serde gives them
+// by default outside of text formats.
+pub fn to_msgpack<T : Serialize>(payload : &T) -> Result<Vec<u8>, String> {
+    let mut encoded = Vec::new();
+    let mut serializer = rmp_serde::Serializer::new(&mut encoded).with_struct_map().with_human_readable();
+    match payload.serialize(&mut serializer) {
+        Ok(()) => Ok(encoded),
+        Err(err) => Err(format!("unable to serialize payload as msgpack: {}", err)),
+    }
+}
 
-pub fn create(config : &Config) -> Result<Vec<Box<Output>>, String> {
-        let mut outputs : Vec<Box<Output>> = Vec::new();
+
+pub fn create(config : &Config, identity : &AgentIdentity) -> Result<Vec<Box<Output>>, Error> {
+    let outputs = create_reusing(config, identity, &mut Vec::new())?;
+    Ok(outputs.into_iter().map(|(_, output)| output).collect())
+}
+
+// An output along with the settings it was made from, outputs made from the
+// same settings are the same output.
+pub type KeyedOutput = (String, Box<dyn Output>);
+
+// What each output is called where it is reported on. The settings can hold
+// passwords, so outputs of the same kind are told apart by number.
+pub fn labels(keys : &[String]) -> Vec<String> {
+    let mut seen : HashMap<&str, usize> = HashMap::new();
+    keys.iter().map(|key| {
+        let kind = key.split(':').next().unwrap_or_default();
+        let count = seen.entry(kind).or_insert(0);
+        *count += 1;
+        match *count {
+            1 => String::from(kind),
+            count => format!("{} {}", kind, count),
+        }
+    }).collect()
+}
+
+// The queue latency of the outputs which have one, by their label.
+pub fn latencies(keys : &[String], outputs : &[Box<dyn Output>]) -> Vec<(String, Arc<LatencyHistogram>)> {
+    labels(keys).into_iter().zip(outputs.iter())
+        .filter_map(|(label, output)| output.queue_latency().map(|latency| (label, latency)))
+        .collect()
+}
+
+fn key<T : Serialize>(output : &str, settings : &T) -> String {
+    format!("{}:{}", output, serde_json::to_string(settings).unwrap_or_default())
+}
+
+// Builds the outputs for the config, taking the ones whose settings haven't
+// changed out of running rather than making them again. Whatever is left in
+// running is no longer configured. When an output can't be made running is
+// left with everything it had.
+pub fn create_reusing(config : &Config, identity : &AgentIdentity, running : &mut Vec<KeyedOutput>) -> Result<Vec<KeyedOutput>, Error> {
+    let mut outputs = Vec::new();
+    match add_outputs(config, identity, running, &mut outputs) {
+        Ok(()) => Ok(outputs),
+        Err(err) => {
+            running.extend(outputs);
+            Err(Error::Output(err))
+        }
+    }
+}
+
+fn add<F>(outputs : &mut Vec<KeyedOutput>, running : &mut Vec<KeyedOutput>, key : String, make : F) -> Result<(), String>
+    where F: FnOnce() -> Result<Box<dyn Output>, String>
+{
+    match running.iter().position(|(running, _)| *running == key) {
+        Some(index) => {
+            debug!("keeping output {}", key);
+            outputs.push(running.remove(index));
+        },
+        None => outputs.push((key, make()?)),
+    };
+
+    Ok(())
+}
+
+fn add_outputs(config : &Config, identity : &AgentIdentity, running : &mut Vec<KeyedOutput>, outputs : &mut Vec<KeyedOutput>) -> Result<(), String> {
+        // None of the configured outputs are made, so nothing is sent.
+        if config.dry_run {
+            return add(outputs, running, String::from("dry_run"), || {
+                info!("dry run, logging payloads instead of sending them");
+                Ok(Box::new(DryRun::new()))
+            });
+        }
+
+        let http = &config.outputs.http;
         if let Some(ref config) = config.outputs.syslog {
             for output in config.iter() {
-            match output {
-                    SyslogConfig::Localhost => {
-                        info!("adding localhost syslog output");
-                        let syslog = Syslog::local()?;
-                        outputs.push(Box::new(syslog));
-                    },
-                    SyslogConfig::TCP{address, port} => {
-                        info!("adding tcp syslog output");
-                        let syslog = Syslog::tcp(address, *port)?;
-                        outputs.push(Box::new(syslog));
-                    },
-                    SyslogConfig::UDP{address, port} => {
-                        info!("adding udp syslog output");
-                        let syslog = Syslog::udp(address, *port)?;
-                        outputs.push(Box::new(syslog));
-                    },
-                };
+                add(outputs, running, key("syslog", output), || {
+                    let syslog = match output {
+                        SyslogConfig::Localhost => {
+                            info!("adding localhost syslog output");
+                            Syslog::local(&SyslogSettings::default())?
+                        },
+                        SyslogConfig::Unix{settings} => {
+                            info!("adding localhost syslog output");
+                            Syslog::local(settings)?
+                        },
+                        SyslogConfig::TCP{address, port, settings} => {
+                            info!("adding tcp syslog output");
+                            Syslog::tcp(address, *port, settings)?
+                        },
+                        SyslogConfig::UDP{address, port, settings} => {
+                            info!("adding udp syslog output");
+                            Syslog::udp(address, *port, settings)?
+                        },
+                    };
+                    Ok(Box::new(syslog))
+                })?;
             }
         }
 
-        if let Some(ref config) = config.outputs.elasticsearch {
-            info!("adding elasticsearch output: {}", config);
-            let elasticsearch = Elasticsearch::new(config)?;
-            outputs.push(Box::new(elasticsearch));
+        // The pipeline and document type go with every elasticsearch url.
+        let pipeline = config.outputs.elasticsearch_pipeline.as_ref();
+        let document_type = config.outputs.elasticsearch_document_type.as_ref();
+        for url in config.outputs.elasticsearch.iter().flatten() {
+            add(outputs, running, key("elasticsearch", &(url, pipeline, document_type, http)), || {
+                info!("adding elasticsearch output: {}", url);
+                Ok(Box::new(Elasticsearch::new(url, pipeline.map(String::as_str), document_type.map(String::as_str), http)?))
+            })?;
         }
 
-        if let Some(ref endpoint_config) = config.outputs.zerotrust_endpoint {
-            info!("adding server output: {} / {:?} / {:?}", endpoint_config, config.name, config.uuid);
-            let server = Server::new(&config.name, &config.uuid, endpoint_config)?;
-            outputs.push(Box::new(server));
+        for mqtt_config in config.outputs.mqtt.iter().flatten() {
+            add(outputs, running, key("mqtt", mqtt_config), || {
+                info!("adding mqtt output: {}:{}", mqtt_config.host, mqtt_config.port);
+                Ok(Box::new(Mqtt::new(mqtt_config, identity)?))
+            })?;
         }
 
-        Ok(outputs)
+        for nats_config in config.outputs.nats.iter().flatten() {
+            add(outputs, running, key("nats", nats_config), || {
+                info!("adding nats output: {}", nats_config.url);
+                Ok(Box::new(Nats::new(nats_config, identity)?))
+            })?;
+        }
+
+        for postgres_config in config.outputs.postgres.iter().flatten() {
+            add(outputs, running, key("postgres", postgres_config), || {
+                info!("adding postgres output");
+                Ok(Box::new(Postgres::new(postgres_config)?))
+            })?;
+        }
+
+        for loki_config in config.outputs.loki.iter().flatten() {
+            add(outputs, running, key("loki", &(loki_config, http)), || {
+                info!("adding loki output: {}", loki_config.url);
+                Ok(Box::new(Loki::new(loki_config, identity, http)?))
+            })?;
+        }
+
+        for redis_config in config.outputs.redis.iter().flatten() {
+            add(outputs, running, key("redis", redis_config), || {
+                info!("adding redis output: {}", redis_config.key);
+                Ok(Box::new(Redis::new(redis_config)?))
+            })?;
+        }
+
+        for webhook_config in config.outputs.webhook.iter().flatten() {
+            add(outputs, running, key("webhook", &(webhook_config, http)), || {
+                info!("adding {:?} webhook output", webhook_config.kind);
+                Ok(Box::new(Webhook::new(webhook_config, http)?))
+            })?;
+        }
+
+        for parquet_config in config.outputs.parquet.iter().flatten() {
+            add(outputs, running, key("parquet", parquet_config), || {
+                info!("adding parquet output: {}", parquet_config.directory);
+                Ok(Box::new(Parquet::new(parquet_config)?))
+            })?;
+        }
+
+        let certificate = config.outputs.zerotrust_client_certificate.as_ref();
+        let serialization = config.outputs.zerotrust_serialization;
+        for endpoint_config in config.outputs.zerotrust_endpoint.iter().flatten() {
+            add(outputs, running, key("zerotrust_endpoint", &(endpoint_config, &config.directory, http, certificate, serialization)), || {
+                info!("adding server output: {} / {} / {}", endpoint_config, identity.name, identity.uuid);
+                Ok(Box::new(Server::new(identity, endpoint_config, &config.directory, http, certificate, serialization)?))
+            })?;
+        }
+
+        Ok(())
+}
+
+// The outputs add their paths onto the URL, so trailing slashes are taken off
+// and one which isn't http or https is refused when the config is loaded
+// rather than failing on every send.
+pub fn normalize_url(url: &str) -> Result<String, String> {
+    let trimmed = url.trim().trim_end_matches('/');
+    if !trimmed.contains("://") {
+        return Err(format!("the url {} has no scheme, such as http://", url));
+    }
+
+    match reqwest::Url::parse(trimmed) {
+        Ok(ref parsed) if parsed.scheme() != "http" && parsed.scheme() != "https" => Err(format!("the url {} has to be http or https", url)),
+        Ok(ref parsed) if parsed.host_str().is_none() => Err(format!("the url {} has no host", url)),
+        Ok(_) => Ok(String::from(trimmed)),
+        Err(err) => Err(format!("unable to parse the url {}: {}", url, err)),
+    }
+}
+
+// The zerotrust endpoints and elasticsearch urls, normalized in place.
+pub fn normalize_urls(config: &mut OutputsConfig) -> Result<(), String> {
+    for url in config.zerotrust_endpoint.iter_mut().flatten().chain(config.elasticsearch.iter_mut().flatten()) {
+        *url = normalize_url(url)?;
+    }
+
+    Ok(())
+}
+
+// Where a URL points, in host:port form, for the reachability check.
+fn url_address(url: &str, default_port: u16) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    Some(format!("{}:{}", host, url.port_or_known_default().unwrap_or(default_port)))
+}
+
+fn postgres_address(connection: &str) -> Option<String> {
+    let mut host = None;
+    let mut port = "5432";
+    for pair in connection.split_whitespace() {
+        let mut split = pair.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some("host"), Some(value)) => host = Some(value),
+            (Some("port"), Some(value)) => port = value,
+            _ => (),
+        }
+    }
+
+    host.filter(|host| !host.starts_with('/')).map(|host| format!("{}:{}", host, port))
+}
+
+// Every output which is configured along with the TCP address it sends to,
+// None when there is nothing to connect to.
+pub fn targets(config: &OutputsConfig) -> Vec<(String, Option<String>)> {
+    let mut targets = Vec::new();
+
+    if let Some(ref syslog) = config.syslog {
+        for output in syslog {
+            match output {
+                SyslogConfig::Localhost | SyslogConfig::Unix { .. } => targets.push((String::from("syslog localhost"), None)),
+                SyslogConfig::TCP { address, port, .. } => targets.push((String::from("syslog tcp"), Some(format!("{}:{}", address, port)))),
+                SyslogConfig::UDP { address, port, .. } => targets.push((format!("syslog udp {}:{}", address, port), None)),
+            }
+        }
+    }
+
+    for url in config.elasticsearch.iter().flatten() {
+        targets.push((String::from("elasticsearch"), url_address(url, 9200)));
+    }
+
+    for mqtt in config.mqtt.iter().flatten() {
+        targets.push((String::from("mqtt"), Some(format!("{}:{}", mqtt.host, mqtt.port))));
+    }
+
+    for nats in config.nats.iter().flatten() {
+        targets.push((String::from("nats"), url_address(&nats.url, 4222)));
+    }
+
+    for postgres in config.postgres.iter().flatten() {
+        targets.push((String::from("postgres"), postgres_address(&postgres.connection)));
+    }
+
+    for loki in config.loki.iter().flatten() {
+        targets.push((String::from("loki"), url_address(&loki.url, 3100)));
+    }
+
+    for redis in config.redis.iter().flatten() {
+        targets.push((String::from("redis"), url_address(&redis.url, 6379)));
+    }
+
+    for webhook in config.webhook.iter().flatten() {
+        targets.push((format!("{:?} webhook", webhook.kind).to_lowercase(), url_address(&webhook.url, 443)));
+    }
+
+    for parquet in config.parquet.iter().flatten() {
+        targets.push((format!("parquet {}", parquet.directory), None));
+    }
+
+    for url in config.zerotrust_endpoint.iter().flatten() {
+        targets.push((String::from("zerotrust_endpoint"), url_address(url, 80)));
+    }
+
+    targets
+}
+
+// The IPv4 addresses the zerotrust endpoints resolve to, looked up once at
+// start up.
+pub fn endpoint_addresses(config: &OutputsConfig) -> Vec<SocketAddrV4> {
+    let mut endpoints = Vec::new();
+    for address in config.zerotrust_endpoint.iter().flatten().filter_map(|url| url_address(url, 80)) {
+        match address.to_socket_addrs() {
+            Ok(addresses) => endpoints.extend(addresses.filter_map(|address| match address {
+                SocketAddr::V4(address) => Some(address),
+                SocketAddr::V6(_) => None,
+            })),
+            Err(err) => warn!("unable to resolve the zerotrust endpoint {}: {}", address, err),
+        }
+    }
+
+    endpoints
 }
 
 #[cfg(test)]
 mod tests {
+    use super::{ Output, OutputsConfig, KeyedOutput, Serialization, SocketAddrV4, to_msgpack, Error, targets, endpoint_addresses, create_reusing, normalize_url, normalize_urls };
+    use tempfile::tempdir;
+    use serde_json;
+    use rmp_serde;
+    use serde_yaml;
     use std::net::{ Ipv4Addr, TcpListener, UdpSocket };
     use enums;
     use filters;
+    use test_support::{ default_config, default_filters, default_open_payload, identity };
 
     #[test]
     fn test_create_failed() {
@@ -89,31 +546,31 @@ mod tests {
         vec.push( super::SyslogConfig::Localhost );
         vec.push( super::SyslogConfig::TCP {
             address : Ipv4Addr::new(127, 0, 0, 1),
-            port: 7233
+            port: 7233,
+            settings: Default::default(),
         });
 
         vec.push( super::SyslogConfig::UDP {
             address : Ipv4Addr::new(127, 0, 0, 1),
-            port: 7233
+            port: 7233,
+            settings: Default::default(),
         });
 
         let config = enums::Config {
-            directory: None,
-            name: None,
-            uuid: None,
-            outputs: super::OutputsConfig {
+            outputs: OutputsConfig {
                 syslog: Some(vec),
-                elasticsearch: None,
-                zerotrust_endpoint: None,
+                .. default_config().outputs
             },
             filters: filters::FiltersConfig {
                 non_process_connections : false,
                 dns_requests: false,
-                zerotrust_track_connections : false
-            }
+                zerotrust_track_connections : false,
+                .. default_filters()
+            },
+            .. default_config()
         };
 
-        let config = super::create(&config);
+        let config = super::create(&config, &identity());
         assert!(config.is_err());
     }
 
@@ -126,32 +583,193 @@ mod tests {
         vec.push( super::SyslogConfig::Localhost );
         vec.push( super::SyslogConfig::TCP {
             address : Ipv4Addr::new(127, 0, 0, 1),
-            port: 7232
+            port: 7232,
+            settings: Default::default(),
         });
         vec.push( super::SyslogConfig::UDP {
             address : Ipv4Addr::new(127, 0, 0, 1),
-            port: 7232
+            port: 7232,
+            settings: Default::default(),
         });
 
         let config = enums::Config {
-            directory: None,
-            name: None,
-            uuid: None,
-            outputs: super::OutputsConfig {
+            outputs: OutputsConfig {
                 syslog: Some(vec),
-                elasticsearch: None,
-                zerotrust_endpoint: None,
+                .. default_config().outputs
             },
             filters: filters::FiltersConfig {
                 non_process_connections : false,
                 dns_requests: false,
-                zerotrust_track_connections : false
-            }
+                zerotrust_track_connections : false,
+                .. default_filters()
+            },
+            .. default_config()
         };
 
-        let config = super::create(&config);
+        let config = super::create(&config, &identity());
         assert!(!config.is_err());
     }
 
+    #[test]
+    fn test_targets() {
+        let config : OutputsConfig = serde_yaml::from_str("
+syslog: [ Localhost, { TCP: { address: 10.0.0.1, port: 514 } } ]
+elasticsearch: \"http://127.0.0.1:9200\"
+redis: { url: \"redis://cache\" }
+postgres: { connection: \"host=db user=zerotrust\" }
+nats: { url: \"nats://I am not a url\" }
+").unwrap();
+
+        assert_eq!(vec!(
+            (String::from("syslog localhost"), None),
+            (String::from("syslog tcp"), Some(String::from("10.0.0.1:514"))),
+            (String::from("elasticsearch"), Some(String::from("127.0.0.1:9200"))),
+            (String::from("nats"), None),
+            (String::from("postgres"), Some(String::from("db:5432"))),
+            (String::from("redis"), Some(String::from("cache:6379"))),
+        ), targets(&config));
+    }
+
+    #[test]
+    fn test_create_several_of_a_kind() {
+        let config = enums::Config {
+            outputs: serde_yaml::from_str("elasticsearch: [ \"http://127.0.0.1:9200\", \"http://127.0.0.1:9201\" ]").unwrap(),
+            .. default_config()
+        };
+
+        let outputs = create_reusing(&config, &identity(), &mut Vec::new()).unwrap();
+        assert_eq!(2, outputs.len());
+        assert!(outputs.iter().all(|(key, _)| key.starts_with("elasticsearch:")));
+        assert!(outputs[0].0.contains("9200") && outputs[1].0.contains("9201"));
+
+        // A single one is still taken on its own.
+        let config : OutputsConfig = serde_yaml::from_str("elasticsearch: \"http://127.0.0.1:9200\"\nmqtt: { host: broker }").unwrap();
+        assert_eq!(Some(vec!(String::from("http://127.0.0.1:9200"))), config.elasticsearch);
+        assert_eq!(1, config.mqtt.map_or(0, |mqtt| mqtt.len()));
+    }
+
+    #[test]
+    fn test_endpoint_addresses() {
+        let config : OutputsConfig = serde_yaml::from_str("zerotrust_endpoint: \"http://127.0.0.1:8080/connections\"").unwrap();
+        assert_eq!(vec!(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 8080)), endpoint_addresses(&config));
+
+        let config : OutputsConfig = serde_yaml::from_str("zerotrust_endpoint: [ \"http://127.0.0.1:8080\", \"http://127.0.0.2:8081\" ]").unwrap();
+        assert_eq!(2, endpoint_addresses(&config).len());
+
+        let config : OutputsConfig = serde_yaml::from_str("syslog: [ Localhost ]").unwrap();
+        assert!(endpoint_addresses(&config).is_empty());
+    }
 
+    #[test]
+    fn test_normalize_url() {
+        assert_eq!(Ok(String::from("http://127.0.0.1:8080")), normalize_url("http://127.0.0.1:8080/"));
+        assert_eq!(Ok(String::from("https://collector.example.com/zerotrust")), normalize_url("https://collector.example.com/zerotrust//"));
+        assert_eq!(Ok(String::from("http://127.0.0.1:9200/index")), normalize_url("http://127.0.0.1:9200/index"));
+
+        assert!(normalize_url("127.0.0.1:9200").unwrap_err().contains("no scheme"));
+        assert!(normalize_url("collector.example.com/zerotrust").is_err());
+        assert!(normalize_url("ftp://collector.example.com").is_err());
+        assert!(normalize_url("http://").is_err());
+    }
+
+    #[test]
+    fn test_normalize_urls() {
+        let mut config : OutputsConfig = serde_yaml::from_str("zerotrust_endpoint: [ \"http://127.0.0.1:8080/\", \"http://127.0.0.2:8081\" ]\nelasticsearch: \"http://127.0.0.1:9200/index/\"").unwrap();
+        normalize_urls(&mut config).unwrap();
+        assert_eq!(Some(vec!(String::from("http://127.0.0.1:8080"), String::from("http://127.0.0.2:8081"))), config.zerotrust_endpoint);
+        assert_eq!(Some(vec!(String::from("http://127.0.0.1:9200/index"))), config.elasticsearch);
+
+        let mut config : OutputsConfig = serde_yaml::from_str("elasticsearch: \"127.0.0.1:9200\"").unwrap();
+        assert!(normalize_urls(&mut config).is_err());
+    }
+
+    // Where the output of the kind lives, the same address is the same output.
+    fn address(outputs : &[KeyedOutput], kind : &str) -> *const () {
+        let (_, output) = outputs.iter().find(|(key, _)| key.starts_with(kind)).unwrap();
+        &**output as *const dyn Output as *const ()
+    }
+
+    #[test]
+    fn test_create_reusing() {
+        let directory = tempdir().unwrap();
+        let parquet = format!("parquet:\n  directory: {}\n", directory.path().to_str().unwrap());
+        let webhook = "webhook:\n  url: http://127.0.0.1:1/hook\n  kind: Slack\n  allowed_networks: []\n";
+        let identity = identity();
+
+        let config = enums::Config { outputs: serde_yaml::from_str(&parquet).unwrap(), .. default_config() };
+        let mut running = create_reusing(&config, &identity, &mut Vec::new()).unwrap();
+        assert_eq!(1, running.len());
+        let parquet_output = address(&running, "parquet:");
+
+        // Adding the webhook leaves the parquet output as it was.
+        let config = enums::Config { outputs: serde_yaml::from_str(&format!("{}{}", parquet, webhook)).unwrap(), .. default_config() };
+        let mut running = create_reusing(&config, &identity, &mut running).unwrap();
+        assert_eq!(2, running.len());
+        assert_eq!(parquet_output, address(&running, "parquet:"));
+        let webhook_output = address(&running, "webhook:");
+
+        // Removing the parquet output hands it back.
+        let config = enums::Config { outputs: serde_yaml::from_str(webhook).unwrap(), .. default_config() };
+        let kept = create_reusing(&config, &identity, &mut running).unwrap();
+        assert_eq!(1, kept.len());
+        assert_eq!(webhook_output, address(&kept, "webhook:"));
+        assert_eq!(1, running.len());
+        assert_eq!(parquet_output, address(&running, "parquet:"));
+    }
+
+    #[test]
+    fn test_create_reusing_failed() {
+        let directory = tempdir().unwrap();
+        let parquet = format!("parquet:\n  directory: {}\n", directory.path().to_str().unwrap());
+        let identity = identity();
+
+        let config = enums::Config { outputs: serde_yaml::from_str(&parquet).unwrap(), .. default_config() };
+        let mut running = create_reusing(&config, &identity, &mut Vec::new()).unwrap();
+        let parquet_output = address(&running, "parquet:");
+
+        // The parquet output is reusable but the new one can't be made.
+        let broken = format!("{}postgres:\n  connection: \"host=127.0.0.1\"\n  open_table: \"not a table\"\n", parquet);
+        let config = enums::Config { outputs: serde_yaml::from_str(&broken).unwrap(), .. default_config() };
+        assert!(matches!(create_reusing(&config, &identity, &mut running), Err(Error::Output(_))));
+        assert_eq!(1, running.len());
+        assert_eq!(parquet_output, address(&running, "parquet:"));
+    }
+
+    #[test]
+    fn test_msgpack_round_trip() {
+        let payload = serde_json::to_value(default_open_payload(40000, 443, None)).unwrap();
+        let message = payload.to_string();
+
+        assert_eq!(message.as_bytes().to_vec(), Serialization::Json.encode(&message).unwrap());
+
+        let encoded = Serialization::Msgpack.encode(&message).unwrap();
+        assert!(encoded.len() < message.len());
+        let decoded : serde_json::Value = rmp_serde::from_slice(&encoded).unwrap();
+        assert_eq!(payload, decoded);
+        assert_eq!(443, decoded["destination_port"]);
+        assert_eq!("open", decoded["event_type"]);
+
+        assert!(Serialization::Msgpack.encode("not json").is_err());
+
+        // Straight from the payload it comes out as it does by way of JSON.
+        let direct : serde_json::Value = rmp_serde::from_slice(&to_msgpack(&default_open_payload(40000, 443, None)).unwrap()).unwrap();
+        assert_eq!("127.0.0.1", direct["source"]);
+        assert_eq!(payload, direct);
+    }
+
+    #[test]
+    fn test_dry_run_makes_no_outputs() {
+        let directory = tempdir().unwrap();
+        let parquet = format!("parquet:\n  directory: {}\n  rows_per_file: 1\n", directory.path().to_str().unwrap());
+        let config = enums::Config { outputs: serde_yaml::from_str(&parquet).unwrap(), dry_run: true, .. default_config() };
+
+        let outputs = create_reusing(&config, &identity(), &mut Vec::new()).unwrap();
+        assert_eq!(vec!("dry_run"), outputs.iter().map(|(key, _)| key.as_str()).collect::<Vec<&str>>());
+
+        let (_, mut output) = outputs.into_iter().next().unwrap();
+        let message = serde_json::to_string(&default_open_payload(40000, 443, None)).unwrap();
+        output.process_open_connection(&message).unwrap();
+        output.flush().unwrap();
+        assert_eq!(0, ::std::fs::read_dir(directory.path()).unwrap().count());
+    }
 }
@@ -0,0 +1,325 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::sync::Arc;
+use std::thread;
+use std::io::{ Read, Write };
+use std::net::TcpStream;
+use std::time::Duration;
+use outputs::{ Output, Route, Serialization, FLUSH_TIMEOUT };
+use outputs::worker::{ queue, Worker };
+use metrics::{ LatencyHistogram };
+use enums::{ AgentIdentity };
+use native_tls::TlsConnector;
+use mqtt::{ Encodable, Decodable, TopicName };
+use mqtt::packet::{ ConnectPacket, PublishPacket, QoSWithPacketIdentifier, VariablePacket };
+use mqtt::control::variable_header::ConnectReturnCode;
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_topic() -> String {
+    String::from("zerotrust/{uuid}/connections")
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MqttConfig {
+    pub host : String,
+    #[serde(default = "default_port")]
+    pub port : u16,
+    #[serde(default)]
+    pub tls : bool,
+    #[serde(default)]
+    pub username : Option<String>,
+    #[serde(default)]
+    pub password : Option<String>,
+    // Only 0 (at most once) and 1 (at least once) are supported.
+    #[serde(default)]
+    pub qos : u8,
+    // {uuid} and {name} are replaced with the details of this agent.
+    #[serde(default = "default_topic")]
+    pub topic : String,
+    #[serde(default)]
+    pub serialization : Serialization,
+}
+
+trait Stream : Read + Write + Send {}
+impl<T : Read + Write + Send> Stream for T {}
+
+pub struct Mqtt {
+    worker : Worker<Vec<u8>>,
+    serialization : Serialization,
+}
+
+fn topic(template: &str, identity: &AgentIdentity) -> String {
+    template.replace("{uuid}", &identity.uuid.to_string()).replace("{name}", &identity.name)
+}
+
+fn connect(config: &MqttConfig, client_id: &str) -> Result<Box<dyn Stream>, String> {
+    let address = format!("{}:{}", config.host, config.port);
+    let tcp = match TcpStream::connect(&address) {
+        Ok(x) => x,
+        Err(err) => return Err(format!("unable to connect to mqtt broker {}: {}", address, err)),
+    };
+    let _ = tcp.set_read_timeout(Some(Duration::from_secs(10)));
+
+    let mut stream : Box<dyn Stream> = match config.tls {
+        false => Box::new(tcp),
+        true => {
+            let connector = match TlsConnector::new() {
+                Ok(x) => x,
+                Err(err) => return Err(format!("unable to create tls connector: {}", err)),
+            };
+
+            match connector.connect(&config.host, tcp) {
+                Ok(x) => Box::new(x),
+                Err(err) => return Err(format!("unable to start tls with mqtt broker: {}", err)),
+            }
+        }
+    };
+
+    let mut packet = ConnectPacket::new(client_id);
+    packet.set_clean_session(true);
+    packet.set_user_name(config.username.clone());
+    packet.set_password(config.password.clone());
+
+    if let Err(err) = packet.encode(&mut stream) {
+        return Err(format!("unable to send connect to mqtt broker: {}", err));
+    }
+
+    match VariablePacket::decode(&mut stream) {
+        Ok(VariablePacket::ConnackPacket(ack)) => match ack.connect_return_code() {
+            ConnectReturnCode::ConnectionAccepted => Ok(stream),
+            code => Err(format!("mqtt broker refused connection: {:?}", code)),
+        },
+        Ok(packet) => Err(format!("unexpected packet from mqtt broker: {:?}", packet)),
+        Err(err) => Err(format!("unable to read connack from mqtt broker: {}", err)),
+    }
+}
+
+fn publish(stream: &mut Box<dyn Stream>, topic: &TopicName, qos: u8, id: u16, message: &[u8]) -> Result<(), String> {
+    let qos = match qos {
+        0 => QoSWithPacketIdentifier::Level0,
+        _ => QoSWithPacketIdentifier::Level1(id),
+    };
+
+    let packet = PublishPacket::new(topic.clone(), qos, message);
+    if let Err(err) = packet.encode(stream) {
+        return Err(format!("unable to publish to mqtt broker: {}", err));
+    }
+
+    if let QoSWithPacketIdentifier::Level1(id) = qos {
+        match VariablePacket::decode(stream) {
+            Ok(VariablePacket::PubackPacket(ref ack)) if ack.packet_identifier() == id => (),
+            Ok(packet) => return Err(format!("unexpected packet from mqtt broker: {:?}", packet)),
+            Err(err) => return Err(format!("unable to read puback from mqtt broker: {}", err)),
+        }
+    }
+
+    Ok(())
+}
+
+impl Mqtt {
+    pub fn new(config: &MqttConfig, identity: &AgentIdentity) -> Result<Mqtt, String> {
+        if config.qos > 1 {
+            return Err(String::from("mqtt qos must be 0 or 1"));
+        }
+
+        let topic = match TopicName::new(topic(&config.topic, identity)) {
+            Ok(x) => x,
+            Err(err) => return Err(format!("invalid mqtt topic: {}", err)),
+        };
+
+        let client_id = format!("zerotrust-track-{}", identity.uuid);
+
+        let serialization = config.serialization;
+        let config = config.clone();
+        let (tx, rx) = queue::<Vec<u8>>();
+
+        let handle = thread::spawn(move || {
+            let mut stream : Option<Box<dyn Stream>> = None;
+            let mut id : u16 = 0;
+
+            loop {
+                let message = match rx.recv() {
+                    Ok(x) => x,
+                    Err(err) => {
+                        error!("closing thread: {}", err);
+                        break;
+                    }
+                };
+
+                id = id.wrapping_add(1).max(1);
+
+                let mut delivered = Ok(());
+
+                // A dropped connection is only noticed on the next publish, so
+                // reconnect and give it a second try.
+                for _ in 0..2 {
+                    if stream.is_none() {
+                        match connect(&config, &client_id) {
+                            Ok(x) => stream = Some(x),
+                            Err(err) => {
+                                error!("{}", err);
+                                delivered = Err(err);
+                                break;
+                            }
+                        }
+                    }
+
+                    if let Some(ref mut connection) = stream {
+                        match publish(connection, &topic, config.qos, id, &message) {
+                            Ok(()) => {
+                                sampled_debug!("published connection to mqtt");
+                                delivered = Ok(());
+                                break;
+                            },
+                            Err(err) => {
+                                error!("{}", err);
+                                delivered = Err(err);
+                            },
+                        }
+                    }
+
+                    stream = None;
+                }
+
+                rx.delivered(1, &delivered);
+            }
+        });
+
+        Ok(Mqtt {
+            worker: Worker::new(tx, handle),
+            serialization,
+        })
+    }
+
+    fn send(&self, message: Vec<u8>) -> Result<(), String> {
+        match self.worker.send(message) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(String::from("mqtt thread has stopped")),
+        }
+    }
+}
+
+impl Output for Mqtt {
+    fn process_open_connection(&self, message: &str) -> Result<(), String> {
+        self.send(self.serialization.encode(message)?)
+    }
+
+    fn process_close_connection(&self, message: &str) -> Result<(), String> {
+        self.send(self.serialization.encode(message)?)
+    }
+
+    fn serialization(&self) -> Serialization {
+        self.serialization
+    }
+
+    fn process_encoded(&self, _ : Route, _ : &str, encoded: &[u8]) -> Result<(), String> {
+        self.send(encoded.to_vec())
+    }
+
+    fn process_alive_connections(&self, _ : &Vec<i64>) { }
+
+    fn queue_latency(&self) -> Option<Arc<LatencyHistogram>> {
+        Some(self.worker.latency())
+    }
+
+    fn confirm(&self) -> Result<(), String> {
+        self.worker.confirm(FLUSH_TIMEOUT)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        match self.worker.flush(FLUSH_TIMEOUT) {
+            true => Ok(()),
+            false => Err(String::from("timed out flushing the mqtt output")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+    use std::sync::mpsc::channel;
+    use std::net::TcpListener;
+    use mqtt::packet::{ ConnackPacket, PubackPacket };
+    use test_support::identity;
+
+    #[test]
+    fn test_config() {
+        let config : MqttConfig = serde_yaml::from_str("host: broker.local").unwrap();
+        assert_eq!("broker.local", config.host);
+        assert_eq!(1883, config.port);
+        assert!(!config.tls);
+        assert_eq!(0, config.qos);
+        assert_eq!("zerotrust/{uuid}/connections", config.topic);
+        assert_eq!(Serialization::Json, config.serialization);
+
+        let config : MqttConfig = serde_yaml::from_str("{ host: broker.local, serialization: msgpack }").unwrap();
+        assert_eq!(Serialization::Msgpack, config.serialization);
+    }
+
+    #[test]
+    fn test_topic() {
+        let identity = identity();
+        assert_eq!(format!("zerotrust/{}/gerald", identity.uuid), topic("zerotrust/{uuid}/{name}", &identity));
+    }
+
+    #[test]
+    fn test_unreachable_broker() {
+        let config : MqttConfig = serde_yaml::from_str("{ host: 127.0.0.1, port: 1, qos: 1 }").unwrap();
+        let mqtt = Mqtt::new(&config, &identity()).unwrap();
+        assert!(mqtt.process_open_connection("{}").is_ok());
+        assert!(mqtt.process_close_connection("{}").is_ok());
+    }
+
+    #[test]
+    fn test_invalid_qos() {
+        let config : MqttConfig = serde_yaml::from_str("{ host: 127.0.0.1, qos: 2 }").unwrap();
+        assert!(Mqtt::new(&config, &identity()).is_err());
+    }
+
+    #[test]
+    fn test_publish() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (published_tx, published_rx) = channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            if let Ok(VariablePacket::ConnectPacket(_)) = VariablePacket::decode(&mut stream) {
+                ConnackPacket::new(false, ConnectReturnCode::ConnectionAccepted).encode(&mut stream).unwrap();
+            }
+
+            if let Ok(VariablePacket::PublishPacket(packet)) = VariablePacket::decode(&mut stream) {
+                if let QoSWithPacketIdentifier::Level1(id) = packet.qos() {
+                    PubackPacket::new(id).encode(&mut stream).unwrap();
+                }
+                published_tx.send((packet.topic_name().to_string(), packet.payload().to_vec())).unwrap();
+            }
+        });
+
+        let config : MqttConfig = serde_yaml::from_str(&format!("{{ host: 127.0.0.1, port: {}, qos: 1, topic: \"zerotrust/{{name}}\" }}", port)).unwrap();
+        let mqtt = Mqtt::new(&config, &identity()).unwrap();
+        mqtt.process_open_connection("{\"hash\":1}").unwrap();
+
+        let (topic, payload) = published_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!("zerotrust/gerald", topic);
+        assert_eq!(b"{\"hash\":1}".to_vec(), payload);
+    }
+}
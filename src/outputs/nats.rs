@@ -0,0 +1,347 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::sync::Arc;
+use std::thread;
+use std::io::{ BufRead, BufReader, Write };
+use std::net::TcpStream;
+use std::time::Duration;
+use outputs::{ Output, Route, Serialization, FLUSH_TIMEOUT };
+use outputs::worker::{ queue, Worker };
+use metrics::{ LatencyHistogram };
+use enums::{ AgentIdentity };
+use serde_json;
+
+fn default_subject() -> String {
+    String::from("zerotrust.{uuid}.connections")
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NatsConfig {
+    // For instance nats://127.0.0.1:4222
+    pub url : String,
+    // {uuid} and {name} are replaced with the details of this agent.
+    #[serde(default = "default_subject")]
+    pub subject : String,
+    #[serde(default)]
+    pub username : Option<String>,
+    #[serde(default)]
+    pub password : Option<String>,
+    #[serde(default)]
+    pub token : Option<String>,
+    #[serde(default)]
+    pub serialization : Serialization,
+}
+
+#[derive(Debug, Serialize)]
+struct ConnectMessage {
+    verbose: bool,
+    pedantic: bool,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pass: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth_token: Option<String>,
+}
+
+pub struct Nats {
+    worker : Worker<Vec<u8>>,
+    serialization : Serialization,
+}
+
+fn subject(template: &str, identity: &AgentIdentity) -> String {
+    template.replace("{uuid}", &identity.uuid.to_string()).replace("{name}", &identity.name)
+}
+
+fn address(url: &str) -> String {
+    let address = url.trim_start_matches("nats://");
+    match address.contains(':') {
+        true => String::from(address),
+        false => format!("{}:4222", address),
+    }
+}
+
+fn read_line(reader: &mut BufReader<TcpStream>) -> Result<String, String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => Err(String::from("nats server closed the connection")),
+        Ok(_) => Ok(line.trim_end().to_string()),
+        Err(err) => Err(format!("unable to read from nats server: {}", err)),
+    }
+}
+
+fn connect(config: &NatsConfig) -> Result<BufReader<TcpStream>, String> {
+    let address = address(&config.url);
+    let stream = match TcpStream::connect(&address) {
+        Ok(x) => x,
+        Err(err) => return Err(format!("unable to connect to nats server {}: {}", address, err)),
+    };
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(10)));
+    let mut reader = BufReader::new(stream);
+
+    let info = read_line(&mut reader)?;
+    if !info.starts_with("INFO") {
+        return Err(format!("unexpected greeting from nats server: {}", info));
+    }
+
+    let connect = ConnectMessage {
+        verbose: false,
+        pedantic: false,
+        name: String::from("zerotrust-track"),
+        user: config.username.clone(),
+        pass: config.password.clone(),
+        auth_token: config.token.clone(),
+    };
+
+    let connect = match serde_json::to_string(&connect) {
+        Ok(x) => x,
+        Err(_err) => return Err(String::from("unable to serialize the nats connect message")),
+    };
+
+    // The PONG tells us the server accepted the CONNECT.
+    if let Err(err) = write!(reader.get_mut(), "CONNECT {}\r\nPING\r\n", connect) {
+        return Err(format!("unable to send connect to nats server: {}", err));
+    }
+
+    match read_line(&mut reader)? {
+        ref x if x == "PONG" => Ok(reader),
+        x => Err(format!("nats server refused connection: {}", x)),
+    }
+}
+
+fn publish(reader: &mut BufReader<TcpStream>, subject: &str, message: &[u8]) -> Result<(), String> {
+    let mut buffer = format!("PUB {} {}\r\n", subject, message.len()).into_bytes();
+    buffer.extend_from_slice(message);
+    buffer.extend_from_slice(b"\r\n");
+
+    match reader.get_mut().write_all(&buffer) {
+        Ok(()) => Ok(()),
+        Err(err) => Err(format!("unable to publish to nats server: {}", err)),
+    }
+}
+
+// The server pings idle clients and drops those which don't answer, so check
+// for anything it sent before publishing.
+fn answer_pings(reader: &mut BufReader<TcpStream>) -> Result<(), String> {
+    let _ = reader.get_ref().set_nonblocking(true);
+    let mut result = Ok(());
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => {
+                result = Err(String::from("nats server closed the connection"));
+                break;
+            },
+            Ok(_) => {
+                if line.starts_with("PING") {
+                    if let Err(err) = reader.get_mut().write_all(b"PONG\r\n") {
+                        result = Err(format!("unable to answer nats ping: {}", err));
+                        break;
+                    }
+                } else if line.starts_with("-ERR") {
+                    warn!("nats server error: {}", line.trim_end());
+                }
+            },
+            Err(_) => break,
+        }
+    }
+    let _ = reader.get_ref().set_nonblocking(false);
+    result
+}
+
+impl Nats {
+    pub fn new(config: &NatsConfig, identity: &AgentIdentity) -> Result<Nats, String> {
+        let subject = subject(&config.subject, identity);
+        if subject.is_empty() || subject.contains(char::is_whitespace) {
+            return Err(format!("invalid nats subject: {}", subject));
+        }
+
+        let serialization = config.serialization;
+        let config = config.clone();
+        let (tx, rx) = queue::<Vec<u8>>();
+
+        let handle = thread::spawn(move || {
+            let mut connection : Option<BufReader<TcpStream>> = None;
+
+            loop {
+                let message = match rx.recv() {
+                    Ok(x) => x,
+                    Err(err) => {
+                        error!("closing thread: {}", err);
+                        break;
+                    }
+                };
+
+                let mut delivered = Ok(());
+
+                // Reconnect and have a second go when the connection has gone away.
+                for _ in 0..2 {
+                    if connection.is_none() {
+                        match connect(&config) {
+                            Ok(x) => connection = Some(x),
+                            Err(err) => {
+                                error!("{}", err);
+                                delivered = Err(err);
+                                break;
+                            }
+                        }
+                    }
+
+                    if let Some(ref mut reader) = connection {
+                        let result = answer_pings(reader)
+                            .and_then(|_| publish(reader, &subject, &message));
+
+                        match result {
+                            Ok(()) => {
+                                sampled_debug!("published connection to nats");
+                                delivered = Ok(());
+                                break;
+                            },
+                            Err(err) => {
+                                error!("{}", err);
+                                delivered = Err(err);
+                            },
+                        }
+                    }
+
+                    connection = None;
+                }
+
+                rx.delivered(1, &delivered);
+            }
+        });
+
+        Ok(Nats {
+            worker: Worker::new(tx, handle),
+            serialization,
+        })
+    }
+
+    fn send(&self, message: Vec<u8>) -> Result<(), String> {
+        match self.worker.send(message) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(String::from("nats thread has stopped")),
+        }
+    }
+}
+
+impl Output for Nats {
+    fn process_open_connection(&self, message: &str) -> Result<(), String> {
+        self.send(self.serialization.encode(message)?)
+    }
+
+    fn process_close_connection(&self, message: &str) -> Result<(), String> {
+        self.send(self.serialization.encode(message)?)
+    }
+
+    fn serialization(&self) -> Serialization {
+        self.serialization
+    }
+
+    fn process_encoded(&self, _ : Route, _ : &str, encoded: &[u8]) -> Result<(), String> {
+        self.send(encoded.to_vec())
+    }
+
+    fn process_alive_connections(&self, _ : &Vec<i64>) { }
+
+    fn queue_latency(&self) -> Option<Arc<LatencyHistogram>> {
+        Some(self.worker.latency())
+    }
+
+    fn confirm(&self) -> Result<(), String> {
+        self.worker.confirm(FLUSH_TIMEOUT)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        match self.worker.flush(FLUSH_TIMEOUT) {
+            true => Ok(()),
+            false => Err(String::from("timed out flushing the nats output")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+    use std::sync::mpsc::channel;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use test_support::identity;
+
+    #[test]
+    fn test_config() {
+        let config : NatsConfig = serde_yaml::from_str("url: nats://127.0.0.1:4222").unwrap();
+        assert_eq!("nats://127.0.0.1:4222", config.url);
+        assert_eq!("zerotrust.{uuid}.connections", config.subject);
+        assert!(config.token.is_none());
+    }
+
+    #[test]
+    fn test_address() {
+        assert_eq!("127.0.0.1:4222", address("nats://127.0.0.1"));
+        assert_eq!("nats.local:4333", address("nats.local:4333"));
+    }
+
+    #[test]
+    fn test_unreachable_server() {
+        let config : NatsConfig = serde_yaml::from_str("url: nats://127.0.0.1:1").unwrap();
+        let nats = Nats::new(&config, &identity()).unwrap();
+        assert!(nats.process_open_connection("{}").is_ok());
+    }
+
+    #[test]
+    fn test_invalid_subject() {
+        let config : NatsConfig = serde_yaml::from_str("{ url: nats://127.0.0.1, subject: \"zerotrust {name}\" }").unwrap();
+        assert!(Nats::new(&config, &identity()).is_err());
+    }
+
+    #[test]
+    fn test_publish() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("nats://{}", listener.local_addr().unwrap());
+        let (published_tx, published_rx) = channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"INFO {}\r\n").unwrap();
+
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            stream.write_all(b"PONG\r\n").unwrap();
+
+            let mut published = String::new();
+            reader.read_line(&mut published).unwrap();
+            let length : usize = published.trim_end().rsplit(' ').next().unwrap().parse().unwrap();
+            let mut payload = vec![0; length];
+            reader.read_exact(&mut payload).unwrap();
+            published_tx.send((published, payload)).unwrap();
+        });
+
+        let config : NatsConfig = serde_yaml::from_str(&format!("{{ url: \"{}\", subject: \"zerotrust.{{name}}\" }}", url)).unwrap();
+        let nats = Nats::new(&config, &identity()).unwrap();
+        nats.process_close_connection("{\"hash\":1}").unwrap();
+
+        let (published, payload) = published_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!("PUB zerotrust.gerald 10\r\n", published);
+        assert_eq!(b"{\"hash\":1}".to_vec(), payload);
+    }
+}
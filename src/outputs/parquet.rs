@@ -0,0 +1,483 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::fs;
+use std::fs::File;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::{ Duration, Instant };
+use chrono::prelude::*;
+use outputs::{ Output, FLUSH_TIMEOUT };
+use outputs::worker::{ queue, QueueReceiver, Worker };
+use metrics::{ LatencyHistogram };
+use parquet::column::writer::ColumnWriter;
+use parquet::data_type::ByteArray;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use serde_json;
+use serde_json::Value;
+
+fn default_rows_per_file() -> usize {
+    10000
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ParquetConfig {
+    pub directory : String,
+    #[serde(default = "default_rows_per_file")]
+    pub rows_per_file : usize,
+    // A file is also written once it has been open this long, 0 turns it off.
+    #[serde(default)]
+    pub seconds_per_file : u64,
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Int32,
+    Int64,
+    Utf8,
+}
+
+// A flat column, the path is where its value lives in the payload.
+struct Column {
+    name: &'static str,
+    kind: Kind,
+    required: bool,
+    path: &'static [&'static str],
+}
+
+const COLUMNS: &[Column] = &[
+    Column { name: "event_type", kind: Kind::Utf8, required: true, path: &["event_type"] },
+    Column { name: "hash", kind: Kind::Int64, required: true, path: &["hash"] },
+    Column { name: "schema_version", kind: Kind::Int32, required: true, path: &["schema_version"] },
+    Column { name: "seq", kind: Kind::Int64, required: false, path: &["seq"] },
+    Column { name: "uuid", kind: Kind::Utf8, required: false, path: &["uuid"] },
+    Column { name: "agent", kind: Kind::Utf8, required: true, path: &["agent"] },
+    Column { name: "timestamp", kind: Kind::Utf8, required: true, path: &["timestamp"] },
+    Column { name: "protocol", kind: Kind::Utf8, required: true, path: &["protocol"] },
+    Column { name: "source", kind: Kind::Utf8, required: true, path: &["source"] },
+    Column { name: "source_port", kind: Kind::Int32, required: true, path: &["source_port"] },
+    Column { name: "destination", kind: Kind::Utf8, required: true, path: &["destination"] },
+    Column { name: "destination_port", kind: Kind::Int32, required: true, path: &["destination_port"] },
+    Column { name: "direction", kind: Kind::Utf8, required: false, path: &["direction"] },
+    Column { name: "local_address", kind: Kind::Utf8, required: false, path: &["local_address"] },
+    Column { name: "local_port", kind: Kind::Int32, required: false, path: &["local_port"] },
+    Column { name: "remote_address", kind: Kind::Utf8, required: false, path: &["remote_address"] },
+    Column { name: "remote_port", kind: Kind::Int32, required: false, path: &["remote_port"] },
+    Column { name: "username", kind: Kind::Utf8, required: false, path: &["username"] },
+    Column { name: "uid", kind: Kind::Int64, required: false, path: &["uid"] },
+    Column { name: "pid", kind: Kind::Int32, required: false, path: &["program_details", "pid"] },
+    Column { name: "process_name", kind: Kind::Utf8, required: false, path: &["program_details", "process_name"] },
+    Column { name: "command_line", kind: Kind::Utf8, required: false, path: &["program_details", "command_line"] },
+    Column { name: "interface", kind: Kind::Utf8, required: false, path: &["interface"] },
+    Column { name: "destination_service", kind: Kind::Utf8, required: false, path: &["destination_service"] },
+    Column { name: "duration_ms", kind: Kind::Int64, required: false, path: &["duration_ms"] },
+    Column { name: "close_reason", kind: Kind::Utf8, required: false, path: &["close_reason"] },
+    Column { name: "update_count", kind: Kind::Int64, required: false, path: &["update_count"] },
+];
+
+pub struct Parquet {
+    worker : Worker<Message>,
+}
+
+enum Message {
+    Row(Value),
+    // Writes out the rows so far without waiting for the file to fill up.
+    Write,
+}
+
+fn schema() -> String {
+    let fields : Vec<String> = COLUMNS.iter().map(|column| {
+        let repetition = if column.required { "REQUIRED" } else { "OPTIONAL" };
+        match column.kind {
+            Kind::Int32 => format!("{} INT32 {};", repetition, column.name),
+            Kind::Int64 => format!("{} INT64 {};", repetition, column.name),
+            Kind::Utf8 => format!("{} BYTE_ARRAY {} (UTF8);", repetition, column.name),
+        }
+    }).collect();
+
+    format!("message connection {{ {} }}", fields.join(" "))
+}
+
+fn lookup<'a>(row: &'a Value, path: &[&str]) -> Option<&'a Value> {
+    let mut value = row;
+    for key in path {
+        value = value.get(key)?;
+    }
+
+    match value {
+        Value::Null => None,
+        value => Some(value),
+    }
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(x) => x.clone(),
+        // The command line is the only list, it is joined back up.
+        Value::Array(items) => items.iter().map(text).collect::<Vec<String>>().join(" "),
+        value => value.to_string(),
+    }
+}
+
+// Required columns are written as given, optional ones need a definition level
+// per row and only the values which are present.
+fn write_column(writer: &mut ColumnWriter, column: &Column, rows: &[Value]) -> Result<(), String> {
+    let values : Vec<Option<&Value>> = rows.iter().map(|row| lookup(row, column.path)).collect();
+    let levels : Vec<i16> = values.iter().map(|value| if value.is_some() { 1 } else { 0 }).collect();
+    let levels = if column.required { None } else { Some(levels.as_slice()) };
+    let present = |value: &Option<&Value>| column.required || value.is_some();
+
+    let result = match (writer, column.kind) {
+        (ColumnWriter::Int32ColumnWriter(ref mut writer), Kind::Int32) => {
+            let data : Vec<i32> = values.iter().filter(|x| present(x))
+                .map(|value| value.and_then(|x| x.as_i64()).unwrap_or(0) as i32).collect();
+            writer.write_batch(&data, levels, None)
+        },
+        (ColumnWriter::Int64ColumnWriter(ref mut writer), Kind::Int64) => {
+            let data : Vec<i64> = values.iter().filter(|x| present(x))
+                .map(|value| value.and_then(|x| x.as_i64()).unwrap_or(0)).collect();
+            writer.write_batch(&data, levels, None)
+        },
+        (ColumnWriter::ByteArrayColumnWriter(ref mut writer), Kind::Utf8) => {
+            let data : Vec<ByteArray> = values.iter().filter(|x| present(x))
+                .map(|value| ByteArray::from(value.map(text).unwrap_or_default().into_bytes())).collect();
+            writer.write_batch(&data, levels, None)
+        },
+        _ => return Err(format!("unexpected parquet column type for {}", column.name)),
+    };
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("unable to write parquet column {}: {}", column.name, err)),
+    }
+}
+
+fn write_file(path: &Path, rows: &[Value]) -> Result<(), String> {
+    let schema = match parse_message_type(&schema()) {
+        Ok(x) => Arc::new(x),
+        Err(err) => return Err(format!("invalid parquet schema: {}", err)),
+    };
+
+    let file = match File::create(path) {
+        Ok(x) => x,
+        Err(err) => return Err(format!("unable to create parquet file: {}", err)),
+    };
+
+    let properties = Arc::new(WriterProperties::builder().build());
+    let mut writer = match SerializedFileWriter::new(file, schema, properties) {
+        Ok(x) => x,
+        Err(err) => return Err(format!("unable to start parquet file: {}", err)),
+    };
+
+    let mut row_group = match writer.next_row_group() {
+        Ok(x) => x,
+        Err(err) => return Err(format!("unable to start parquet row group: {}", err)),
+    };
+
+    let mut columns = COLUMNS.iter();
+    loop {
+        let mut column_writer = match row_group.next_column() {
+            Ok(Some(x)) => x,
+            Ok(None) => break,
+            Err(err) => return Err(format!("unable to start parquet column: {}", err)),
+        };
+
+        let column = match columns.next() {
+            Some(x) => x,
+            None => return Err(String::from("parquet schema has more columns than expected")),
+        };
+
+        write_column(column_writer.untyped(), column, rows)?;
+        if let Err(err) = column_writer.close() {
+            return Err(format!("unable to finish parquet column: {}", err));
+        }
+    }
+
+    if let Err(err) = row_group.close() {
+        return Err(format!("unable to finish parquet row group: {}", err));
+    }
+
+    match writer.close() {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("unable to finish parquet file: {}", err)),
+    }
+}
+
+// Files are written under a temporary name and renamed once complete, so
+// anything reading the directory never sees half a file.
+fn flush(directory: &str, count: &mut usize, rows: &mut Vec<Value>, rx: &QueueReceiver<Message>) {
+    if rows.is_empty() {
+        return;
+    }
+
+    let name = format!("zerotrust-{}-{}.parquet", Utc::now().format("%Y%m%dT%H%M%S"), count);
+    let path : PathBuf = Path::new(directory).join(&name);
+    let partial : PathBuf = Path::new(directory).join(format!(".{}.partial", name));
+    *count += 1;
+
+    let result = write_file(&partial, rows).and_then(|()| {
+        fs::rename(&partial, &path).map_err(|err| format!("unable to rename parquet file: {}", err))
+    });
+
+    match result {
+        Ok(()) => sampled_debug!("wrote {} rows to {:?}", rows.len(), path),
+        Err(ref err) => {
+            error!("{}", err);
+            let _ = fs::remove_file(&partial);
+        }
+    }
+
+    rx.delivered(rows.len(), &result);
+    rows.clear();
+}
+
+fn run(config: ParquetConfig, rx: QueueReceiver<Message>) {
+    let window = Duration::from_secs(config.seconds_per_file);
+    let mut rows : Vec<Value> = Vec::new();
+    let mut count = 0;
+    let mut started = Instant::now();
+
+    loop {
+        match rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(Message::Row(row)) => {
+                if rows.is_empty() {
+                    started = Instant::now();
+                }
+                rows.push(row);
+            },
+            Ok(Message::Write) => {
+                flush(&config.directory, &mut count, &mut rows, &rx);
+                rx.delivered(1, &Ok(()));
+            },
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => {
+                flush(&config.directory, &mut count, &mut rows, &rx);
+                debug!("closing parquet thread");
+                break;
+            },
+        }
+
+        let expired = config.seconds_per_file > 0 && started.elapsed() >= window;
+        if rows.len() >= config.rows_per_file || (expired && !rows.is_empty()) {
+            flush(&config.directory, &mut count, &mut rows, &rx);
+        }
+    }
+}
+
+impl Parquet {
+    pub fn new(config: &ParquetConfig) -> Result<Parquet, String> {
+        if !Path::new(&config.directory).is_dir() {
+            return Err(format!("parquet directory {} does not exist", config.directory));
+        }
+
+        // The schema is fixed, this just makes sure it is valid up front.
+        if let Err(err) = parse_message_type(&schema()) {
+            return Err(format!("invalid parquet schema: {}", err));
+        }
+
+        let config = config.clone();
+        let (tx, rx) = queue();
+        let handle = thread::spawn(move || run(config, rx));
+
+        Ok(Parquet {
+            worker: Worker::new(tx, handle),
+        })
+    }
+
+    fn send(&self, message: &str) -> Result<(), String> {
+        let row : Value = match serde_json::from_str(message) {
+            Ok(x) => x,
+            Err(err) => return Err(format!("unable to parse payload for parquet: {}", err)),
+        };
+
+        match self.worker.send(Message::Row(row)) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(String::from("parquet thread has stopped")),
+        }
+    }
+}
+
+// Writes out the last partial file even if the output wasn't flushed.
+impl Drop for Parquet {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl Output for Parquet {
+    fn process_open_connection(&self, message: &str) -> Result<(), String> {
+        self.send(message)
+    }
+
+    fn process_close_connection(&self, message: &str) -> Result<(), String> {
+        self.send(message)
+    }
+
+    fn process_alive_connections(&self, _ : &Vec<i64>) { }
+
+    // The file only has columns for connections.
+    fn process_event(&self, _ : &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn queue_latency(&self) -> Option<Arc<LatencyHistogram>> {
+        Some(self.worker.latency())
+    }
+
+    // The rows are only kept once they are in a file, so whatever is waiting
+    // is written out early.
+    fn confirm(&self) -> Result<(), String> {
+        if self.worker.send(Message::Write).is_err() {
+            return Err(String::from("parquet thread has stopped"));
+        }
+
+        self.worker.confirm(FLUSH_TIMEOUT)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        match self.worker.flush(FLUSH_TIMEOUT) {
+            true => Ok(()),
+            false => Err(String::from("timed out flushing the parquet output")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+    use tempfile::{ tempdir };
+    use parquet::file::reader::{ FileReader, SerializedFileReader };
+    use parser::{ Payload, OpenConnection, CloseConnection, Program };
+    use enums::{ CloseReason, Direction, StatusFlag };
+    use test_support::{ default_open_payload, default_close_payload, program };
+
+    // Made from the payloads the outputs are really given, so the rows follow
+    // them when their fields change.
+    fn open() -> String {
+        let ssh = Program {
+            command_line: vec!(String::from("ssh"), String::from("localhost")),
+            .. program("ssh")
+        };
+
+        let payload = match default_open_payload(40000, 22, Some(ssh)) {
+            Payload::Open(connection) => Payload::Open(OpenConnection {
+                seq: 1,
+                direction: Some(Direction::Outbound),
+                local_address: Some(connection.source),
+                local_port: Some(40000),
+                remote_address: Some(connection.destination),
+                remote_port: Some(22),
+                interface: Some(String::from("lo")),
+                .. connection
+            }),
+            _ => panic!("expected an open"),
+        };
+        serde_json::to_string(&payload).unwrap()
+    }
+
+    fn close() -> String {
+        let payload = match default_close_payload() {
+            Payload::Close(connection) => Payload::Close(CloseConnection {
+                seq: 2,
+                duration_ms: Some(1000),
+                close_reason: Some(CloseReason::Assured),
+                status_flags: vec!(StatusFlag::SeenReply, StatusFlag::Assured, StatusFlag::Confirmed),
+                update_count: 2,
+                .. connection
+            }),
+            _ => panic!("expected a close"),
+        };
+        serde_json::to_string(&payload).unwrap()
+    }
+
+    fn files(directory: &Path) -> Vec<PathBuf> {
+        let mut files : Vec<PathBuf> = fs::read_dir(directory).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_config() {
+        let config : ParquetConfig = serde_yaml::from_str("directory: /tmp").unwrap();
+        assert_eq!(10000, config.rows_per_file);
+        assert_eq!(0, config.seconds_per_file);
+    }
+
+    #[test]
+    fn test_missing_directory() {
+        let config : ParquetConfig = serde_yaml::from_str("directory: /I_like_strange_things").unwrap();
+        assert!(Parquet::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_text() {
+        let value : Value = serde_json::from_str("[\"ssh\", \"-p\", \"22\"]").unwrap();
+        assert_eq!("ssh -p 22", text(&value));
+        assert_eq!("7", text(&Value::from(7)));
+    }
+
+    #[test]
+    fn test_writes_files() {
+        let directory = tempdir().unwrap();
+        let config = ParquetConfig {
+            directory: directory.path().to_str().unwrap().to_string(),
+            rows_per_file: 4,
+            seconds_per_file: 0,
+        };
+
+        let parquet = Parquet::new(&config).unwrap();
+        for _ in 0..3 {
+            parquet.process_open_connection(&open()).unwrap();
+            parquet.process_close_connection(&close()).unwrap();
+        }
+        assert!(parquet.process_open_connection("I am not json").is_err());
+        drop(parquet);
+
+        // A full file of four rows and the partial one written on shutdown.
+        let files = files(directory.path());
+        assert_eq!(2, files.len());
+
+        let mut total = 0;
+        for file in &files {
+            let reader = SerializedFileReader::new(File::open(file).unwrap()).unwrap();
+            let metadata = reader.metadata().file_metadata();
+            let names : Vec<&str> = metadata.schema_descr().columns().iter().map(|column| column.name()).collect();
+            let expected : Vec<&str> = COLUMNS.iter().map(|column| column.name).collect();
+
+            assert_eq!(expected, names);
+            total += metadata.num_rows();
+        }
+        assert_eq!(6, total);
+
+        let reader = SerializedFileReader::new(File::open(&files[0]).unwrap()).unwrap();
+        let rows : Vec<String> = reader.get_row_iter(None).unwrap().map(|row| row.to_string()).collect();
+        assert_eq!(4, rows.len());
+        assert!(rows[0].contains("command_line: \"ssh localhost\""), "{}", rows[0]);
+        assert!(rows[0].contains("direction: \"outbound\""), "{}", rows[0]);
+        assert!(rows[0].contains("remote_port: 22"), "{}", rows[0]);
+        assert!(rows[1].contains("uuid: null"), "{}", rows[1]);
+        assert!(rows[1].contains("duration_ms: 1000"), "{}", rows[1]);
+        assert!(rows[1].contains("close_reason: \"assured\""), "{}", rows[1]);
+        assert!(rows[1].contains("update_count: 2"), "{}", rows[1]);
+    }
+}
@@ -0,0 +1,308 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::sync::Arc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::Duration;
+use outputs::{ Output, FLUSH_TIMEOUT };
+use outputs::worker::{ queue, QueueReceiver, Worker, Linger };
+use metrics::{ LatencyHistogram };
+use postgres::{ Client, NoTls };
+use serde_json;
+use serde_json::Value;
+
+fn default_open_table() -> String {
+    String::from("zerotrust_open_connections")
+}
+
+fn default_close_table() -> String {
+    String::from("zerotrust_close_connections")
+}
+
+fn default_batch_size() -> usize {
+    100
+}
+
+fn default_linger_ms() -> u64 {
+    2000
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PostgresConfig {
+    // For instance "host=localhost user=zerotrust dbname=zerotrust"
+    pub connection : String,
+    #[serde(default = "default_open_table")]
+    pub open_table : String,
+    #[serde(default = "default_close_table")]
+    pub close_table : String,
+    #[serde(default = "default_batch_size")]
+    pub batch_size : usize,
+    // The longest a partial batch is held before it is sent anyway.
+    #[serde(default = "default_linger_ms")]
+    pub linger_ms : u64,
+}
+
+enum MessageType {
+    Open(String),
+    Close(String),
+}
+
+// A row waiting to be inserted, the uuid ties the open to its close.
+struct Row {
+    table: String,
+    uuid: Option<String>,
+    payload: Value,
+}
+
+pub struct Postgres {
+    worker : Worker<MessageType>,
+}
+
+// Table names end up in the SQL, so only allow plain identifiers.
+fn valid_table(table: &str) -> bool {
+    !table.is_empty() && table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn create_sql(table: &str) -> String {
+    format!("CREATE TABLE IF NOT EXISTS {} (id BIGSERIAL PRIMARY KEY, uuid TEXT, payload JSONB NOT NULL)", table)
+}
+
+fn insert_sql(table: &str) -> String {
+    format!("INSERT INTO {} (uuid, payload) VALUES ($1, $2)", table)
+}
+
+fn row(table: &str, message: &str) -> Result<Row, String> {
+    let payload : Value = match serde_json::from_str(message) {
+        Ok(x) => x,
+        Err(err) => return Err(format!("unable to parse payload for postgres: {}", err)),
+    };
+
+    let uuid = payload["uuid"].as_str().map(String::from);
+    Ok(Row {
+        table: String::from(table),
+        uuid,
+        payload,
+    })
+}
+
+fn connect(config: &PostgresConfig) -> Result<Client, String> {
+    let mut client = match Client::connect(&config.connection, NoTls) {
+        Ok(x) => x,
+        Err(err) => return Err(format!("unable to connect to postgres: {}", err)),
+    };
+
+    for table in &[&config.open_table, &config.close_table] {
+        if let Err(err) = client.batch_execute(&create_sql(table)) {
+            return Err(format!("unable to create postgres table {}: {}", table, err));
+        }
+    }
+
+    Ok(client)
+}
+
+fn insert(client: &mut Client, rows: &[Row]) -> Result<(), String> {
+    let mut transaction = match client.transaction() {
+        Ok(x) => x,
+        Err(err) => return Err(format!("unable to start postgres transaction: {}", err)),
+    };
+
+    for row in rows {
+        if let Err(err) = transaction.execute(insert_sql(&row.table).as_str(), &[&row.uuid, &row.payload]) {
+            return Err(format!("unable to insert into postgres: {}", err));
+        }
+    }
+
+    match transaction.commit() {
+        Ok(()) => Ok(()),
+        Err(err) => Err(format!("unable to commit to postgres: {}", err)),
+    }
+}
+
+fn flush(config: &PostgresConfig, client: &mut Option<Client>, rows: &mut Vec<Row>, rx: &QueueReceiver<MessageType>) {
+    if rows.is_empty() {
+        return;
+    }
+
+    if client.is_none() {
+        match connect(config) {
+            Ok(x) => *client = Some(x),
+            Err(err) => {
+                error!("{}", err);
+                rx.delivered(rows.len(), &Err(err));
+            },
+        }
+    }
+
+    // The batch is dropped when postgres can't be reached, rather than
+    // building up without bound.
+    if let Some(ref mut connection) = client {
+        let result = insert(connection, rows);
+        match result {
+            Ok(()) => sampled_debug!("inserted {} rows into postgres", rows.len()),
+            Err(ref err) => {
+                error!("{}", err);
+                *client = None;
+            }
+        }
+        rx.delivered(rows.len(), &result);
+    }
+
+    rows.clear();
+}
+
+fn run(config: PostgresConfig, rx: QueueReceiver<MessageType>) {
+    let mut client : Option<Client> = None;
+    let mut rows : Vec<Row> = Vec::new();
+    let mut linger = Linger::new(Duration::from_millis(config.linger_ms));
+
+    loop {
+        let message = match linger.recv(&rx) {
+            Ok(x) => x,
+            Err(RecvTimeoutError::Timeout) => {
+                flush(&config, &mut client, &mut rows, &rx);
+                linger.reset();
+                continue;
+            },
+            Err(err) => {
+                flush(&config, &mut client, &mut rows, &rx);
+                error!("closing thread: {}", err);
+                break;
+            }
+        };
+
+        let result = match message {
+            MessageType::Open(message) => row(&config.open_table, &message),
+            MessageType::Close(message) => row(&config.close_table, &message),
+        };
+
+        match result {
+            Ok(x) => rows.push(x),
+            Err(err) => {
+                error!("{}", err);
+                rx.delivered(1, &Err(err));
+            },
+        }
+
+        if rows.len() >= config.batch_size || linger.expired() {
+            flush(&config, &mut client, &mut rows, &rx);
+            linger.reset();
+        }
+    }
+}
+
+impl Postgres {
+    pub fn new(config: &PostgresConfig) -> Result<Postgres, String> {
+        for table in &[&config.open_table, &config.close_table] {
+            if !valid_table(table) {
+                return Err(format!("invalid postgres table name: {}", table));
+            }
+        }
+
+        let config = config.clone();
+        let (tx, rx) = queue();
+        let handle = thread::spawn(move || run(config, rx));
+
+        Ok(Postgres {
+            worker: Worker::new(tx, handle),
+        })
+    }
+}
+
+impl Output for Postgres {
+    fn process_open_connection(&self, message: &str) -> Result<(), String> {
+        match self.worker.send(MessageType::Open(message.to_string())) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(String::from("postgres thread has stopped")),
+        }
+    }
+
+    fn process_close_connection(&self, message: &str) -> Result<(), String> {
+        match self.worker.send(MessageType::Close(message.to_string())) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(String::from("postgres thread has stopped")),
+        }
+    }
+
+    fn process_alive_connections(&self, _ : &Vec<i64>) { }
+
+    // The table only has columns for connections.
+    fn process_event(&self, _ : &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn queue_latency(&self) -> Option<Arc<LatencyHistogram>> {
+        Some(self.worker.latency())
+    }
+
+    fn confirm(&self) -> Result<(), String> {
+        self.worker.confirm(FLUSH_TIMEOUT)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        match self.worker.flush(FLUSH_TIMEOUT) {
+            true => Ok(()),
+            false => Err(String::from("timed out flushing the postgres output")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+
+    #[test]
+    fn test_config() {
+        let config : PostgresConfig = serde_yaml::from_str("connection: host=localhost").unwrap();
+        assert_eq!("zerotrust_open_connections", config.open_table);
+        assert_eq!("zerotrust_close_connections", config.close_table);
+        assert_eq!(100, config.batch_size);
+        assert_eq!(2000, config.linger_ms);
+    }
+
+    #[test]
+    fn test_sql() {
+        assert_eq!("INSERT INTO opens (uuid, payload) VALUES ($1, $2)", insert_sql("opens"));
+        assert_eq!("CREATE TABLE IF NOT EXISTS opens (id BIGSERIAL PRIMARY KEY, uuid TEXT, payload JSONB NOT NULL)", create_sql("opens"));
+    }
+
+    #[test]
+    fn test_row() {
+        let open = row("opens", "{\"uuid\":\"b2f0281d-da73-4116-8639-8a1c693511b0\",\"hash\":1}").unwrap();
+        assert_eq!("opens", open.table);
+        assert_eq!(Some(String::from("b2f0281d-da73-4116-8639-8a1c693511b0")), open.uuid);
+        assert_eq!(1, open.payload["hash"]);
+
+        let close = row("closes", "{\"uuid\":null,\"hash\":1}").unwrap();
+        assert!(close.uuid.is_none());
+
+        assert!(row("opens", "I am not json").is_err());
+    }
+
+    #[test]
+    fn test_invalid_table() {
+        let config : PostgresConfig = serde_yaml::from_str("{ connection: host=localhost, open_table: \"opens; DROP TABLE x\" }").unwrap();
+        assert!(Postgres::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_unreachable_server() {
+        let config : PostgresConfig = serde_yaml::from_str("{ connection: \"host=127.0.0.1 port=1 connect_timeout=1\", batch_size: 1 }").unwrap();
+        let postgres = Postgres::new(&config).unwrap();
+        assert!(postgres.process_open_connection("{\"uuid\":null}").is_ok());
+    }
+}
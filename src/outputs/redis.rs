@@ -0,0 +1,301 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::sync::Arc;
+use std::thread;
+use std::io::{ BufRead, BufReader, Read, Write };
+use std::net::TcpStream;
+use std::time::Duration;
+use outputs::{ Output, FLUSH_TIMEOUT };
+use outputs::worker::{ queue, Worker };
+use metrics::{ LatencyHistogram };
+
+fn default_key() -> String {
+    String::from("zerotrust")
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RedisMode {
+    // PUBLISH to a channel.
+    #[default]
+    Publish,
+    // XADD to a stream.
+    Stream,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RedisConfig {
+    // For instance redis://:password@127.0.0.1:6379/0
+    pub url : String,
+    // The channel or stream name.
+    #[serde(default = "default_key")]
+    pub key : String,
+    #[serde(default)]
+    pub mode : RedisMode,
+}
+
+// Where to connect to, pulled out of the url.
+#[derive(Debug, PartialEq)]
+struct Address {
+    host: String,
+    password: Option<String>,
+    database: Option<String>,
+}
+
+pub struct Redis {
+    worker : Worker<String>,
+}
+
+fn address(url: &str) -> Address {
+    let rest = url.trim_start_matches("redis://");
+    let (password, rest) = match rest.rfind('@') {
+        Some(at) => {
+            let password = rest[..at].trim_start_matches(':');
+            (Some(String::from(password)).filter(|x| !x.is_empty()), &rest[at + 1..])
+        },
+        None => (None, rest),
+    };
+
+    let (host, database) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], Some(String::from(&rest[slash + 1..])).filter(|x| !x.is_empty())),
+        None => (rest, None),
+    };
+
+    let host = match host.contains(':') {
+        true => String::from(host),
+        false => format!("{}:6379", host),
+    };
+
+    Address {
+        host,
+        password,
+        database,
+    }
+}
+
+// Encodes a command as a RESP array of bulk strings.
+fn command(args: &[&str]) -> Vec<u8> {
+    let mut buffer = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buffer.extend(format!("${}\r\n", arg.len()).into_bytes());
+        buffer.extend(arg.as_bytes());
+        buffer.extend(b"\r\n");
+    }
+
+    buffer
+}
+
+fn event(mode: &RedisMode, key: &str, message: &str) -> Vec<u8> {
+    match mode {
+        RedisMode::Publish => command(&["PUBLISH", key, message]),
+        RedisMode::Stream => command(&["XADD", key, "*", "payload", message]),
+    }
+}
+
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Result<(), String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => return Err(String::from("redis closed the connection")),
+        Ok(_) => (),
+        Err(err) => return Err(format!("unable to read from redis: {}", err)),
+    };
+
+    let line = line.trim_end();
+    match line.chars().next() {
+        Some('+') | Some(':') => Ok(()),
+        Some('-') => Err(format!("redis returned an error: {}", &line[1..])),
+        Some('$') => {
+            let length : i64 = line[1..].parse().unwrap_or(-1);
+            if length >= 0 {
+                let mut body = vec![0; length as usize + 2];
+                if let Err(err) = reader.read_exact(&mut body) {
+                    return Err(format!("unable to read from redis: {}", err));
+                }
+            }
+            Ok(())
+        },
+        _ => Err(format!("unexpected reply from redis: {}", line)),
+    }
+}
+
+fn send(reader: &mut BufReader<TcpStream>, buffer: &[u8]) -> Result<(), String> {
+    if let Err(err) = reader.get_mut().write_all(buffer) {
+        return Err(format!("unable to write to redis: {}", err));
+    }
+
+    read_reply(reader)
+}
+
+fn connect(address: &Address) -> Result<BufReader<TcpStream>, String> {
+    let stream = match TcpStream::connect(&address.host) {
+        Ok(x) => x,
+        Err(err) => return Err(format!("unable to connect to redis {}: {}", address.host, err)),
+    };
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(10)));
+    let mut reader = BufReader::new(stream);
+
+    if let Some(ref password) = address.password {
+        send(&mut reader, &command(&["AUTH", password]))?;
+    }
+
+    if let Some(ref database) = address.database {
+        send(&mut reader, &command(&["SELECT", database]))?;
+    }
+
+    Ok(reader)
+}
+
+impl Redis {
+    pub fn new(config: &RedisConfig) -> Result<Redis, String> {
+        if config.key.is_empty() {
+            return Err(String::from("redis key can't be empty"));
+        }
+
+        let address = address(&config.url);
+        let config = config.clone();
+        let (tx, rx) = queue::<String>();
+
+        let handle = thread::spawn(move || {
+            let mut connection : Option<BufReader<TcpStream>> = None;
+
+            loop {
+                let message = match rx.recv() {
+                    Ok(x) => x,
+                    Err(err) => {
+                        error!("closing thread: {}", err);
+                        break;
+                    }
+                };
+
+                let buffer = event(&config.mode, &config.key, &message);
+
+                let mut delivered = Ok(());
+
+                // Reconnect and have a second go when the connection has gone away.
+                for _ in 0..2 {
+                    if connection.is_none() {
+                        match connect(&address) {
+                            Ok(x) => connection = Some(x),
+                            Err(err) => {
+                                error!("{}", err);
+                                delivered = Err(err);
+                                break;
+                            }
+                        }
+                    }
+
+                    if let Some(ref mut reader) = connection {
+                        match send(reader, &buffer) {
+                            Ok(()) => {
+                                sampled_debug!("sent connection to redis");
+                                delivered = Ok(());
+                                break;
+                            },
+                            Err(err) => {
+                                error!("{}", err);
+                                delivered = Err(err);
+                            },
+                        }
+                    }
+
+                    connection = None;
+                }
+
+                rx.delivered(1, &delivered);
+            }
+        });
+
+        Ok(Redis {
+            worker: Worker::new(tx, handle),
+        })
+    }
+}
+
+impl Output for Redis {
+    fn process_open_connection(&self, message: &str) -> Result<(), String> {
+        match self.worker.send(message.to_string()) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(String::from("redis thread has stopped")),
+        }
+    }
+
+    fn process_close_connection(&self, message: &str) -> Result<(), String> {
+        match self.worker.send(message.to_string()) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(String::from("redis thread has stopped")),
+        }
+    }
+
+    fn process_alive_connections(&self, _ : &Vec<i64>) { }
+
+    fn queue_latency(&self) -> Option<Arc<LatencyHistogram>> {
+        Some(self.worker.latency())
+    }
+
+    fn confirm(&self) -> Result<(), String> {
+        self.worker.confirm(FLUSH_TIMEOUT)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        match self.worker.flush(FLUSH_TIMEOUT) {
+            true => Ok(()),
+            false => Err(String::from("timed out flushing the redis output")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+
+    #[test]
+    fn test_config() {
+        let config : RedisConfig = serde_yaml::from_str("url: redis://127.0.0.1").unwrap();
+        assert_eq!("zerotrust", config.key);
+        assert_eq!(RedisMode::Publish, config.mode);
+
+        let config : RedisConfig = serde_yaml::from_str("{ url: redis://127.0.0.1, key: connections, mode: stream }").unwrap();
+        assert_eq!("connections", config.key);
+        assert_eq!(RedisMode::Stream, config.mode);
+    }
+
+    #[test]
+    fn test_address() {
+        assert_eq!(Address { host: String::from("127.0.0.1:6379"), password: None, database: None }, address("redis://127.0.0.1"));
+        assert_eq!(Address {
+            host: String::from("redis.local:6380"),
+            password: Some(String::from("secret")),
+            database: Some(String::from("2")),
+        }, address("redis://:secret@redis.local:6380/2"));
+    }
+
+    #[test]
+    fn test_event() {
+        assert_eq!(b"*3\r\n$7\r\nPUBLISH\r\n$9\r\nzerotrust\r\n$10\r\n{\"hash\":1}\r\n".to_vec(),
+            event(&RedisMode::Publish, "zerotrust", "{\"hash\":1}"));
+        assert_eq!(b"*5\r\n$4\r\nXADD\r\n$9\r\nzerotrust\r\n$1\r\n*\r\n$7\r\npayload\r\n$10\r\n{\"hash\":1}\r\n".to_vec(),
+            event(&RedisMode::Stream, "zerotrust", "{\"hash\":1}"));
+    }
+
+    #[test]
+    fn test_unreachable_server() {
+        let config : RedisConfig = serde_yaml::from_str("url: redis://127.0.0.1:1").unwrap();
+        let redis = Redis::new(&config).unwrap();
+        assert!(redis.process_open_connection("{}").is_ok());
+    }
+}
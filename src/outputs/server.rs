@@ -14,58 +14,148 @@
  *
  */
 
-use std::sync::mpsc::Sender;
-use std::sync::mpsc::channel;
+use std::sync::{ Arc, Mutex };
+use std::collections::BTreeMap;
 use std::thread;
-use outputs::{ Output };
+use std::io;
+use std::fs;
+use std::path::{ Path, PathBuf };
+use outputs::{ Output, Route, Serialization, FLUSH_TIMEOUT };
+use outputs::worker::{ queue, Worker };
+use metrics::{ LatencyHistogram };
 use reqwest;
 use reqwest::{ StatusCode };
 use reqwest::header::{ CONTENT_TYPE };
 use uuid::Uuid;
+use chrono::{ DateTime, Utc };
+use rand;
+use rand::Rng;
+use enums::{ AgentIdentity };
 use serde_json;
 use ipnetwork::IpNetwork;
-use std::net::Ipv4Addr;
+use std::net::{ IpAddr };
+use parser::SCHEMA_VERSION;
+use outputs::http;
+use outputs::http::{ HttpConfig, ClientCertificateConfig };
 
 
 
 enum MessageType {
     OpenHashes(Vec<i64>),
-    Open(String),
-    Close(String),
+    Stats(String),
+    Open(Vec<u8>),
+    Close(Vec<u8>),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct OpenMessage {
-    uuid: Option<Uuid>,
-    name: Option<String>,
-    interfaces : Vec<Ipv4Addr>
+    schema_version: u32,
+    uuid: Uuid,
+    name: String,
+    hostname: String,
+    interfaces : Vec<IpAddr>
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct InterfaceMessage {
-    interfaces: Vec<Ipv4Addr>
+    schema_version: u32,
+    interfaces: Vec<IpAddr>
 }
 
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq)]
+struct DirectionCounts {
+    outbound: u64,
+    inbound: u64,
+    unknown: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StatsMessage {
+    schema_version: u32,
+    since: String,
+    until: String,
+    connections: BTreeMap<String, DirectionCounts>,
+}
+
+// Only the fields which are counted, the rest of the payload is skipped.
+#[derive(Deserialize)]
+struct Counted {
+    event_type: String,
+    protocol: String,
+    direction: Option<String>,
+}
+
+// Connections seen by protocol and direction since the last report, sent with
+// the alive connections so the server can tell a quiet agent from a broken one.
+struct Stats {
+    since: DateTime<Utc>,
+    connections: BTreeMap<String, DirectionCounts>,
+}
+
+impl Stats {
+    fn new() -> Stats {
+        Stats { since: Utc::now(), connections: BTreeMap::new() }
+    }
+
+    // Counts opens, and the single record sent per connection in close only
+    // mode, closes would count every connection twice.
+    fn record(&mut self, message: &str) {
+        let counted : Counted = match serde_json::from_str(message) {
+            Ok(x) => x,
+            Err(_err) => return,
+        };
+
+        if counted.event_type != "open" && counted.event_type != "connection" {
+            return;
+        }
+
+        let counts = self.connections.entry(counted.protocol).or_default();
+        match counted.direction.as_deref() {
+            Some("outbound") => counts.outbound += 1,
+            Some("inbound") => counts.inbound += 1,
+            _ => counts.unknown += 1,
+        }
+    }
+
+    // The counts so far, starting again from now.
+    fn take(&mut self, now: DateTime<Utc>) -> StatsMessage {
+        let since = std::mem::replace(&mut self.since, now);
+        StatsMessage {
+            schema_version: SCHEMA_VERSION,
+            since: since.to_rfc3339(),
+            until: now.to_rfc3339(),
+            connections: std::mem::take(&mut self.connections),
+        }
+    }
+}
+
+// Fields are dropped in order, so the interface updates are cancelled before
+// their timer is stopped.
 #[allow(dead_code)]
 pub struct Server {
-    tx: Sender<MessageType>,
+    worker : Worker<MessageType>,
+    serialization : Serialization,
+    stats : Mutex<Stats>,
+    interface_update_guard : timer::Guard,
     timer: timer::Timer,
-    interface_update_guard : Option<timer::Guard>,
 }
 
-fn post(payload: &str, url: &str) -> Result<(), String> {
-    let payload = String::from(payload);
-    let res = reqwest::Client::new()
+fn post(client: &reqwest::Client, payload: &[u8], content_type: &str, url: &str) -> Result<(), String> {
+    let res = client
         .post(url)
-        .header(CONTENT_TYPE, "application/json")
-        .body(payload)
+        .header(CONTENT_TYPE, content_type)
+        .body(payload.to_vec())
         .send();
 
     match res {
         Err(err) => Err(format!("unable to send to server: {}", err)),
         Ok(mut res) => {
             match res.status() {
-                StatusCode::OK => Ok(()),
+                StatusCode::OK => {
+                    // Reading the body hands the connection back to the pool.
+                    let _ = res.copy_to(&mut io::sink());
+                    Ok(())
+                },
                     _ => match res.text() {
                     Err(err) => Err(format!("failed to insert to server: {}", err)),
                     Ok(body) => Err(format!("failed to insert to server: {}", body)),
@@ -76,64 +166,129 @@ fn post(payload: &str, url: &str) -> Result<(), String> {
 }
 
 
-fn send_data(url : &str, message : &str) {
-    match post(&message, url) {
-        Err(err) => error!("{}", err),
+fn send_data(client: &reqwest::Client, url : &str, message : &[u8], content_type : &str) -> Result<(), String> {
+    let result = post(client, message, content_type, url);
+    match result {
+        Err(ref err) => error!("{}", err),
         Ok(()) => info!("successfully sent connection to zerotrust server"),
     };
+    result
 }
 
-fn open_connection(url: &str, open_message: OpenMessage) -> Result<(), String>{
+fn open_connection(client: &reqwest::Client, url: &str, open_message: OpenMessage) -> Result<(), String>{
     let open_message = match serde_json::to_string(&open_message) {
         Ok(x) => x,
         Err(_err) => return Err(String::from("unable to serialize the open_message!")),
     };
 
     info!("marking agent online to URL: {} with payload: \"{}\"", url, open_message);
-    post(&open_message, url)
+    post(client, open_message.as_bytes(), "application/json", url)
 }
 
 
-fn get_interfaces() ->  Vec<Ipv4Addr>{
-    let mut interfaces : Vec<Ipv4Addr> = Vec::new();
+fn get_interfaces() ->  Vec<IpAddr>{
+    let networks = pnet::datalink::interfaces().into_iter()
+        .flat_map(|interface| interface.ips);
 
-    for interface in pnet::datalink::interfaces() {
-        for address in interface.ips {
-            if let IpNetwork::V4(network) = address {
-                let ip = network.ip();
-                if !ip.is_loopback() {
-                    interfaces.push(ip);
-                }
-            }
+    collect_addresses(networks)
+}
+
+fn collect_addresses<I: Iterator<Item = IpNetwork>>(networks: I) -> Vec<IpAddr> {
+    networks
+        .map(|network| network.ip())
+        .filter(|ip| !ip.is_loopback())
+        .collect()
+}
+
+// Interfaces can still be coming up when we start, so the last list which was
+// found is kept in the data directory and used until they are back.
+fn interfaces_file(directory: &Option<String>) -> Option<PathBuf> {
+    directory.as_ref().map(|directory| Path::new(directory).join("interfaces.json"))
+}
+
+fn load_interfaces(file: &Path) -> Vec<IpAddr> {
+    let contents = match fs::read_to_string(file) {
+        Ok(x) => x,
+        Err(_err) => return Vec::new(),
+    };
+
+    match serde_json::from_str(&contents) {
+        Ok(x) => x,
+        Err(err) => {
+            warn!("unable to parse the saved interfaces: {}", err);
+            Vec::new()
         }
     }
-    interfaces
 }
 
+fn store_interfaces(file: &Path, interfaces: &[IpAddr]) {
+    let contents = match serde_json::to_string(interfaces) {
+        Ok(x) => x,
+        Err(_err) => return,
+    };
+
+    if let Err(err) = fs::write(file, contents) {
+        warn!("unable to save the interfaces: {}", err);
+    }
+}
+
+// Saves the live interfaces when there are some, otherwise falls back to the
+// ones which were saved last.
+fn known_interfaces(live: Vec<IpAddr>, file: &Option<PathBuf>) -> Vec<IpAddr> {
+    let file = match file {
+        Some(x) => x,
+        None => return live,
+    };
+
+    if live.is_empty() {
+        let saved = load_interfaces(file);
+        debug!("no interfaces found, using the saved ones: {:?}", saved);
+        return saved;
+    }
 
-fn send_interfaces(url: &str, interfaces_message: InterfaceMessage) -> Result<(), String> {
+    store_interfaces(file, &live);
+    live
+}
+
+fn send_interfaces(client: &reqwest::Client, url: &str, interfaces_message: InterfaceMessage) -> Result<(), String> {
     let interfaces_message = match serde_json::to_string(&interfaces_message) {
         Ok(x) => x,
         Err(_err) => return Err(String::from("unable to serialize the interface_mesage!")),
     };
 
     info!("sending interface information to URL: {} with payload: \"{}\"", url, interfaces_message);
-    post(&interfaces_message, url)
+    post(client, interfaces_message.as_bytes(), "application/json", url)
 }
 
 
-fn create_interface_scheduled_call(timer: &timer::Timer, minutes : i64, url: &str) -> timer::Guard  {
+// Agents restarted together would otherwise all send their interfaces at the
+// same moments, so the first update is at a random point in the interval and
+// the interval itself is moved by up to a tenth either way.
+fn jittered<R: Rng>(minutes : i64, rng : &mut R) -> (chrono::Duration, chrono::Duration) {
+    let seconds = minutes * 60;
+    let spread = seconds / 10;
+    let offset = rng.gen_range(0, seconds.max(1));
+    let interval = seconds + rng.gen_range(-spread, spread + 1);
+
+    (chrono::Duration::seconds(offset), chrono::Duration::seconds(interval.max(1)))
+}
+
+fn create_interface_scheduled_call(timer: &timer::Timer, client: &reqwest::Client, minutes : i64, url: &str, file: &Option<PathBuf>) -> timer::Guard  {
     let url : String = String::from(url);
-    debug!("setting timer to {}", minutes);
-    timer.schedule_repeating(chrono::Duration::minutes(minutes), move || {
-        let interfaces = get_interfaces();
+    let client = client.clone();
+    let file = file.clone();
+    let (offset, interval) = jittered(minutes, &mut rand::thread_rng());
+    debug!("setting timer to {} seconds, starting in {} seconds", interval.num_seconds(), offset.num_seconds());
+    timer.schedule(Utc::now() + offset, Some(interval), move || {
+        let interfaces = known_interfaces(get_interfaces(), &file);
         debug!("getting interfaces");
         debug!("found IPs: {:?}", interfaces);
         let interface_message =  InterfaceMessage {
+            schema_version: SCHEMA_VERSION,
             interfaces
         };
 
-        match send_interfaces(&url, interface_message) {
+        match send_interfaces(&client, &url, interface_message) {
             Ok(()) => info!("successfully send interface information"),
             Err(_err) => error!("unable to update the interface information")
         };
@@ -142,55 +297,61 @@ fn create_interface_scheduled_call(timer: &timer::Timer, minutes : i64, url: &st
 
 
 impl Server {
-    pub fn new(name: &Option<String>, uuid: &Option<Uuid>, url: &str) -> Result<Server, String> {
+    pub fn new(identity: &AgentIdentity, url: &str, directory: &Option<String>, config: &HttpConfig, certificate: Option<&ClientCertificateConfig>, serialization: Serialization) -> Result<Server, String> {
         let timer : timer::Timer = timer::Timer::new();
+        let client = match certificate {
+            Some(certificate) => http::client_with_identity(config, certificate)?,
+            None => http::client(config)?,
+        };
+        let file = interfaces_file(directory);
         let open_message =  OpenMessage {
-            name: name.clone(),
-            uuid: uuid.clone(),
-            interfaces: get_interfaces(),
+            schema_version: SCHEMA_VERSION,
+            name: identity.name.clone(),
+            uuid: identity.uuid,
+            hostname: identity.hostname.clone(),
+            interfaces: known_interfaces(get_interfaces(), &file),
         };
 
 
         let open_url = format!("{}/connections/open", url);
         let close_url = format!("{}/connections/close", url);
         let open_connection_url = format!("{}/agents/online", url);
-        let mut hashes_url : Option<String> = None;
+        let hashes_url = format!("{}/agents/{}/alive-connections", url, identity.uuid);
+        let stats_url = format!("{}/agents/{}/stats", url, identity.uuid);
 
-        match open_connection(&open_connection_url, open_message) {
+        match open_connection(&client, &open_connection_url, open_message) {
             Ok(()) => info!("successfully opened agent on server"),
             Err(err) => return Err(err),
         };
 
-        let interface_update_guard = match uuid {
-            Some(uuid) => {
-                debug!("creating callback guard");
-                let interface_url = format!("{}/agents/{}/interfaces", url, uuid);
-                hashes_url = Some(format!("{}/agents/{}/alive-connections", url, uuid));
-                Some(create_interface_scheduled_call(&timer, 30, &interface_url))
-            },
-            None => {
-                warn!("unable to send interface details as uuid isn't set");
-                None
-            }
-        };
+        debug!("creating callback guard");
+        let interface_url = format!("{}/agents/{}/interfaces", url, identity.uuid);
+        let interface_update_guard = create_interface_scheduled_call(&timer, &client, 30, &interface_url, &file);
 
 
-        let (tx, rx) = channel();
+        let (tx, rx) = queue();
+        let content_type = serialization.content_type();
 
-        thread::spawn(move || {
+        let handle = thread::spawn(move || {
             loop {
                 match rx.recv() {
                     Ok(message) => {
-                        match message {
-                            MessageType::Open(connection) => { send_data(&open_url, &connection); },
-                            MessageType::Close(connection) => { send_data(&close_url, &connection); },
+                        let result = match message {
+                            MessageType::Open(connection) => send_data(&client, &open_url, &connection, content_type),
+                            MessageType::Close(connection) => send_data(&client, &close_url, &connection, content_type),
                             MessageType::OpenHashes(hashes) => {
                                 let hashes = serde_json::to_string(&hashes).unwrap();
-                                if let Some(ref url) = hashes_url {
-                                    send_data(&url, &hashes);
+                                send_data(&client, &hashes_url, hashes.as_bytes(), "application/json")
+                            },
+                            MessageType::Stats(stats) => {
+                                let result = post(&client, stats.as_bytes(), "application/json", &stats_url);
+                                if let Err(ref err) = result {
+                                    error!("unable to send the connection stats: {}", err);
                                 }
-                            }
+                                result
+                            },
                         };
+                        rx.delivered(1, &result);
                     },
                     Err(err) => {
                         error!("closing thread: {}", err);
@@ -201,25 +362,89 @@ impl Server {
         });
 
         Ok(Server {
-            tx,
+            worker: Worker::new(tx, handle),
+            serialization,
+            stats: Mutex::new(Stats::new()),
+            interface_update_guard,
             timer,
-            interface_update_guard
         })
     }
 
+    fn record(&self, message: &str) {
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.record(message);
+        }
+    }
+
+    fn send(&self, message: MessageType) -> Result<(), String> {
+        match self.worker.send(message) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(String::from("server thread has stopped")),
+        }
+    }
 }
 
 impl Output for Server {
-    fn process_open_connection(&self, message: &str) {
-        let _ = self.tx.send(MessageType::Open(message.to_string()));
+    fn process_open_connection(&self, message: &str) -> Result<(), String> {
+        self.record(message);
+        self.send(MessageType::Open(self.serialization.encode(message)?))
     }
 
-    fn process_close_connection(&self, message: &str) {
-        let _ = self.tx.send(MessageType::Close(message.to_string()));
+    fn process_close_connection(&self, message: &str) -> Result<(), String> {
+        self.record(message);
+        self.send(MessageType::Close(self.serialization.encode(message)?))
+    }
+
+    fn serialization(&self) -> Serialization {
+        self.serialization
+    }
+
+    // The stats are kept from the JSON whichever way the payload is sent.
+    fn process_encoded(&self, route: Route, message: &str, encoded: &[u8]) -> Result<(), String> {
+        match route {
+            Route::Open => {
+                self.record(message);
+                self.send(MessageType::Open(encoded.to_vec()))
+            },
+            Route::Close | Route::Connection => {
+                self.record(message);
+                self.send(MessageType::Close(encoded.to_vec()))
+            },
+            Route::Event => Ok(()),
+        }
     }
 
     fn process_alive_connections(&self, connections: &Vec<i64>) {
-        let _ = self.tx.send(MessageType::OpenHashes(connections.clone()));
+        let _ = self.worker.send(MessageType::OpenHashes(connections.clone()));
+
+        let stats = match self.stats.lock() {
+            Ok(mut stats) => stats.take(Utc::now()),
+            Err(_err) => return,
+        };
+        match serde_json::to_string(&stats) {
+            Ok(stats) => { let _ = self.worker.send(MessageType::Stats(stats)); },
+            Err(err) => error!("unable to serialize the connection stats: {}", err),
+        }
+    }
+
+    // The server only knows about connections.
+    fn process_event(&self, _ : &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn queue_latency(&self) -> Option<Arc<LatencyHistogram>> {
+        Some(self.worker.latency())
+    }
+
+    fn confirm(&self) -> Result<(), String> {
+        self.worker.confirm(FLUSH_TIMEOUT)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        match self.worker.flush(FLUSH_TIMEOUT) {
+            true => Ok(()),
+            false => Err(String::from("timed out flushing the server output")),
+        }
     }
 
 }
@@ -228,9 +453,160 @@ impl Output for Server {
 mod tests {
 
     use super::*;
+    use tempfile::{ tempdir };
+    use std::str::FromStr;
+    use std::net::Ipv4Addr;
+    use enums::{ Direction, Protocol };
+    use parser::{ Payload, OpenConnection };
+    use test_support::{ open_payload, close_payload, HttpServer };
+    use rand::{ SeedableRng, StdRng };
 
     #[test]
     fn test_get_interfaces() {
         get_interfaces();
     }
+
+    #[test]
+    fn test_messages_schema_version() {
+        let open_message = OpenMessage {
+            schema_version: SCHEMA_VERSION,
+            uuid: Uuid::new_v4(),
+            name: String::from("gerald"),
+            hostname: String::from("localhost"),
+            interfaces: Vec::new(),
+        };
+
+        let interface_message = InterfaceMessage {
+            schema_version: SCHEMA_VERSION,
+            interfaces: Vec::new(),
+        };
+
+        let open_message = serde_json::to_value(open_message).unwrap();
+        let interface_message = serde_json::to_value(interface_message).unwrap();
+
+        assert_eq!(u64::from(SCHEMA_VERSION), open_message["schema_version"].as_u64().unwrap());
+        assert_eq!(u64::from(SCHEMA_VERSION), interface_message["schema_version"].as_u64().unwrap());
+    }
+
+    #[test]
+    fn test_known_interfaces_fallback() {
+        let directory = tempdir().unwrap();
+        let file = interfaces_file(&Some(directory.path().to_str().unwrap().to_string()));
+        let live = vec!(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)), IpAddr::from_str("fd00::1").unwrap());
+
+        assert!(known_interfaces(Vec::new(), &file).is_empty());
+        assert_eq!(live, known_interfaces(live.clone(), &file));
+        assert_eq!(live, known_interfaces(Vec::new(), &file));
+
+        let other = vec!(IpAddr::V4(Ipv4Addr::new(172, 16, 0, 1)));
+        assert_eq!(other, known_interfaces(other.clone(), &file));
+        assert_eq!(other, known_interfaces(Vec::new(), &file));
+    }
+
+    #[test]
+    fn test_known_interfaces_without_directory() {
+        assert!(known_interfaces(Vec::new(), &None).is_empty());
+    }
+
+    #[test]
+    fn test_collect_addresses_v6() {
+        let networks = vec!(
+            IpNetwork::from_str("127.0.0.1/8").unwrap(),
+            IpNetwork::from_str("192.168.1.10/24").unwrap(),
+            IpNetwork::from_str("::1/128").unwrap(),
+            IpNetwork::from_str("2001:db8::10/64").unwrap(),
+            IpNetwork::from_str("fe80::1/64").unwrap(),
+        );
+
+        let addresses = collect_addresses(networks.into_iter());
+        assert_eq!(vec!(
+            IpAddr::from_str("192.168.1.10").unwrap(),
+            IpAddr::from_str("2001:db8::10").unwrap(),
+            IpAddr::from_str("fe80::1").unwrap(),
+        ), addresses);
+    }
+
+    fn simulated(hash : i64, protocol : Protocol, direction : Option<Direction>) -> String {
+        let payload = match open_payload(hash) {
+            Payload::Open(connection) => Payload::Open(OpenConnection { protocol, direction, .. connection }),
+            payload => payload,
+        };
+        serde_json::to_string(&payload).unwrap()
+    }
+
+    #[test]
+    fn test_jittered_interface_updates() {
+        let mut first = StdRng::from_seed([1; 32]);
+        let mut second = StdRng::from_seed([2; 32]);
+        let (first_offset, first_interval) = jittered(30, &mut first);
+        let (second_offset, _) = jittered(30, &mut second);
+        assert_ne!(first_offset, second_offset);
+
+        for _ in 0..100 {
+            let (offset, interval) = jittered(30, &mut first);
+            assert!(offset >= chrono::Duration::zero() && offset < chrono::Duration::minutes(30));
+            assert!(interval >= chrono::Duration::minutes(27) && interval <= chrono::Duration::minutes(33));
+        }
+        assert!(first_interval >= chrono::Duration::minutes(27));
+    }
+
+    #[test]
+    fn test_stats_message() {
+        let mut stats = Stats::new();
+        stats.record(&simulated(1, Protocol::TCP, Some(Direction::Outbound)));
+        stats.record(&simulated(2, Protocol::TCP, Some(Direction::Outbound)));
+        stats.record(&simulated(3, Protocol::TCP, Some(Direction::Inbound)));
+        stats.record(&simulated(4, Protocol::UDP, None));
+        stats.record(&serde_json::to_string(&close_payload(1)).unwrap());
+        stats.record("not json");
+
+        let message = serde_json::to_value(stats.take(Utc::now())).unwrap();
+        assert_eq!(SCHEMA_VERSION, message["schema_version"]);
+        assert_eq!(2, message["connections"]["TCP"]["outbound"]);
+        assert_eq!(1, message["connections"]["TCP"]["inbound"]);
+        assert_eq!(0, message["connections"]["TCP"]["unknown"]);
+        assert_eq!(1, message["connections"]["UDP"]["unknown"]);
+        assert_eq!(message["until"], serde_json::to_value(stats.since.to_rfc3339()).unwrap());
+
+        // Each report only counts what was seen since the one before.
+        assert!(stats.take(Utc::now()).connections.is_empty());
+    }
+
+    #[test]
+    fn test_open_message_identity() {
+        let collector = HttpServer::start("200 OK");
+
+        let identity = AgentIdentity {
+            name: String::from("gerald"),
+            uuid: Uuid::new_v4(),
+            hostname: String::from("gerald.local"),
+        };
+        let _server = Server::new(&identity, &collector.url, &None, &HttpConfig::default(), None, Serialization::Json).unwrap();
+
+        let request = collector.request();
+        let message : serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!("/agents/online", request.path);
+        assert_eq!("gerald", message["name"]);
+        assert_eq!(identity.uuid.to_string(), message["uuid"]);
+        assert_eq!("gerald.local", message["hostname"]);
+    }
+
+    #[test]
+    fn test_missing_client_certificate() {
+        let identity = AgentIdentity {
+            name: String::from("gerald"),
+            uuid: Uuid::new_v4(),
+            hostname: String::from("gerald.local"),
+        };
+        let certificate = ClientCertificateConfig {
+            certificate: String::from("/does/not/exist.p12"),
+            key: None,
+            password: None,
+        };
+
+        match Server::new(&identity, "http://127.0.0.1:1", &None, &HttpConfig::default(), Some(&certificate), Serialization::Json) {
+            Err(err) => assert!(err.starts_with("unable to load client certificate")),
+            Ok(_) => panic!("server started without its certificate"),
+        }
+    }
 }
@@ -16,108 +16,251 @@
 
 
 use syslog;
-use syslog::{Formatter3164, Facility};
-use std::net::Ipv4Addr;
+use syslog::{ Formatter3164, Facility, Logger, LoggerBackend };
+use std::io;
+use std::io::{ BufWriter, Write };
+use std::net::{ Ipv4Addr, TcpStream, ToSocketAddrs, UdpSocket };
+use std::os::unix::net::UnixDatagram;
+use std::str::FromStr;
+use chrono::prelude::*;
 use libc::{getpid};
-use std::sync::mpsc::Sender;
-use std::sync::mpsc::Receiver;
-use std::sync::mpsc::channel;
+use std::sync::Arc;
 use std::thread;
 use sys_info;
 
-use outputs::{ Output };
+use outputs::{ Output, FLUSH_TIMEOUT };
+use outputs::worker::{ queue, Worker };
+use metrics::{ LatencyHistogram };
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum SyslogFormat {
+    #[default]
+    RFC3164,
+    RFC5424,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum SyslogSeverity {
+    Emergency,
+    Alert,
+    Critical,
+    #[default]
+    Error,
+    Warning,
+    Notice,
+    Info,
+    Debug,
+}
+
+fn default_facility() -> String {
+    String::from("user")
+}
+
+fn default_app_name() -> String {
+    String::from("zerotrust-track")
+}
 
+// How the messages sent to one syslog target are written.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SyslogSettings {
+    #[serde(default)]
+    pub format : SyslogFormat,
+    // One of the syslog facility names, such as user, daemon or local0.
+    #[serde(default = "default_facility")]
+    pub facility : String,
+    #[serde(default)]
+    pub severity : SyslogSeverity,
+    #[serde(default = "default_app_name")]
+    pub app_name : String,
+}
+
+impl Default for SyslogSettings {
+    fn default() -> SyslogSettings {
+        SyslogSettings {
+            format: SyslogFormat::default(),
+            facility: default_facility(),
+            severity: SyslogSeverity::default(),
+            app_name: default_app_name(),
+        }
+    }
+}
+
+// Localhost is the local syslog with the default settings, Unix is the same
+// socket with settings of its own.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SyslogConfig {
     Localhost,
-    TCP{ address: Ipv4Addr, port : u16 },
-    UDP{ address: Ipv4Addr, port: u16 },
+    Unix{ #[serde(flatten)] settings: SyslogSettings },
+    TCP{ address: Ipv4Addr, port : u16, #[serde(flatten)] settings: SyslogSettings },
+    UDP{ address: Ipv4Addr, port: u16, #[serde(flatten)] settings: SyslogSettings },
 }
-pub struct Syslog {
-    tx : Sender<String>,
+
+enum Target {
+    Unix,
+    Tcp(String),
+    Udp(String),
 }
 
-impl Syslog {
-    pub fn local() -> Result<Syslog, String> {
-        let formatter = create_formatter();
-        let (tx, rx) : (Sender<String>, Receiver<String>) = channel();
-        let mut writer = match syslog::unix(formatter) {
-            Ok(writer) => writer,
-            Err(_) => return Err(String::from("unable to start localhost syslog"))
-        };
+// The logger only takes the severity through which method is called.
+macro_rules! log_at {
+    ($logger:expr, $severity:expr, $message:expr) => {
+        match $severity {
+            SyslogSeverity::Emergency => $logger.emerg($message),
+            SyslogSeverity::Alert => $logger.alert($message),
+            SyslogSeverity::Critical => $logger.crit($message),
+            SyslogSeverity::Error => $logger.err($message),
+            SyslogSeverity::Warning => $logger.warning($message),
+            SyslogSeverity::Notice => $logger.notice($message),
+            SyslogSeverity::Info => $logger.info($message),
+            SyslogSeverity::Debug => $logger.debug($message),
+        }
+    };
+}
 
-        thread::spawn(move || {
-            loop {
-                match rx.recv() {
-                    Ok(message) => {
-                        if let Err(_) = writer.err(message) {
-                            error!("unable to write to syslog");
-                        }
-                    },
-                    Err(err) => {
-                        error!("closing thread: {}", err);
-                        break;
-                    }
-                };
-            }
-        });
+// The syslog crate can only connect a logger whose messages can be
+// displayed, which rules out its own RFC 5424 formatter, so those lines are
+// put together here and written to a backend of our own.
+struct Rfc5424 {
+    backend : LoggerBackend,
+    priority : u8,
+    hostname : String,
+    app_name : String,
+    pid : i32,
+    // Streams have to be told where each message ends.
+    framed : bool,
+}
 
-        Ok(Syslog {
-            tx,
-        })
+impl Rfc5424 {
+    fn write(&mut self, message: &str) -> io::Result<()> {
+        let line = format!("<{}>1 {} {} {} {} - - {}",
+            self.priority,
+            Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.hostname,
+            self.app_name,
+            self.pid,
+            message);
+
+        match self.framed {
+            true => write!(self.backend, "{} {}", line.len(), line)?,
+            false => self.backend.write_all(line.as_bytes())?,
+        }
+        self.backend.flush()
     }
+}
 
-    pub fn udp(address : &Ipv4Addr, port: u16) -> Result<Syslog, String> {
-        let formatter = create_formatter();
-        let (tx, rx) : (Sender<String>, Receiver<String>) = channel();
-        let connect_string = address.to_string() + ":" + &port.to_string();
+fn backend(target: &Target) -> io::Result<LoggerBackend> {
+    match target {
+        Target::Unix => {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect("/dev/log").or_else(|_err| socket.connect("/var/run/syslog"))?;
+            Ok(LoggerBackend::Unix(socket))
+        },
+        Target::Udp(server) => {
+            let server = match server.to_socket_addrs()?.next() {
+                Some(x) => x,
+                None => return Err(io::Error::new(io::ErrorKind::NotFound, "no address for the syslog server")),
+            };
+            Ok(LoggerBackend::Udp(UdpSocket::bind("0.0.0.0:0")?, server))
+        },
+        Target::Tcp(server) => Ok(LoggerBackend::Tcp(BufWriter::new(TcpStream::connect(server)?))),
+    }
+}
 
-        let mut writer = match syslog::udp(formatter,  "127.0.0.1:3514", &connect_string) {
-            Ok(writer) => writer,
-            Err(_) => return Err(String::from("unable to start UDP syslog sender"))
+fn severity_code(severity: SyslogSeverity) -> u8 {
+    match severity {
+        SyslogSeverity::Emergency => 0,
+        SyslogSeverity::Alert => 1,
+        SyslogSeverity::Critical => 2,
+        SyslogSeverity::Error => 3,
+        SyslogSeverity::Warning => 4,
+        SyslogSeverity::Notice => 5,
+        SyslogSeverity::Info => 6,
+        SyslogSeverity::Debug => 7,
+    }
+}
+
+enum Writer {
+    Rfc3164(Logger<LoggerBackend, String, Formatter3164>, SyslogSeverity),
+    Rfc5424(Rfc5424),
+}
+
+impl Writer {
+    fn new(target: &Target, settings: &SyslogSettings) -> Result<Writer, String> {
+        let facility = match Facility::from_str(&settings.facility) {
+            Ok(x) => x,
+            Err(()) => return Err(format!("unknown syslog facility {}", settings.facility)),
         };
+        let hostname = sys_info::hostname().ok();
+        let pid = unsafe { getpid() };
 
-        thread::spawn(move || {
-            loop {
-                match rx.recv() {
-                    Ok(message) => {
-                        if let Err(_) = writer.err(message) {
-                            error!("unable to write to syslog");
-                        }
-                    },
-                    Err(err) => {
-                        error!("closing thread: {}", err);
-                        break;
+        let error = match target {
+            Target::Unix => "unable to start localhost syslog",
+            Target::Tcp(_) => "unable to start TCP syslog sender",
+            Target::Udp(_) => "unable to start UDP syslog sender",
+        };
 
-                    }
+        match settings.format {
+            SyslogFormat::RFC3164 => {
+                let formatter = Formatter3164 {
+                    facility,
+                    hostname,
+                    process: settings.app_name.clone(),
+                    pid,
                 };
-            }
-        });
-
-        Ok(Syslog {
-            tx,
-        })
+                let logger = match target {
+                    Target::Unix => syslog::unix(formatter),
+                    // Bound to any port, so several UDP targets can be used at once.
+                    Target::Udp(server) => syslog::udp(formatter, "0.0.0.0:0", server),
+                    Target::Tcp(server) => syslog::tcp(formatter, server),
+                };
+                match logger {
+                    Ok(logger) => Ok(Writer::Rfc3164(logger, settings.severity)),
+                    Err(_err) => Err(String::from(error)),
+                }
+            },
+            SyslogFormat::RFC5424 => match backend(target) {
+                Ok(backend) => Ok(Writer::Rfc5424(Rfc5424 {
+                    backend,
+                    priority: facility as u8 | severity_code(settings.severity),
+                    hostname: hostname.unwrap_or_else(|| String::from("-")),
+                    app_name: settings.app_name.clone(),
+                    pid,
+                    framed: match target {
+                        Target::Tcp(_) => true,
+                        Target::Unix | Target::Udp(_) => false,
+                    },
+                })),
+                Err(_err) => Err(String::from(error)),
+            },
+        }
     }
 
+    fn write(&mut self, message: String) -> Result<(), ()> {
+        match self {
+            Writer::Rfc3164(logger, severity) => log_at!(logger, severity, message).map_err(|_| ()),
+            Writer::Rfc5424(writer) => writer.write(&message).map_err(|_| ()),
+        }
+    }
+}
 
-    pub fn tcp(address : &Ipv4Addr, port : u16 ) -> Result<Syslog, String> {
-        let formatter = create_formatter();
-        let (tx, rx) : (Sender<String>, Receiver<String>) = channel();
-        let connect_string = address.to_string() + ":" + &port.to_string();
+pub struct Syslog {
+    worker : Worker<String>,
+}
 
-        let mut writer = match syslog::tcp(formatter,  connect_string) {
-            Ok(writer) => writer,
-            Err(_) => return Err(String::from("unable to start TCP syslog sender"))
-        };
+impl Syslog {
+    fn start(target: Target, settings: &SyslogSettings) -> Result<Syslog, String> {
+        let mut writer = Writer::new(&target, settings)?;
+        let (tx, rx) = queue::<String>();
 
-        thread::spawn(move || {
+        let handle = thread::spawn(move || {
             loop {
                 match rx.recv() {
                     Ok(message) => {
-                        if let Err(_) = writer.err(message) {
-                            error!("unable to write to syslog");
+                        let result = writer.write(message).map_err(|()| String::from("unable to write to syslog"));
+                        if let Err(ref err) = result {
+                            error!("{}", err);
                         }
+                        rx.delivered(1, &result);
                     },
                     Err(err) => {
                         error!("closing thread: {}", err);
@@ -128,48 +271,84 @@ impl Syslog {
         });
 
         Ok(Syslog {
-            tx,
+            worker: Worker::new(tx, handle),
         })
     }
+
+    pub fn local(settings: &SyslogSettings) -> Result<Syslog, String> {
+        Syslog::start(Target::Unix, settings)
+    }
+
+    pub fn udp(address : &Ipv4Addr, port: u16, settings: &SyslogSettings) -> Result<Syslog, String> {
+        Syslog::start(Target::Udp(format!("{}:{}", address, port)), settings)
+    }
+
+    pub fn tcp(address : &Ipv4Addr, port : u16, settings: &SyslogSettings) -> Result<Syslog, String> {
+        Syslog::start(Target::Tcp(format!("{}:{}", address, port)), settings)
+    }
 }
 
 impl Output for Syslog {
-    fn process_open_connection(&self, message: &str) {
-        let _ = self.tx.send(format!("CONNECTION OPENED: {}", message.to_string()));
+    fn process_open_connection(&self, message: &str) -> Result<(), String> {
+        match self.worker.send(format!("CONNECTION OPENED: {}", message.to_string())) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(String::from("syslog thread has stopped")),
+        }
     }
 
-    fn process_close_connection(&self, message: &str) {
-        let _ = self.tx.send(format!("CONNECTION CLOSED: {}", message.to_string()));
+    fn process_close_connection(&self, message: &str) -> Result<(), String> {
+        match self.worker.send(format!("CONNECTION CLOSED: {}", message.to_string())) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(String::from("syslog thread has stopped")),
+        }
     }
 
     fn process_alive_connections(&self, _ : &Vec<i64>) { }
 
-}
+    fn process_event(&self, message: &str) -> Result<(), String> {
+        match self.worker.send(format!("EVENT: {}", message)) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(String::from("syslog thread has stopped")),
+        }
+    }
+
+    fn queue_latency(&self) -> Option<Arc<LatencyHistogram>> {
+        Some(self.worker.latency())
+    }
+
+    fn confirm(&self) -> Result<(), String> {
+        self.worker.confirm(FLUSH_TIMEOUT)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        match self.worker.flush(FLUSH_TIMEOUT) {
+            true => Ok(()),
+            false => Err(String::from("timed out flushing the syslog output")),
+        }
+    }
 
-fn create_formatter() -> Formatter3164 {
-    return Formatter3164  {
-        facility: Facility::LOG_USER,
-        hostname: match sys_info::hostname() {
-            Ok(name) => Some(name.to_string()),
-            _ => None
-        },
-        process: "zerotrust-track".into(),
-        pid: unsafe { getpid() },
-    };
 }
 
 #[cfg(test)]
 mod tests {
     use std::net::TcpListener;
     use std::net::UdpSocket;
+    use std::time::Duration;
+    use serde_yaml;
 
     use super::*;
 
+    fn receive(socket: &UdpSocket) -> String {
+        let mut buffer = [0u8; 4096];
+        let length = socket.recv(&mut buffer).unwrap();
+        String::from_utf8_lossy(&buffer[..length]).to_string()
+    }
+
     #[test]
     fn test_create_syslog_unix() {
-        if let Ok(writer) = Syslog::local() {
-            writer.process_open_connection("Hello people");
-            writer.process_close_connection("Hello people");
+        if let Ok(writer) = Syslog::local(&SyslogSettings::default()) {
+            writer.process_open_connection("Hello people").unwrap();
+            writer.process_close_connection("Hello people").unwrap();
         } else {
             assert!(false, "unable to create syslog client");
         }
@@ -179,9 +358,9 @@ mod tests {
     #[test]
     fn test_create_syslog_tcp() {
         let _listener = TcpListener::bind("127.0.0.1:3514").unwrap();
-        if let Ok(writer) = Syslog::tcp(&Ipv4Addr::new(127, 0, 0, 1), 3514) {
-            writer.process_open_connection("Hello people");
-            writer.process_close_connection("Hello people");
+        if let Ok(writer) = Syslog::tcp(&Ipv4Addr::new(127, 0, 0, 1), 3514, &SyslogSettings::default()) {
+            writer.process_open_connection("Hello people").unwrap();
+            writer.process_close_connection("Hello people").unwrap();
         } else {
             assert!(false, "unable to create the syslog client");
         }
@@ -191,12 +370,75 @@ mod tests {
     #[test]
     fn test_create_syslog_udp() {
         let _listener = UdpSocket::bind("127.0.0.1:5514").unwrap();
-        if let Ok(writer) = Syslog::udp(&Ipv4Addr::new(127, 0, 0, 1), 5514) {
-            writer.process_open_connection("Hello people");
-            writer.process_close_connection("Hello people");
+        if let Ok(writer) = Syslog::udp(&Ipv4Addr::new(127, 0, 0, 1), 5514, &SyslogSettings::default()) {
+            writer.process_open_connection("Hello people").unwrap();
+            writer.process_close_connection("Hello people").unwrap();
         } else {
             assert!(false, "unable to create the syslog client");
         }
     }
 
+    #[test]
+    fn test_config() {
+        let config : Vec<SyslogConfig> = serde_yaml::from_str("
+- Localhost
+- { UDP: { address: 10.0.0.1, port: 514, format: RFC5424, facility: local0, severity: Info, app_name: edge } }
+- { TCP: { address: 10.0.0.2, port: 514 } }
+").unwrap();
+
+        match config[1] {
+            SyslogConfig::UDP { ref settings, .. } => {
+                assert_eq!(SyslogFormat::RFC5424, settings.format);
+                assert_eq!("local0", settings.facility);
+                assert_eq!(SyslogSeverity::Info, settings.severity);
+                assert_eq!("edge", settings.app_name);
+            },
+            _ => panic!("expected a UDP target"),
+        }
+
+        match config[2] {
+            SyslogConfig::TCP { ref settings, .. } => assert_eq!(SyslogSettings::default(), *settings),
+            _ => panic!("expected a TCP target"),
+        }
+    }
+
+    #[test]
+    fn test_unknown_facility() {
+        let settings = SyslogSettings { facility: String::from("I_am_not_a_facility"), .. Default::default() };
+        assert!(Syslog::udp(&Ipv4Addr::new(127, 0, 0, 1), 5515, &settings).is_err());
+    }
+
+    #[test]
+    fn test_targets_with_their_own_format() {
+        let local = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let siem = UdpSocket::bind("127.0.0.1:0").unwrap();
+        local.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        siem.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let address = Ipv4Addr::new(127, 0, 0, 1);
+        let mut rfc3164 = Syslog::udp(&address, local.local_addr().unwrap().port(), &SyslogSettings::default()).unwrap();
+        let mut rfc5424 = Syslog::udp(&address, siem.local_addr().unwrap().port(), &SyslogSettings {
+            format: SyslogFormat::RFC5424,
+            facility: String::from("local0"),
+            severity: SyslogSeverity::Info,
+            app_name: String::from("edge"),
+        }).unwrap();
+
+        rfc3164.process_open_connection("Hello people").unwrap();
+        rfc5424.process_open_connection("Hello people").unwrap();
+        rfc3164.flush().unwrap();
+        rfc5424.flush().unwrap();
+
+        // user.err
+        let message = receive(&local);
+        assert!(message.starts_with("<11>"), "{}", message);
+        assert!(message.contains(" zerotrust-track["), "{}", message);
+        assert!(message.ends_with(": CONNECTION OPENED: Hello people"), "{}", message);
+
+        // local0.info, with the RFC 5424 version after the priority.
+        let message = receive(&siem);
+        assert!(message.starts_with("<134>1 "), "{}", message);
+        assert!(message.contains(" edge "), "{}", message);
+        assert!(message.ends_with(" CONNECTION OPENED: Hello people"), "{}", message);
+    }
 }
@@ -0,0 +1,319 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::thread;
+use std::io;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::time::{ Duration, Instant };
+use outputs::{ Output, FLUSH_TIMEOUT };
+use outputs::worker::{ queue, Worker };
+use metrics::{ LatencyHistogram };
+use outputs::http;
+use outputs::http::{ HttpConfig };
+use ipnetwork::Ipv4Network;
+use reqwest::{ StatusCode };
+use reqwest::header::{ CONTENT_TYPE };
+use serde_json;
+use serde_json::Value;
+
+fn default_rate_limit() -> u64 {
+    300
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum WebhookKind {
+    Slack,
+    Teams,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookConfig {
+    // The incoming webhook URL.
+    pub url : String,
+    pub kind : WebhookKind,
+    // Opened connections to a remote end outside of these are alerted on.
+    pub allowed_networks : Vec<String>,
+    // Seconds to wait before alerting on the same remote end again.
+    #[serde(default = "default_rate_limit")]
+    pub rate_limit : u64,
+}
+
+// Decides which connections are alert-worthy and builds the message for them.
+struct Alerter {
+    kind: WebhookKind,
+    allowed: Vec<Ipv4Network>,
+    rate_limit: Duration,
+    last_alert: HashMap<Ipv4Addr, Instant>,
+}
+
+pub struct Webhook {
+    worker : Worker<String>,
+}
+
+impl Alerter {
+    fn new(config: &WebhookConfig) -> Result<Alerter, String> {
+        let mut allowed = Vec::new();
+        for network in &config.allowed_networks {
+            match Ipv4Network::from_str(network) {
+                Ok(x) => allowed.push(x),
+                Err(_err) => return Err(format!("invalid network for webhook: {}", network)),
+            }
+        }
+
+        Ok(Alerter {
+            kind: config.kind.clone(),
+            allowed,
+            rate_limit: Duration::from_secs(config.rate_limit),
+            last_alert: HashMap::new(),
+        })
+    }
+
+    // Connections with no remote end, such as traffic being forwarded, are
+    // not this host's and are left alone.
+    fn alert(&mut self, message: &str, now: Instant) -> Option<Value> {
+        let payload : Value = serde_json::from_str(message).ok()?;
+        let remote = Ipv4Addr::from_str(payload["remote_address"].as_str()?).ok()?;
+
+        if self.allowed.iter().any(|network| network.contains(remote)) {
+            return None;
+        }
+
+        if let Some(last) = self.last_alert.get(&remote) {
+            if now.duration_since(*last) < self.rate_limit {
+                return None;
+            }
+        }
+
+        // Anything which would no longer hold back an alert is dropped, so
+        // only the remote ends alerted on within the rate limit are kept.
+        let rate_limit = self.rate_limit;
+        self.last_alert.retain(|_, last| now.duration_since(*last) < rate_limit);
+        self.last_alert.insert(remote, now);
+
+        let connected = match payload["direction"].as_str() {
+            Some("inbound") => "accepted a connection from",
+            _ => "connected to",
+        };
+
+        let process = payload["program_details"]["process_name"].as_str().unwrap_or("an unknown process");
+        let text = format!("{} ({}) {} {}:{} over {}, which is outside of the allowed networks",
+            process,
+            payload["username"].as_str().unwrap_or("unknown user"),
+            connected,
+            remote,
+            payload["remote_port"],
+            payload["protocol"].as_str().unwrap_or("unknown protocol"));
+
+        Some(match self.kind {
+            WebhookKind::Slack => serde_json::json!({ "text": text }),
+            WebhookKind::Teams => serde_json::json!({
+                "@type": "MessageCard",
+                "@context": "http://schema.org/extensions",
+                "summary": "ZeroTrust-Track alert",
+                "title": "ZeroTrust-Track alert",
+                "text": text,
+            }),
+        })
+    }
+}
+
+fn post(client: &reqwest::Client, url: &str, payload: &Value) -> Result<(), String> {
+    let res = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(payload.to_string())
+        .send();
+
+    match res {
+        Err(err) => Err(format!("unable to send to webhook: {}", err)),
+        Ok(mut res) => {
+            match res.status() {
+                StatusCode::OK => {
+                    let _ = res.copy_to(&mut io::sink());
+                    Ok(())
+                },
+                _ => match res.text() {
+                    Err(err) => Err(format!("failed to post to webhook: {}", err)),
+                    Ok(body) => Err(format!("failed to post to webhook: {}", body)),
+                },
+            }
+        }
+    }
+}
+
+impl Webhook {
+    pub fn new(config: &WebhookConfig, http_config: &HttpConfig) -> Result<Webhook, String> {
+        let client = http::client(http_config)?;
+        let mut alerter = Alerter::new(config)?;
+        let url = config.url.clone();
+        let (tx, rx) = queue::<String>();
+
+        let handle = thread::spawn(move || {
+            loop {
+                match rx.recv() {
+                    Ok(message) => {
+                        // A connection which isn't alerted on was delivered as
+                        // far as the webhook goes.
+                        let result = match alerter.alert(&message, Instant::now()) {
+                            Some(alert) => post(&client, &url, &alert).map(|()| info!("sent alert to webhook")),
+                            None => Ok(()),
+                        };
+
+                        if let Err(ref err) = result {
+                            error!("{}", err);
+                        }
+                        rx.delivered(1, &result);
+                    },
+                    Err(err) => {
+                        error!("closing thread: {}", err);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Webhook {
+            worker: Worker::new(tx, handle),
+        })
+    }
+}
+
+impl Output for Webhook {
+    fn process_open_connection(&self, message: &str) -> Result<(), String> {
+        match self.worker.send(message.to_string()) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(String::from("webhook thread has stopped")),
+        }
+    }
+
+    // Only new connections are alerted on.
+    fn process_close_connection(&self, _ : &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    // In close only mode the record is all there is of the open.
+    fn process_connection(&self, message: &str) -> Result<(), String> {
+        self.process_open_connection(message)
+    }
+
+    fn process_alive_connections(&self, _ : &Vec<i64>) { }
+
+    fn queue_latency(&self) -> Option<Arc<LatencyHistogram>> {
+        Some(self.worker.latency())
+    }
+
+    fn confirm(&self) -> Result<(), String> {
+        self.worker.confirm(FLUSH_TIMEOUT)
+    }
+
+    fn flush(&mut self) -> Result<(), String> {
+        match self.worker.flush(FLUSH_TIMEOUT) {
+            true => Ok(()),
+            false => Err(String::from("timed out flushing the webhook output")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_yaml;
+
+    fn alerter(kind: &str) -> Alerter {
+        let config : WebhookConfig = serde_yaml::from_str(&format!(
+            "{{ url: \"http://127.0.0.1:1\", kind: {}, allowed_networks: [ 10.0.0.0/8, 127.0.0.1/32 ] }}", kind)).unwrap();
+        Alerter::new(&config).unwrap()
+    }
+
+    fn open(remote: &str) -> String {
+        format!("{{\"source\":\"192.168.0.2\",\"destination\":\"{0}\",\"direction\":\"outbound\",\"remote_address\":\"{0}\",\"remote_port\":443,\"protocol\":\"TCP\",\"username\":\"root\",\"program_details\":{{\"process_name\":\"curl\"}}}}", remote)
+    }
+
+    #[test]
+    fn test_config() {
+        let config : WebhookConfig = serde_yaml::from_str("{ url: \"http://hooks\", kind: Teams, allowed_networks: [] }").unwrap();
+        assert_eq!(WebhookKind::Teams, config.kind);
+        assert_eq!(300, config.rate_limit);
+    }
+
+    #[test]
+    fn test_invalid_network() {
+        let config : WebhookConfig = serde_yaml::from_str("{ url: \"http://hooks\", kind: Slack, allowed_networks: [ I_am_not_a_network ] }").unwrap();
+        assert!(Alerter::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_slack_alert() {
+        let mut alerter = alerter("Slack");
+        let alert = alerter.alert(&open("8.8.8.8"), Instant::now()).unwrap();
+        assert_eq!(serde_json::json!({ "text": "curl (root) connected to 8.8.8.8:443 over TCP, which is outside of the allowed networks" }), alert);
+    }
+
+    #[test]
+    fn test_teams_alert() {
+        let mut alerter = alerter("Teams");
+        let alert = alerter.alert(&open("8.8.8.8"), Instant::now()).unwrap();
+        assert_eq!("MessageCard", alert["@type"]);
+        assert_eq!("curl (root) connected to 8.8.8.8:443 over TCP, which is outside of the allowed networks", alert["text"]);
+    }
+
+    #[test]
+    fn test_allowed_destination() {
+        let mut alerter = alerter("Slack");
+        assert!(alerter.alert(&open("10.1.2.3"), Instant::now()).is_none());
+        assert!(alerter.alert(&open("127.0.0.1"), Instant::now()).is_none());
+    }
+
+    #[test]
+    fn test_inbound_alert() {
+        let mut alerter = alerter("Slack");
+        let message = "{\"source\":\"8.8.8.8\",\"destination\":\"192.168.0.2\",\"direction\":\"inbound\",\"remote_address\":\"8.8.8.8\",\"remote_port\":51000,\"protocol\":\"TCP\",\"username\":\"www-data\",\"program_details\":{\"process_name\":\"nginx\"}}";
+        let alert = alerter.alert(message, Instant::now()).unwrap();
+        assert_eq!("nginx (www-data) accepted a connection from 8.8.8.8:51000 over TCP, which is outside of the allowed networks", alert["text"]);
+    }
+
+    #[test]
+    fn test_no_remote_end() {
+        let mut alerter = alerter("Slack");
+        assert!(alerter.alert("{\"source\":\"8.8.8.8\",\"destination\":\"8.8.4.4\",\"remote_address\":null}", Instant::now()).is_none());
+    }
+
+    #[test]
+    fn test_rate_limit_pruned() {
+        let mut alerter = alerter("Slack");
+        let now = Instant::now();
+        assert!(alerter.alert(&open("8.8.8.8"), now).is_some());
+        assert!(alerter.alert(&open("8.8.4.4"), now + Duration::from_secs(200)).is_some());
+        assert_eq!(2, alerter.last_alert.len());
+
+        assert!(alerter.alert(&open("1.1.1.1"), now + Duration::from_secs(400)).is_some());
+        assert_eq!(2, alerter.last_alert.len());
+        assert!(!alerter.last_alert.contains_key(&Ipv4Addr::new(8, 8, 8, 8)));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut alerter = alerter("Slack");
+        let now = Instant::now();
+        assert!(alerter.alert(&open("8.8.8.8"), now).is_some());
+        assert!(alerter.alert(&open("8.8.8.8"), now + Duration::from_secs(10)).is_none());
+        assert!(alerter.alert(&open("8.8.4.4"), now + Duration::from_secs(10)).is_some());
+        assert!(alerter.alert(&open("8.8.8.8"), now + Duration::from_secs(301)).is_some());
+    }
+}
@@ -0,0 +1,410 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::sync::{ Arc, Condvar, Mutex };
+use std::sync::mpsc::{ channel, Receiver, RecvError, RecvTimeoutError, Sender };
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{ Duration, Instant };
+use metrics::{ LatencyHistogram };
+
+// A channel which carries when each message was queued, so the thread of an
+// output can record how long messages waited for it.
+pub fn queue<T>() -> (Queue<T>, QueueReceiver<T>) {
+    let (tx, rx) = channel();
+    let latency = Arc::new(LatencyHistogram::new());
+    let delivery = Arc::new(Delivery::default());
+    (Queue { tx, latency: latency.clone(), delivery: delivery.clone() }, QueueReceiver { rx, latency, delivery })
+}
+
+pub struct Queue<T> {
+    tx : Sender<(T, Instant)>,
+    latency : Arc<LatencyHistogram>,
+    delivery : Arc<Delivery>,
+}
+
+impl<T> Queue<T> {
+    pub fn send(&self, message: T) -> Result<(), ()> {
+        self.delivery.queued(1);
+        self.tx.send((message, Instant::now())).map_err(|_| self.delivery.queued_failed())
+    }
+}
+
+// Counts the messages the thread of an output has finished with against the
+// ones queued for it, along with the first error since it was last checked,
+// so a sender can find out whether a message was sent and not only queued.
+#[derive(Default)]
+pub struct Delivery {
+    state : Mutex<DeliveryState>,
+    changed : Condvar,
+}
+
+#[derive(Default)]
+struct DeliveryState {
+    queued : usize,
+    done : usize,
+    error : Option<String>,
+}
+
+impl Delivery {
+    fn queued(&self, count: usize) {
+        self.state.lock().unwrap().queued += count;
+    }
+
+    fn queued_failed(&self) {
+        self.state.lock().unwrap().queued -= 1;
+    }
+
+    // Called by the thread once count messages have been sent, or have
+    // failed to be.
+    pub fn record(&self, count: usize, result: &Result<(), String>) {
+        let mut state = self.state.lock().unwrap();
+        state.done += count;
+        if let Err(ref err) = *result {
+            state.error.get_or_insert_with(|| err.clone());
+        }
+
+        self.changed.notify_all();
+    }
+
+    // Waits for everything queued so far to be recorded.
+    fn wait(&self, timeout: Duration) -> Result<(), String> {
+        let started = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        while state.done < state.queued {
+            let left = match timeout.checked_sub(started.elapsed()) {
+                Some(x) => x,
+                None => return Err(String::from("timed out waiting for the output")),
+            };
+
+            state = self.changed.wait_timeout(state, left).unwrap().0;
+        }
+
+        match state.error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct QueueReceiver<T> {
+    rx : Receiver<(T, Instant)>,
+    latency : Arc<LatencyHistogram>,
+    delivery : Arc<Delivery>,
+}
+
+impl<T> QueueReceiver<T> {
+    fn received(&self, (message, queued): (T, Instant)) -> T {
+        self.latency.record(queued.elapsed());
+        message
+    }
+
+    // Every message received has to be recorded, batches once they are sent.
+    pub fn delivered(&self, count: usize, result: &Result<(), String>) {
+        self.delivery.record(count, result);
+    }
+
+    // For the threads which finish with messages somewhere other than where
+    // they are received.
+    pub fn delivery(&self) -> Arc<Delivery> {
+        self.delivery.clone()
+    }
+
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.rx.recv().map(|queued| self.received(queued))
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        self.rx.recv_timeout(timeout).map(|queued| self.received(queued))
+    }
+}
+
+pub struct QueueIter<T> {
+    rx : QueueReceiver<T>,
+}
+
+impl<T> Iterator for QueueIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.rx.recv().ok()
+    }
+}
+
+impl<T> IntoIterator for QueueReceiver<T> {
+    type Item = T;
+    type IntoIter = QueueIter<T>;
+
+    fn into_iter(self) -> QueueIter<T> {
+        QueueIter { rx: self }
+    }
+}
+
+// The background thread of an output along with the channel feeding it.
+// Dropping the sender lets the thread work through whatever is still queued
+// and then stop, which is what flush waits on.
+//
+// Dropping the worker does the same with a shorter wait, so an output which
+// is replaced on a reload doesn't leave its thread behind.
+pub struct Worker<T> {
+    tx : Option<Queue<T>>,
+    handle : Option<JoinHandle<()>>,
+    latency : Arc<LatencyHistogram>,
+    delivery : Arc<Delivery>,
+}
+
+impl<T> Worker<T> {
+    pub fn new(tx: Queue<T>, handle: JoinHandle<()>) -> Worker<T> {
+        Worker {
+            latency: tx.latency.clone(),
+            delivery: tx.delivery.clone(),
+            tx: Some(tx),
+            handle: Some(handle),
+        }
+    }
+
+    pub fn send(&self, message: T) -> Result<(), ()> {
+        match self.tx {
+            Some(ref tx) => tx.send(message),
+            None => Err(()),
+        }
+    }
+
+    pub fn latency(&self) -> Arc<LatencyHistogram> {
+        self.latency.clone()
+    }
+
+    // Blocks until the thread has finished with everything sent so far and
+    // returns the first error it ran into since the last confirm. Unlike
+    // flush the worker can carry on being used.
+    pub fn confirm(&self, timeout: Duration) -> Result<(), String> {
+        match self.handle {
+            Some(ref handle) if !handle.is_finished() => (),
+            _ => return Err(String::from("output thread has stopped")),
+        }
+
+        self.delivery.wait(timeout)
+    }
+
+    // Returns false if the thread was still busy when the timeout ran out, it
+    // is left to finish on its own.
+    pub fn flush(&mut self, timeout: Duration) -> bool {
+        self.tx.take();
+
+        let handle = match self.handle.take() {
+            Some(x) => x,
+            None => return true,
+        };
+
+        let started = Instant::now();
+        while !handle.is_finished() {
+            if started.elapsed() >= timeout {
+                return false;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let _ = handle.join();
+        true
+    }
+}
+
+// How long a dropped worker waits for its thread, one which is stuck sending
+// is left to finish on its own.
+const DROP_TIMEOUT : Duration = Duration::from_secs(2);
+
+impl<T> Drop for Worker<T> {
+    fn drop(&mut self) {
+        if !self.flush(DROP_TIMEOUT) {
+            warn!("output thread did not stop within {:?}", DROP_TIMEOUT);
+        }
+    }
+}
+
+// How long a batching output holds on to a partial batch, from its first
+// message. The thread waits on the channel for no longer than the batch has
+// left, so on a quiet host the batch goes out once it is due rather than
+// when the next message happens to arrive.
+pub struct Linger {
+    linger : Duration,
+    since : Option<Instant>,
+}
+
+impl Linger {
+    pub fn new(linger: Duration) -> Linger {
+        Linger {
+            linger,
+            since: None,
+        }
+    }
+
+    // With nothing waiting there is nothing to time, so this blocks until a
+    // message arrives or the sender is dropped.
+    pub fn recv<T>(&mut self, rx: &QueueReceiver<T>) -> Result<T, RecvTimeoutError> {
+        let result = match self.since {
+            Some(since) => rx.recv_timeout(self.linger.checked_sub(since.elapsed()).unwrap_or_default()),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        if result.is_ok() && self.since.is_none() {
+            self.since = Some(Instant::now());
+        }
+
+        result
+    }
+
+    pub fn expired(&self) -> bool {
+        self.since.is_some_and(|since| since.elapsed() >= self.linger)
+    }
+
+    // Called once the batch has been sent.
+    pub fn reset(&mut self) {
+        self.since = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{ Arc, Mutex };
+    use std::sync::atomic::{ AtomicBool, Ordering };
+
+    fn slow_worker(delivered: Arc<Mutex<Vec<u32>>>, delay: Duration) -> Worker<u32> {
+        let (tx, rx) = queue();
+        let handle = thread::spawn(move || {
+            for message in rx {
+                thread::sleep(delay);
+                delivered.lock().unwrap().push(message);
+            }
+        });
+
+        Worker::new(tx, handle)
+    }
+
+    #[test]
+    fn test_flush_delivers_queued() {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let mut worker = slow_worker(delivered.clone(), Duration::from_millis(20));
+
+        for message in 0..10 {
+            worker.send(message).unwrap();
+        }
+
+        assert!(worker.flush(Duration::from_secs(10)));
+        assert_eq!((0..10).collect::<Vec<u32>>(), *delivered.lock().unwrap());
+        assert!(worker.send(10).is_err());
+        assert!(worker.flush(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_flush_timeout() {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let mut worker = slow_worker(delivered.clone(), Duration::from_secs(1));
+
+        for message in 0..10 {
+            worker.send(message).unwrap();
+        }
+
+        assert!(!worker.flush(Duration::from_millis(50)));
+        assert!(delivered.lock().unwrap().len() < 10);
+    }
+
+    #[test]
+    fn test_queue_latency() {
+        let delivered = Arc::new(Mutex::new(Vec::new()));
+        let mut worker = slow_worker(delivered.clone(), Duration::from_millis(100));
+        let latency = worker.latency();
+
+        // The second waits behind the first for as long as it takes to send.
+        worker.send(1).unwrap();
+        worker.send(2).unwrap();
+        assert!(worker.flush(Duration::from_secs(10)));
+
+        let snapshot = latency.snapshot();
+        assert_eq!(2, snapshot.count);
+        assert!(snapshot.max_ms >= 90, "max latency was {}ms", snapshot.max_ms);
+    }
+
+    #[test]
+    fn test_confirm() {
+        let (tx, rx) = queue::<u32>();
+        let handle = thread::spawn(move || {
+            while let Ok(message) = rx.recv() {
+                thread::sleep(Duration::from_millis(20));
+                match message {
+                    0 => rx.delivered(1, &Err(String::from("I am unreachable"))),
+                    _ => rx.delivered(1, &Ok(())),
+                }
+            }
+        });
+
+        let mut worker = Worker::new(tx, handle);
+        worker.send(1).unwrap();
+        worker.send(2).unwrap();
+        assert_eq!(Ok(()), worker.confirm(Duration::from_secs(10)));
+
+        // The error is only reported once, and only after the send it was for.
+        worker.send(0).unwrap();
+        worker.send(3).unwrap();
+        assert_eq!(Err(String::from("I am unreachable")), worker.confirm(Duration::from_secs(10)));
+        assert_eq!(Ok(()), worker.confirm(Duration::from_secs(10)));
+
+        assert!(worker.flush(Duration::from_secs(10)));
+        assert!(worker.confirm(Duration::from_secs(10)).is_err());
+    }
+
+    #[test]
+    fn test_drop_stops_thread() {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = queue::<u32>();
+        let thread_stopped = stopped.clone();
+        let handle = thread::spawn(move || {
+            for _ in rx {}
+            thread_stopped.store(true, Ordering::SeqCst);
+        });
+
+        let worker = Worker::new(tx, handle);
+        worker.send(1).unwrap();
+
+        let started = Instant::now();
+        drop(worker);
+        assert!(stopped.load(Ordering::SeqCst));
+        assert!(started.elapsed() < DROP_TIMEOUT);
+    }
+
+    #[test]
+    fn test_linger() {
+        let (tx, rx) = queue();
+        let mut linger = Linger::new(Duration::from_millis(100));
+        assert!(!linger.expired());
+
+        tx.send(1).unwrap();
+        assert_eq!(Ok(1), linger.recv(&rx));
+        assert!(!linger.expired());
+
+        // Nothing else arrives, the wait ends once the batch is due.
+        let started = Instant::now();
+        assert_eq!(Err(RecvTimeoutError::Timeout), linger.recv(&rx));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(linger.expired());
+
+        linger.reset();
+        assert!(!linger.expired());
+        drop(tx);
+        assert_eq!(Err(RecvTimeoutError::Disconnected), linger.recv(&rx));
+    }
+}
@@ -14,20 +14,60 @@
  *
  */
 
-use std::net::Ipv4Addr;
+use std::net::{ IpAddr, Ipv4Addr };
 use std::io;
 use std::thread;
 use std::time;
 use std::u32;
-use std::hash::{Hash, Hasher};
-use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::sync::Arc;
 use users::{Users, UsersCache};
-use proc_chomper::{ProcChomper};
-use enums::{ Protocol, State };
-use proc::{Proc};
+use proc_chomper::{ProcChomper, SocketConnection};
+use enums::{ CloseReason, Direction, Protocol, Resolution, State, StatusFlag };
+use proc;
+use proc::{ Proc, Owner };
+use parser::redact::{ Redactor };
+use interfaces::{ Interfaces };
+use services::{ Services };
+use filters::{ PreFilter };
+use metrics::{ Metrics };
 use conn_track;
 use chrono::prelude::*;
 use uuid::Uuid;
+use rollup::{ RollupSummary };
+
+pub mod pool;
+pub mod redact;
+
+// 64 bit FNV-1a. Unlike DefaultHasher its output is fixed, so a connection
+// hashes the same on every agent, every run and every build. It is only meant
+// for correlating payloads, it is trivial to find collisions for.
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes : &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    // The 0xff can't appear in UTF-8, so the protocol and what follows it
+    // can't run into each other.
+    fn write_str(&mut self, value : &str) {
+        self.write(value.as_bytes());
+        self.write(&[0xff]);
+    }
+
+    fn write_endpoint(&mut self, address : &Ipv4Addr, port : u16) {
+        self.write(&address.octets());
+        self.write(&port.to_be_bytes());
+    }
+}
 
 pub fn generate_hash(
     protocol : &str,
@@ -36,26 +76,93 @@ pub fn generate_hash(
     destination: &Ipv4Addr,
     destination_port: &u16
 ) -> u64 {
-    let mut s = DefaultHasher::new();
-    protocol.hash(&mut s);
-    source.hash(&mut s);
-    source_port.hash(&mut s);
-    destination.hash(&mut s);
-    destination_port.hash(&mut s);
-    s.finish()
+    let mut s = Fnv::new();
+    s.write_str(protocol);
+    s.write_endpoint(source, *source_port);
+    s.write_endpoint(destination, *destination_port);
+    s.0
 }
 
+// The source, source port, destination and destination port of a tuple.
+pub type Endpoints = (Ipv4Addr, u16, Ipv4Addr, u16);
+
+fn canonical(tuple : Endpoints) -> Endpoints {
+    let reversed = (tuple.2, tuple.3, tuple.0, tuple.1);
+    if reversed < tuple {
+        reversed
+    } else {
+        tuple
+    }
+}
+
+// The protocol and ends of a connection, as the deterministic uuid sees them.
+pub struct ConnectionTuple<'a> {
+    pub protocol : &'a str,
+    pub source : Ipv4Addr,
+    pub source_port : u16,
+    pub destination : Ipv4Addr,
+    pub destination_port : u16,
+}
+
+// The same connection opened within the same bucket of seconds always gets
+// the same uuid under the same namespace, a bucket of 0 leaves the time out.
+pub fn connection_uuid(namespace : &Uuid, tuple : &ConnectionTuple, timestamp : i64, bucket : u64) -> Uuid {
+    let bucket = match bucket {
+        0 => 0,
+        bucket => timestamp.div_euclid(bucket as i64),
+    };
+
+    let name = format!("{}|{}:{}|{}:{}|{}", tuple.protocol, tuple.source, tuple.source_port, tuple.destination, tuple.destination_port, bucket);
+    Uuid::new_v5(namespace, name.as_bytes())
+}
+
+// Identifies the logical flow behind a conntrack entry, with hairpin NAT the
+// same flow is seen twice with the original and reply tuples swapped around,
+// so the pair is made independent of order and direction before hashing.
+pub fn generate_flow_hash(protocol : &str, orig : Endpoints, reply : Option<Endpoints>) -> u64 {
+    let orig = canonical(orig);
+    let reply = canonical(reply.unwrap_or(orig));
+    let (first, second) = if orig <= reply { (orig, reply) } else { (reply, orig) };
+
+    let mut s = Fnv::new();
+    s.write_str(protocol);
+    for tuple in &[first, second] {
+        s.write_endpoint(&tuple.0, tuple.1);
+        s.write_endpoint(&tuple.2, tuple.3);
+    }
+    s.0
+}
+
+// Bumped whenever the fields of the emitted payloads or the server messages
+// change.
+pub const SCHEMA_VERSION : u32 = 23;
 
 #[derive(Debug, Serialize)]
+#[serde(tag = "event_type", rename_all = "lowercase")]
 pub enum Payload {
     Open(OpenConnection),
     Close(CloseConnection),
+    #[serde(rename = "listen-open")]
+    ListenOpen(ListenEvent),
+    #[serde(rename = "listen-close")]
+    ListenClose(ListenEvent),
+    Rollup(RollupSummary),
+    Connection(ConnectionRecord),
+    // Only ever counted by the state, it is never sent.
+    #[serde(skip)]
+    Update(UpdateConnection),
 }
 
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Clone)]
 pub struct OpenConnection {
     pub hash: i64,
+    #[serde(skip_serializing)]
+    pub flow: i64,
+    pub schema_version: u32,
+    // Counts up from 1 in the order the payloads are emitted, restarting with
+    // the agent.
+    pub seq: u64,
     pub uuid : Uuid,
     pub agent: Uuid,
     pub timestamp : String,
@@ -64,14 +171,83 @@ pub struct OpenConnection {
     pub destination : Ipv4Addr,
     pub source_port : u16,
     pub destination_port : u16,
+    // Which of the ends is this host, the source and destination are always
+    // the ones conntrack saw first. None when no socket was found.
+    pub direction : Option<Direction>,
+    // The same ends again as this host and the other side, so they don't
+    // have to be worked out from the direction. None when neither end is
+    // this host, such as traffic being forwarded.
+    pub local_address : Option<Ipv4Addr>,
+    pub local_port : Option<u16>,
+    pub remote_address : Option<Ipv4Addr>,
+    pub remote_port : Option<u16>,
     pub username : String,
-    pub uid : u16,
+    // False when the uid has no user, the username is then empty.
+    pub username_resolved : bool,
+    pub uid : u32,
     pub program_details : Option<Program>,
+    // True when the connection wasn't picked for attribution, so there is no
+    // socket owner or process, see attribution_rate.
+    pub attribution_skipped : bool,
+    // The conntrack status bits when the connection was seen, such as whether
+    // a reply has come back yet or NAT was applied.
+    pub status_flags : Vec<StatusFlag>,
+    pub interface : Option<String>,
+    pub destination_service : Option<String>,
+    // Whether the process has connected to the destination before, only set
+    // when the baseline is enabled.
+    pub novel : Option<bool>,
+    // Found in a dump of the conntrack table, such as the one when the agent
+    // starts, rather than seen opening.
+    pub pre_existing : bool,
+}
+
+impl OpenConnection {
+    // The address and port on this host.
+    pub fn local(&self) -> (Ipv4Addr, u16) {
+        match self.direction {
+            Some(Direction::Inbound) => (self.destination, self.destination_port),
+            _ => (self.source, self.source_port),
+        }
+    }
+
+    pub fn remote(&self) -> (Ipv4Addr, u16) {
+        match self.direction {
+            Some(Direction::Inbound) => (self.source, self.source_port),
+            _ => (self.destination, self.destination_port),
+        }
+    }
+
+    // Fills in the local and remote ends, from the direction when a socket
+    // was found and otherwise from which end has an address of this host.
+    pub fn set_ends(&mut self, source_is_local : bool, destination_is_local : bool) {
+        let source_local = match self.direction {
+            Some(Direction::Outbound) => true,
+            Some(Direction::Inbound) => false,
+            None if source_is_local => true,
+            None if destination_is_local => false,
+            None => return,
+        };
+
+        let (local, remote) = match source_local {
+            true => ((self.source, self.source_port), (self.destination, self.destination_port)),
+            false => ((self.destination, self.destination_port), (self.source, self.source_port)),
+        };
+
+        self.local_address = Some(local.0);
+        self.local_port = Some(local.1);
+        self.remote_address = Some(remote.0);
+        self.remote_port = Some(remote.1);
+    }
 }
 
 #[derive(Debug, Serialize)]
 pub struct CloseConnection {
     pub hash: i64,
+    #[serde(skip_serializing)]
+    pub flow: i64,
+    pub schema_version: u32,
+    pub seq: u64,
     pub agent: Uuid,
     pub uuid: Option<Uuid>,
     pub timestamp : String,
@@ -80,14 +256,315 @@ pub struct CloseConnection {
     pub destination : Ipv4Addr,
     pub source_port : u16,
     pub destination_port : u16,
+    // How long the connection was open for, only known when the close was
+    // matched up with its open.
+    pub duration_ms: Option<u64>,
+    // From the status conntrack had for it, None when the destroy event
+    // didn't carry one.
+    pub close_reason: Option<CloseReason>,
+    pub status_flags: Vec<StatusFlag>,
+    // How many times conntrack told us of the connection again while it was
+    // open, zero when it wasn't matched up with its open.
+    pub update_count: u32,
+}
+
+// Conntrack telling us of a connection again while it is open, it is only
+// counted against the open so there is nothing to look up for it.
+#[derive(Debug)]
+pub struct UpdateConnection {
+    pub hash: i64,
+    pub flow: i64,
 }
 
+// A whole connection, sent once it has closed rather than an open and a close
+// when the emit mode is CloseOnly.
 #[derive(Debug, Serialize)]
+pub struct ConnectionRecord {
+    #[serde(flatten)]
+    pub open : OpenConnection,
+    // When it closed, None when it was still open after the emit ttl or when
+    // the agent stopped.
+    pub closed : Option<String>,
+    pub duration_ms : Option<u64>,
+    pub close_reason : Option<CloseReason>,
+    // The open has its own status_flags, these are the ones at the close.
+    pub closed_status_flags : Vec<StatusFlag>,
+    pub update_count : u32,
+}
+
+// A process starting or stopping to listen on a port, see the listeners module.
+#[derive(Debug, Serialize)]
+pub struct ListenEvent {
+    pub schema_version: u32,
+    pub seq: u64,
+    pub agent: Uuid,
+    pub timestamp : String,
+    pub protocol : Protocol,
+    pub address : IpAddr,
+    pub port : u16,
+    pub username : String,
+    pub uid : u32,
+    pub program_details : Option<Program>,
+}
+
+#[derive(Debug, Serialize, Clone)]
 pub struct Program {
     pub inode: u32,
     pub pid: u32,
     pub process_name : String,
     pub command_line : Vec<String>,
+    // Whether arguments were dropped or cut short to keep within the limits.
+    pub command_line_truncated : bool,
+    pub environment : Option<BTreeMap<String, String>>,
+    pub fd_count : Option<u32>,
+    pub process_is_privileged : Option<bool>,
+    pub resolution : Resolution,
+    // Where the process is in the cgroup hierarchy, only read when asked for.
+    pub cgroup_path : Option<String>,
+}
+
+impl Program {
+    // A process known by its name alone, the rest is filled in as it is
+    // read.
+    pub fn new(inode: u32, pid: u32, process_name: String, resolution: Resolution) -> Program {
+        Program {
+            inode,
+            pid,
+            process_name,
+            command_line: Vec::new(),
+            command_line_truncated: false,
+            environment: None,
+            fd_count: None,
+            process_is_privileged: None,
+            resolution,
+            cgroup_path: None,
+        }
+    }
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+fn default_attribution_rate() -> f64 {
+    1.0
+}
+
+fn default_max_command_line_args() -> usize {
+    64
+}
+
+fn default_max_command_line_length() -> usize {
+    4096
+}
+
+fn default_uuid_bucket() -> u64 {
+    60
+}
+
+fn default_attribution_max_wait_ms() -> u64 {
+    100
+}
+
+fn default_attribution_poll_ms() -> u64 {
+    2
+}
+
+// Whether an open gets a random uuid or one worked out from the connection,
+// so others seeing the same connection can come up with the same one.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum UuidMode {
+    #[default]
+    Random,
+    Deterministic,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ParserConfig {
+    // Environ can contain secrets, so it is only read when asked for and only
+    // the variables whose key starts with one of the prefixes are kept.
+    #[serde(default)]
+    pub include_environment : bool,
+    #[serde(default)]
+    pub environment_keys : Vec<String>,
+    // Adds the cgroup path of the process, to tie it to its container.
+    #[serde(default)]
+    pub include_cgroup : bool,
+    // Number of threads doing the process and user attribution.
+    #[serde(default = "default_workers")]
+    pub workers : usize,
+    // Adds the well known service name of the destination port.
+    #[serde(default)]
+    pub resolve_services : bool,
+    // When the owner of a socket has exited put the connection down to its
+    // parent, or the name the owner had, rather than no process at all.
+    #[serde(default)]
+    pub follow_parents : bool,
+    // Some command lines are enormous, anything past these is dropped, 0
+    // turns the limit off.
+    #[serde(default = "default_max_command_line_args")]
+    pub max_command_line_args : usize,
+    #[serde(default = "default_max_command_line_length")]
+    pub max_command_line_length : usize,
+    // Regular expressions for credentials, what they match in the command
+    // line or environment is replaced with ***.
+    #[serde(default)]
+    pub redact : Vec<String>,
+    // The fraction of connections, from 0.0 to 1.0, which are matched to
+    // their socket and process. The rest are still sent without them.
+    #[serde(default = "default_attribution_rate")]
+    pub attribution_rate : f64,
+    // The socket table can be behind conntrack, so a socket without its inode
+    // yet is read again every poll until max_wait is used up, after which the
+    // connection is sent without its process.
+    #[serde(default = "default_attribution_max_wait_ms")]
+    pub attribution_max_wait_ms : u64,
+    #[serde(default = "default_attribution_poll_ms")]
+    pub attribution_poll_ms : u64,
+    #[serde(default)]
+    pub uuid_mode : UuidMode,
+    // Deterministic uuids are made under this, the agent uuid when it isn't
+    // set, along with the seconds the open was in bucketed by uuid_bucket.
+    #[serde(default)]
+    pub uuid_namespace : Option<Uuid>,
+    #[serde(default = "default_uuid_bucket")]
+    pub uuid_bucket : u64,
+}
+
+impl Default for ParserConfig {
+    fn default() -> ParserConfig {
+        ParserConfig {
+            include_environment: false,
+            environment_keys: Vec::new(),
+            include_cgroup: false,
+            workers: default_workers(),
+            resolve_services: false,
+            follow_parents: false,
+            max_command_line_args: default_max_command_line_args(),
+            max_command_line_length: default_max_command_line_length(),
+            redact: Vec::new(),
+            attribution_rate: default_attribution_rate(),
+            attribution_max_wait_ms: default_attribution_max_wait_ms(),
+            attribution_poll_ms: default_attribution_poll_ms(),
+            uuid_mode: UuidMode::default(),
+            uuid_namespace: None,
+            uuid_bucket: default_uuid_bucket(),
+        }
+    }
+}
+
+// Running as root, going by the effective uid of the process when its status
+// can be read and the owner of the socket when it can't.
+fn process_is_privileged(euid: Option<i32>, socket_uid: u32) -> Option<bool> {
+    match euid {
+        Some(euid) => Some(euid == 0),
+        None => Some(socket_uid == 0),
+    }
+}
+
+// The name of the user with the uid, and whether there is one, as an empty
+// name is otherwise indistinguishable from a user which wasn't found.
+pub fn resolve_username(user_cache: &UsersCache, uid: u32) -> (String, bool) {
+    match user_cache.get_user_by_uid(uid) {
+        Some(user) => (user.name().to_string(), true),
+        None => (String::new(), false),
+    }
+}
+
+// Calls find until what it finds is ready, the socket table can be behind
+// conntrack so a socket can turn up before its inode does. What was found
+// last is returned once max_wait is used up, and None as soon as nothing is.
+fn find_within<T, F, R>(mut find : F, ready : R, max_wait : time::Duration, poll : time::Duration) -> Option<T>
+    where F: FnMut() -> Option<T>, R: Fn(&T) -> bool
+{
+    let started = time::Instant::now();
+    loop {
+        let found = find()?;
+        if ready(&found) || started.elapsed() + poll > max_wait {
+            return Some(found);
+        }
+
+        thread::sleep(poll);
+    }
+}
+
+// Whether the connection is one of those picked for attribution, going by its
+// hash so the open and close of a connection are picked together.
+fn attribution_sampled(hash: i64, rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+
+    (hash as u64 % 10_000) < (rate.max(0.0) * 10_000.0) as u64
+}
+
+// Keeps the first max_args arguments and the first max_length bytes of them,
+// saying whether anything was dropped.
+fn truncate_command_line(mut command_line: Vec<String>, max_args: usize, max_length: usize) -> (Vec<String>, bool) {
+    let mut truncated = false;
+    if max_args > 0 && command_line.len() > max_args {
+        command_line.truncate(max_args);
+        truncated = true;
+    }
+
+    if max_length == 0 {
+        return (command_line, truncated);
+    }
+
+    let mut remaining = max_length;
+    for (index, arg) in command_line.iter_mut().enumerate() {
+        if arg.len() > remaining {
+            let mut end = remaining;
+            while !arg.is_char_boundary(end) {
+                end -= 1;
+            }
+            arg.truncate(end);
+            command_line.truncate(index + 1);
+            return (command_line, true);
+        }
+        remaining -= arg.len();
+    }
+
+    (command_line, truncated)
+}
+
+// The process which owns the socket with the inode.
+pub fn program_details(proc: &mut Proc, inode: u32, uid: u32, config: &ParserConfig, redactor: &Redactor) -> Option<Program> {
+    let (process, resolution) = match proc.owner(inode, config.follow_parents)? {
+        Owner::Found(process, resolution) => (process, resolution),
+        Owner::Exited(pid, process_name) => return Some(Program::new(inode, pid as u32, process_name, Resolution::Unknown)),
+    };
+    let pid : u32 = process.stat.pid as u32;
+    let process_name = process.stat.comm.clone();
+    // Redacted first so a credential isn't cut short before it can match.
+    let (command_line, command_line_truncated) = truncate_command_line(
+        redactor.command_line(process.cmdline().unwrap_or_default()),
+        config.max_command_line_args,
+        config.max_command_line_length,
+    );
+    let environment = match config.include_environment {
+        true => proc::environment(process.pid(), &config.environment_keys).map(|environment| redactor.environment(environment)),
+        false => None,
+    };
+    let cgroup_path = match config.include_cgroup {
+        true => proc::cgroup_path(process.pid()),
+        false => None,
+    };
+    let fd_count = proc.fd_count(process.pid());
+    let euid = process.status().ok().map(|status| status.euid);
+
+    Some(Program {
+        inode,
+        pid,
+        process_name,
+        command_line,
+        command_line_truncated,
+        environment,
+        fd_count,
+        process_is_privileged: process_is_privileged(euid, uid),
+        resolution,
+        cgroup_path,
+    })
 }
 
 pub struct Parser {
@@ -95,32 +572,110 @@ pub struct Parser {
     tcp_chomper : ProcChomper,
     udp_chomper : ProcChomper,
     proc: Proc,
+    interfaces: Interfaces,
+    services: Option<Services>,
+    pre_filter: PreFilter,
+    redactor: Redactor,
+    // Number of connections which went through process attribution.
+    attributed: usize,
+    metrics: Arc<Metrics>,
     agent : Uuid,
+    config : ParserConfig,
 }
 
 impl Parser {
-    pub fn new(agent : Uuid) -> Result<Parser, io::Error> {
+    pub fn new(agent : Uuid, config : ParserConfig, pre_filter : PreFilter, redactor : Redactor, metrics : Arc<Metrics>) -> Result<Parser, io::Error> {
         let tcp_chomper = ProcChomper::new(Protocol::TCP)?;
         let udp_chomper = ProcChomper::new(Protocol::UDP)?;
         let user_cache = UsersCache::new();
         let proc = Proc::new()?;
+        let interfaces = Interfaces::new();
+        let services = match config.resolve_services {
+            true => Some(Services::new()),
+            false => None,
+        };
 
         Ok(Parser {
             user_cache,
             tcp_chomper,
             udp_chomper,
             proc,
+            interfaces,
+            services,
+            pre_filter,
+            redactor,
+            attributed: 0,
+            metrics,
             agent,
+            config,
         })
     }
 
     pub fn parse(&mut self, con : conn_track::Connection) -> Option<Payload> {
         let state = con.state;
+        let reply = match con.reply {
+            Some(conn_track::ConnectionDetails { source, destination, protocol: conn_track::ProtoDetails::IP { source_port, destination_port, .. } }) =>
+                Some((source, source_port, destination, destination_port)),
+            _ => None,
+        };
 
         match con.details.protocol {
-            conn_track::ProtoDetails::IP{ protocol, source_port, destination_port } => self.parse_ip_connection(state, protocol, con.details.source, con.details.destination, source_port, destination_port),
+            conn_track::ProtoDetails::IP{ protocol, source_port, destination_port } => {
+                if let Some(rule) = self.pre_filter.apply(&con.details.source, &con.details.destination, destination_port) {
+                    self.metrics.connection_filtered(rule);
+                    return None;
+                }
+
+                let flow = generate_flow_hash(
+                    &protocol.to_string(),
+                    (con.details.source, source_port, con.details.destination, destination_port),
+                    reply) as i64;
+
+                match state {
+                    State::Update => {
+                        let hash = generate_hash(
+                            &protocol.to_string(),
+                            &con.details.source,
+                            &source_port,
+                            &con.details.destination,
+                            &destination_port) as i64;
+                        return Some(Payload::Update(UpdateConnection { hash, flow }));
+                    },
+                    State::Unknown => {
+                        sampled_trace!("unknown conntrack message, dropping it");
+                        return None;
+                    },
+                    _ => (),
+                }
+
+                let mut payload = self.parse_ip_connection(state, protocol, con.details.source, con.details.destination, source_port, destination_port);
+                match payload {
+                    Some(Payload::Open(ref mut connection)) => {
+                        connection.flow = flow;
+                        connection.pre_existing = con.pre_existing;
+                        connection.status_flags = con.status.map(conn_track::status_flags).unwrap_or_default();
+                        let source_interface = self.interfaces.find(&connection.source);
+                        let destination_interface = self.interfaces.find(&connection.destination);
+                        connection.set_ends(source_interface.is_some(), destination_interface.is_some());
+                        connection.interface = source_interface.or(destination_interface);
+                        connection.destination_service = self.services.as_ref()
+                            .and_then(|services| services.find(&connection.protocol, connection.destination_port));
+                    },
+                    Some(Payload::Close(ref mut connection)) => {
+                        connection.flow = flow;
+                        if let Some(status) = con.status {
+                            connection.close_reason = Some(conn_track::close_reason(status));
+                            connection.status_flags = conn_track::status_flags(status);
+                        }
+                    },
+                    _ => (),
+                };
+
+                payload
+            },
             _ => {
-                trace!("protocol isn't IP, dropping it");
+                sampled_trace!("protocol isn't IP, dropping it");
+                self.metrics.protocol_unsupported();
                 None
             },
         }
@@ -132,67 +687,81 @@ impl Parser {
             Protocol::TCP => &self.tcp_chomper,
         };
 
-        let mut inode = 0;
+        // This is used to tie connections together.
+        let hash =  generate_hash(
+            &protocol.to_string(),
+            &source,
+            &source_port,
+            &destination,
+            &destination_port) as i64;
+
+        // Connections which weren't picked skip the lookups, as though no
+        // socket was found for them.
+        let attribution_skipped = !attribution_sampled(hash, self.config.attribution_rate);
+        let mut inode = <u32>::max_value();
         let mut uid = 0;
         let mut username = String::new();
+        let mut username_resolved = false;
+        let mut direction = None;
+
+        if !attribution_skipped {
+            self.attributed += 1;
 
-        while inode == 0 {
-            let _ = chomper.update();
-            if let Some(connection) = chomper.find(&source, source_port) {
-                inode = connection.inode;
+            let max_wait = time::Duration::from_millis(self.config.attribution_max_wait_ms);
+            let poll = time::Duration::from_millis(self.config.attribution_poll_ms);
+            let found = find_within(
+                || {
+                    let _ = chomper.update();
+                    chomper.find(&source, source_port, &destination, destination_port)
+                },
+                |found : &(SocketConnection, Direction)| found.0.inode != 0,
+                max_wait,
+                poll);
+
+            if let Some((connection, found)) = found {
                 uid = connection.uid;
-                if let Some(user) = self.user_cache.get_user_by_uid(uid as u32) {
-                    username = user.name().to_string();
-                }
+                direction = Some(found);
+                let resolved = resolve_username(&self.user_cache, uid);
+                username = resolved.0;
+                username_resolved = resolved.1;
 
-                if inode == 0 {
-                    // We're too quick the socket table hasn't been updated yet.
-                    thread::sleep(time::Duration::from_millis(2));
+                match connection.inode {
+                    0 => sampled_debug!("socket had no inode within {:?}, sending without the process", max_wait),
+                    x => inode = x,
                 }
-            } else {
-                inode = <u32>::max_value();
             }
         }
 
         let program_details = match inode == <u32>::max_value() {
             true => None,
-            false => {
-                match self.proc.get(inode) {
-                    Some(process) => {
-                        let pid : u32 = process.stat.pid as u32;
-                        let process_name = process.stat.comm.clone();
-                        let command_line = process.cmdline().unwrap();
-
-                        Some(Program {
-                            inode,
-                            pid,
-                            process_name,
-                            command_line
-                        })
-                    },
-                    None => {
-                        None
-                    }
-                }
-            }
+            false => program_details(&mut self.proc, inode, uid, &self.config, &self.redactor),
         };
 
-        let timestamp = Utc::now().to_rfc3339();
+        let now = Utc::now();
+        let timestamp = now.to_rfc3339();
 
-        // This is used to tie connections together.
-        let hash =  generate_hash(
-            &protocol.to_string(),
-            &source,
-            &source_port,
-            &destination,
-            &destination_port) as i64;
-
-        let uuid = Uuid::new_v4();
+        let uuid = match self.config.uuid_mode {
+            UuidMode::Random => Uuid::new_v4(),
+            UuidMode::Deterministic => connection_uuid(
+                self.config.uuid_namespace.as_ref().unwrap_or(&self.agent),
+                &ConnectionTuple {
+                    protocol: &protocol.to_string(),
+                    source,
+                    source_port,
+                    destination,
+                    destination_port,
+                },
+                now.timestamp(),
+                self.config.uuid_bucket),
+        };
         let agent = self.agent.clone();
         let payload = match state {
             State::New => Some(
                 Payload::Open(OpenConnection {
                     hash,
+                    flow: hash,
+                    schema_version: SCHEMA_VERSION,
+                    seq: 0,
                     uuid,
                     agent,
                     timestamp,
@@ -201,13 +770,28 @@ impl Parser {
                     destination,
                     source_port,
                     destination_port,
+                    direction,
+                    local_address: None,
+                    local_port: None,
+                    remote_address: None,
+                    remote_port: None,
                     username,
+                    username_resolved,
                     uid,
                     program_details,
+                    attribution_skipped,
+                    status_flags: Vec::new(),
+                    interface: None,
+                    destination_service: None,
+                    novel: None,
+                    pre_existing: false,
                 })),
             State::Destroy => Some(
                 Payload::Close(CloseConnection {
                     hash,
+                    flow: hash,
+                    schema_version: SCHEMA_VERSION,
+                    seq: 0,
                     uuid: None,
                     agent,
                     timestamp,
@@ -216,6 +800,10 @@ impl Parser {
                     destination,
                     source_port,
                     destination_port,
+                    duration_ms: None,
+                    close_reason: None,
+                    status_flags: Vec::new(),
+                    update_count: 0,
                 })),
             _ => None,
         };
@@ -225,3 +813,422 @@ impl Parser {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+    use filters::{ FiltersConfig };
+    use test_support::{ default_filters, default_open_payload };
+    use uuid::Version;
+
+    fn connection(destination_port : u16) -> conn_track::Connection {
+        conn_track::Connection {
+            state: State::New,
+            details: conn_track::ConnectionDetails {
+                source: Ipv4Addr::new(127, 0, 0, 1),
+                destination: Ipv4Addr::new(127, 0, 0, 1),
+                protocol: conn_track::ProtoDetails::IP {
+                    protocol: Protocol::TCP,
+                    source_port: 40000,
+                    destination_port,
+                },
+            },
+            reply: None,
+            status: None,
+            pre_existing: false,
+        }
+    }
+
+    #[test]
+    fn test_pre_filter_skips_attribution() {
+        let pre_filter = PreFilter::new(&FiltersConfig {
+            non_process_connections: false,
+            dns_requests: false,
+            zerotrust_track_connections: false,
+            ignore_ports: vec!(8080),
+            .. default_filters()
+        }).unwrap();
+        let metrics = Arc::new(Metrics::new());
+        let mut parser = Parser::new(Uuid::new_v4(), ParserConfig::default(), pre_filter, Redactor::default(), metrics.clone()).unwrap();
+
+        assert!(parser.parse(connection(8080)).is_none());
+        assert_eq!(0, parser.attributed);
+        assert_eq!(1, metrics.filtered_connections());
+
+        assert!(parser.parse(connection(8081)).is_some());
+        assert_eq!(1, parser.attributed);
+    }
+
+    #[test]
+    fn test_attribution_rate() {
+        let rate = |attribution_rate| ParserConfig { attribution_rate, .. ParserConfig::default() };
+
+        let mut parser = Parser::new(Uuid::new_v4(), rate(0.0), PreFilter::default(), Redactor::default(), Arc::new(Metrics::new())).unwrap();
+        for port in 8081..8091 {
+            match parser.parse(connection(port)) {
+                Some(Payload::Open(open)) => {
+                    assert!(open.program_details.is_none());
+                    assert!(open.attribution_skipped);
+                },
+                _ => panic!("expected an open"),
+            }
+        }
+        assert_eq!(0, parser.attributed);
+
+        let mut parser = Parser::new(Uuid::new_v4(), rate(1.0), PreFilter::default(), Redactor::default(), Arc::new(Metrics::new())).unwrap();
+        for port in 8081..8091 {
+            match parser.parse(connection(port)) {
+                Some(Payload::Open(open)) => assert!(!open.attribution_skipped),
+                _ => panic!("expected an open"),
+            }
+        }
+        assert_eq!(10, parser.attributed);
+
+        // Picked by the hash, so about the rate of them and always the same ones.
+        let picked = (0..10_000).filter(|hash| attribution_sampled(*hash * 7919, 0.25)).count();
+        assert!(picked > 2000 && picked < 3000, "{} picked", picked);
+        assert_eq!(attribution_sampled(12345, 0.5), attribution_sampled(12345, 0.5));
+    }
+
+    #[test]
+    fn test_find_within_gives_up() {
+        let max_wait = time::Duration::from_millis(50);
+        let poll = time::Duration::from_millis(5);
+
+        // A socket whose inode never turns up is given back once the wait is used up.
+        let mut tries = 0;
+        let started = time::Instant::now();
+        let found = find_within(|| { tries += 1; Some(0u32) }, |inode| *inode != 0, max_wait, poll);
+        assert_eq!(Some(0), found);
+        assert!(started.elapsed() < max_wait + time::Duration::from_millis(25), "took {:?}", started.elapsed());
+        assert!(tries > 1);
+
+        let mut tries = 0;
+        assert_eq!(Some(7), find_within(|| { tries += 1; Some(7u32) }, |inode| *inode != 0, max_wait, poll));
+        assert_eq!(1, tries);
+
+        let started = time::Instant::now();
+        assert_eq!(None, find_within(|| None::<u32>, |inode| *inode != 0, max_wait, poll));
+        assert!(started.elapsed() < poll);
+    }
+
+    #[test]
+    fn test_update_skips_attribution() {
+        let mut parser = Parser::new(Uuid::new_v4(), ParserConfig::default(), PreFilter::default(), Redactor::default(), Arc::new(Metrics::new())).unwrap();
+
+        let open = match parser.parse(connection(8081)) {
+            Some(Payload::Open(open)) => open,
+            _ => panic!("expected an open"),
+        };
+
+        match parser.parse(conn_track::Connection { state: State::Update, .. connection(8081) }) {
+            Some(Payload::Update(update)) => {
+                assert_eq!(open.hash, update.hash);
+                assert_eq!(open.flow, update.flow);
+            },
+            _ => panic!("expected an update"),
+        }
+        assert!(parser.parse(conn_track::Connection { state: State::Unknown, .. connection(8081) }).is_none());
+        assert_eq!(1, parser.attributed);
+    }
+
+    #[test]
+    fn test_agent_on_payloads() {
+        let agent = Uuid::new_v4();
+        let mut parser = Parser::new(agent, ParserConfig::default(), PreFilter::default(), Redactor::default(), Arc::new(Metrics::new())).unwrap();
+
+        match parser.parse(connection(8081)) {
+            Some(Payload::Open(open)) => assert_eq!(agent, open.agent),
+            _ => panic!("expected an open"),
+        }
+
+        let close = conn_track::Connection { state: State::Destroy, .. connection(8081) };
+        match parser.parse(close) {
+            Some(Payload::Close(close)) => assert_eq!(agent, close.agent),
+            _ => panic!("expected a close"),
+        }
+    }
+
+    #[test]
+    fn test_status_flags() {
+        let mut parser = Parser::new(Uuid::new_v4(), ParserConfig::default(), PreFilter::default(), Redactor::default(), Arc::new(Metrics::new())).unwrap();
+
+        // Confirmed and source NATed, but nothing has come back yet.
+        let open = conn_track::Connection { status: Some(0b1_1000), .. connection(8081) };
+        match parser.parse(open) {
+            Some(Payload::Open(open)) => {
+                assert_eq!(vec!(StatusFlag::Confirmed, StatusFlag::SrcNat), open.status_flags);
+                assert_eq!(serde_json::json!(["confirmed", "src_nat"]), serde_json::to_value(&open.status_flags).unwrap());
+            },
+            _ => panic!("expected an open"),
+        }
+
+        let close = conn_track::Connection { state: State::Destroy, status: Some(0b1_1110), .. connection(8081) };
+        match parser.parse(close) {
+            Some(Payload::Close(close)) => assert_eq!(Some(CloseReason::Assured), close.close_reason),
+            _ => panic!("expected a close"),
+        }
+
+        match parser.parse(connection(8082)) {
+            Some(Payload::Open(open)) => assert!(open.status_flags.is_empty()),
+            _ => panic!("expected an open"),
+        }
+    }
+
+    #[test]
+    fn test_pre_existing() {
+        let mut parser = Parser::new(Uuid::new_v4(), ParserConfig::default(), PreFilter::default(), Redactor::default(), Arc::new(Metrics::new())).unwrap();
+
+        let dumped = conn_track::Connection { pre_existing: true, .. connection(8081) };
+        match parser.parse(dumped) {
+            Some(Payload::Open(open)) => {
+                assert!(open.pre_existing);
+                assert_eq!(true, serde_json::to_value(Payload::Open(open)).unwrap()["pre_existing"]);
+            },
+            _ => panic!("expected an open"),
+        }
+
+        match parser.parse(connection(8082)) {
+            Some(Payload::Open(open)) => assert!(!open.pre_existing),
+            _ => panic!("expected an open"),
+        }
+    }
+
+    #[test]
+    fn test_unsupported_protocol_counted() {
+        let metrics = Arc::new(Metrics::new());
+        let mut parser = Parser::new(Uuid::new_v4(), ParserConfig::default(), PreFilter::default(), Redactor::default(), metrics.clone()).unwrap();
+        let con = conn_track::Connection {
+            details: conn_track::ConnectionDetails {
+                protocol: conn_track::ProtoDetails::NotSupported,
+                .. connection(22).details
+            },
+            .. connection(22)
+        };
+
+        assert!(parser.parse(con).is_none());
+        assert_eq!(1, metrics.unsupported_protocol());
+        assert_eq!(0, metrics.parse_errors());
+        assert_eq!(0, metrics.filtered_connections());
+        assert_eq!(0, parser.attributed);
+    }
+
+    #[test]
+    fn test_hash_is_stable() {
+        // Agents on other machines and later versions have to agree on this.
+        let hash = generate_hash("TCP", &Ipv4Addr::new(192, 168, 1, 10), &40000, &Ipv4Addr::new(93, 184, 216, 34), &443);
+        assert_eq!(10094357086824799505, hash);
+
+        let other = generate_hash("UDP", &Ipv4Addr::new(192, 168, 1, 10), &40000, &Ipv4Addr::new(93, 184, 216, 34), &443);
+        assert!(hash != other);
+    }
+
+    #[test]
+    fn test_flow_hash_hairpin_halves() {
+        let client = Ipv4Addr::new(192, 168, 1, 10);
+        let public = Ipv4Addr::new(203, 0, 113, 1);
+        let server = Ipv4Addr::new(192, 168, 1, 20);
+
+        let first = generate_flow_hash("TCP", (client, 40000, public, 80), Some((server, 80, public, 40000)));
+        let second = generate_flow_hash("TCP", (server, 80, public, 40000), Some((client, 40000, public, 80)));
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_flow_hash_without_nat() {
+        let client = Ipv4Addr::new(192, 168, 1, 10);
+        let server = Ipv4Addr::new(192, 168, 1, 20);
+
+        let with_reply = generate_flow_hash("TCP", (client, 40000, server, 80), Some((server, 80, client, 40000)));
+        let without_reply = generate_flow_hash("TCP", (client, 40000, server, 80), None);
+        let other = generate_flow_hash("TCP", (client, 40001, server, 80), None);
+
+        assert_eq!(with_reply, without_reply);
+        assert!(with_reply != other);
+    }
+
+    #[test]
+    fn test_uuid_v5() {
+        // The example from the uuid crate, which Python's uuid5 agrees with.
+        assert_eq!("c66bbb60-d62e-5f17-a399-3a0bd237c503", Uuid::new_v5(&Uuid::parse_str("6ba7b810-9dad-11d1-80b4-00c04fd430c8").unwrap(), b"rust-lang.org").to_string());
+    }
+
+    #[test]
+    fn test_connection_uuid_deterministic() {
+        let namespace = Uuid::parse_str("b15da2a9-67dd-446c-82ce-9512174bc16f").unwrap();
+        let (client, server) = (Ipv4Addr::new(192, 168, 1, 10), Ipv4Addr::new(93, 184, 216, 34));
+        let uuid = |namespace : &Uuid, source_port, timestamp, bucket| {
+            let tuple = ConnectionTuple { protocol: "TCP", source: client, source_port, destination: server, destination_port: 443 };
+            connection_uuid(namespace, &tuple, timestamp, bucket)
+        };
+
+        let first = uuid(&namespace, 40000, 1_540_204_860, 60);
+        assert_eq!(first, uuid(&namespace, 40000, 1_540_204_919, 60));
+        assert_eq!(Some(Version::Sha1), first.get_version());
+
+        // Another connection, bucket or namespace is another uuid.
+        assert!(first != uuid(&namespace, 40001, 1_540_204_860, 60));
+        assert!(first != uuid(&namespace, 40000, 1_540_204_920, 60));
+        let other = Uuid::new_v4();
+        assert!(first != uuid(&other, 40000, 1_540_204_860, 60));
+
+        assert_eq!(uuid(&namespace, 40000, 0, 0), uuid(&namespace, 40000, 1_540_204_920, 0));
+    }
+
+    #[test]
+    fn test_local_and_remote() {
+        let host = Ipv4Addr::new(10, 0, 0, 1);
+        let peer = Ipv4Addr::new(10, 0, 0, 5);
+        let connection = |source, source_port, destination, destination_port, direction| match default_open_payload(source_port, destination_port, None) {
+            Payload::Open(connection) => OpenConnection { source, destination, direction: Some(direction), .. connection },
+            _ => panic!("expected an open"),
+        };
+
+        // This host connecting out and the peer connecting in both give the
+        // host as the local end.
+        let initiated = connection(host, 40000, peer, 22, Direction::Outbound);
+        let received = connection(peer, 50000, host, 22, Direction::Inbound);
+
+        assert_eq!((host, 40000), initiated.local());
+        assert_eq!((peer, 22), initiated.remote());
+        assert_eq!((host, 22), received.local());
+        assert_eq!((peer, 50000), received.remote());
+
+        let json = serde_json::to_value(Payload::Open(received)).unwrap();
+        assert_eq!("inbound", json["direction"]);
+    }
+
+    #[test]
+    fn test_set_ends() {
+        let host = Ipv4Addr::new(10, 0, 0, 1);
+        let peer = Ipv4Addr::new(10, 0, 0, 5);
+        let connection = |source, source_port, destination, destination_port, direction| match default_open_payload(source_port, destination_port, None) {
+            Payload::Open(connection) => OpenConnection { source, destination, direction, .. connection },
+            _ => panic!("expected an open"),
+        };
+        let ends = |connection : &OpenConnection| (connection.local_address, connection.local_port, connection.remote_address, connection.remote_port);
+
+        let mut outbound = connection(host, 40000, peer, 443, Some(Direction::Outbound));
+        outbound.set_ends(true, false);
+        assert_eq!((Some(host), Some(40000), Some(peer), Some(443)), ends(&outbound));
+
+        let mut inbound = connection(peer, 50000, host, 22, Some(Direction::Inbound));
+        inbound.set_ends(false, true);
+        assert_eq!((Some(host), Some(22), Some(peer), Some(50000)), ends(&inbound));
+
+        // Without a socket the end with an address of this host is local.
+        let mut inbound = connection(peer, 50000, host, 22, None);
+        inbound.set_ends(false, true);
+        assert_eq!((Some(host), Some(22), Some(peer), Some(50000)), ends(&inbound));
+
+        let mut forwarded = connection(peer, 50000, Ipv4Addr::new(192, 168, 1, 1), 22, None);
+        forwarded.set_ends(false, false);
+        assert_eq!((None, None, None, None), ends(&forwarded));
+
+        let json = serde_json::to_value(Payload::Open(outbound)).unwrap();
+        assert_eq!("10.0.0.1", json["local_address"]);
+        assert_eq!(40000, json["local_port"]);
+        assert_eq!("10.0.0.5", json["remote_address"]);
+        assert_eq!(443, json["remote_port"]);
+    }
+
+    #[test]
+    fn test_process_is_privileged() {
+        assert_eq!(Some(true), process_is_privileged(Some(0), 1000));
+        assert_eq!(Some(false), process_is_privileged(Some(1000), 0));
+
+        // Without the status the owner of the socket is used.
+        assert_eq!(Some(true), process_is_privileged(None, 0));
+        assert_eq!(Some(false), process_is_privileged(None, 1000));
+    }
+
+    #[test]
+    fn test_resolve_username() {
+        let user_cache = UsersCache::new();
+        assert_eq!((String::from("root"), true), resolve_username(&user_cache, 0));
+
+        // Container and idmapped uids are often past what a u16 holds.
+        assert_eq!((String::new(), false), resolve_username(&user_cache, 4_000_000_000));
+    }
+
+    #[test]
+    fn test_truncate_command_line() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<String>>();
+        let java = args(&["java", "-Xmx4g", "-cp", "a.jar:b.jar:c.jar", "Main"]);
+
+        assert_eq!((java.clone(), false), truncate_command_line(java.clone(), 64, 4096));
+        assert_eq!((java.clone(), false), truncate_command_line(java.clone(), 0, 0));
+        assert_eq!((args(&["java", "-Xmx4g"]), true), truncate_command_line(java.clone(), 2, 4096));
+
+        // Cut part way through the argument which goes over.
+        assert_eq!((args(&["java", "-Xmx4g", "-cp", "a.jar"]), true), truncate_command_line(java.clone(), 64, 18));
+        assert_eq!((args(&["ja"]), true), truncate_command_line(java, 64, 2));
+
+        // Never in the middle of a character.
+        assert_eq!((args(&["caf"]), true), truncate_command_line(args(&["café"]), 64, 4));
+    }
+
+    #[test]
+    fn test_payload_event_type() {
+        let open = Payload::Open(OpenConnection {
+            hash: 1,
+            flow: 1,
+            schema_version: SCHEMA_VERSION,
+            seq: 0,
+            uuid: Uuid::new_v4(),
+            agent: Uuid::new_v4(),
+            timestamp: Utc::now().to_rfc3339(),
+            protocol: Protocol::TCP,
+            source: Ipv4Addr::new(127, 0, 0, 1),
+            destination: Ipv4Addr::new(127, 0, 0, 1),
+            source_port: 22,
+            destination_port: 22,
+            direction: None,
+            local_address: None,
+            local_port: None,
+            remote_address: None,
+            remote_port: None,
+            username: String::from("root"),
+            username_resolved: true,
+            uid: 0,
+            program_details: None,
+            attribution_skipped: false,
+            status_flags: Vec::new(),
+            interface: None,
+            destination_service: None,
+            novel: None,
+            pre_existing: false,
+        });
+
+        let close = Payload::Close(CloseConnection {
+            hash: 1,
+            flow: 1,
+            schema_version: SCHEMA_VERSION,
+            seq: 0,
+            agent: Uuid::new_v4(),
+            uuid: None,
+            timestamp: Utc::now().to_rfc3339(),
+            protocol: Protocol::TCP,
+            source: Ipv4Addr::new(127, 0, 0, 1),
+            destination: Ipv4Addr::new(127, 0, 0, 1),
+            source_port: 22,
+            destination_port: 22,
+            duration_ms: None,
+            close_reason: Some(CloseReason::Unreplied),
+            status_flags: vec!(StatusFlag::Confirmed),
+            update_count: 0,
+        });
+
+        let open : serde_json::Value = serde_json::to_value(open).unwrap();
+        let close : serde_json::Value = serde_json::to_value(close).unwrap();
+
+        assert_eq!("open", open["event_type"]);
+        assert_eq!("close", close["event_type"]);
+        assert_eq!("unreplied", close["close_reason"]);
+        assert_eq!(serde_json::json!(["confirmed"]), close["status_flags"]);
+        assert_eq!(1, open["hash"]);
+        assert_eq!(u64::from(SCHEMA_VERSION), open["schema_version"].as_u64().unwrap());
+        assert_eq!(u64::from(SCHEMA_VERSION), close["schema_version"].as_u64().unwrap());
+    }
+}
@@ -0,0 +1,199 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::io;
+use std::thread;
+use std::sync::Arc;
+use std::sync::mpsc::{ Sender, SyncSender, TrySendError, sync_channel };
+use uuid::Uuid;
+use conn_track::{ Connection, ProtoDetails };
+use parser::{ Parser, ParserConfig, Payload, generate_hash };
+use parser::redact::{ Redactor };
+use filters::{ PreFilter };
+use metrics::{ Metrics };
+
+// Runs a parser per worker thread, connections are sharded on their tuple so
+// the open and close of a connection are always handled by the same worker and
+// stay in order. Each worker has a bounded channel, like the one in front of
+// the pool, which drops when full unless the pool is blocking.
+pub struct ParserPool {
+    workers : Vec<SyncSender<Connection>>,
+    metrics : Arc<Metrics>,
+    blocking : bool,
+}
+
+impl ParserPool {
+    pub fn new(agent : Uuid, config : ParserConfig, pre_filter : PreFilter, redactor : Redactor, metrics : Arc<Metrics>, depth : usize, tx : Sender<Payload>) -> Result<ParserPool, io::Error> {
+        let count = if config.workers == 0 { 1 } else { config.workers };
+        let mut workers = Vec::with_capacity(count);
+
+        for worker in 0..count {
+            let mut parser = Parser::new(agent, config.clone(), pre_filter.clone(), redactor.clone(), metrics.clone())?;
+            let (worker_tx, worker_rx) = sync_channel::<Connection>(depth);
+            let tx = tx.clone();
+            let worker_metrics = metrics.clone();
+
+            thread::spawn(move || {
+                debug!("starting parser worker {}", worker);
+                for con in worker_rx {
+                    worker_metrics.channel_pop();
+                    sampled_trace!("worker {} received {:?} from channel, parsing", worker, con);
+                    match parser.parse(con) {
+                        Some(payload) => {
+                            if tx.send(payload).is_err() {
+                                break;
+                            }
+                        },
+                        None => sampled_debug!("received none, dropping packet"),
+                    }
+                }
+
+                debug!("closing parser worker {}", worker);
+            });
+
+            workers.push(worker_tx);
+        }
+
+        Ok(ParserPool {
+            workers,
+            metrics,
+            blocking: false,
+        })
+    }
+
+    // Waits for room in a worker's channel instead of dropping, see
+    // Dispatcher::blocking.
+    pub fn with_blocking(self, blocking : bool) -> ParserPool {
+        ParserPool {
+            blocking,
+            .. self
+        }
+    }
+
+    pub fn dispatch(&self, con : Connection) {
+        let worker = shard(&con, self.workers.len());
+        self.metrics.channel_push();
+        if self.blocking {
+            if let Err(err) = self.workers[worker].send(con) {
+                self.metrics.channel_pop();
+                error!("unable to send connection to parser worker {}: {}", worker, err);
+            }
+            return;
+        }
+
+        match self.workers[worker].try_send(con) {
+            Ok(()) => (),
+            Err(TrySendError::Full(con)) => {
+                self.metrics.channel_pop();
+                self.metrics.channel_dropped();
+                warn!("parser worker {} is full, dropping {:?}", worker, con);
+            },
+            Err(TrySendError::Disconnected(con)) => {
+                self.metrics.channel_pop();
+                error!("unable to send connection to parser worker {}: {:?}", worker, con);
+            },
+        }
+    }
+}
+
+fn shard(con : &Connection, workers : usize) -> usize {
+    let hash = match con.details.protocol {
+        ProtoDetails::IP { ref protocol, source_port, destination_port } => generate_hash(
+            &protocol.to_string(),
+            &con.details.source,
+            &source_port,
+            &con.details.destination,
+            &destination_port),
+        _ => 0,
+    };
+
+    (hash % workers as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::collections::HashMap;
+    use std::sync::mpsc::channel;
+    use conn_track::ConnectionDetails;
+    use enums::{ Protocol, State };
+
+    fn connection(state : State, source_port : u16) -> Connection {
+        Connection {
+            state,
+            details: ConnectionDetails {
+                source: Ipv4Addr::new(127, 0, 0, 1),
+                destination: Ipv4Addr::new(127, 0, 0, 1),
+                protocol: ProtoDetails::IP {
+                    protocol: Protocol::TCP,
+                    source_port,
+                    destination_port: 1,
+                },
+            },
+            reply: None,
+            status: None,
+            pre_existing: false,
+        }
+    }
+
+    #[test]
+    fn test_shard_is_stable() {
+        let first = shard(&connection(State::New, 40001), 4);
+        let second = shard(&connection(State::Destroy, 40001), 4);
+        assert_eq!(first, second);
+        assert!(first < 4);
+    }
+
+    #[test]
+    fn test_pool_processes_batch() {
+        let (tx, rx) = channel();
+        let config = ParserConfig {
+            workers: 4,
+            .. ParserConfig::default()
+        };
+
+        let metrics = Arc::new(Metrics::new());
+        let pool = ParserPool::new(Uuid::new_v4(), config, PreFilter::default(), Redactor::default(), metrics.clone(), 1, tx).unwrap().with_blocking(true);
+        for port in 40001..40009 {
+            pool.dispatch(connection(State::New, port));
+            pool.dispatch(connection(State::Destroy, port));
+        }
+        drop(pool);
+
+        let mut seen : HashMap<i64, usize> = HashMap::new();
+        let mut count = 0;
+        for payload in rx {
+            count += 1;
+            match payload {
+                Payload::Open(connection) => {
+                    assert!(!seen.contains_key(&connection.hash), "close was handled before open");
+                    seen.insert(connection.hash, 1);
+                },
+                Payload::Close(connection) => {
+                    assert_eq!(Some(&1), seen.get(&connection.hash), "close was handled before open");
+                    seen.insert(connection.hash, 2);
+                },
+                _ => panic!("the parsers only create connections"),
+            }
+        }
+
+        assert_eq!(16, count);
+        assert_eq!(8, seen.len());
+        assert_eq!(0, metrics.channel_depth());
+        assert_eq!(0, metrics.dropped_events());
+    }
+}
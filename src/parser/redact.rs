@@ -0,0 +1,131 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::collections::BTreeMap;
+use regex::Regex;
+
+const REDACTED : &str = "***";
+
+// Hides credentials passed on the command line or in the environment before
+// they are sent anywhere.
+#[derive(Clone, Default)]
+pub struct Redactor {
+    patterns : Vec<Regex>,
+}
+
+impl Redactor {
+    pub fn new(patterns : &[String]) -> Result<Redactor, String> {
+        let mut compiled = Vec::with_capacity(patterns.len());
+        for pattern in patterns {
+            match Regex::new(pattern) {
+                Ok(x) => compiled.push(x),
+                Err(err) => return Err(format!("unable to parse redaction {}: {}", pattern, err)),
+            }
+        }
+
+        Ok(Redactor { patterns: compiled })
+    }
+
+    // Each argument is matched on its own, whatever matches is replaced. A
+    // flag given its value in the next argument is matched as flag=value as
+    // well, so --password hunter2 is caught by the same pattern as
+    // --password=hunter2 and the value is hidden.
+    pub fn command_line(&self, command_line : Vec<String>) -> Vec<String> {
+        if self.patterns.is_empty() {
+            return command_line;
+        }
+
+        let mut redacted = Vec::with_capacity(command_line.len());
+        let mut hide_next = false;
+        for (index, arg) in command_line.iter().enumerate() {
+            if hide_next {
+                redacted.push(String::from(REDACTED));
+                hide_next = false;
+                continue;
+            }
+
+            if arg.starts_with('-') && !arg.contains('=') {
+                if let Some(next) = command_line.get(index + 1) {
+                    let joined = format!("{}={}", arg, next);
+                    hide_next = self.patterns.iter().any(|pattern| pattern.is_match(&joined));
+                }
+            }
+
+            redacted.push(self.patterns.iter().fold(arg.clone(), |arg, pattern| pattern.replace_all(&arg, REDACTED).into_owned()));
+        }
+
+        redacted
+    }
+
+    // A variable is matched as KEY=value and its whole value is hidden when
+    // any part of that matches.
+    pub fn environment(&self, mut environment : BTreeMap<String, String>) -> BTreeMap<String, String> {
+        for (key, value) in environment.iter_mut() {
+            let entry = format!("{}={}", key, value);
+            if self.patterns.iter().any(|pattern| pattern.is_match(&entry)) {
+                *value = String::from(REDACTED);
+            }
+        }
+
+        environment
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args : &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_redact_command_line() {
+        let redactor = Redactor::new(&[String::from("--password=\\S+"), String::from("token=\\S+")]).unwrap();
+        let command_line = args(&["mysql", "--user=root", "--password=hunter2", "-h", "db"]);
+
+        assert_eq!(args(&["mysql", "--user=root", "***", "-h", "db"]), redactor.command_line(command_line));
+        assert_eq!(args(&["curl", "https://api/?***"]), redactor.command_line(args(&["curl", "https://api/?token=abc"])));
+        assert_eq!(args(&["ssh", "host"]), Redactor::default().command_line(args(&["ssh", "host"])));
+    }
+
+    #[test]
+    fn test_redact_flag_and_argument() {
+        let redactor = Redactor::new(&[String::from("--password=\\S+"), String::from("^-p=")]).unwrap();
+
+        assert_eq!(args(&["mysql", "--password", "***", "-h", "db"]), redactor.command_line(args(&["mysql", "--password", "hunter2", "-h", "db"])));
+        assert_eq!(args(&["mysql", "-p", "***"]), redactor.command_line(args(&["mysql", "-p", "hunter2"])));
+        assert_eq!(args(&["mysql", "--password"]), redactor.command_line(args(&["mysql", "--password"])));
+        assert_eq!(args(&["mysql", "-h", "db", "password=x"]), redactor.command_line(args(&["mysql", "-h", "db", "password=x"])));
+    }
+
+    #[test]
+    fn test_redact_environment() {
+        let redactor = Redactor::new(&[String::from("(?i)token=")]).unwrap();
+        let mut environment = BTreeMap::new();
+        environment.insert(String::from("GITHUB_TOKEN"), String::from("ghp_secret"));
+        environment.insert(String::from("LANG"), String::from("C"));
+
+        let environment = redactor.environment(environment);
+        assert_eq!(Some(&String::from("***")), environment.get("GITHUB_TOKEN"));
+        assert_eq!(Some(&String::from("C")), environment.get("LANG"));
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        assert!(Redactor::new(&[String::from("(")]).is_err());
+    }
+}
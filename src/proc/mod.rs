@@ -15,19 +15,54 @@
  */
 
 use std::io;
-use std::collections::HashMap;
+use std::fs;
+use std::collections::{ HashMap, BTreeMap };
 use procfs;
 use procfs::{FDTarget, Process};
 use libc::pid_t;
+use enums::Resolution;
 
 pub struct Proc {
     map : HashMap<u32, pid_t>,
+    fd_counts : HashMap<pid_t, u32>,
+    // The parent and name of every process at the last update, for when the
+    // owner of a socket exits before it can be read.
+    parents : HashMap<pid_t, (pid_t, String)>,
+}
+
+// Who a socket belongs to.
+pub enum Owner {
+    Found(Box<Process>, Resolution),
+    // The owner and its parent have both gone, only the name is left.
+    Exited(pid_t, String),
+}
+
+// The process to attribute a socket to, the owner when it can still be opened
+// and otherwise its parent when following parents.
+fn resolve<T, F>(pid: pid_t, parents: &HashMap<pid_t, (pid_t, String)>, follow_parents: bool, open: F) -> Option<(T, Resolution)>
+    where F: Fn(pid_t) -> Option<T>
+{
+    if let Some(process) = open(pid) {
+        return Some((process, Resolution::Direct));
+    }
+
+    if !follow_parents {
+        return None;
+    }
+
+    match parents.get(&pid) {
+        // Orphans are reparented to init, which says nothing about them.
+        Some((ppid, _)) if *ppid > 1 => open(*ppid).map(|process| (process, Resolution::Parent)),
+        _ => None,
+    }
 }
 
 impl Proc {
     pub fn new() -> Result<Proc, io::Error> {
         let mut proc = Proc {
             map: HashMap::new(),
+            fd_counts: HashMap::new(),
+            parents: HashMap::new(),
         };
         proc.update()?;
 
@@ -37,8 +72,12 @@ impl Proc {
     pub fn update(&mut self) -> Result<(), io::Error> {
         let processes = procfs::all_processes();
         let mut map: HashMap<u32, pid_t> = HashMap::new();
+        let mut fd_counts: HashMap<pid_t, u32> = HashMap::new();
+        let mut parents: HashMap<pid_t, (pid_t, String)> = HashMap::new();
         for process in &processes {
+            parents.insert(process.pid(), (process.stat.ppid, process.stat.comm.clone()));
             if let Result::Ok(fds) = process.fd() {
+                fd_counts.insert(process.pid(), fds.len() as u32);
                 for fd in fds {
                     if let FDTarget::Socket(inode) = fd.target {
                         map.insert(inode, process.pid());
@@ -47,23 +86,190 @@ impl Proc {
             }
         }
         self.map = map;
+        self.fd_counts = fd_counts;
+        self.parents = parents;
 
         Ok(())
     }
 
-    pub fn get(&mut self, inode : u32) -> Option<Process> {
+    // The number of open file descriptors the process had at the last update.
+    pub fn fd_count(&self, pid : pid_t) -> Option<u32> {
+        self.fd_counts.get(&pid).cloned()
+    }
+
+    // The owner of the socket with the inode. When following parents a socket
+    // whose owner has already exited, such as one handed to a short lived
+    // child, is put down to the parent or failing that the name the owner had.
+    pub fn owner(&mut self, inode : u32, follow_parents : bool) -> Option<Owner> {
         if !self.map.contains_key(&inode) {
             let _ = self.update();
         }
 
-        match self.map.get(&inode) {
-            Some(pid) => {
-                match Process::new(*pid) {
-                    Result::Ok(process) => Some(process),
-                    _ => None
-                }
-            },
-            None => None
+        let pid = *self.map.get(&inode)?;
+        let open = |pid| Process::new(pid).ok();
+        match resolve(pid, &self.parents, follow_parents, open) {
+            Some((process, resolution)) => Some(Owner::Found(Box::new(process), resolution)),
+            None if follow_parents => self.parents.get(&pid).map(|(_, name)| Owner::Exited(pid, name.clone())),
+            None => None,
+        }
+    }
+}
+
+// Reads the environment of a process, only keeping the variables whose key
+// starts with one of the allowed prefixes.
+pub fn environment(pid: pid_t, prefixes: &[String]) -> Option<BTreeMap<String, String>> {
+    match fs::read(format!("/proc/{}/environ", pid)) {
+        Ok(blob) => Some(parse_environ(&blob, prefixes)),
+        Err(err) => {
+            debug!("unable to read environment for {}: {}", pid, err);
+            None
+        }
+    }
+}
+
+// The cgroup of a process as it is in /proc/<pid>/cgroup.
+pub fn cgroup_path(pid: pid_t) -> Option<String> {
+    match fs::read_to_string(format!("/proc/{}/cgroup", pid)) {
+        Ok(contents) => parse_cgroup(&contents),
+        Err(err) => {
+            debug!("unable to read cgroup for {}: {}", pid, err);
+            None
+        }
+    }
+}
+
+// Each line is hierarchy-id:controllers:path. The cgroup v2 line is 0 with no
+// controllers, under v1 there is a line per hierarchy and the systemd one is
+// taken, or else the first. On hybrid hosts the v2 line is often just / with
+// the v1 lines saying where the process really is.
+fn parse_cgroup(contents: &str) -> Option<String> {
+    let mut unified = None;
+    let mut systemd = None;
+    let mut first = None;
+
+    for line in contents.lines() {
+        let mut split = line.splitn(3, ':');
+        let (id, controllers, path) = match (split.next(), split.next(), split.next()) {
+            (Some(id), Some(controllers), Some(path)) if !path.is_empty() => (id, controllers, path),
+            _ => continue,
+        };
+
+        if id == "0" && controllers.is_empty() {
+            unified = Some(path);
+        } else if controllers == "name=systemd" {
+            systemd = Some(path);
+        } else if first.is_none() {
+            first = Some(path);
+        }
+    }
+
+    let legacy = systemd.or(first);
+    unified.filter(|path| *path != "/" || legacy.is_none())
+        .or(legacy)
+        .map(String::from)
+}
+
+fn parse_environ(blob: &[u8], prefixes: &[String]) -> BTreeMap<String, String> {
+    let mut environment = BTreeMap::new();
+
+    for entry in blob.split(|byte| *byte == 0) {
+        let entry = String::from_utf8_lossy(entry);
+        let mut split = entry.splitn(2, '=');
+        let key = match split.next() {
+            Some(key) if !key.is_empty() => key,
+            _ => continue,
+        };
+
+        if prefixes.iter().any(|prefix| key.starts_with(prefix.as_str())) {
+            let value = split.next().unwrap_or("");
+            environment.insert(key.to_string(), value.to_string());
         }
     }
+
+    environment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_environ_allowlist() {
+        let blob = b"HOME=/root\0LANG=C\0DOCKER_HOST=tcp://1.2.3.4\0SECRET_TOKEN=hunter2\0LC_ALL=C\0";
+        let prefixes = vec!(String::from("LANG"), String::from("DOCKER_"));
+        let environment = parse_environ(blob, &prefixes);
+
+        assert_eq!(2, environment.len());
+        assert_eq!(Some(&String::from("C")), environment.get("LANG"));
+        assert_eq!(Some(&String::from("tcp://1.2.3.4")), environment.get("DOCKER_HOST"));
+        assert!(environment.get("SECRET_TOKEN").is_none());
+    }
+
+    #[test]
+    fn test_parse_environ_no_prefixes() {
+        let blob = b"HOME=/root\0LANG=C\0";
+        assert!(parse_environ(blob, &Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_fd_count_current_process() {
+        let proc = Proc::new().unwrap();
+        let pid = unsafe { ::libc::getpid() };
+
+        assert!(proc.fd_count(pid).unwrap_or(0) > 0, "no fd count for the current process");
+    }
+
+    #[test]
+    fn test_fd_count_missing_process() {
+        let proc = Proc::new().unwrap();
+        assert!(proc.fd_count(-1).is_none());
+    }
+
+    #[test]
+    fn test_resolve_direct_or_parent() {
+        let mut parents = HashMap::new();
+        parents.insert(200, (100, String::from("sh")));
+        parents.insert(300, (1, String::from("orphan")));
+        let running = vec!(1, 100, 200);
+        let open = |pid| if running.contains(&pid) { Some(pid) } else { None };
+
+        assert_eq!(Some((200, Resolution::Direct)), resolve(200, &parents, false, open));
+        assert_eq!(Some((200, Resolution::Direct)), resolve(200, &parents, true, open));
+
+        // The owner has exited, its parent is still about.
+        parents.insert(201, (100, String::from("curl")));
+        assert_eq!(None, resolve(201, &parents, false, open));
+        assert_eq!(Some((100, Resolution::Parent)), resolve(201, &parents, true, open));
+
+        // Not put down to init once reparented.
+        assert_eq!(None, resolve(300, &parents, true, open));
+        assert_eq!(None, resolve(400, &parents, true, open));
+    }
+
+    #[test]
+    fn test_parse_cgroup_v2() {
+        let contents = "0::/kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod1d7c.slice/cri-containerd-4e1a.scope\n";
+        assert_eq!(Some(String::from("/kubepods.slice/kubepods-besteffort.slice/kubepods-besteffort-pod1d7c.slice/cri-containerd-4e1a.scope")), parse_cgroup(contents));
+    }
+
+    #[test]
+    fn test_parse_cgroup_v1() {
+        let contents = "12:pids:/docker/4e1a\n11:cpu,cpuacct:/docker/4e1a\n1:name=systemd:/system.slice/docker-4e1a.scope\n";
+        assert_eq!(Some(String::from("/system.slice/docker-4e1a.scope")), parse_cgroup(contents));
+
+        assert_eq!(Some(String::from("/docker/4e1a")), parse_cgroup("12:pids:/docker/4e1a\n11:cpu,cpuacct:/docker/4e1a\n"));
+    }
+
+    #[test]
+    fn test_parse_cgroup_hybrid() {
+        assert_eq!(Some(String::from("/system.slice/sshd.service")), parse_cgroup("1:name=systemd:/system.slice/sshd.service\n0::/\n"));
+        assert_eq!(Some(String::from("/user.slice")), parse_cgroup("1:name=systemd:/system.slice/sshd.service\n0::/user.slice\n"));
+        assert_eq!(Some(String::from("/")), parse_cgroup("0::/\n"));
+        assert_eq!(None, parse_cgroup("not a cgroup\n"));
+    }
+
+    #[test]
+    fn test_environment_missing_process() {
+        assert!(environment(-1, &[String::from("HOME")]).is_none());
+    }
 }
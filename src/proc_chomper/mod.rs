@@ -22,7 +22,7 @@ use std::net::Ipv4Addr;
 use std::collections::HashMap;
 use std::cell::RefCell;
 
-use enums::Protocol;
+use enums::{ Direction, Protocol };
 
 pub static TCP_LIST: &'static  str = "/proc/net/tcp";
 pub static UDP_LIST: &'static str = "/proc/net/udp";
@@ -33,26 +33,61 @@ pub struct SocketConnection {
     local_port : u16,
     remote_address : Ipv4Addr,
     remote_port : u16,
-    pub uid : u16,
+    pub uid : u32,
     pub inode : u32
 }
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
 struct Key{
     address :Ipv4Addr,
     port: u16
 }
 
+// The sockets by both of their ends and by each end on its own. Several
+// sockets can share an end, every connection to the same server shares its
+// remote end, so all of them are kept.
+#[derive(Default)]
+struct Sockets {
+    tuples : HashMap<(Key, Key), SocketConnection>,
+    local : HashMap<Key, Vec<SocketConnection>>,
+    remote : HashMap<Key, Vec<SocketConnection>>,
+}
+
+impl Sockets {
+    fn insert(&mut self, connection: SocketConnection) {
+        let local = Key { address: connection.local_address, port: connection.local_port };
+        let remote = Key { address: connection.remote_address, port: connection.remote_port };
+
+        // Kept apart, in one map the remote end of one loopback socket
+        // would replace the local end of the other.
+        self.local.entry(local).or_default().push(connection.clone());
+        self.remote.entry(remote).or_default().push(connection.clone());
+        self.tuples.insert((local, remote), connection);
+    }
+}
+
+// Only an end which belongs to a single socket says which socket it is.
+fn only(connections: Option<&Vec<SocketConnection>>) -> Option<&SocketConnection> {
+    match connections.map(|connections| connections.as_slice()) {
+        Some([connection]) => Some(connection),
+        Some([]) | None => None,
+        Some(_) => {
+            sampled_debug!("more than one socket shares the end, not guessing");
+            None
+        },
+    }
+}
+
 pub struct ProcChomper{
     protocol : Protocol,
-    map : RefCell<HashMap<Key, SocketConnection>>,
+    sockets : RefCell<Sockets>,
 }
 
 impl ProcChomper {
     pub fn new(protocol : Protocol) -> Result<ProcChomper, io::Error> {
         let chomper = ProcChomper {
             protocol,
-            map: RefCell::new(HashMap::new()),
+            sockets: RefCell::new(Sockets::default()),
         };
 
         chomper.update()?;
@@ -65,8 +100,12 @@ impl ProcChomper {
             Protocol::TCP => File::open(TCP_LIST)?,
         };
 
-        let reader = BufReader::new(file);
-        let mut map : HashMap<Key, SocketConnection> = HashMap::new();
+        self.load(BufReader::new(file));
+        Ok(())
+    }
+
+    fn load<R : BufRead>(&self, reader: R) {
+        let mut sockets = Sockets::default();
 
         for (num, line) in reader.lines().enumerate() {
             let line = line.unwrap();
@@ -76,80 +115,156 @@ impl ProcChomper {
             }
 
             if let Some(connection) = parse_connection(&line) {
-                map.insert(Key {
-                    address: connection.local_address.clone(),
-                    port: connection.local_port
-                }, connection.clone());
-
-                map.insert(Key {
-                    address: connection.remote_address.clone(),
-                    port: connection.remote_port
-                }, connection.clone());
+                sockets.insert(connection);
             }
         }
 
-        self.map.replace(map);
-        Ok(())
+        self.sockets.replace(sockets);
     }
 
-    pub fn find(&self, address : &Ipv4Addr, port : u16) -> Option<SocketConnection> {
-        let map = self.map.borrow();
+    // The socket behind the conntrack tuple, and which way the connection
+    // goes. Both ends are matched first, as a loopback connection has two
+    // sockets with the same two ends swapped around, before falling back on
+    // the source alone for sockets which aren't connected, as long as only
+    // one socket has it.
+    pub fn find(&self, source : &Ipv4Addr, source_port : u16, destination : &Ipv4Addr, destination_port : u16) -> Option<(SocketConnection, Direction)> {
+        let sockets = self.sockets.borrow();
+        let source = Key { address: *source, port: source_port };
+        let destination = Key { address: *destination, port: destination_port };
+
+        if let Some(connection) = sockets.tuples.get(&(source, destination)) {
+            return Some((connection.clone(), Direction::Outbound));
+        }
+
+        if let Some(connection) = sockets.tuples.get(&(destination, source)) {
+            return Some((connection.clone(), Direction::Inbound));
+        }
 
-        match map.get(&Key {
-            address: address.clone(),
-            port
-        }) {
-            Some(connection) => Some(connection.clone()),
-            None => None,
+        if let Some(connection) = only(sockets.local.get(&source)) {
+            return Some((connection.clone(), Direction::Outbound));
         }
+
+        only(sockets.remote.get(&source)).map(|connection| (connection.clone(), Direction::Inbound))
     }
 }
 
-fn parse_connection(line: &str) -> Option<SocketConnection> {
-    let split = line.split(" ");
-    let mut split = split.collect::<Vec<&str>>();
-    split.retain(|&x| x.len() != 0);
-
-    let mut local_address : Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
-    let mut local_port : u16 = 0;
-    let mut remote_address : Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
-    let mut remote_port : u16 = 0;
-    let mut uid : u16 = 0;
-    let mut inode : u32 = 0;
-
-
-    for(count, item) in split.iter().enumerate() {
-        match count {
-            1 => {
-                if let Some(tuple) = split_address(item) {
-                    let address = u32::from_be(u32::from_str_radix(&tuple.0, 16).unwrap());
-                    local_address = Ipv4Addr::from(address);
-                    local_port = u16::from_str_radix(&tuple.1, 16).unwrap();
-                }
-            },
-            2 => {
-                if let Some(tuple) = split_address(item) {
-                    let address = u32::from_be(u32::from_str_radix(&tuple.0, 16).unwrap());
-                    remote_address = Ipv4Addr::from(address);
-                    remote_port = u16::from_str_radix(&tuple.1, 16).unwrap();
-                }
-            },
-            7 => { uid = item.parse().unwrap(); },
-            9 => { inode = item.parse().unwrap(); },
-            _ => ()
+// Hex digits only, and exactly length of them when it is given.
+pub fn is_hex(field : &str, length : Option<usize>) -> bool {
+    !field.is_empty() && length.is_none_or(|length| field.len() == length) && field.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn is_hex_pair(field : &str) -> bool {
+    let mut split = field.splitn(2, ':');
+    match (split.next(), split.next()) {
+        (Some(first), Some(second)) => is_hex(first, None) && is_hex(second, None),
+        _ => false,
+    }
+}
+
+// The columns of a line of a socket table, with the ends parsed by whichever
+// parser suits the table.
+pub struct SocketLine<'a, A> {
+    pub local : (A, u16),
+    pub remote : (A, u16),
+    pub state : &'a str,
+    pub uid : u32,
+    pub inode : u32,
+}
+
+// The columns are local, remote, st, tx:rx, tr:when, retrnsmt, uid, timeout
+// and inode. Some kernels and hardened configs add or move columns, so rather
+// than going by position the line is anchored on the local address and each
+// column has to look as it should, or the line is skipped instead of being
+// misread. Whatever comes after the inode is ignored.
+pub fn parse_socket_line<A, F>(line: &str, parse_end: F) -> Option<SocketLine<'_, A>>
+    where F: Fn(&str) -> Option<(A, u16)>
+{
+    let fields : Vec<&str> = line.split_whitespace().collect();
+    let start = match fields.iter().position(|field| parse_end(field).is_some()) {
+        Some(x) => x,
+        None => {
+            debug!("skipping line without an address: {}", line);
+            return None;
+        }
+    };
+
+    let columns = &fields[start..];
+    if columns.len() < 9 {
+        debug!("skipping truncated line: {}", line);
+        return None;
+    }
+
+    let local = parse_end(columns[0])?;
+    let remote = match parse_end(columns[1]) {
+        Some(x) => x,
+        None => {
+            debug!("skipping line with bad remote address: {}", line);
+            return None;
         }
+    };
+
+    if !is_hex(columns[2], Some(2)) || !is_hex_pair(columns[3]) || !is_hex_pair(columns[4]) || !is_hex(columns[5], None) {
+        debug!("skipping line with an unexpected layout: {}", line);
+        return None;
     }
 
-    Some(SocketConnection {
-        local_address,
-        local_port,
-        remote_address,
-        remote_port,
+    let uid : u32 = match columns[6].parse() {
+        Ok(x) => x,
+        Err(_err) => {
+            debug!("skipping line with bad uid: {}", line);
+            return None;
+        }
+    };
+
+    if columns[7].parse::<u64>().is_err() {
+        debug!("skipping line with bad timeout: {}", line);
+        return None;
+    }
+
+    let inode : u32 = match columns[8].parse() {
+        Ok(x) => x,
+        Err(_err) => {
+            debug!("skipping line with bad inode: {}", line);
+            return None;
+        }
+    };
+
+    Some(SocketLine {
+        local,
+        remote,
+        state: columns[2],
         uid,
-        inode
+        inode,
     })
 }
 
+fn parse_connection(line: &str) -> Option<SocketConnection> {
+    let line = parse_socket_line(line, parse_address)?;
+
+    Some(SocketConnection {
+        local_address: line.local.0,
+        local_port: line.local.1,
+        remote_address: line.remote.0,
+        remote_port: line.remote.1,
+        uid: line.uid,
+        inode: line.inode
+    })
+}
+
+// An IPv4 address and port, 8 and 4 hex digits, so the queue and timer
+// columns can't be taken for one.
+fn parse_address(item : &str) -> Option<(Ipv4Addr, u16)> {
+    let tuple = split_address(item)?;
+    if !is_hex(&tuple.0, Some(8)) || !is_hex(&tuple.1, Some(4)) {
+        return None;
+    }
+
+    let address = u32::from_str_radix(&tuple.0, 16).ok()?;
+    let port = u16::from_str_radix(&tuple.1, 16).ok()?;
+
+    Some((Ipv4Addr::from(u32::from_be(address)), port))
+}
+
 fn split_address(pair : &str) -> Option<(String, String)> {
     let tuple = pair.split(":");
     let tuple = tuple.collect::<Vec<&str>>();
@@ -206,4 +321,131 @@ mod tests {
         }
 
     }
+
+    #[test]
+    fn test_parse_connection_wide_uid() {
+        let string = "   3: 669010AC:0016 019010AC:D575 01 00000000:00000000 02:000577BD 00000000 165536        0 1227937 2 0000000000000000 20 4 25 2 2";
+        assert_eq!(Some(165536), parse_connection(string).map(|connection| connection.uid));
+    }
+
+    #[test]
+    fn test_parse_connection_truncated() {
+        let string = "   3: 669010AC:0016 019010AC:D575 01 00000000:00000000";
+        assert!(parse_connection(string).is_none());
+        assert!(parse_connection("").is_none());
+    }
+
+    #[test]
+    fn test_parse_connection_garbage() {
+        let string = "   3: ZZZZ:0016 019010AC:D575 01 00000000:00000000 02:000577BD 00000000     0        0 1227937 2 0000000000000000 20 4 25 2 2";
+        assert!(parse_connection(string).is_none());
+
+        let string = "   3: 669010AC:0016 019010AC:D575 01 00000000:00000000 02:000577BD 00000000     0        0 inode 2 0000000000000000 20 4 25 2 2";
+        assert!(parse_connection(string).is_none());
+
+        let string = "   3: 669010AC:0016 019010AC 01 00000000:00000000 02:000577BD 00000000     0        0 1227937 2 0000000000000000 20 4 25 2 2";
+        assert!(parse_connection(string).is_none());
+
+        assert!(parse_connection("I am not a proc line at all, but I do have enough fields in me").is_none());
+    }
+
+    #[test]
+    fn test_parse_connection_extra_fields() {
+        let string = "   3: 669010AC:0016 019010AC:D575 01 00000000:00000000 02:000577BD 00000000  1000        0 1227937 2 0000000000000000 20 4 25 2 2 extra 0:0 ffff";
+        let connection = parse_connection(string).unwrap();
+        assert_eq!((1000, 1227937), (connection.uid, connection.inode));
+    }
+
+    #[test]
+    fn test_parse_connection_shifted_layout() {
+        // Without the slot, or with a column of its own in front, the
+        // columns from the local address on are the same.
+        for string in &[
+            "669010AC:0016 019010AC:D575 01 00000000:00000000 02:000577BD 00000000  1000        0 1227937 2",
+            "   3: net0 669010AC:0016 019010AC:D575 01 00000000:00000000 02:000577BD 00000000  1000        0 1227937 2",
+        ] {
+            let connection = parse_connection(string).unwrap();
+            assert_eq!(Ipv4Addr::new(172, 16, 144, 102), connection.local_address);
+            assert_eq!(54645, connection.remote_port);
+            assert_eq!((1000, 1227937), (connection.uid, connection.inode));
+        }
+
+        // A column added in the middle moves the uid and inode, which would
+        // otherwise be read from the wrong place.
+        let string = "   3: 669010AC:0016 019010AC:D575 01 00000000:00000000 02:000577BD 00:00 00000000  1000        0 1227937 2";
+        assert!(parse_connection(string).is_none());
+
+        // A tcp6 address isn't taken for anything else.
+        let string = "   0: 00000000000000000000000001000000:0016 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1227937 1";
+        assert!(parse_connection(string).is_none());
+    }
+
+    fn chomper(lines : &[(&str, &str, u32)]) -> ProcChomper {
+        let mut contents = String::from("  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n");
+        for (local, remote, inode) in lines {
+            contents.push_str(&format!("   0: {} {} 01 00000000:00000000 00:00000000 00000000  1000        0 {} 1 0000000000000000 20 4 25 2 2\n", local, remote, inode));
+        }
+
+        let chomper = ProcChomper { protocol: Protocol::TCP, sockets: RefCell::new(Sockets::default()) };
+        chomper.load(contents.as_bytes());
+        chomper
+    }
+
+    #[test]
+    fn test_find_initiated_connection() {
+        // ssh to the sshd on the same host, the client is 40000 and the
+        // server socket has the same ends the other way around.
+        let chomper = chomper(&[
+            ("0100007F:9C40", "0100007F:0016", 100),
+            ("0100007F:0016", "0100007F:9C40", 200),
+            ("00000000:0016", "00000000:0000", 300),
+        ]);
+        let localhost = Ipv4Addr::new(127, 0, 0, 1);
+
+        let (connection, direction) = chomper.find(&localhost, 40000, &localhost, 22).unwrap();
+        assert_eq!(100, connection.inode);
+        assert_eq!(Direction::Outbound, direction);
+    }
+
+    #[test]
+    fn test_find_received_connection() {
+        // 10.0.0.5:50000 connecting to the sshd on 10.0.0.1.
+        let chomper = chomper(&[
+            ("00000000:0016", "00000000:0000", 300),
+            ("0100000A:0016", "0500000A:C350", 400),
+        ]);
+
+        let (connection, direction) = chomper.find(&Ipv4Addr::new(10, 0, 0, 5), 50000, &Ipv4Addr::new(10, 0, 0, 1), 22).unwrap();
+        assert_eq!(400, connection.inode);
+        assert_eq!(Direction::Inbound, direction);
+    }
+
+    #[test]
+    fn test_find_unconnected_socket() {
+        // A UDP socket which sends without connecting has no remote end.
+        let chomper = chomper(&[("0100000A:D431", "00000000:0000", 500)]);
+
+        let (connection, direction) = chomper.find(&Ipv4Addr::new(10, 0, 0, 1), 54321, &Ipv4Addr::new(8, 8, 8, 8), 53).unwrap();
+        assert_eq!(500, connection.inode);
+        assert_eq!(Direction::Outbound, direction);
+        assert!(chomper.find(&Ipv4Addr::new(10, 0, 0, 1), 54322, &Ipv4Addr::new(8, 8, 8, 8), 53).is_none());
+    }
+
+    #[test]
+    fn test_find_with_a_shared_end() {
+        // Two connections from 10.0.0.1 to the same web server share their
+        // remote end.
+        let chomper = chomper(&[
+            ("0100000A:9C40", "22D8B85D:01BB", 600),
+            ("0100000A:9C41", "22D8B85D:01BB", 601),
+        ]);
+        let host = Ipv4Addr::new(10, 0, 0, 1);
+        let web = Ipv4Addr::new(93, 184, 216, 34);
+
+        assert_eq!(600, chomper.find(&host, 40000, &web, 443).unwrap().0.inode);
+        assert_eq!(601, chomper.find(&host, 40001, &web, 443).unwrap().0.inode);
+
+        // Without the full tuple the shared end could be either of them.
+        assert!(chomper.find(&web, 443, &Ipv4Addr::new(10, 0, 0, 2), 40000).is_none());
+    }
 }
@@ -0,0 +1,223 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::{ PKey, Private };
+use openssl::sign::Signer;
+use serde::Serialize;
+use serde_json;
+use serde_json::Value;
+use outputs;
+
+// What is sent in place of a value which couldn't be hashed, the real value
+// is never sent.
+const UNAVAILABLE : &str = "***";
+
+// Replaces the usernames and source addresses in the payloads with a keyed
+// hash of them, so the same user or host can be followed from payload to
+// payload without the value itself leaving the agent.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PseudonymizeConfig {
+    #[serde(default)]
+    pub username : bool,
+    #[serde(default)]
+    pub source : bool,
+    // The HMAC key, every agent whose payloads are compared needs the same one.
+    #[serde(default)]
+    pub key : Option<String>,
+}
+
+// Only the payloads as they are sent are changed, the state and the filters
+// see the real values.
+#[derive(Default, Clone)]
+pub struct Pseudonymizer {
+    username : bool,
+    source : bool,
+    key : Option<PKey<Private>>,
+}
+
+impl Pseudonymizer {
+    pub fn new(config : &PseudonymizeConfig) -> Result<Pseudonymizer, String> {
+        if !config.username && !config.source {
+            return Ok(Pseudonymizer::default());
+        }
+
+        let key = match config.key {
+            Some(ref key) if !key.is_empty() => key,
+            _ => return Err(String::from("pseudonymize needs a key")),
+        };
+
+        match PKey::hmac(key.as_bytes()) {
+            Ok(key) => Ok(Pseudonymizer {
+                username: config.username,
+                source: config.source,
+                key: Some(key),
+            }),
+            Err(err) => Err(format!("unable to use the pseudonymize key: {}", err)),
+        }
+    }
+
+    // HMAC-SHA256 of the value in hex.
+    fn hash(&self, value : &str) -> String {
+        let key = match self.key {
+            Some(ref x) => x,
+            None => return String::from(UNAVAILABLE),
+        };
+
+        let signed = Signer::new(MessageDigest::sha256(), key).and_then(|mut signer| {
+            signer.update(value.as_bytes())?;
+            signer.sign_to_vec()
+        });
+
+        match signed {
+            Ok(digest) => digest.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            Err(err) => {
+                warn!("unable to pseudonymize a value: {}", err);
+                String::from(UNAVAILABLE)
+            },
+        }
+    }
+
+    // The ends of a connection are the source again when it is local, so
+    // they are hashed along with it. An empty username is left as it is, it
+    // says the user couldn't be found. The uid would give the user away just
+    // the same, so it is left out.
+    fn apply(&self, value : &mut Value) {
+        match value {
+            Value::Object(object) => {
+                if self.source {
+                    if let Some(source) = object.get("source").cloned() {
+                        if let Value::String(ref address) = source {
+                            let hashed = Value::String(self.hash(address));
+                            for key in &["source", "local_address", "remote_address"] {
+                                if object.get(*key) == Some(&source) {
+                                    object.insert(key.to_string(), hashed.clone());
+                                }
+                            }
+                        }
+                    }
+                }
+
+                if self.username {
+                    let hashed = match object.get("username") {
+                        Some(Value::String(username)) if !username.is_empty() => Some(self.hash(username)),
+                        _ => None,
+                    };
+
+                    if let Some(hashed) = hashed {
+                        object.insert(String::from("username"), Value::String(hashed));
+                    }
+
+                    if object.contains_key("uid") {
+                        object.insert(String::from("uid"), Value::Null);
+                    }
+                }
+
+                for child in object.values_mut() {
+                    self.apply(child);
+                }
+            },
+            Value::Array(array) => {
+                for child in array.iter_mut() {
+                    self.apply(child);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    // The payload as JSON, with the values hashed.
+    pub fn to_json<T : Serialize>(&self, payload : &T) -> Result<String, serde_json::Error> {
+        if !self.username && !self.source {
+            return serde_json::to_string(payload);
+        }
+
+        let mut value = serde_json::to_value(payload)?;
+        self.apply(&mut value);
+        serde_json::to_string(&value)
+    }
+
+    // The payload as MessagePack, with the values hashed.
+    pub fn to_msgpack<T : Serialize>(&self, payload : &T) -> Result<Vec<u8>, String> {
+        if !self.username && !self.source {
+            return outputs::to_msgpack(payload);
+        }
+
+        let mut value = match serde_json::to_value(payload) {
+            Ok(x) => x,
+            Err(err) => return Err(format!("unable to serialize payload: {}", err)),
+        };
+        self.apply(&mut value);
+        outputs::to_msgpack(&value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmp_serde;
+
+    fn config(username : bool, source : bool) -> PseudonymizeConfig {
+        PseudonymizeConfig {
+            username,
+            source,
+            key: Some(String::from("a secret")),
+        }
+    }
+
+    #[test]
+    fn test_needs_key() {
+        assert!(Pseudonymizer::new(&PseudonymizeConfig::default()).is_ok());
+        assert!(Pseudonymizer::new(&PseudonymizeConfig { username: true, .. PseudonymizeConfig::default() }).is_err());
+        assert!(Pseudonymizer::new(&PseudonymizeConfig { source: true, key: Some(String::new()), .. PseudonymizeConfig::default() }).is_err());
+    }
+
+    #[test]
+    fn test_hash_is_keyed() {
+        let pseudonymizer = Pseudonymizer::new(&config(true, false)).unwrap();
+        let other = Pseudonymizer::new(&PseudonymizeConfig { key: Some(String::from("another secret")), .. config(true, false) }).unwrap();
+
+        assert_eq!(64, pseudonymizer.hash("root").len());
+        assert_eq!(pseudonymizer.hash("root"), pseudonymizer.hash("root"));
+        assert_ne!(pseudonymizer.hash("root"), pseudonymizer.hash("alisle"));
+        assert_ne!(pseudonymizer.hash("root"), other.hash("root"));
+    }
+
+    #[test]
+    fn test_source_and_its_end() {
+        let pseudonymizer = Pseudonymizer::new(&config(false, true)).unwrap();
+        let payload = serde_json::json!({
+            "source": "10.0.0.1",
+            "destination": "10.0.0.2",
+            "local_address": "10.0.0.1",
+            "remote_address": "10.0.0.2",
+            "username": "root",
+            "open": { "source": "10.0.0.1" },
+        });
+
+        let value : Value = serde_json::from_str(&pseudonymizer.to_json(&payload).unwrap()).unwrap();
+        let hashed = pseudonymizer.hash("10.0.0.1");
+        assert_eq!(hashed, value["source"]);
+        assert_eq!(hashed, value["local_address"]);
+        assert_eq!(hashed, value["open"]["source"]);
+        assert_eq!("10.0.0.2", value["destination"]);
+        assert_eq!("10.0.0.2", value["remote_address"]);
+        assert_eq!("root", value["username"]);
+
+        let encoded : Value = rmp_serde::from_slice(&pseudonymizer.to_msgpack(&payload).unwrap()).unwrap();
+        assert_eq!(value, encoded);
+    }
+}
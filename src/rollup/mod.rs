@@ -0,0 +1,291 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::collections::{ BTreeMap, HashMap };
+use std::hash::Hash;
+use std::net::Ipv4Addr;
+use std::time::{ Duration, Instant };
+use chrono::prelude::*;
+use uuid::Uuid;
+use parser::{ OpenConnection, SCHEMA_VERSION };
+
+// Past this many distinct processes, remote ends or ports in an interval the
+// new ones are only counted in the totals, so a scan can't eat the memory.
+const MAX_KEYS : usize = 4096;
+
+fn default_top() -> usize {
+    10
+}
+
+// Every interval seconds a rollup of the connections opened in it is emitted,
+// 0 turns it off.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RollupConfig {
+    #[serde(default)]
+    pub interval : u64,
+    #[serde(default = "default_top")]
+    pub top : usize,
+}
+
+impl Default for RollupConfig {
+    fn default() -> RollupConfig {
+        RollupConfig {
+            interval: 0,
+            top: default_top(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Count<T> {
+    pub value : T,
+    pub count : u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RollupSummary {
+    pub schema_version: u32,
+    pub seq: u64,
+    pub agent: Uuid,
+    pub timestamp : String,
+    pub interval_secs : u64,
+    pub connections : u64,
+    pub connections_per_second : f64,
+    pub protocols : BTreeMap<String, u64>,
+    pub top_processes : Vec<Count<String>>,
+    // The other side of each connection, the source of the ones this host
+    // accepted and the destination when neither end is this host.
+    pub top_remote_addresses : Vec<Count<Ipv4Addr>>,
+    // The port being connected to, which for an accepted connection is the
+    // service on this host.
+    pub top_destination_ports : Vec<Count<u16>>,
+}
+
+struct Counter<T> {
+    counts : HashMap<T, u64>,
+}
+
+impl<T : Hash + Eq + Ord + Clone> Counter<T> {
+    fn new() -> Counter<T> {
+        Counter { counts: HashMap::new() }
+    }
+
+    fn add(&mut self, value : T) {
+        if self.counts.len() >= MAX_KEYS && !self.counts.contains_key(&value) {
+            return;
+        }
+
+        *self.counts.entry(value).or_insert(0) += 1;
+    }
+
+    // The most common first, ties in the order of the values so the rollup
+    // comes out the same every time.
+    fn top(&mut self, top : usize) -> Vec<Count<T>> {
+        let mut counts : Vec<Count<T>> = self.counts.drain()
+            .map(|(value, count)| Count { value, count })
+            .collect();
+        counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+        counts.truncate(top);
+        counts
+    }
+}
+
+// Counts the opens emitted between rollups.
+pub struct Rollup {
+    interval : Duration,
+    top : usize,
+    started : Instant,
+    connections : u64,
+    protocols : BTreeMap<String, u64>,
+    processes : Counter<String>,
+    remotes : Counter<Ipv4Addr>,
+    ports : Counter<u16>,
+}
+
+impl Rollup {
+    pub fn new(config : &RollupConfig, now : Instant) -> Rollup {
+        Rollup {
+            interval: Duration::from_secs(config.interval),
+            top: config.top,
+            started: now,
+            connections: 0,
+            protocols: BTreeMap::new(),
+            processes: Counter::new(),
+            remotes: Counter::new(),
+            ports: Counter::new(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.interval > Duration::from_secs(0)
+    }
+
+    pub fn record(&mut self, connection : &OpenConnection) {
+        if !self.enabled() {
+            return;
+        }
+
+        self.connections += 1;
+        *self.protocols.entry(connection.protocol.to_string()).or_insert(0) += 1;
+        if let Some(ref details) = connection.program_details {
+            self.processes.add(details.process_name.clone());
+        }
+        self.remotes.add(connection.remote_address.unwrap_or(connection.destination));
+        self.ports.add(connection.destination_port);
+    }
+
+    // The rollup once the interval is up.
+    pub fn due(&mut self, agent : Uuid, now : Instant) -> Option<RollupSummary> {
+        if !self.enabled() || now.duration_since(self.started) < self.interval {
+            return None;
+        }
+
+        Some(self.finish(agent, now))
+    }
+
+    // The rollup of what has been counted so far, the counters start again.
+    pub fn finish(&mut self, agent : Uuid, now : Instant) -> RollupSummary {
+        let elapsed = now.duration_since(self.started);
+        let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_millis()) / 1000.0;
+        let connections_per_second = match seconds > 0.0 {
+            true => self.connections as f64 / seconds,
+            false => 0.0,
+        };
+
+        let summary = RollupSummary {
+            schema_version: SCHEMA_VERSION,
+            seq: 0,
+            agent,
+            timestamp: Utc::now().to_rfc3339(),
+            interval_secs: elapsed.as_secs(),
+            connections: self.connections,
+            connections_per_second,
+            protocols: self.protocols.clone(),
+            top_processes: self.processes.top(self.top),
+            top_remote_addresses: self.remotes.top(self.top),
+            top_destination_ports: self.ports.top(self.top),
+        };
+
+        self.started = now;
+        self.connections = 0;
+        self.protocols.clear();
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enums::{ Direction, Protocol };
+    use parser::Payload;
+    use test_support::{ default_open_payload, program };
+    use serde_yaml;
+
+    fn connection(process : &str, destination : Ipv4Addr, port : u16, protocol : Protocol) -> OpenConnection {
+        match default_open_payload(40000, port, Some(program(process))) {
+            Payload::Open(connection) => OpenConnection { destination, protocol, .. connection },
+            _ => panic!("expected an open"),
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        let config : RollupConfig = serde_yaml::from_str("interval: 60").unwrap();
+        assert_eq!(60, config.interval);
+        assert_eq!(10, config.top);
+        assert!(!Rollup::new(&RollupConfig::default(), Instant::now()).enabled());
+    }
+
+    #[test]
+    fn test_top_talkers() {
+        let start = Instant::now();
+        let mut rollup = Rollup::new(&RollupConfig { interval: 10, top: 2 }, start);
+        let web = Ipv4Addr::new(93, 184, 216, 34);
+        let dns = Ipv4Addr::new(8, 8, 8, 8);
+        let other = Ipv4Addr::new(10, 0, 0, 1);
+
+        for _ in 0..5 {
+            rollup.record(&connection("curl", web, 443, Protocol::TCP));
+        }
+        for _ in 0..3 {
+            rollup.record(&connection("systemd-resolve", dns, 53, Protocol::UDP));
+        }
+        rollup.record(&connection("ssh", other, 22, Protocol::TCP));
+        rollup.record(&connection("ssh", other, 22, Protocol::TCP));
+
+        assert!(rollup.due(Uuid::nil(), start + Duration::from_secs(5)).is_none());
+
+        let summary = rollup.due(Uuid::nil(), start + Duration::from_secs(10)).unwrap();
+        assert_eq!(10, summary.connections);
+        assert!((summary.connections_per_second - 1.0).abs() < 1e-9);
+        assert_eq!(Some(&7), summary.protocols.get("TCP"));
+        assert_eq!(Some(&3), summary.protocols.get("UDP"));
+        assert_eq!(vec!(
+            Count { value: String::from("curl"), count: 5 },
+            Count { value: String::from("systemd-resolve"), count: 3 },
+        ), summary.top_processes);
+        assert_eq!(vec!(Count { value: web, count: 5 }, Count { value: dns, count: 3 }), summary.top_remote_addresses);
+        assert_eq!(vec!(Count { value: 443, count: 5 }, Count { value: 53, count: 3 }), summary.top_destination_ports);
+
+        // Each interval starts from nothing.
+        let summary = rollup.due(Uuid::nil(), start + Duration::from_secs(20)).unwrap();
+        assert_eq!(0, summary.connections);
+        assert!(summary.top_processes.is_empty());
+    }
+
+    #[test]
+    fn test_accepted_connections_count_the_client() {
+        let start = Instant::now();
+        let mut rollup = Rollup::new(&RollupConfig { interval: 10, top: 2 }, start);
+        let local = Ipv4Addr::new(192, 168, 0, 2);
+        let client = Ipv4Addr::new(93, 184, 216, 34);
+        let accepted = OpenConnection {
+            source: client,
+            direction: Some(Direction::Inbound),
+            local_address: Some(local),
+            remote_address: Some(client),
+            .. connection("nginx", local, 443, Protocol::TCP)
+        };
+
+        rollup.record(&accepted);
+        rollup.record(&accepted);
+
+        let summary = rollup.due(Uuid::nil(), start + Duration::from_secs(10)).unwrap();
+        assert_eq!(vec!(Count { value: client, count: 2 }), summary.top_remote_addresses);
+        assert_eq!(vec!(Count { value: 443, count: 2 }), summary.top_destination_ports);
+    }
+
+    #[test]
+    fn test_disabled_counts_nothing() {
+        let start = Instant::now();
+        let mut rollup = Rollup::new(&RollupConfig::default(), start);
+        rollup.record(&connection("curl", Ipv4Addr::new(93, 184, 216, 34), 443, Protocol::TCP));
+        assert!(rollup.due(Uuid::nil(), start + Duration::from_secs(3600)).is_none());
+        assert_eq!(0, rollup.connections);
+    }
+
+    #[test]
+    fn test_counter_is_bounded() {
+        let mut counter = Counter::new();
+        for port in 0..(MAX_KEYS as u16 + 10) {
+            counter.add(port);
+        }
+        counter.add(0);
+
+        assert_eq!(MAX_KEYS, counter.counts.len());
+        assert_eq!(vec!(Count { value: 0, count: 2 }), counter.top(1));
+    }
+}
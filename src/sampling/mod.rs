@@ -0,0 +1,75 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::sync::atomic::{ AtomicUsize, Ordering };
+
+// Only 1 in every RATE of the sampled log lines is written.
+static RATE: AtomicUsize = AtomicUsize::new(1);
+
+pub fn set_rate(rate: usize) {
+    RATE.store(rate, Ordering::Relaxed);
+}
+
+pub fn rate() -> usize {
+    RATE.load(Ordering::Relaxed)
+}
+
+pub fn sample(counter: &AtomicUsize, rate: usize) -> bool {
+    let rate = if rate == 0 { 1 } else { rate };
+    counter.fetch_add(1, Ordering::Relaxed).is_multiple_of(rate)
+}
+
+// Wraps trace! and debug! in the hot paths so each call site only logs 1 in
+// every N times, errors and warnings are never sampled.
+macro_rules! sampled {
+    ($level:ident, $($arg:tt)+) => {{
+        static COUNTER: ::std::sync::atomic::AtomicUsize = ::std::sync::atomic::AtomicUsize::new(0);
+        if log_enabled!(::log::Level::Debug) {
+            if $crate::sampling::sample(&COUNTER, $crate::sampling::rate()) {
+                $level!($($arg)+);
+            }
+        }
+    }};
+}
+
+macro_rules! sampled_trace {
+    ($($arg:tt)+) => { sampled!(trace, $($arg)+) };
+}
+
+macro_rules! sampled_debug {
+    ($($arg:tt)+) => { sampled!(debug, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_one_in_n() {
+        let counter = AtomicUsize::new(0);
+        let emitted = (0..1000).filter(|_| sample(&counter, 10)).count();
+        assert_eq!(100, emitted);
+    }
+
+    #[test]
+    fn test_sample_everything() {
+        let counter = AtomicUsize::new(0);
+        assert_eq!(10, (0..10).filter(|_| sample(&counter, 1)).count());
+
+        let counter = AtomicUsize::new(0);
+        assert_eq!(10, (0..10).filter(|_| sample(&counter, 0)).count());
+    }
+}
@@ -0,0 +1,136 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::collections::HashMap;
+use std::fs;
+use enums::Protocol;
+
+pub static SERVICES_LIST: &str = "/etc/services";
+
+// Used when /etc/services can't be read, for instance in minimal containers.
+static BUILTIN : &[(u16, &str, &str)] = &[
+    (20, "tcp", "ftp-data"),
+    (21, "tcp", "ftp"),
+    (22, "tcp", "ssh"),
+    (23, "tcp", "telnet"),
+    (25, "tcp", "smtp"),
+    (53, "tcp", "domain"),
+    (53, "udp", "domain"),
+    (67, "udp", "bootps"),
+    (68, "udp", "bootpc"),
+    (80, "tcp", "http"),
+    (110, "tcp", "pop3"),
+    (123, "udp", "ntp"),
+    (143, "tcp", "imap2"),
+    (161, "udp", "snmp"),
+    (389, "tcp", "ldap"),
+    (443, "tcp", "https"),
+    (443, "udp", "https"),
+    (465, "tcp", "submissions"),
+    (514, "udp", "syslog"),
+    (587, "tcp", "submission"),
+    (636, "tcp", "ldaps"),
+    (993, "tcp", "imaps"),
+    (995, "tcp", "pop3s"),
+    (3306, "tcp", "mysql"),
+    (5353, "udp", "mdns"),
+    (5432, "tcp", "postgresql"),
+    (6379, "tcp", "redis"),
+];
+
+// Maps well known ports to their service names.
+pub struct Services {
+    map : HashMap<(u16, String), String>,
+}
+
+fn parse_services(contents : &str) -> HashMap<(u16, String), String> {
+    let mut map = HashMap::new();
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or("");
+        let mut fields = line.split_whitespace();
+
+        let name = match fields.next() {
+            Some(x) => x,
+            None => continue,
+        };
+
+        let mut port = fields.next().unwrap_or("").split('/');
+        let number = port.next().and_then(|x| x.parse::<u16>().ok());
+        let protocol = port.next();
+
+        if let (Some(number), Some(protocol)) = (number, protocol) {
+            map.entry((number, protocol.to_lowercase())).or_insert_with(|| String::from(name));
+        }
+    }
+
+    map
+}
+
+impl Services {
+    pub fn new() -> Services {
+        Services::load(SERVICES_LIST)
+    }
+
+    pub fn load(file : &str) -> Services {
+        match fs::read_to_string(file) {
+            Ok(contents) => Services { map: parse_services(&contents) },
+            Err(_err) => {
+                debug!("unable to read {}, using the builtin services", file);
+                Services::builtin()
+            }
+        }
+    }
+
+    pub fn builtin() -> Services {
+        let map = BUILTIN.iter()
+            .map(|(port, protocol, name)| ((*port, protocol.to_string()), name.to_string()))
+            .collect();
+
+        Services { map }
+    }
+
+    pub fn find(&self, protocol : &Protocol, port : u16) -> Option<String> {
+        self.map.get(&(port, protocol.to_string().to_lowercase())).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin() {
+        let services = Services::builtin();
+        assert_eq!(Some(String::from("https")), services.find(&Protocol::TCP, 443));
+        assert_eq!(Some(String::from("ssh")), services.find(&Protocol::TCP, 22));
+        assert_eq!(None, services.find(&Protocol::TCP, 48213));
+        assert_eq!(None, services.find(&Protocol::UDP, 22));
+    }
+
+    #[test]
+    fn test_parse_services() {
+        let map = parse_services("# comment\nhttps\t\t443/tcp\t\t\t\t# http protocol over TLS/SSL\nhttps 443/udp\nbroken\nalias 443/tcp\n");
+        assert_eq!(Some(&String::from("https")), map.get(&(443, String::from("tcp"))));
+        assert_eq!(Some(&String::from("https")), map.get(&(443, String::from("udp"))));
+        assert_eq!(2, map.len());
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let services = Services::load("/I_like_strange_things");
+        assert_eq!(Some(String::from("https")), services.find(&Protocol::TCP, 443));
+    }
+}
@@ -0,0 +1,110 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{ Duration, Instant };
+use parser::{ OpenConnection, CloseConnection };
+use uuid::Uuid;
+use chrono::{ DateTime, FixedOffset };
+
+// Reconnects come from a fresh ephemeral port, so the source port is left out.
+#[derive(PartialEq, Eq, Hash, Clone)]
+struct Key {
+    protocol: String,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    destination_port: u16,
+}
+
+impl Key {
+    fn open(connection: &OpenConnection) -> Key {
+        Key {
+            protocol: connection.protocol.to_string(),
+            source: connection.source,
+            destination: connection.destination,
+            destination_port: connection.destination_port,
+        }
+    }
+
+    fn close(connection: &CloseConnection) -> Key {
+        Key {
+            protocol: connection.protocol.to_string(),
+            source: connection.source,
+            destination: connection.destination,
+            destination_port: connection.destination_port,
+        }
+    }
+}
+
+// Holds closes back for a short window so that a matching open straight after
+// can be treated as the same connection carrying on.
+pub struct Debounce {
+    window: Duration,
+    // The time the connection was first opened is kept so a reconnect can
+    // carry it on.
+    pending: HashMap<Key, (Instant, Option<DateTime<FixedOffset>>, CloseConnection)>,
+}
+
+impl Debounce {
+    pub fn new(window: Duration) -> Debounce {
+        Debounce {
+            window,
+            pending: HashMap::new(),
+        }
+    }
+
+    // Returns the close when it should go out straight away.
+    pub fn close(&mut self, connection: CloseConnection, opened: Option<DateTime<FixedOffset>>, now: Instant) -> Option<CloseConnection> {
+        if self.window == Duration::from_secs(0) {
+            return Some(connection);
+        }
+
+        // Only the latest close for a key is held, anything it replaces goes out.
+        self.pending.insert(Key::close(&connection), (now, opened, connection))
+            .map(|(_, _, previous)| previous)
+    }
+
+    // Returns the uuid and open time of the held close the open carries on from.
+    pub fn open(&mut self, connection: &OpenConnection, now: Instant) -> Option<(Uuid, Option<DateTime<FixedOffset>>)> {
+        let key = Key::open(connection);
+        let carried = match self.pending.get(&key) {
+            Some((closed, opened, close)) if now.duration_since(*closed) <= self.window => (close.uuid?, *opened),
+            _ => return None,
+        };
+
+        self.pending.remove(&key);
+        Some(carried)
+    }
+
+    // Closes which were held for longer than the window.
+    pub fn expired(&mut self, now: Instant) -> Vec<CloseConnection> {
+        let window = self.window;
+        let keys : Vec<Key> = self.pending.iter()
+            .filter(|(_, (closed, _, _))| now.duration_since(*closed) > window)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        keys.into_iter()
+            .filter_map(|key| self.pending.remove(&key))
+            .map(|(_, _, close)| close)
+            .collect()
+    }
+
+    pub fn drain(&mut self) -> Vec<CloseConnection> {
+        self.pending.drain().map(|(_, (_, _, close))| close).collect()
+    }
+}
@@ -14,35 +14,328 @@
  *
  */
 
-use std::collections::HashMap;
-use parser::{ Payload, CloseConnection };
+use std::collections::{ BTreeMap, HashMap, VecDeque };
+use std::sync::Arc;
+use std::time::{ Duration, Instant };
+use parser::{ Payload, OpenConnection, CloseConnection, ConnectionRecord };
+use metrics::{ Metrics };
 use uuid::Uuid;
+use chrono::{ DateTime, FixedOffset };
+use state::debounce::{ Debounce };
+use state::udp::{ UdpAggregator };
+use live::{ Connections };
+
+mod debounce;
+mod udp;
+
+struct Tracked {
+    uuid: Uuid,
+    flow: i64,
+    order: u64,
+    opened: Option<DateTime<FixedOffset>>,
+    // Updates from conntrack, and opens after the first one, while it was open.
+    updates: u32,
+}
 
 pub struct State {
-    connections: HashMap<i64, Uuid>
+    connections: HashMap<i64, Tracked>,
+    // The order the connections, and the duplicates, were opened in, the
+    // first entry is the oldest.
+    order: BTreeMap<u64, i64>,
+    next: u64,
+    // Zero means there is no cap.
+    max_connections: usize,
+    metrics: Arc<Metrics>,
+    // The logical flow and the hash of the half we are tracking it through.
+    flows: HashMap<i64, i64>,
+    // Hashes of the halves which were dropped as the flow was already
+    // tracked, with their place in the order. Their close can go missing, so
+    // they count against the cap the same as the connections.
+    duplicates: HashMap<i64, u64>,
+    debounce: Debounce,
+    udp: UdpAggregator,
+    // In CloseOnly the opens are held here by uuid until their close, the
+    // queue being the order they came in.
+    close_only: Option<Duration>,
+    pending: HashMap<Uuid, OpenConnection>,
+    pending_order: VecDeque<(Instant, Uuid)>,
+    // A copy of every tracked open for the live server, when it is running.
+    live: Option<Connections>,
 }
 
 impl State {
-    pub fn new() -> Result<State, ()> {
+    pub fn new(max_connections: usize, debounce: Duration, udp_window: Duration, metrics: Arc<Metrics>) -> Result<State, ()> {
         let state = State {
-            connections: HashMap::new()
+            connections: HashMap::new(),
+            order: BTreeMap::new(),
+            next: 0,
+            max_connections,
+            metrics,
+            flows: HashMap::new(),
+            duplicates: HashMap::new(),
+            debounce: Debounce::new(debounce),
+            udp: UdpAggregator::new(udp_window),
+            close_only: None,
+            pending: HashMap::new(),
+            pending_order: VecDeque::new(),
+            live: None,
         };
 
         Ok(state)
     }
-    
-    pub fn transform(&mut self, payload: Payload) -> Payload {
+
+    // Holds each open back and sends it with its close, one still open after
+    // the ttl is sent on its own.
+    pub fn close_only(mut self, ttl: Duration) -> State {
+        self.close_only = Some(ttl);
+        self
+    }
+
+    // Keeps the tracked opens in connections as well, for the live server.
+    pub fn live(mut self, connections: Connections) -> State {
+        self.live = Some(connections);
+        self
+    }
+
+    fn hold(&mut self, connection: OpenConnection, now: Instant) -> Option<Payload> {
+        if self.close_only.is_none() {
+            return Some(Payload::Open(connection));
+        }
+
+        self.pending_order.push_back((now, connection.uuid));
+        self.pending.insert(connection.uuid, connection);
+        None
+    }
+
+    // The close along with its open when that was held back.
+    fn complete(&mut self, close: CloseConnection) -> Payload {
+        match close.uuid.and_then(|uuid| self.pending.remove(&uuid)) {
+            Some(open) => Payload::Connection(ConnectionRecord {
+                open,
+                closed: Some(close.timestamp),
+                duration_ms: close.duration_ms,
+                close_reason: close.close_reason,
+                closed_status_flags: close.status_flags,
+                update_count: close.update_count,
+            }),
+            None => Payload::Close(close),
+        }
+    }
+
+    // Opens held for longer than the ttl, or all of them.
+    fn unclosed(&mut self, now: Option<Instant>) -> Vec<Payload> {
+        let ttl = match self.close_only {
+            Some(x) => x,
+            None => return Vec::new(),
+        };
+
+        let mut payloads = Vec::new();
+        while let Some((held, uuid)) = self.pending_order.front().cloned() {
+            if let Some(now) = now {
+                if now.duration_since(held) < ttl {
+                    break;
+                }
+            }
+
+            self.pending_order.pop_front();
+            if let Some(open) = self.pending.remove(&uuid) {
+                let update_count = self.connections.get(&open.hash).map_or(0, |tracked| tracked.updates);
+                payloads.push(Payload::Connection(ConnectionRecord { open, closed: None, duration_ms: None, close_reason: None, closed_status_flags: Vec::new(), update_count }));
+            }
+        }
+
+        payloads
+    }
+
+    fn next_order(&mut self) -> u64 {
+        let order = self.next;
+        self.next += 1;
+        order
+    }
+
+    fn track(&mut self, connection: &OpenConnection, uuid: Uuid, opened: Option<DateTime<FixedOffset>>) {
+        let (hash, flow) = (connection.hash, connection.flow);
+        let order = self.next_order();
+
+        if let Some(ref live) = self.live {
+            if let Ok(mut live) = live.lock() {
+                live.insert(hash, OpenConnection { uuid, .. connection.clone() });
+            }
+        }
+
+        if let Some(previous) = self.connections.insert(hash, Tracked { uuid, flow, order, opened, updates: 0 }) {
+            self.order.remove(&previous.order);
+        }
+        self.order.insert(order, hash);
+        self.cap();
+    }
+
+    fn track_duplicate(&mut self, hash: i64) {
+        let order = self.next_order();
+        if let Some(previous) = self.duplicates.insert(hash, order) {
+            self.order.remove(&previous);
+        }
+        self.order.insert(order, hash);
+        self.cap();
+    }
+
+    // Whether the hash was a duplicate, it no longer is.
+    fn untrack_duplicate(&mut self, hash: i64) -> bool {
+        match self.duplicates.remove(&hash) {
+            Some(order) => {
+                self.order.remove(&order);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn cap(&mut self) {
+        while self.max_connections > 0 && self.connections.len() + self.duplicates.len() > self.max_connections {
+            self.evict_oldest();
+        }
+    }
+
+    fn untrack(&mut self, hash: i64) -> Option<Tracked> {
+        let tracked = self.connections.remove(&hash)?;
+        self.order.remove(&tracked.order);
+
+        if let Some(ref live) = self.live {
+            if let Ok(mut live) = live.lock() {
+                live.remove(&hash);
+            }
+        }
+
+        if self.flows.get(&tracked.flow) == Some(&hash) {
+            self.flows.remove(&tracked.flow);
+        }
+
+        Some(tracked)
+    }
+
+    fn evict_oldest(&mut self) {
+        let (order, hash) = match self.order.iter().next() {
+            Some((order, hash)) => (*order, *hash),
+            None => return,
+        };
+
+        // Its close is dropped as it is anyway, nothing is lost by forgetting it.
+        if self.duplicates.get(&hash) == Some(&order) {
+            sampled_debug!("forgetting the other half of flow {} as the state is full", hash);
+            self.untrack_duplicate(hash);
+            return;
+        }
+
+        sampled_debug!("evicting connection {} as the state is full", hash);
+        self.untrack(hash);
+        self.metrics.connection_evicted();
+    }
+
+    // Returns None when the payload is the second half of a hairpinned flow
+    // which is already being tracked, when it is a close being held back for
+    // the debounce window or an open carrying on from one, and for updates
+    // as they are only counted.
+    pub fn transform(&mut self, payload: Payload) -> Option<Payload> {
+        self.transform_at(payload, Instant::now())
+    }
+
+    // Closes which sat out the debounce window without a matching open, and
+    // in CloseOnly the connections open for longer than the ttl.
+    pub fn expired(&mut self) -> Vec<Payload> {
+        self.expired_at(Instant::now())
+    }
+
+    fn expired_at(&mut self, now: Instant) -> Vec<Payload> {
+        let mut payloads : Vec<Payload> = self.debounce.expired(now).into_iter().map(|close| self.complete(close)).collect();
+        payloads.extend(self.unclosed(Some(now)));
+        payloads
+    }
+
+    // Every close and open still being held back, for when we are shutting down.
+    pub fn drain(&mut self) -> Vec<Payload> {
+        let mut payloads : Vec<Payload> = self.debounce.drain().into_iter().map(|close| self.complete(close)).collect();
+        payloads.extend(self.unclosed(None));
+        payloads
+    }
+
+    fn transform_at(&mut self, payload: Payload, now: Instant) -> Option<Payload> {
         match payload {
             Payload::Open(connection )=> {
-                self.connections.insert(connection.hash, connection.uuid.clone());
-                return Payload::Open(connection);
+                if let Some(hash) = self.flows.get(&connection.flow) {
+                    if *hash != connection.hash {
+                        debug!("dropping open as the flow is already tracked");
+                        self.track_duplicate(connection.hash);
+                        return None;
+                    }
+                }
+
+                // Conntrack can tell us of a connection again while it is
+                // open, that is counted and given with the close rather than
+                // sent as another open. A dump of the table finding one we
+                // already have isn't a repeat.
+                if let Some(tracked) = self.connections.get_mut(&connection.hash) {
+                    if !connection.pre_existing {
+                        sampled_debug!("counting repeated open of a tracked connection");
+                        tracked.updates += 1;
+                    }
+                    return None;
+                }
+
+                self.flows.insert(connection.flow, connection.hash);
+
+                // Carried on connections keep the time of the open which was emitted.
+                if let Some((uuid, opened)) = self.debounce.open(&connection, now) {
+                    debug!("coalescing open into the connection which just closed");
+                    self.track(&connection, uuid, opened);
+                    return None;
+                }
+
+                let opened = DateTime::parse_from_rfc3339(&connection.timestamp).ok();
+                if let Some((uuid, opened)) = self.udp.open(&connection, opened, now) {
+                    sampled_debug!("folding udp flow into the flow already open");
+                    self.track(&connection, uuid, opened);
+                    return None;
+                }
+
+                self.track(&connection, connection.uuid.clone(), opened);
+                return self.hold(connection, now);
             },
             Payload::Close(connection) =>  {
-                match self.connections.remove(&connection.hash) {
-                   Some(uuid) =>  return Payload::Close(CloseConnection { uuid: Some(uuid), .. connection }),
-                   None => return Payload::Close(connection),
+                if self.untrack_duplicate(connection.hash) {
+                    debug!("dropping close as the flow is tracked through its other half");
+                    return None;
+                }
+
+                if self.flows.get(&connection.flow) == Some(&connection.hash) {
+                    self.flows.remove(&connection.flow);
+                }
+
+                let (connection, opened) = match self.untrack(connection.hash) {
+                   Some(tracked) => {
+                       let duration_ms = duration_ms(tracked.opened, &connection.timestamp);
+                       (CloseConnection { uuid: Some(tracked.uuid), duration_ms, update_count: tracked.updates, .. connection }, tracked.opened)
+                   },
+                   None => (connection, None),
+                };
+
+                if !self.udp.close(connection.hash) {
+                    sampled_debug!("dropping close as the udp flow has other parts open");
+                    return None;
+                }
+
+                self.debounce.close(connection, opened, now).map(|close| self.complete(close))
+           },
+           // Counted against the half the flow is tracked through, an update
+           // for a connection which isn't tracked is dropped.
+           Payload::Update(update) => {
+               let hash = self.flows.get(&update.flow).cloned().unwrap_or(update.hash);
+               if let Some(tracked) = self.connections.get_mut(&hash) {
+                   tracked.updates += 1;
                }
-           }
+               None
+           },
+           // Listeners and rollups aren't connections, there is nothing to track.
+           other => Some(other),
        }
     }
 
@@ -57,67 +350,26 @@ impl State {
     }
 }
 
+// Clocks can step backwards between the open and the close, which is reported
+// as a zero length connection rather than dropped.
+fn duration_ms(opened: Option<DateTime<FixedOffset>>, closed: &str) -> Option<u64> {
+    let closed = DateTime::parse_from_rfc3339(closed).ok()?;
+    let duration = closed.signed_duration_since(opened?).num_milliseconds();
+    Some(duration.max(0) as u64)
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use parser::{ Payload, OpenConnection, CloseConnection };
+    use parser::{ Payload, OpenConnection, CloseConnection, UpdateConnection };
     use enums::{ Protocol };
-    use std::net::Ipv4Addr;
-    use parser::{ Program, generate_hash };
     use chrono::prelude::*;
-    use uuid::Uuid;
-
-    fn default_close_payload() -> Payload {
-        Payload::Close(CloseConnection {
-            hash: generate_hash(
-                &Protocol::TCP.to_string(),
-                &Ipv4Addr::new(127, 0, 0, 1),
-                &22,
-                &Ipv4Addr::new(127, 0, 0, 1),
-                &22
-            ) as i64,
-            uuid: None,
-            agent: Uuid::new_v4(),
-            timestamp: Utc::now().to_rfc3339(),
-            protocol: Protocol::TCP,
-            source_port : 22,
-            source: Ipv4Addr::new(127, 0, 0, 1),
-            destination_port : 22,
-            destination : Ipv4Addr::new(127, 0, 0, 1),
-        })
-    }
-
-    fn default_open_payload(
-        source_port : u16,
-        destination_port : u16,
-        program_details: Option<Program>
-    ) -> Payload {
-        Payload::Open(OpenConnection {
-            hash: generate_hash(
-                &Protocol::TCP.to_string(),
-                &Ipv4Addr::new(127, 0, 0, 1),
-                &22,
-                &Ipv4Addr::new(127, 0, 0, 1),
-                &22
-            ) as i64,
-            uuid: Uuid::new_v4(),
-            agent: Uuid::new_v4(),
-            timestamp: Utc::now().to_rfc3339(),
-            protocol: Protocol::TCP,
-            source_port : source_port,
-            source: Ipv4Addr::new(127, 0, 0, 1),
-            destination_port : destination_port,
-            destination : Ipv4Addr::new(127, 0, 0, 1),
-            username : String::from("hello"),
-            uid: 10,
-            program_details : program_details,
-        })
-    }
+    use serde_json;
+    use test_support::{ default_open_payload, default_close_payload, open_payload, close_payload };
 
     #[test]
     fn test_no_state() {
-        let mut state = State::new().unwrap();
+        let mut state = State::new(0, Duration::from_secs(0), Duration::from_secs(0), Arc::new(Metrics::new())).unwrap();
         let close_payload = default_close_payload();
         if let Payload::Close(ref close_connection) = close_payload {
             assert_eq!(true, close_connection.uuid.is_none());
@@ -125,7 +377,7 @@ mod tests {
             assert_eq!(true, false);
         }
 
-        let close_payload = state.transform(close_payload);
+        let close_payload = state.transform(close_payload).unwrap();
 
         if let Payload::Close(ref close_connection) = close_payload {
             assert_eq!(true, close_connection.uuid.is_none());
@@ -136,7 +388,7 @@ mod tests {
     }
     #[test]
     fn test_added_state() {
-        let mut state = State::new().unwrap();
+        let mut state = State::new(0, Duration::from_secs(0), Duration::from_secs(0), Arc::new(Metrics::new())).unwrap();
         let open_payload = default_open_payload(22, 22, None);
         let close_payload = default_close_payload();
         if let Payload::Close(ref close_connection) = close_payload {
@@ -145,8 +397,8 @@ mod tests {
             assert_eq!(true, false);
         }
 
-        let open_payload = state.transform(open_payload);
-        let close_payload = state.transform(close_payload);
+        let open_payload = state.transform(open_payload).unwrap();
+        let close_payload = state.transform(close_payload).unwrap();
 
         if let Payload::Close(close_connection) = close_payload {
             if let Payload::Open(open_connection) = open_payload {
@@ -160,4 +412,362 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_close_reports_duration() {
+        let mut state = State::new(0, Duration::from_secs(0), Duration::from_secs(0), Arc::new(Metrics::new())).unwrap();
+        let opened = Utc::now();
+
+        match default_open_payload(22, 22, None) {
+            Payload::Open(connection) => state.transform(Payload::Open(OpenConnection { timestamp: opened.to_rfc3339(), .. connection })),
+            _ => panic!("expected an open"),
+        };
+
+        let closed = opened + chrono::Duration::milliseconds(1500);
+        let close = match default_close_payload() {
+            Payload::Close(connection) => state.transform(Payload::Close(CloseConnection { timestamp: closed.to_rfc3339(), .. connection })),
+            _ => panic!("expected a close"),
+        };
+
+        match close {
+            Some(Payload::Close(close)) => assert_eq!(Some(1500), close.duration_ms),
+            _ => panic!("the close should be emitted"),
+        }
+
+        // Without the open there is nothing to measure from.
+        match state.transform(default_close_payload()) {
+            Some(Payload::Close(close)) => assert_eq!(None, close.duration_ms),
+            _ => panic!("the close should be emitted"),
+        }
+    }
+
+    #[test]
+    fn test_hairpin_halves_single_connection() {
+        let mut state = State::new(0, Duration::from_secs(0), Duration::from_secs(0), Arc::new(Metrics::new())).unwrap();
+
+        let mut opens = Vec::new();
+        let mut closes = Vec::new();
+        for hash in 1..3 {
+            if let Payload::Open(connection) = default_open_payload(22, 22, None) {
+                opens.push(Payload::Open(OpenConnection { hash, flow: 7, .. connection }));
+            }
+
+            if let Payload::Close(connection) = default_close_payload() {
+                closes.push(Payload::Close(CloseConnection { hash, flow: 7, .. connection }));
+            }
+        }
+
+        let emitted : Vec<Payload> = opens.into_iter()
+            .chain(closes.into_iter())
+            .filter_map(|payload| state.transform(payload))
+            .collect();
+
+        assert_eq!(2, emitted.len());
+        match (&emitted[0], &emitted[1]) {
+            (Payload::Open(open), Payload::Close(close)) => {
+                assert_eq!(1, open.hash);
+                assert_eq!(Some(open.uuid), close.uuid);
+            },
+            _ => assert_eq!(true, false),
+        }
+        assert!(state.connections().is_empty());
+    }
+
+    #[test]
+    fn test_cap_evicts_oldest_first() {
+        let metrics = Arc::new(Metrics::new());
+        let mut state = State::new(3, Duration::from_secs(0), Duration::from_secs(0), metrics.clone()).unwrap();
+
+        for hash in 1..6 {
+            state.transform(open_payload(hash));
+        }
+
+        let mut connections = state.connections();
+        connections.sort();
+        assert_eq!(vec!(3, 4, 5), connections);
+        assert_eq!(2, metrics.evicted_connections());
+
+        // The evicted connection closes without its uuid, the tracked one keeps it.
+        match state.transform(close_payload(1)) {
+            Some(Payload::Close(close)) => assert!(close.uuid.is_none()),
+            _ => assert_eq!(true, false),
+        }
+
+        match state.transform(close_payload(3)) {
+            Some(Payload::Close(close)) => assert!(close.uuid.is_some()),
+            _ => assert_eq!(true, false),
+        }
+
+        state.transform(open_payload(6));
+        assert_eq!(2, metrics.evicted_connections());
+    }
+
+    #[test]
+    fn test_cap_covers_duplicates() {
+        let metrics = Arc::new(Metrics::new());
+        let mut state = State::new(2, Duration::from_secs(0), Duration::from_secs(0), metrics.clone()).unwrap();
+
+        // Hairpinned flows whose second half never closes.
+        for flow in 0..5 {
+            for hash in &[flow * 2 + 1, flow * 2 + 2] {
+                if let Payload::Open(connection) = open_payload(*hash) {
+                    state.transform(Payload::Open(OpenConnection { flow, .. connection }));
+                }
+            }
+
+            if let Payload::Close(connection) = close_payload(flow * 2 + 1) {
+                assert!(state.transform(Payload::Close(CloseConnection { flow, .. connection })).is_some());
+            }
+        }
+
+        assert_eq!(2, state.duplicates.len());
+        assert_eq!(2, state.order.len());
+        assert!(state.connections().is_empty());
+        assert_eq!(0, metrics.evicted_connections());
+
+        // The last ones are still dropped when they close.
+        if let Payload::Close(connection) = close_payload(10) {
+            assert!(state.transform(Payload::Close(CloseConnection { flow: 4, .. connection })).is_none());
+        }
+        assert_eq!(1, state.duplicates.len());
+    }
+
+    #[test]
+    fn test_no_cap() {
+        let metrics = Arc::new(Metrics::new());
+        let mut state = State::new(0, Duration::from_secs(0), Duration::from_secs(0), metrics.clone()).unwrap();
+
+        for hash in 1..100 {
+            state.transform(open_payload(hash));
+        }
+
+        assert_eq!(99, state.connections().len());
+        assert_eq!(0, metrics.evicted_connections());
+    }
+
+    #[test]
+    fn test_debounce_coalesces_reconnect() {
+        let mut state = State::new(0, Duration::from_secs(5), Duration::from_secs(0), Arc::new(Metrics::new())).unwrap();
+        let start = Instant::now();
+
+        let uuid = match state.transform_at(open_payload(1), start) {
+            Some(Payload::Open(open)) => open.uuid,
+            _ => panic!("the first open should be emitted"),
+        };
+
+        assert!(state.transform_at(close_payload(1), start).is_none());
+        assert!(state.transform_at(open_payload(2), start + Duration::from_secs(1)).is_none());
+        assert_eq!(vec!(2), state.connections());
+
+        assert!(state.transform_at(close_payload(2), start + Duration::from_secs(2)).is_none());
+        assert!(state.expired_at(start + Duration::from_secs(3)).is_empty());
+
+        let expired = state.expired_at(start + Duration::from_secs(8));
+        assert_eq!(1, expired.len());
+        match expired[0] {
+            Payload::Close(ref close) => {
+                assert_eq!(2, close.hash);
+                assert_eq!(Some(uuid), close.uuid);
+            },
+            _ => panic!("only closes are held back"),
+        }
+    }
+
+    #[test]
+    fn test_debounce_outside_window() {
+        let mut state = State::new(0, Duration::from_secs(5), Duration::from_secs(0), Arc::new(Metrics::new())).unwrap();
+        let start = Instant::now();
+
+        assert!(state.transform_at(open_payload(1), start).is_some());
+        assert!(state.transform_at(close_payload(1), start).is_none());
+        assert_eq!(1, state.expired_at(start + Duration::from_secs(6)).len());
+        assert!(state.transform_at(open_payload(2), start + Duration::from_secs(7)).is_some());
+
+        assert!(state.transform_at(close_payload(2), start + Duration::from_secs(8)).is_none());
+        assert_eq!(1, state.drain().len());
+        assert!(state.expired_at(start + Duration::from_secs(20)).is_empty());
+    }
+
+    fn udp_open_payload(hash : i64, source_port : u16) -> Payload {
+        match default_open_payload(source_port, 53, None) {
+            Payload::Open(connection) => Payload::Open(OpenConnection { hash, flow: hash, protocol: Protocol::UDP, .. connection }),
+            payload => payload,
+        }
+    }
+
+    fn udp_close_payload(hash : i64) -> Payload {
+        match default_close_payload() {
+            Payload::Close(connection) => Payload::Close(CloseConnection { hash, flow: hash, protocol: Protocol::UDP, .. connection }),
+            payload => payload,
+        }
+    }
+
+    #[test]
+    fn test_udp_aggregation() {
+        let mut state = State::new(0, Duration::from_secs(0), Duration::from_secs(30), Arc::new(Metrics::new())).unwrap();
+        let start = Instant::now();
+
+        let uuid = match state.transform_at(udp_open_payload(1, 40000), start) {
+            Some(Payload::Open(open)) => open.uuid,
+            _ => panic!("the first open should be emitted"),
+        };
+
+        assert!(state.transform_at(udp_open_payload(2, 40001), start + Duration::from_secs(1)).is_none());
+        assert!(state.transform_at(udp_open_payload(3, 40002), start + Duration::from_secs(2)).is_none());
+
+        assert!(state.transform_at(udp_close_payload(1), start + Duration::from_secs(3)).is_none());
+        assert!(state.transform_at(udp_close_payload(3), start + Duration::from_secs(4)).is_none());
+
+        match state.transform_at(udp_close_payload(2), start + Duration::from_secs(5)) {
+            Some(Payload::Close(close)) => assert_eq!(Some(uuid), close.uuid),
+            _ => panic!("the last close should be emitted"),
+        }
+
+        // Outside of the window a new logical flow is started.
+        assert!(state.transform_at(udp_open_payload(4, 40003), start + Duration::from_secs(6)).is_some());
+        assert!(state.transform_at(udp_open_payload(5, 40004), start + Duration::from_secs(40)).is_some());
+    }
+
+    #[test]
+    fn test_udp_per_flow() {
+        let mut state = State::new(0, Duration::from_secs(0), Duration::from_secs(0), Arc::new(Metrics::new())).unwrap();
+        let start = Instant::now();
+
+        for hash in 1..4 {
+            assert!(state.transform_at(udp_open_payload(hash, 40000 + hash as u16), start).is_some());
+        }
+
+        for hash in 1..4 {
+            assert!(state.transform_at(udp_close_payload(hash), start).is_some());
+        }
+    }
+
+    #[test]
+    fn test_udp_aggregation_ignores_tcp() {
+        let mut state = State::new(0, Duration::from_secs(0), Duration::from_secs(30), Arc::new(Metrics::new())).unwrap();
+        let start = Instant::now();
+
+        assert!(state.transform_at(open_payload(1), start).is_some());
+        assert!(state.transform_at(open_payload(2), start).is_some());
+    }
+
+    #[test]
+    fn test_close_only_single_record() {
+        let mut state = State::new(0, Duration::from_secs(0), Duration::from_secs(0), Arc::new(Metrics::new())).unwrap()
+            .close_only(Duration::from_secs(3600));
+        let opened = Utc::now();
+        let start = Instant::now();
+
+        let open = match default_open_payload(40000, 443, None) {
+            Payload::Open(connection) => Payload::Open(OpenConnection { timestamp: opened.to_rfc3339(), .. connection }),
+            _ => panic!("expected an open"),
+        };
+        assert!(state.transform_at(open, start).is_none());
+
+        let closed = (opened + chrono::Duration::milliseconds(250)).to_rfc3339();
+        let close = match default_close_payload() {
+            Payload::Close(connection) => Payload::Close(CloseConnection { timestamp: closed.clone(), .. connection }),
+            _ => panic!("expected a close"),
+        };
+
+        let record = match state.transform_at(close, start) {
+            Some(Payload::Connection(record)) => record,
+            _ => panic!("the close should bring the whole connection"),
+        };
+        assert_eq!(443, record.open.destination_port);
+        assert_eq!(String::from("hello"), record.open.username);
+        assert_eq!(Some(closed), record.closed);
+        assert_eq!(Some(250), record.duration_ms);
+
+        let json = serde_json::to_value(Payload::Connection(record)).unwrap();
+        assert_eq!("connection", json["event_type"]);
+        assert_eq!(443, json["destination_port"]);
+        assert_eq!(250, json["duration_ms"]);
+        assert!(json["status_flags"].is_array() && json["closed_status_flags"].is_array());
+
+        // Nothing is left to send.
+        assert!(state.drain().is_empty());
+    }
+
+    #[test]
+    fn test_close_only_flushed_at_ttl() {
+        let mut state = State::new(0, Duration::from_secs(0), Duration::from_secs(0), Arc::new(Metrics::new())).unwrap()
+            .close_only(Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert!(state.transform_at(open_payload(1), start).is_none());
+        assert!(state.transform_at(open_payload(2), start + Duration::from_secs(30)).is_none());
+        assert!(state.expired_at(start + Duration::from_secs(59)).is_empty());
+
+        match state.expired_at(start + Duration::from_secs(60)).as_slice() {
+            [Payload::Connection(record)] => {
+                assert_eq!(1, record.open.hash);
+                assert!(record.closed.is_none());
+            },
+            _ => panic!("only the first connection is past the ttl"),
+        }
+
+        // Both are still tracked, the late close goes out as it is.
+        match state.transform_at(close_payload(1), start + Duration::from_secs(61)) {
+            Some(Payload::Close(close)) => assert!(close.uuid.is_some()),
+            _ => panic!("the close should be emitted"),
+        }
+
+        assert_eq!(1, state.drain().len());
+    }
+
+    fn update_payload(hash : i64, flow : i64) -> Payload {
+        Payload::Update(UpdateConnection { hash, flow })
+    }
+
+    #[test]
+    fn test_updates_counted() {
+        let mut state = State::new(0, Duration::from_secs(0), Duration::from_secs(0), Arc::new(Metrics::new())).unwrap();
+
+        assert!(state.transform(open_payload(1)).is_some());
+        assert!(state.transform(update_payload(1, 1)).is_none());
+        // Updates for the other half of the flow count against the tracked one.
+        assert!(state.transform(update_payload(9, 1)).is_none());
+        // Nothing is tracked for these.
+        assert!(state.transform(update_payload(2, 2)).is_none());
+        assert_eq!(vec!(1), state.connections());
+
+        match state.transform(close_payload(1)) {
+            Some(Payload::Close(close)) => assert_eq!(2, close.update_count),
+            _ => panic!("the close should be emitted"),
+        }
+    }
+
+    #[test]
+    fn test_update_count() {
+        let mut state = State::new(0, Duration::from_secs(0), Duration::from_secs(0), Arc::new(Metrics::new())).unwrap();
+
+        let uuid = match state.transform(open_payload(1)) {
+            Some(Payload::Open(open)) => open.uuid,
+            _ => panic!("the first open should be emitted"),
+        };
+        for _ in 0..3 {
+            assert!(state.transform(open_payload(1)).is_none());
+        }
+        state.transform(open_payload(2));
+
+        match state.transform(close_payload(1)) {
+            Some(Payload::Close(close)) => {
+                assert_eq!(Some(uuid), close.uuid);
+                assert_eq!(3, close.update_count);
+            },
+            _ => panic!("the close should be emitted"),
+        }
+
+        match state.transform(close_payload(2)) {
+            Some(Payload::Close(close)) => assert_eq!(0, close.update_count),
+            _ => panic!("the close should be emitted"),
+        }
+
+        // Once closed, an open for the same connection starts again.
+        assert!(state.transform(open_payload(1)).is_some());
+        match state.transform(close_payload(1)) {
+            Some(Payload::Close(close)) => assert_eq!(0, close.update_count),
+            _ => panic!("the close should be emitted"),
+        }
+    }
 }
@@ -0,0 +1,123 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::collections::{ HashMap, HashSet };
+use std::net::Ipv4Addr;
+use std::time::{ Duration, Instant };
+use enums::Protocol;
+use parser::{ OpenConnection };
+use uuid::Uuid;
+use chrono::{ DateTime, FixedOffset };
+
+// UDP has no real connection, conntrack makes one up per source port, so
+// repeated flows (DNS look ups, NTP) are grouped on everything else.
+#[derive(PartialEq, Eq, Hash, Clone)]
+struct Key {
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    destination_port: u16,
+}
+
+struct Aggregate {
+    uuid: Uuid,
+    members: HashSet<i64>,
+    last_seen: Instant,
+    opened: Option<DateTime<FixedOffset>>,
+}
+
+// Folds UDP flows to the same destination seen within the window of each
+// other into one logical flow, with a single open and a single close.
+pub struct UdpAggregator {
+    window: Duration,
+    next: u64,
+    current: HashMap<Key, u64>,
+    aggregates: HashMap<u64, Aggregate>,
+    members: HashMap<i64, u64>,
+}
+
+impl UdpAggregator {
+    pub fn new(window: Duration) -> UdpAggregator {
+        UdpAggregator {
+            window,
+            next: 0,
+            current: HashMap::new(),
+            aggregates: HashMap::new(),
+            members: HashMap::new(),
+        }
+    }
+
+    // Returns the uuid and open time of the logical flow the open joined, None
+    // when the open starts a flow of its own and should be emitted.
+    pub fn open(&mut self, connection: &OpenConnection, opened: Option<DateTime<FixedOffset>>, now: Instant) -> Option<(Uuid, Option<DateTime<FixedOffset>>)> {
+        if self.window == Duration::from_secs(0) {
+            return None;
+        }
+
+        if connection.protocol != Protocol::UDP {
+            return None;
+        }
+
+        let key = Key {
+            source: connection.source,
+            destination: connection.destination,
+            destination_port: connection.destination_port,
+        };
+
+        if let Some(id) = self.current.get(&key) {
+            if let Some(aggregate) = self.aggregates.get_mut(id) {
+                if now.duration_since(aggregate.last_seen) <= self.window {
+                    aggregate.members.insert(connection.hash);
+                    aggregate.last_seen = now;
+                    self.members.insert(connection.hash, *id);
+                    return Some((aggregate.uuid, aggregate.opened));
+                }
+            }
+        }
+
+        let id = self.next;
+        self.next += 1;
+
+        let mut members = HashSet::new();
+        members.insert(connection.hash);
+        self.aggregates.insert(id, Aggregate { uuid: connection.uuid, members, last_seen: now, opened });
+        self.members.insert(connection.hash, id);
+        self.current.insert(key, id);
+        None
+    }
+
+    // Returns false while other flows of the logical flow are still open.
+    pub fn close(&mut self, hash: i64) -> bool {
+        let id = match self.members.remove(&hash) {
+            Some(x) => x,
+            None => return true,
+        };
+
+        let finished = match self.aggregates.get_mut(&id) {
+            Some(aggregate) => {
+                aggregate.members.remove(&hash);
+                aggregate.members.is_empty()
+            },
+            None => true,
+        };
+
+        if finished {
+            self.aggregates.remove(&id);
+            self.current.retain(|_, current| *current != id);
+        }
+
+        finished
+    }
+}
@@ -0,0 +1,180 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+use std::net::{ TcpStream, ToSocketAddrs };
+use std::time::Duration;
+use uuid::Uuid;
+use enums::Config;
+use filters::ProtocolMode;
+use outputs;
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct OutputSummary {
+    pub output: String,
+    pub target: Option<String>,
+    // None when the output has nothing to connect to.
+    pub reachable: Option<bool>,
+}
+
+// What the agent is going to do, logged once on start up.
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub name: Option<String>,
+    pub uuid: Option<Uuid>,
+    pub outputs: Vec<OutputSummary>,
+    pub filters: Vec<String>,
+    pub conntrack: bool,
+}
+
+// Best effort, only a TCP connect with a short timeout.
+fn reachable(address: &str) -> bool {
+    let addresses = match address.to_socket_addrs() {
+        Ok(x) => x,
+        Err(_err) => return false,
+    };
+
+    for address in addresses {
+        if TcpStream::connect_timeout(&address, Duration::from_secs(2)).is_ok() {
+            return true;
+        }
+    }
+
+    false
+}
+
+fn filters(config: &Config) -> Vec<String> {
+    let filters = &config.filters;
+    let mut active = Vec::new();
+
+    if filters.non_process_connections {
+        active.push(String::from("non_process_connections"));
+    }
+
+    if filters.dns_requests {
+        active.push(String::from("dns_requests"));
+    }
+
+    if filters.zerotrust_track_connections {
+        active.push(String::from("zerotrust_track_connections"));
+    }
+
+    if !filters.ignore_ports.is_empty() {
+        active.push(format!("ignore_ports {:?}", filters.ignore_ports));
+    }
+
+    if !filters.ignore_networks.is_empty() {
+        active.push(format!("ignore_networks {:?}", filters.ignore_networks));
+    }
+
+    if !filters.ignore_processes.is_empty() {
+        active.push(format!("ignore_processes {}", filters.ignore_processes.len()));
+    }
+
+    if !filters.protocols.is_empty() {
+        let mode = match filters.protocol_mode {
+            ProtocolMode::Allow => "allow",
+            ProtocolMode::Deny => "deny",
+        };
+        active.push(format!("protocols {} {:?}", mode, filters.protocols));
+    }
+
+    if let Some(ref rules_file) = filters.rules_file {
+        active.push(format!("rules_file {}", rules_file));
+    }
+
+    if !filters.ignore_domains.is_empty() {
+        active.push(format!("ignore_domains {:?}", filters.ignore_domains));
+    }
+
+    active
+}
+
+pub fn summary(config: &Config, conntrack: bool) -> Summary {
+    let outputs = outputs::targets(&config.outputs).into_iter()
+        .map(|(output, target)| {
+            let reachable = target.as_ref().map(|target| reachable(target));
+            OutputSummary { output, target, reachable }
+        })
+        .collect();
+
+    Summary {
+        name: config.name.clone(),
+        uuid: config.uuid,
+        outputs,
+        filters: filters(config),
+        conntrack,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::HttpServer;
+    use serde_yaml;
+    use serde_json;
+
+    #[test]
+    fn test_summary_two_outputs() {
+        let collector = HttpServer::start("200 OK");
+        let config : Config = serde_yaml::from_str(&format!("
+name: agent
+outputs:
+  elasticsearch: \"{}\"
+  redis: {{ url: \"redis://127.0.0.1:1\" }}
+filters:
+  non_process_connections: true
+  dns_requests: false
+  zerotrust_track_connections: false
+  ignore_ports: [ 22 ]
+", collector.url)).unwrap();
+
+        let summary = summary(&config, true);
+
+        assert_eq!(Some(String::from("agent")), summary.name);
+        assert!(summary.conntrack);
+        assert_eq!(vec!(
+            OutputSummary { output: String::from("elasticsearch"), target: Some(collector.address.clone()), reachable: Some(true) },
+            OutputSummary { output: String::from("redis"), target: Some(String::from("127.0.0.1:1")), reachable: Some(false) },
+        ), summary.outputs);
+        assert_eq!(vec!(String::from("non_process_connections"), String::from("ignore_ports [22]")), summary.filters);
+
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(2, json["outputs"].as_array().unwrap().len());
+    }
+
+    #[test]
+    fn test_summary_rules_and_domains() {
+        let config : Config = serde_yaml::from_str("
+outputs: {}
+filters:
+  non_process_connections: false
+  dns_requests: false
+  zerotrust_track_connections: false
+  rules_file: /etc/zerotrust-track/rules
+  ignore_domains: [ updates.example.com ]
+").unwrap();
+
+        assert_eq!(vec!(
+            String::from("rules_file /etc/zerotrust-track/rules"),
+            String::from("ignore_domains [\"updates.example.com\"]"),
+        ), filters(&config));
+    }
+
+    #[test]
+    fn test_reachable_unresolvable() {
+        assert!(!reachable("I am not a host:80"));
+    }
+}
@@ -0,0 +1,394 @@
+/*
+ *  Licensed under the Apache License, Version 2.0 (the "License");
+ *  you may not use this file except in compliance with the License.
+ *  You may obtain a copy of the License at
+ *
+ *  http://www.apache.org/licenses/LICENSE-2.0
+ *
+ *  Unless required by applicable law or agreed to in writing, software
+ *  distributed under the License is distributed on an "AS IS" BASIS,
+ *  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ *  See the License for the specific language governing permissions and
+ *  limitations under the License.
+ *
+ *
+ */
+
+// Builders shared by the tests, so the payloads and configs only have to be
+// updated in one place when a field is added.
+
+use std::io::{ BufRead, BufReader, Read, Write };
+use std::net::{ Ipv4Addr, TcpListener };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::mpsc::{ channel, Receiver, Sender };
+use std::thread;
+use std::time::Duration;
+use chrono::prelude::*;
+use native_tls::TlsAcceptor;
+use uuid::Uuid;
+use enums::{ AgentIdentity, Config, Protocol, Resolution };
+use enums;
+use filters::{ FiltersConfig, ProtocolMode };
+use filters;
+use outputs::OutputsConfig;
+use parser::{ Payload, OpenConnection, CloseConnection, Program, ParserConfig, generate_hash, SCHEMA_VERSION };
+use conn_track::ConntrackConfig;
+
+fn default_hash() -> i64 {
+    generate_hash(
+        &Protocol::TCP.to_string(),
+        &Ipv4Addr::new(127, 0, 0, 1),
+        &22,
+        &Ipv4Addr::new(127, 0, 0, 1),
+        &22
+    ) as i64
+}
+
+pub fn default_close_payload() -> Payload {
+    Payload::Close(CloseConnection {
+        hash: default_hash(),
+        flow: 0,
+        schema_version: SCHEMA_VERSION,
+        seq: 0,
+        uuid: None,
+        agent: Uuid::new_v4(),
+        timestamp: Utc::now().to_rfc3339(),
+        protocol: Protocol::TCP,
+        source_port : 22,
+        source: Ipv4Addr::new(127, 0, 0, 1),
+        destination_port : 22,
+        destination : Ipv4Addr::new(127, 0, 0, 1),
+        duration_ms: None,
+        close_reason: None,
+        status_flags: Vec::new(),
+        update_count: 0,
+    })
+}
+
+pub fn default_open_payload(
+    source_port : u16,
+    destination_port : u16,
+    program_details: Option<Program>
+) -> Payload {
+    Payload::Open(OpenConnection {
+        hash: default_hash(),
+        flow: 0,
+        schema_version: SCHEMA_VERSION,
+        seq: 0,
+        uuid: Uuid::new_v4(),
+        agent: Uuid::new_v4(),
+        timestamp: Utc::now().to_rfc3339(),
+        protocol: Protocol::TCP,
+        source_port,
+        source: Ipv4Addr::new(127, 0, 0, 1),
+        destination_port,
+        destination : Ipv4Addr::new(127, 0, 0, 1),
+        direction: None,
+        local_address: None,
+        local_port: None,
+        remote_address: None,
+        remote_port: None,
+        username : String::from("hello"),
+        username_resolved: true,
+        uid: 10,
+        program_details,
+        attribution_skipped: false,
+        status_flags: Vec::new(),
+        interface: None,
+        destination_service: None,
+        novel: None,
+        pre_existing: false,
+    })
+}
+
+// A process found by its socket, with nothing more than its name.
+pub fn program(process_name : &str) -> Program {
+    Program::new(1, 1, String::from(process_name), Resolution::Direct)
+}
+
+// An open with its own hash and flow, for tests which track several at once.
+pub fn open_payload(hash : i64) -> Payload {
+    match default_open_payload(22, 22, None) {
+        Payload::Open(connection) => Payload::Open(OpenConnection { hash, flow: hash, .. connection }),
+        payload => payload,
+    }
+}
+
+pub fn close_payload(hash : i64) -> Payload {
+    match default_close_payload() {
+        Payload::Close(connection) => Payload::Close(CloseConnection { hash, flow: hash, .. connection }),
+        payload => payload,
+    }
+}
+
+pub fn default_filters() -> FiltersConfig {
+    FiltersConfig {
+        non_process_connections: true,
+        dns_requests : true,
+        zerotrust_track_connections: true,
+        ignore_ports: Vec::new(),
+        ignore_networks: Vec::new(),
+        protocols: Vec::new(),
+        protocol_mode: ProtocolMode::Allow,
+        ignore_processes: Vec::new(),
+        rules_file: None,
+        rules_reload: filters::default_rules_reload(),
+        ignore_domains: Vec::new(),
+        ignore_domains_refresh: filters::default_ignore_domains_refresh(),
+    }
+}
+
+pub fn default_config() -> Config {
+    Config {
+        directory: None,
+        create_directory: false,
+        name: None,
+        use_hostname_as_name: enums::default_use_hostname_as_name(),
+        uuid: None,
+        persist_identity: enums::default_persist_identity(),
+        outputs : OutputsConfig {
+            zerotrust_endpoint: None,
+            zerotrust_client_certificate: None,
+            zerotrust_serialization: Default::default(),
+            syslog : Some(Vec::new()),
+            elasticsearch : None,
+            elasticsearch_pipeline : None,
+            elasticsearch_document_type : None,
+            mqtt : None,
+            nats : None,
+            postgres : None,
+            loki : None,
+            redis : None,
+            webhook : None,
+            parquet : None,
+            http : Default::default(),
+        },
+        filters: default_filters(),
+        parser: ParserConfig::default(),
+        channel_depth: enums::default_channel_depth(),
+        log_sample_rate: enums::default_log_sample_rate(),
+        conntrack: ConntrackConfig::default(),
+        max_connections: enums::default_max_connections(),
+        debounce_window: 0,
+        udp_aggregate_window: 0,
+        emit_mode: Default::default(),
+        emit_ttl: enums::default_emit_ttl(),
+        dry_run: false,
+        dead_letter: None,
+        listeners: Default::default(),
+        rollup: Default::default(),
+        baseline: Default::default(),
+        live: Default::default(),
+        pseudonymize: Default::default(),
+    }
+}
+
+pub fn identity() -> AgentIdentity {
+    AgentIdentity {
+        name: String::from("gerald"),
+        uuid: Uuid::new_v4(),
+        hostname: String::from("localhost"),
+    }
+}
+
+// An address which was free a moment ago, for a server under test to listen
+// on.
+pub fn free_address() -> String {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string()
+}
+
+// The path and body of a request made to an HttpServer. The path is the
+// whole url when the request was made through a proxy.
+#[derive(Debug)]
+pub struct Request {
+    pub path : String,
+    pub body : String,
+}
+
+// What the HttpServer keeps count of.
+#[derive(Default)]
+struct Counts {
+    connections : AtomicUsize,
+    active : AtomicUsize,
+    most : AtomicUsize,
+}
+
+// A collector for the HTTP outputs to send to, every request is answered with
+// the status given and handed back. Connections are kept open, as the
+// clients reuse them.
+pub struct HttpServer {
+    pub url : String,
+    // The host and port the server is listening on.
+    pub address : String,
+    requests : Receiver<Request>,
+    counts : Arc<Counts>,
+}
+
+impl HttpServer {
+    pub fn start(status : &'static str) -> HttpServer {
+        HttpServer::with_delay(status, Duration::from_secs(0))
+    }
+
+    // Takes delay over every request before answering it.
+    pub fn with_delay(status : &'static str, delay : Duration) -> HttpServer {
+        HttpServer::listen(status, delay, None)
+    }
+
+    // Serves over TLS, at localhost so the certificate can match. Clients
+    // which don't trust the certificate give up during the handshake.
+    pub fn start_tls(status : &'static str, acceptor : TlsAcceptor) -> HttpServer {
+        HttpServer::listen(status, Duration::from_secs(0), Some(acceptor))
+    }
+
+    fn listen(status : &'static str, delay : Duration, acceptor : Option<TlsAcceptor>) -> HttpServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let url = match acceptor {
+            Some(_) => format!("https://localhost:{}", port),
+            None => format!("http://127.0.0.1:{}", port),
+        };
+        let (tx, requests) = channel();
+        let counts = Arc::new(Counts::default());
+
+        let server_counts = counts.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                server_counts.connections.fetch_add(1, Ordering::SeqCst);
+                let (tx, counts, acceptor) = (tx.clone(), server_counts.clone(), acceptor.clone());
+                thread::spawn(move || {
+                    let stream : Box<dyn ReadWrite> = match acceptor {
+                        Some(acceptor) => match acceptor.accept(stream) {
+                            Ok(x) => Box::new(x),
+                            Err(_) => return,
+                        },
+                        None => Box::new(stream),
+                    };
+                    serve(stream, status, delay, &tx, &counts);
+                });
+            }
+        });
+
+        HttpServer {
+            url,
+            address: format!("127.0.0.1:{}", port),
+            requests,
+            counts,
+        }
+    }
+
+    // The next request, waiting for up to 10 seconds.
+    pub fn request(&self) -> Request {
+        self.requests.recv_timeout(Duration::from_secs(10)).unwrap()
+    }
+
+    // The requests answered so far which haven't been handed back yet.
+    pub fn received(&self) -> Vec<Request> {
+        self.requests.try_iter().collect()
+    }
+
+    // How many connections the clients opened.
+    pub fn connections(&self) -> usize {
+        self.counts.connections.load(Ordering::SeqCst)
+    }
+
+    // The most requests which were being handled at once.
+    pub fn most_at_once(&self) -> usize {
+        self.counts.most.load(Ordering::SeqCst)
+    }
+}
+
+// A TCP or TLS stream.
+trait ReadWrite : Read + Write {}
+
+impl<T : Read + Write> ReadWrite for T {}
+
+// Answers the requests on the connection until the client closes it. Each
+// request is handed back before it is answered, so a client which has its
+// answer finds the request there.
+fn serve(stream : Box<dyn ReadWrite>, status : &str, delay : Duration, tx : &Sender<Request>, counts : &Counts) {
+    let mut reader = BufReader::new(stream);
+    while let Some(request) = read_request(&mut reader) {
+        let now = counts.active.fetch_add(1, Ordering::SeqCst) + 1;
+        counts.most.fetch_max(now, Ordering::SeqCst);
+        thread::sleep(delay);
+        counts.active.fetch_sub(1, Ordering::SeqCst);
+
+        let _ = tx.send(request);
+        let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+        if reader.get_mut().write_all(response.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+// None once the client has closed the connection.
+fn read_request<T : Read>(reader : &mut BufReader<T>) -> Option<Request> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+        return None;
+    }
+    let path = line.split_whitespace().nth(1).unwrap_or("").to_string();
+
+    let mut length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return None;
+        }
+
+        let line = line.trim().to_lowercase();
+        if line.is_empty() {
+            break;
+        }
+
+        if let Some(value) = line.strip_prefix("content-length:") {
+            length = value.trim().parse().unwrap();
+        }
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    Some(Request { path, body: String::from_utf8(body).unwrap() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+    use serde_yaml;
+
+    #[test]
+    fn test_default_payloads_match() {
+        match (default_open_payload(22, 22, None), default_close_payload()) {
+            (Payload::Open(open), Payload::Close(close)) => {
+                assert_eq!(open.hash, close.hash);
+                assert_eq!(SCHEMA_VERSION, open.schema_version);
+                assert_eq!(SCHEMA_VERSION, close.schema_version);
+            },
+            _ => panic!("the builders made the wrong payloads"),
+        }
+    }
+
+    #[test]
+    fn test_payloads_with_hash() {
+        match (open_payload(7), close_payload(7)) {
+            (Payload::Open(open), Payload::Close(close)) => {
+                assert_eq!((7, 7), (open.hash, open.flow));
+                assert_eq!((7, 7), (close.hash, close.flow));
+            },
+            _ => panic!("the builders made the wrong payloads"),
+        }
+
+        assert!(serde_json::to_string(&open_payload(7)).unwrap().contains("\"event_type\":\"open\""));
+    }
+
+    #[test]
+    fn test_default_config_round_trips() {
+        let yaml = serde_yaml::to_string(&default_config()).unwrap();
+        let config : Config = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(Some(0), config.outputs.syslog.map(|syslog| syslog.len()));
+        assert!(config.filters.non_process_connections);
+    }
+}